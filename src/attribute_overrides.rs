@@ -0,0 +1,168 @@
+//! Applies the `attribute_aliases` and `enum_values` device overrides to everything the hub
+//! describes, so the rest of the bridge only ever sees the overridden attributes.
+use crate::config::Config;
+use crate::controller::{
+    AttributeId, AttributeValue, DeviceAttribute, DeviceController, DeviceId, FaultConfig, Group,
+    GroupId, LongDevice, MaintenanceProgress, PairingResult, Scene, ShortDevice, ZwaveOperation,
+};
+use crate::stats::OperationStats;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+pub struct AttributeOverridesController {
+    inner: Arc<dyn DeviceController>,
+    config: Config,
+}
+
+impl AttributeOverridesController {
+    pub fn new(inner: Arc<dyn DeviceController>, config: &Config) -> AttributeOverridesController {
+        AttributeOverridesController {
+            inner,
+            config: config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl DeviceController for AttributeOverridesController {
+    async fn list(&self) -> Result<Vec<ShortDevice>, Box<dyn Error>> {
+        self.inner.list().await
+    }
+
+    async fn describe(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        let mut device = self.inner.describe(master_id).await?;
+        self.config.apply_attribute_overrides(&mut device);
+        Ok(device)
+    }
+
+    async fn set(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        self.inner.set(master_id, attribute_id, value).await
+    }
+
+    async fn set_many(
+        &self,
+        master_id: DeviceId,
+        values: &[(AttributeId, AttributeValue)],
+    ) -> Result<(), Box<dyn Error>> {
+        self.inner.set_many(master_id, values).await
+    }
+
+    async fn attribute_schema(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        let mut device = self.inner.attribute_schema(master_id).await?;
+        self.config.apply_attribute_overrides(&mut device);
+        Ok(device)
+    }
+
+    async fn get(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+    ) -> Result<DeviceAttribute, Box<dyn Error>> {
+        self.inner.get(master_id, attribute_id).await
+    }
+
+    fn stats(&self) -> BTreeMap<String, OperationStats> {
+        self.inner.stats()
+    }
+
+    async fn forget_cached(&self, master_id: DeviceId) {
+        self.inner.forget_cached(master_id).await
+    }
+
+    async fn describe_raw(&self, master_id: DeviceId) -> Result<String, Box<dyn Error>> {
+        self.inner.describe_raw(master_id).await
+    }
+
+    async fn faults(&self) -> Result<FaultConfig, Box<dyn Error>> {
+        self.inner.faults().await
+    }
+
+    async fn set_faults(&self, faults: FaultConfig) -> Result<(), Box<dyn Error>> {
+        self.inner.set_faults(faults).await
+    }
+
+    async fn start_pairing(
+        &self,
+        radio: &str,
+        timeout: Duration,
+    ) -> Result<PairingResult, Box<dyn Error>> {
+        self.inner.start_pairing(radio, timeout).await
+    }
+
+    async fn remove_device(&self, master_id: DeviceId) -> Result<PairingResult, Box<dyn Error>> {
+        self.inner.remove_device(master_id).await
+    }
+
+    async fn rename(&self, master_id: DeviceId, name: &str) -> Result<(), Box<dyn Error>> {
+        self.inner.rename(master_id, name).await
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, Box<dyn Error>> {
+        self.inner.list_groups().await
+    }
+
+    async fn set_group(
+        &self,
+        group_id: GroupId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        self.inner.set_group(group_id, attribute_id, value).await
+    }
+
+    async fn zwave_maintenance(
+        &self,
+        operation: ZwaveOperation,
+        progress: &Mutex<MaintenanceProgress>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.inner.zwave_maintenance(operation, progress).await
+    }
+
+    async fn activate_scene(&self, scene: &Scene) -> Result<(), Box<dyn Error>> {
+        self.inner.activate_scene(scene).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::FakeController;
+    use crate::overrides::DeviceOverride;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn aliases_applied() {
+        let config = test_utils::config();
+        config.set_overrides(
+            vec![(
+                4,
+                DeviceOverride {
+                    attribute_aliases: Some(
+                        vec![("On_Off".to_string(), "Power".to_string())]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    ..DeviceOverride::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let controller =
+            AttributeOverridesController::new(Arc::new(FakeController::new()), &config);
+
+        let device = controller.describe(4).await.unwrap();
+        assert!(device.attribute("Power").is_some());
+        assert!(device.attribute("On_Off").is_none());
+        assert_eq!(device, controller.attribute_schema(4).await.unwrap());
+    }
+}
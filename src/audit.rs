@@ -0,0 +1,143 @@
+use crate::controller::{AttributeId, AttributeValue, DeviceAttribute, DeviceId, GroupId};
+use crate::utils::unix_timestamp;
+use async_channel::Sender;
+use regex::Regex;
+use serde::Serialize;
+use slog::{debug, info};
+use std::sync::RwLock;
+
+lazy_static! {
+    /// Where to mirror audit events (e.g. `bridge/audit`), if anywhere.
+    static ref AUDIT_SINK: RwLock<Option<Sender<AuditEvent>>> = RwLock::new(None);
+    /// Attributes whose values shouldn't end up in logs, e.g. lock user codes.
+    static ref SECRET_ATTRIBUTE_REGEX: Regex =
+        Regex::new(r"(?i)(^|_)(pin|code|user_?code|password|secret)(_?[0-9]+)?($|_)").unwrap();
+}
+
+const REDACTED: &str = "<redacted>";
+
+/// A record of a command that was (or wasn't) sent to a device or group.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: u64,
+    pub source: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<DeviceId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<GroupId>,
+    pub attribute_id: AttributeId,
+    pub attribute: String,
+    pub value: AttributeValue,
+    pub allowed: bool,
+    pub reason: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        source: &'static str,
+        device_id: DeviceId,
+        attribute: &DeviceAttribute,
+        value: &AttributeValue,
+    ) -> AuditEvent {
+        let value = if SECRET_ATTRIBUTE_REGEX.is_match(&attribute.description) {
+            AttributeValue::String(REDACTED.to_string())
+        } else {
+            value.clone()
+        };
+        AuditEvent {
+            timestamp: unix_timestamp(),
+            source,
+            device_id: Some(device_id),
+            group_id: None,
+            attribute_id: attribute.id,
+            attribute: attribute.description.clone(),
+            value,
+            allowed: true,
+            reason: None,
+        }
+    }
+
+    /// A command to a group. Groups have no attribute names, so `attribute` is the id.
+    pub fn group(
+        source: &'static str,
+        group_id: GroupId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> AuditEvent {
+        AuditEvent {
+            timestamp: unix_timestamp(),
+            source,
+            device_id: None,
+            group_id: Some(group_id),
+            attribute_id,
+            attribute: attribute_id.to_string(),
+            value: value.clone(),
+            allowed: true,
+            reason: None,
+        }
+    }
+
+    pub fn denied(mut self, reason: &str) -> AuditEvent {
+        self.allowed = false;
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+/// Mirrors every recorded event to `sender` from now on.
+pub fn set_sink(sender: Sender<AuditEvent>) {
+    *AUDIT_SINK.write().unwrap_or_else(|e| e.into_inner()) = Some(sender);
+}
+
+pub fn record(event: &AuditEvent) {
+    info!(slog_scope::logger(), "audit"; "event" => serde_json::to_string(event).unwrap_or_default());
+    if let Some(sink) = AUDIT_SINK
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+    {
+        if let Err(e) = sink.try_send(event.clone()) {
+            debug!(slog_scope::logger(), "dropped_audit_event"; "error" => ?e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::AttributeType;
+    use crate::test_utils::attribute;
+
+    #[test]
+    fn redacts_secrets() {
+        let value = AttributeValue::String("1234".to_string());
+        for name in ["UserCode", "User_Code_2", "PIN", "Master_Password"].iter() {
+            assert_eq!(
+                AttributeValue::String(REDACTED.to_string()),
+                AuditEvent::new(
+                    "mqtt",
+                    2,
+                    &attribute(3, name, AttributeType::String),
+                    &value
+                )
+                .value,
+                "{}",
+                name
+            );
+        }
+        for name in ["On_Off", "Spinning", "BarrierOperator"].iter() {
+            assert_eq!(
+                value,
+                AuditEvent::new(
+                    "mqtt",
+                    2,
+                    &attribute(3, name, AttributeType::String),
+                    &value
+                )
+                .value,
+                "{}",
+                name
+            );
+        }
+    }
+}
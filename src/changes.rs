@@ -0,0 +1,95 @@
+//! When each attribute last changed value, as opposed to when it was last polled. Only changes
+//! seen by this process count, so after a restart everything starts out as "changed" at the first
+//! poll.
+use crate::controller::{DeviceId, LongDevice};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Default)]
+pub struct ChangeTracker {
+    devices: HashMap<DeviceId, HashMap<String, (Value, u64)>>,
+}
+
+impl ChangeTracker {
+    pub fn new() -> ChangeTracker {
+        Default::default()
+    }
+
+    /// Records the device's current attribute values and returns the ones that changed since
+    /// the last update. Attributes seen for the first time are recorded but not returned.
+    pub fn update(&mut self, device: &LongDevice, now: u64) -> Vec<(String, Value)> {
+        let known = self.devices.entry(device.id).or_default();
+        let mut changed = Vec::new();
+        for attribute in device.attributes.iter() {
+            let name = attribute.canonical_name();
+            let value = attribute
+                .setting_value
+                .or(&attribute.current_value)
+                .to_json();
+            match known.get(&name) {
+                Some((last, _)) if *last == value => {}
+                Some(_) => {
+                    known.insert(name.clone(), (value.clone(), now));
+                    changed.push((name, value));
+                }
+                None => {
+                    known.insert(name, (value, now));
+                }
+            }
+        }
+        changed
+    }
+
+    /// Unix timestamps of the last change of each attribute, by canonical attribute name.
+    pub fn last_changed(&self, device_id: DeviceId) -> BTreeMap<String, u64> {
+        self.devices
+            .get(&device_id)
+            .map(|attributes| {
+                attributes
+                    .iter()
+                    .map(|(name, (_, at))| (name.clone(), *at))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn remove(&mut self, device_id: DeviceId) {
+        self.devices.remove(&device_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{AttributeType, AttributeValue, DeviceAttribute};
+    use crate::test_utils::{self, attribute};
+    use serde_json::json;
+
+    fn device(level: u8) -> LongDevice {
+        LongDevice {
+            id: 3,
+            name: "Lamp".to_string(),
+            ..test_utils::device(vec![DeviceAttribute {
+                current_value: AttributeValue::UInt8(level),
+                ..attribute(1, "Level", AttributeType::UInt8)
+            }])
+        }
+    }
+
+    #[test]
+    fn tracks_changes() {
+        let mut tracker = ChangeTracker::new();
+        assert!(tracker.update(&device(10), 100).is_empty());
+        assert!(tracker.update(&device(10), 200).is_empty());
+        assert_eq!(100, tracker.last_changed(3)["Level"]);
+
+        assert_eq!(
+            vec![("Level".to_string(), json!(20))],
+            tracker.update(&device(20), 300)
+        );
+        assert_eq!(300, tracker.last_changed(3)["Level"]);
+
+        tracker.remove(3);
+        assert!(tracker.last_changed(3).is_empty());
+    }
+}
@@ -0,0 +1,431 @@
+use crate::controller::{
+    take_attribute, AttributeId, AttributeValue, DeviceAttribute, DeviceController, DeviceId,
+    FaultConfig, Group, GroupId, LongDevice, MaintenanceProgress, PairingResult, ShortDevice,
+    ZwaveOperation,
+};
+use crate::stats::OperationStats;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, simple_error};
+use slog::{error, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// A virtual device made of several physical ones, e.g. a "Living Room" light made of three
+/// bulbs. It has the attributes all its members have, and setting one sets it on every member.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CompositeDevice {
+    /// Must not be used by a device on the hub.
+    pub id: DeviceId,
+    pub name: String,
+    pub members: Vec<DeviceId>,
+}
+
+/// Adds composite devices to another controller's devices.
+pub struct CompositeController {
+    inner: Arc<dyn DeviceController>,
+    composites: Vec<CompositeDevice>,
+    /// Composites whose id turned out to be a device's on the hub. The device wins.
+    shadowed: Mutex<HashSet<DeviceId>>,
+}
+
+impl CompositeController {
+    pub fn new(
+        inner: Arc<dyn DeviceController>,
+        composites: Vec<CompositeDevice>,
+    ) -> Result<CompositeController, Box<dyn Error>> {
+        let ids = composites.iter().map(|c| c.id).collect::<HashSet<_>>();
+        if ids.len() != composites.len() {
+            bail!("Composite device ids must be unique")
+        }
+        for composite in composites.iter() {
+            if composite.members.is_empty() {
+                bail!("Composite device {} has no members", composite.id)
+            }
+            if let Some(member) = composite.members.iter().find(|m| ids.contains(m)) {
+                bail!(
+                    "Composite device {} can't have composite device {} as a member",
+                    composite.id,
+                    member
+                )
+            }
+        }
+        Ok(CompositeController {
+            inner,
+            composites,
+            shadowed: Mutex::new(HashSet::new()),
+        })
+    }
+
+    async fn composite(&self, device_id: DeviceId) -> Option<&CompositeDevice> {
+        if self.shadowed.lock().await.contains(&device_id) {
+            return None;
+        }
+        self.composites.iter().find(|c| c.id == device_id)
+    }
+}
+
+// Members that disagree (e.g. one bulb was turned off by hand) report the value of the first one
+// that's on.
+fn merge_values(values: &[&AttributeValue]) -> AttributeValue {
+    if values.iter().all(|v| *v == values[0]) {
+        return values[0].clone();
+    }
+    (*values.iter().find(|v| v.is_truthy()).unwrap_or(&values[0])).clone()
+}
+
+/// The composite device from its members' devices: the attributes (and ids) of the first member
+/// that every other member has too.
+fn merge_devices(composite: &CompositeDevice, members: &[LongDevice]) -> LongDevice {
+    let first = &members[0];
+    let attributes = first
+        .attributes
+        .iter()
+        .filter_map(|attribute| {
+            let name = attribute.canonical_name();
+            let others = members[1..]
+                .iter()
+                .map(|m| m.attribute(&name))
+                .collect::<Option<Vec<_>>>()?;
+            let all = std::iter::once(attribute).chain(others).collect::<Vec<_>>();
+            let mut merged = attribute.clone();
+            merged.current_value =
+                merge_values(&all.iter().map(|a| &a.current_value).collect::<Vec<_>>());
+            merged.setting_value =
+                merge_values(&all.iter().map(|a| &a.setting_value).collect::<Vec<_>>());
+            merged.supports_write = all.iter().all(|a| a.supports_write);
+            Some(merged)
+        })
+        .collect();
+    // An offline member makes the whole device show up as such.
+    let status = members
+        .iter()
+        .map(|m| &m.status)
+        .find(|s| !s.is_empty() && s.as_str() != "ONLINE")
+        .unwrap_or(&first.status)
+        .clone();
+    LongDevice {
+        gang_id: None,
+        generic_device_type: first.generic_device_type,
+        specific_device_type: first.specific_device_type,
+        manufacturer_id: None,
+        product_type: None,
+        product_number: None,
+        interconnect: None,
+        battery_level: None,
+        wakeup_interval: None,
+        failed_tx: None,
+        seconds_since_rx: None,
+        polling_period: None,
+        id: composite.id,
+        status,
+        name: composite.name.clone(),
+        attributes,
+    }
+}
+
+#[async_trait]
+impl DeviceController for CompositeController {
+    async fn list(&self) -> Result<Vec<ShortDevice>, Box<dyn Error>> {
+        let mut devices = self.inner.list().await?;
+        let mut shadowed = self.shadowed.lock().await;
+        for composite in self.composites.iter() {
+            if devices.iter().any(|d| d.id == composite.id) {
+                if shadowed.insert(composite.id) {
+                    error!(slog_scope::logger(), "composite_device_id_taken"; "device_id" => composite.id);
+                }
+                continue;
+            }
+            shadowed.remove(&composite.id);
+            devices.push(ShortDevice {
+                id: composite.id,
+                name: composite.name.clone(),
+                interconnect: None,
+            });
+        }
+        Ok(devices)
+    }
+
+    async fn describe(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        let composite = match self.composite(master_id).await {
+            Some(composite) => composite,
+            None => return self.inner.describe(master_id).await,
+        };
+        let mut members = Vec::with_capacity(composite.members.len());
+        for member in composite.members.iter() {
+            members.push(self.inner.describe(*member).await?);
+        }
+        Ok(merge_devices(composite, &members))
+    }
+
+    async fn attribute_schema(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        let composite = match self.composite(master_id).await {
+            Some(composite) => composite,
+            None => return self.inner.attribute_schema(master_id).await,
+        };
+        let mut members = Vec::with_capacity(composite.members.len());
+        for member in composite.members.iter() {
+            members.push(self.inner.attribute_schema(*member).await?);
+        }
+        Ok(merge_devices(composite, &members))
+    }
+
+    async fn set(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        self.set_many(master_id, &[(attribute_id, value.clone())])
+            .await
+    }
+
+    /// Sets the attributes on every member, by name: members' attribute ids may differ. A
+    /// member failing doesn't stop the rest.
+    async fn set_many(
+        &self,
+        master_id: DeviceId,
+        values: &[(AttributeId, AttributeValue)],
+    ) -> Result<(), Box<dyn Error>> {
+        let composite = match self.composite(master_id).await {
+            Some(composite) => composite,
+            None => return self.inner.set_many(master_id, values).await,
+        };
+        let schema = self.attribute_schema(master_id).await?;
+        let names = values
+            .iter()
+            .map(|(attribute_id, value)| {
+                let attribute = schema
+                    .attributes
+                    .iter()
+                    .find(|a| a.id == *attribute_id)
+                    .ok_or_else(|| {
+                        simple_error!("No attribute {} on device {}", attribute_id, master_id)
+                    })?;
+                Ok((attribute.canonical_name(), value.clone()))
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+        let mut failures = Vec::new();
+        for member in composite.members.iter() {
+            let member_schema = match self.inner.attribute_schema(*member).await {
+                Ok(schema) => schema,
+                Err(e) => {
+                    failures.push(format!("{}: {}", member, e));
+                    continue;
+                }
+            };
+            let member_values = names
+                .iter()
+                .filter_map(|(name, value)| {
+                    member_schema
+                        .attribute(name)
+                        .map(|attribute| (attribute.id, value.clone()))
+                })
+                .collect::<Vec<_>>();
+            if let Err(e) = self.inner.set_many(*member, &member_values).await {
+                warn!(slog_scope::logger(), "composite_member_set_failed"; "device_id" => master_id, "member" => member, "error" => ?e);
+                failures.push(format!("{}: {}", member, e));
+            }
+        }
+        if !failures.is_empty() {
+            bail!(
+                "Setting composite device {} failed for some members: {}",
+                master_id,
+                failures.join(", ")
+            )
+        }
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+    ) -> Result<DeviceAttribute, Box<dyn Error>> {
+        if self.composite(master_id).await.is_none() {
+            return self.inner.get(master_id, attribute_id).await;
+        }
+        take_attribute(self.describe(master_id).await?, attribute_id)
+    }
+
+    fn stats(&self) -> BTreeMap<String, OperationStats> {
+        self.inner.stats()
+    }
+
+    async fn forget_cached(&self, master_id: DeviceId) {
+        match self.composite(master_id).await {
+            Some(composite) => {
+                for member in composite.members.iter() {
+                    self.inner.forget_cached(*member).await;
+                }
+            }
+            None => self.inner.forget_cached(master_id).await,
+        }
+    }
+
+    async fn describe_raw(&self, master_id: DeviceId) -> Result<String, Box<dyn Error>> {
+        if self.composite(master_id).await.is_some() {
+            bail!("Device {} is a composite device", master_id)
+        }
+        self.inner.describe_raw(master_id).await
+    }
+
+    async fn faults(&self) -> Result<FaultConfig, Box<dyn Error>> {
+        self.inner.faults().await
+    }
+
+    async fn set_faults(&self, faults: FaultConfig) -> Result<(), Box<dyn Error>> {
+        self.inner.set_faults(faults).await
+    }
+
+    async fn start_pairing(
+        &self,
+        radio: &str,
+        timeout: Duration,
+    ) -> Result<PairingResult, Box<dyn Error>> {
+        self.inner.start_pairing(radio, timeout).await
+    }
+
+    async fn remove_device(&self, master_id: DeviceId) -> Result<PairingResult, Box<dyn Error>> {
+        if self.composite(master_id).await.is_some() {
+            bail!("Device {} is a composite device", master_id)
+        }
+        self.inner.remove_device(master_id).await
+    }
+
+    async fn rename(&self, master_id: DeviceId, name: &str) -> Result<(), Box<dyn Error>> {
+        if self.composite(master_id).await.is_some() {
+            bail!("Composite devices are named in their config file")
+        }
+        self.inner.rename(master_id, name).await
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, Box<dyn Error>> {
+        self.inner.list_groups().await
+    }
+
+    async fn set_group(
+        &self,
+        group_id: GroupId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        self.inner.set_group(group_id, attribute_id, value).await
+    }
+
+    async fn zwave_maintenance(
+        &self,
+        operation: ZwaveOperation,
+        progress: &Mutex<MaintenanceProgress>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.inner.zwave_maintenance(operation, progress).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::FakeController;
+
+    #[tokio::test]
+    async fn composite_light() {
+        let fake = Arc::new(FakeController::new());
+        let paired = fake
+            .start_pairing("zwave", Duration::from_secs(1))
+            .await
+            .unwrap()
+            .added[0]
+            .id;
+        let controller = CompositeController::new(
+            fake.clone(),
+            vec![CompositeDevice {
+                id: 100,
+                name: "Living Room".to_string(),
+                members: vec![4, paired],
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some("Living Room"),
+            controller
+                .list()
+                .await
+                .unwrap()
+                .iter()
+                .find(|d| d.id == 100)
+                .map(|d| d.name.as_str())
+        );
+        let device = controller.describe(100).await.unwrap();
+        assert_eq!(
+            vec!["On_Off"],
+            device
+                .attributes
+                .iter()
+                .map(|a| a.description.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            AttributeValue::Bool(false),
+            device.attribute("On_Off").unwrap().current_value
+        );
+
+        // One member on is enough for the composite to be on.
+        let on_off = fake
+            .describe(paired)
+            .await
+            .unwrap()
+            .attribute("On_Off")
+            .unwrap()
+            .id;
+        fake.set(paired, on_off, &AttributeValue::Bool(true))
+            .await
+            .unwrap();
+        let device = controller.describe(100).await.unwrap();
+        let attribute = device.attribute("On_Off").unwrap();
+        assert_eq!(AttributeValue::Bool(true), attribute.current_value);
+
+        controller
+            .set(100, attribute.id, &AttributeValue::Bool(false))
+            .await
+            .unwrap();
+        for member in [4, paired].iter() {
+            assert_eq!(
+                AttributeValue::Bool(false),
+                fake.describe(*member)
+                    .await
+                    .unwrap()
+                    .attribute("On_Off")
+                    .unwrap()
+                    .current_value
+            );
+        }
+        assert!(controller.rename(100, "Den").await.is_err());
+    }
+
+    #[test]
+    fn validation() {
+        let composite = |id, members| CompositeDevice {
+            id,
+            name: "Composite".to_string(),
+            members,
+        };
+        let fake = Arc::new(FakeController::new());
+        assert!(CompositeController::new(fake.clone(), vec![composite(100, vec![])]).is_err());
+        assert!(CompositeController::new(
+            fake.clone(),
+            vec![composite(100, vec![2]), composite(100, vec![4])]
+        )
+        .is_err());
+        assert!(CompositeController::new(
+            fake.clone(),
+            vec![composite(100, vec![2]), composite(101, vec![100])]
+        )
+        .is_err());
+        assert!(CompositeController::new(fake, vec![composite(100, vec![2, 4])]).is_ok());
+    }
+}
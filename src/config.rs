@@ -1,12 +1,120 @@
-use crate::config::TopicType::{DiscoveryTopic, SetAttributeTopic, SetJsonTopic, StatusTopic};
-use crate::controller::{AttributeId, DeviceId};
+use crate::config::TopicType::{
+    ActionTopic, AttributesTopic, BridgeTopic, DiscoveryEntityTopic, DiscoveryTopic, ExternalTopic,
+    GroupSetTopic, GroupStatusTopic, HomieSetTopic, SceneSetTopic, SetAttributeTopic, SetJsonTopic,
+    StatusTopic,
+};
+use crate::controller::{
+    canonical_attribute_name, AttributeId, DeviceId, GroupId, LongDevice, Scene, SceneId,
+};
+use crate::homie;
+use crate::overrides::{ComponentOverride, DeviceOverride};
 use crate::utils::Numberish;
 use regex::Regex;
-use rumqttc::MqttOptions;
-use simple_error::bail;
+use rumqttc::{MqttOptions, QoS};
+use serde::Deserialize;
+use simple_error::{bail, simple_error};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt;
 use std::ops::Add;
+use std::sync::{Arc, RwLock};
+
+pub const DEFAULT_STATUS_TOPIC: &str = "{prefix}{id}/status";
+pub const DEFAULT_SET_TOPIC: &str = "{prefix}{id}/set";
+pub const DEFAULT_SET_ATTRIBUTE_TOPIC: &str = "{prefix}{id}/{attribute_id}/set";
+
+/// A topic layout like `{prefix}{name}/{id}/state`. The same template is used to build topics
+/// and to parse incoming ones; `{name}` is ignored when parsing.
+#[derive(Debug, Clone)]
+pub struct TopicTemplate {
+    /// The template with `{prefix}` already filled in.
+    template: String,
+    regex: Regex,
+}
+
+impl TopicTemplate {
+    pub fn new(
+        template: &str,
+        prefix: &str,
+        with_attribute: bool,
+    ) -> Result<TopicTemplate, Box<dyn Error>> {
+        if !template.starts_with("{prefix}") {
+            bail!("Topic template {} must start with {{prefix}}", template)
+        }
+        if !template.contains("{id}") {
+            bail!("Topic template {} must contain {{id}}", template)
+        }
+        if with_attribute != template.contains("{attribute_id}") {
+            bail!(
+                "Topic template {} must {}contain {{attribute_id}}",
+                template,
+                if with_attribute { "" } else { "not " }
+            )
+        }
+        let template = template.replacen("{prefix}", prefix, 1);
+        let mut pattern = "^".to_string();
+        let mut last = 0;
+        for caps in TEMPLATE_PLACEHOLDER_REGEX.captures_iter(&template) {
+            let placeholder = caps.get(0).unwrap();
+            pattern.push_str(&regex::escape(&template[last..placeholder.start()]));
+            pattern.push_str(match &caps["placeholder"] {
+                "id" => "(?P<id>[0-9]+)",
+                "attribute_id" => "(?P<attribute_id>[0-9]+)",
+                _ => "[^/]+",
+            });
+            last = placeholder.end();
+        }
+        pattern.push_str(&regex::escape(&template[last..]));
+        pattern.push('$');
+        Ok(TopicTemplate {
+            regex: Regex::new(&pattern)?,
+            template,
+        })
+    }
+
+    pub fn format(&self, device_id: DeviceId, name: &str, attribute_id: AttributeId) -> String {
+        self.template
+            .replace("{id}", &device_id.to_string())
+            .replace("{attribute_id}", &attribute_id.to_string())
+            .replace("{name}", name)
+    }
+
+    pub fn parse(&self, topic: &str) -> Option<(DeviceId, AttributeId)> {
+        let caps = self.regex.captures(topic)?;
+        let device_id = caps["id"].parse().ok()?;
+        let attribute_id = match caps.name("attribute_id") {
+            Some(v) => v.as_str().parse().ok()?,
+            None => 0,
+        };
+        Some((device_id, attribute_id))
+    }
+
+    /// The MQTT subscription matching every topic of this template.
+    pub fn subscribe_pattern(&self) -> String {
+        self.template
+            .split('/')
+            .map(|part| {
+                if TEMPLATE_PLACEHOLDER_REGEX.is_match(part) {
+                    "+"
+                } else {
+                    part
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Device names can't be used in topics as-is.
+fn topic_safe_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '+' | '#' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -14,8 +122,122 @@ pub struct Config {
     pub topic_prefix: Option<String>,
     pub discovery_topic_prefix: Option<String>,
     pub discovery_listen_topic: Option<String>,
+    /// Publish discovery configs retained, so Home Assistant picks them up on restart even
+    /// without a birth message.
+    pub retain_discovery: bool,
     pub resync_interval: u64,
     pub http_port: Option<u16>,
+    pub reconcile_retained_state: bool,
+    /// Publish statuses even when the device reports no attributes, instead of keeping the
+    /// last one.
+    pub publish_empty_status: bool,
+    /// Started after a crash loop: don't act on commands or publish discovery.
+    pub safe_mode: bool,
+    /// `--read-only`: commands are refused (and published to `bridge/dead_letter`) instead of
+    /// changing the hub.
+    pub read_only: bool,
+    /// Add a `last_changed` map (attribute name to unix timestamp) to status messages.
+    pub status_last_changed: bool,
+    /// Drop retained messages on command topics; the broker replays them on every subscribe.
+    pub ignore_retained_commands: bool,
+    pub mqtt_queue_size: usize,
+    pub publish_rate_limit: Option<f64>,
+    pub publish_burst: u32,
+    /// How many devices are polled at once. Every poll is an aprontest process, and the hub
+    /// doesn't have memory for many of those.
+    pub poll_concurrency: usize,
+    /// Only devices on these radios (e.g. ZWAVE) are polled; all are when empty.
+    pub poll_radios: Vec<String>,
+    pub status_qos: QoS,
+    pub critical: Vec<(DeviceId, Option<AttributeId>)>,
+    pub set_debounce_ms: u64,
+    /// Json commands with a `ts` older than this many seconds are dropped; 0 disables the check.
+    pub max_command_age: u64,
+    /// Seconds between publishes to `bridge/stats`; 0 disables them.
+    pub stats_interval: u64,
+    pub max_devices: Option<usize>,
+    pub allow_garage_open: bool,
+    /// Template for device names in discovery; `{name}` and `{id}` are replaced.
+    pub name_template: String,
+    pub device_name_templates: HashMap<DeviceId, String>,
+    /// Components given with --device-override; these take precedence over the `component`
+    /// device override.
+    pub component_overrides: HashMap<DeviceId, ComponentOverride>,
+    /// Area names to recognize at the start of device names, for `suggested_area`.
+    pub areas: Vec<String>,
+    /// Attribute names from --attribute-synonym, mapped to the name the bridge uses.
+    pub attribute_synonyms: BTreeMap<String, String>,
+    pub data_dir: Option<String>,
+    /// Per-device overrides; shared so they can be replaced at runtime via the http api.
+    overrides: Arc<RwLock<HashMap<DeviceId, DeviceOverride>>>,
+    /// Mirror raw aprontest output to `{prefix}bridge/debug/aprontest`.
+    pub debug_aprontest: bool,
+    /// Mirror audit events (every command sent to a device) to `{prefix}bridge/audit`.
+    pub audit_to_mqtt: bool,
+    /// Whether to expose device notes in Home Assistant as a diagnostic sensor.
+    pub expose_notes: bool,
+    /// Base unique_ids on the hub's gang ids instead of the topic prefix and device id.
+    pub stable_unique_ids: bool,
+    /// Whether to expose read-only attributes nothing else uses (firmware versions, power source,
+    /// ...) in Home Assistant as diagnostic sensors.
+    pub diagnostic_sensors: bool,
+    /// Whether lights' `brightness` is a percentage instead of the raw Level, unless overridden
+    /// per device.
+    pub brightness_percent: bool,
+    pub status_topic: Option<TopicTemplate>,
+    pub set_topic: Option<TopicTemplate>,
+    pub set_attribute_topic: Option<TopicTemplate>,
+    /// Device names for the `{name}` topic placeholder, shared between all copies of the config.
+    device_names: Arc<RwLock<HashMap<DeviceId, String>>>,
+    /// `homie/{device_id}/` when publishing in the Homie convention.
+    pub homie_topic_prefix: Option<String>,
+    /// A second discovery prefix that gets discovery configs built with the experimental
+    /// mappings, so they can be tried out (e.g. in a test Home Assistant) before they become
+    /// the default.
+    pub canary_discovery_prefix: Option<String>,
+    /// Use converter mappings that are still being tested. Only set for the canary config.
+    pub experimental_mappings: bool,
+    /// Topics outside the bridge's own that set device attributes when a message arrives.
+    pub extra_subscriptions: Vec<ExtraSubscription>,
+    /// Scenes from --scenes, each discovered as a Home Assistant scene.
+    pub scenes: Vec<Scene>,
+}
+
+/// Sets a device attribute whenever a message arrives on `topic` (which may contain `+`/`#`
+/// wildcards), e.g. `zigbee2mqtt/button1/action` -> device 4, attribute 1.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ExtraSubscription {
+    pub topic: String,
+    pub device_id: DeviceId,
+    pub attribute_id: AttributeId,
+    /// Read the value from this key of a json payload instead of using the whole payload.
+    #[serde(default)]
+    pub value_key: Option<String>,
+    /// Maps incoming values to the value to set; values not in the map are ignored. Without a
+    /// map, values are passed through as-is.
+    #[serde(default)]
+    pub values: Option<HashMap<String, String>>,
+}
+
+impl ExtraSubscription {
+    /// The attribute value to set for an incoming payload, or None to ignore it.
+    pub fn map_payload(&self, payload: &[u8]) -> Option<String> {
+        let payload = std::str::from_utf8(payload).ok()?;
+        let value = match self.value_key.as_ref() {
+            Some(key) => match serde_json::from_str::<serde_json::Value>(payload)
+                .ok()?
+                .get(key)?
+            {
+                serde_json::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            },
+            None => payload.to_string(),
+        };
+        match self.values.as_ref() {
+            Some(values) => values.get(&value).cloned(),
+            None => Some(value),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -24,7 +246,44 @@ pub enum TopicType {
     SetAttributeTopic(DeviceId, AttributeId),
     StatusTopic(DeviceId),
     DiscoveryTopic(String, DeviceId),
+    DiscoveryEntityTopic(String, DeviceId, String),
     DiscoveryListenTopic(),
+    BridgeTopic(String),
+    HomieSetTopic(DeviceId, String),
+    ExternalTopic(String),
+    /// Button presses and other momentary events, for Home Assistant device triggers.
+    ActionTopic(DeviceId),
+    /// Secondary device info (status, signal, versions), for Home Assistant entity attributes.
+    AttributesTopic(DeviceId),
+    /// Commands for every device in a hub group, as `{"<attribute id>": value}`.
+    GroupSetTopic(GroupId),
+    GroupStatusTopic(GroupId),
+    /// Activates a scene; the payload is ignored.
+    SceneSetTopic(SceneId),
+}
+
+impl TopicType {
+    /// Whether messages on this topic change device state.
+    pub fn is_command(&self) -> bool {
+        match self {
+            SetJsonTopic(_)
+            | SetAttributeTopic(_, _)
+            | HomieSetTopic(_, _)
+            | ExternalTopic(_)
+            | GroupSetTopic(_)
+            | SceneSetTopic(_) => true,
+            // Pairing and removing devices change the hub, so they're commands too.
+            BridgeTopic(name) if name == "pair" || name == "remove" || name == "rename" => true,
+            StatusTopic(_)
+            | DiscoveryTopic(_, _)
+            | DiscoveryEntityTopic(_, _, _)
+            | TopicType::DiscoveryListenTopic()
+            | BridgeTopic(_)
+            | ActionTopic(_)
+            | AttributesTopic(_)
+            | GroupStatusTopic(_) => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -39,12 +298,31 @@ impl Error for NotInterestingTopicError {}
 
 lazy_static! {
     static ref SLASHES_ON_END_REGEX: Regex = Regex::new("/+$").unwrap();
-    static ref DISCOVERY_SUFFIX_REGEX: Regex =
-        Regex::new("(?P<component>[^/]+)/wink_(?P<device_id>[0-9]+)/config").unwrap();
+    static ref TEMPLATE_PLACEHOLDER_REGEX: Regex =
+        Regex::new("\\{(?P<placeholder>id|attribute_id|name)\\}").unwrap();
+    static ref DISCOVERY_SUFFIX_REGEX: Regex = Regex::new(
+        "(?P<component>[^/]+)/wink_(?P<device_id>[0-9]+)(?:/(?P<object_id>[^/]+))?/config"
+    )
+    .unwrap();
+}
+
+/// Whether `topic` matches an MQTT subscription `pattern` (with `+` and `#` wildcards).
+pub fn topic_matches_pattern(pattern: &str, topic: &str) -> bool {
+    let mut topic_parts = topic.split('/');
+    for pattern_part in pattern.split('/') {
+        match (pattern_part, topic_parts.next()) {
+            ("#", _) => return true,
+            (_, None) => return false,
+            ("+", Some(_)) => {}
+            (p, Some(t)) if p == t => {}
+            _ => return false,
+        }
+    }
+    topic_parts.next().is_none()
 }
 
 impl Config {
-    fn normalize_topic_prefix(x: &str) -> String {
+    pub fn normalize_topic_prefix(x: &str) -> String {
         SLASHES_ON_END_REGEX.replace(x, "").into_owned().add("/")
     }
 
@@ -56,13 +334,257 @@ impl Config {
         resync_interval: u64,
         http_port: Option<u16>,
     ) -> Config {
+        let topic_prefix = topic_prefix.map(Self::normalize_topic_prefix);
+        let template = |t, with_attribute| {
+            topic_prefix
+                .as_ref()
+                .map(|prefix| TopicTemplate::new(t, prefix, with_attribute).unwrap())
+        };
         Config {
             mqtt_options: mqtt_options.map(|x| x.clone()),
-            topic_prefix: topic_prefix.map(Self::normalize_topic_prefix),
+            status_topic: template(DEFAULT_STATUS_TOPIC, false),
+            set_topic: template(DEFAULT_SET_TOPIC, false),
+            set_attribute_topic: template(DEFAULT_SET_ATTRIBUTE_TOPIC, true),
+            device_names: Arc::new(RwLock::new(HashMap::new())),
+            topic_prefix,
             discovery_topic_prefix: discovery_topic_prefix.map(Self::normalize_topic_prefix),
             discovery_listen_topic: discovery_listen_topic.map(|x| x.to_string()),
             resync_interval,
             http_port,
+            reconcile_retained_state: false,
+            retain_discovery: true,
+            publish_empty_status: false,
+            status_last_changed: false,
+            safe_mode: false,
+            read_only: false,
+            ignore_retained_commands: false,
+            mqtt_queue_size: 100,
+            publish_rate_limit: None,
+            publish_burst: 10,
+            poll_concurrency: 3,
+            poll_radios: Vec::new(),
+            status_qos: QoS::AtLeastOnce,
+            critical: Vec::new(),
+            set_debounce_ms: 0,
+            stats_interval: 0,
+            max_command_age: 0,
+            max_devices: None,
+            allow_garage_open: false,
+            name_template: "{name}".to_string(),
+            device_name_templates: HashMap::new(),
+            areas: Vec::new(),
+            attribute_synonyms: BTreeMap::new(),
+            component_overrides: HashMap::new(),
+            data_dir: None,
+            debug_aprontest: false,
+            audit_to_mqtt: false,
+            overrides: Arc::new(RwLock::new(HashMap::new())),
+            expose_notes: false,
+            diagnostic_sensors: false,
+            brightness_percent: false,
+            stable_unique_ids: false,
+            homie_topic_prefix: None,
+            canary_discovery_prefix: None,
+            experimental_mappings: false,
+            extra_subscriptions: Vec::new(),
+            scenes: Vec::new(),
+        }
+    }
+
+    /// Parses a `device_id[/attribute_id]` critical target.
+    pub fn parse_critical_target(
+        s: &str,
+    ) -> Result<(DeviceId, Option<AttributeId>), Box<dyn Error>> {
+        let mut parts = s.trim().splitn(2, '/');
+        let device_id = parts.next().unwrap().parse_numberish()?;
+        let attribute_id = parts.next().map(|x| x.parse_numberish()).transpose()?;
+        Ok((device_id, attribute_id))
+    }
+
+    /// Replaces the default topic layout. Only valid with a topic prefix.
+    pub fn set_topic_templates(
+        &mut self,
+        status: &str,
+        set: &str,
+        set_attribute: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let prefix = self
+            .topic_prefix
+            .as_ref()
+            .ok_or_else(|| simple_error!("Topic templates need a topic prefix"))?;
+        let status = TopicTemplate::new(status, prefix, false)?;
+        let set = TopicTemplate::new(set, prefix, false)?;
+        let set_attribute = TopicTemplate::new(set_attribute, prefix, true)?;
+        self.status_topic = Some(status);
+        self.set_topic = Some(set);
+        self.set_attribute_topic = Some(set_attribute);
+        Ok(())
+    }
+
+    /// Remembers the device name for the `{name}` topic placeholder.
+    pub fn remember_device_name(&self, device_id: DeviceId, name: &str) {
+        self.device_names
+            .write()
+            .unwrap()
+            .insert(device_id, topic_safe_name(name));
+    }
+
+    fn device_name(&self, device_id: DeviceId) -> String {
+        self.device_names
+            .read()
+            .unwrap()
+            .get(&device_id)
+            .cloned()
+            .unwrap_or_else(|| device_id.to_string())
+    }
+
+    /// Parses a `device_id=template` per-device name template.
+    pub fn parse_device_name_template(s: &str) -> Result<(DeviceId, String), Box<dyn Error>> {
+        let mut parts = s.splitn(2, '=');
+        let device_id = parts.next().unwrap().trim().parse_numberish()?;
+        let template = parts
+            .next()
+            .ok_or_else(|| simple_error!("Expected device_id=template, got {}", s))?;
+        Ok((device_id, template.to_string()))
+    }
+
+    /// The config to build canary discovery messages with, if --canary-discovery-prefix is set.
+    pub fn canary_config(&self) -> Option<Config> {
+        let prefix = self.canary_discovery_prefix.as_ref()?;
+        let mut config = self.clone();
+        config.discovery_topic_prefix = Some(prefix.clone());
+        config.canary_discovery_prefix = None;
+        config.experimental_mappings = true;
+        Some(config)
+    }
+
+    pub fn device_override(&self, device_id: DeviceId) -> Option<DeviceOverride> {
+        self.overrides
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&device_id)
+            .cloned()
+    }
+
+    pub fn overrides(&self) -> HashMap<DeviceId, DeviceOverride> {
+        self.overrides
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn set_overrides(&self, overrides: HashMap<DeviceId, DeviceOverride>) {
+        *self.overrides.write().unwrap_or_else(|e| e.into_inner()) = overrides;
+    }
+
+    /// The device's overrides, or the defaults if it has none. Options with a global default
+    /// (e.g. --brightness-percent) are filled in.
+    pub fn device_options(&self, device_id: DeviceId) -> DeviceOverride {
+        let mut options = self.device_override(device_id).unwrap_or_default();
+        options
+            .brightness_percent
+            .get_or_insert(self.brightness_percent);
+        options
+    }
+
+    /// The name the bridge uses for an attribute, counting the --attribute-synonym ones.
+    pub fn canonical_attribute_name<'a>(&'a self, name: &'a str) -> &'a str {
+        canonical_attribute_name(
+            self.attribute_synonyms
+                .get(name)
+                .map_or(name, |n| n.as_str()),
+        )
+    }
+
+    /// Renames the device's attributes per --attribute-synonym and its `attribute_aliases`
+    /// override, then turns the ones listed in its `enum_values` override into enums.
+    pub fn apply_attribute_overrides(&self, device: &mut LongDevice) {
+        device.apply_attribute_aliases(&self.attribute_synonyms);
+        let device_override = match self.device_override(device.id) {
+            Some(device_override) => device_override,
+            None => return,
+        };
+        if let Some(aliases) = device_override.attribute_aliases {
+            device.apply_attribute_aliases(&aliases);
+        }
+        if let Some(enum_values) = device_override.enum_values {
+            device.apply_enum_values(&enum_values);
+        }
+    }
+
+    /// The component to discover the device as, if it shouldn't be guessed.
+    pub fn component_override(&self, device_id: DeviceId) -> Option<ComponentOverride> {
+        if let Some(component_override) = self.component_overrides.get(&device_id) {
+            return Some(component_override.clone());
+        }
+        let device_override = self.device_override(device_id)?;
+        Some(ComponentOverride {
+            component: device_override.component?,
+            attribute: device_override.component_attribute,
+        })
+    }
+
+    /// The name to give the device in discovery messages.
+    pub fn discovery_name(&self, device_id: DeviceId, name: &str) -> String {
+        let name = self
+            .device_override(device_id)
+            .and_then(|o| o.name)
+            .unwrap_or_else(|| name.to_string());
+        self.device_name_templates
+            .get(&device_id)
+            .unwrap_or(&self.name_template)
+            .replace("{name}", &name)
+            .replace("{id}", &device_id.to_string())
+    }
+
+    /// Whether devices on this radio are polled. Devices on an unknown radio always are.
+    pub fn polls_radio(&self, interconnect: Option<&str>) -> bool {
+        match interconnect {
+            Some(interconnect) if !self.poll_radios.is_empty() => self
+                .poll_radios
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(interconnect)),
+            _ => true,
+        }
+    }
+
+    /// The Home Assistant area for the device: its `area` override, or else the longest of
+    /// `areas` its name starts with (so "Bedroom Fan" is in the Bedroom).
+    pub fn suggested_area(&self, device_id: DeviceId, name: &str) -> Option<String> {
+        let device_override = self.device_override(device_id).unwrap_or_default();
+        if device_override.area.is_some() {
+            return device_override.area;
+        }
+        let name = device_override
+            .name
+            .unwrap_or_else(|| name.to_string())
+            .to_lowercase();
+        self.areas
+            .iter()
+            .filter(|area| {
+                let area = area.to_lowercase();
+                name.starts_with(&area)
+                    && name[area.len()..]
+                        .chars()
+                        .next()
+                        .map_or(true, |c| !c.is_alphanumeric())
+            })
+            .max_by_key(|area| area.len())
+            .cloned()
+    }
+
+    /// Whether commands to this attribute (or any attribute, if None) of the device are critical.
+    pub fn is_critical(&self, device_id: DeviceId, attribute_id: Option<AttributeId>) -> bool {
+        self.critical.iter().any(|(d, a)| {
+            *d == device_id && (a.is_none() || attribute_id.is_none() || *a == attribute_id)
+        })
+    }
+
+    pub fn status_qos_for(&self, device_id: DeviceId) -> QoS {
+        if self.is_critical(device_id, None) {
+            QoS::AtLeastOnce
+        } else {
+            self.status_qos
         }
     }
 
@@ -80,6 +602,21 @@ impl Config {
             && topic.starts_with(self.discovery_topic_prefix.as_ref().unwrap().as_str())
     }
 
+    /// The extra subscriptions `topic` was received for.
+    pub fn extra_subscriptions_for<'a>(
+        &'a self,
+        topic: &'a str,
+    ) -> impl Iterator<Item = &'a ExtraSubscription> + 'a {
+        self.extra_subscriptions
+            .iter()
+            .filter(move |s| topic_matches_pattern(&s.topic, topic))
+    }
+
+    pub fn is_homie_topic(&self, topic: &str) -> bool {
+        self.homie_topic_prefix.is_some()
+            && topic.starts_with(self.homie_topic_prefix.as_ref().unwrap().as_str())
+    }
+
     pub fn is_discovery_listen_topic(&self, topic: &str) -> bool {
         self.discovery_listen_topic.is_some()
             && topic == self.discovery_listen_topic.as_ref().unwrap()
@@ -88,18 +625,65 @@ impl Config {
     pub fn mqtt_topic_subscribe_patterns(&self) -> impl Iterator<Item = String> {
         let mut result: Vec<String> = Vec::with_capacity(3);
         if let Some(prefix) = self.topic_prefix.as_ref() {
-            result.push(format!("{}+/set", prefix));
-            result.push(format!("{}+/+/set", prefix));
+            result.extend(
+                self.set_topic
+                    .as_ref()
+                    .map(TopicTemplate::subscribe_pattern),
+            );
+            result.extend(
+                self.set_attribute_topic
+                    .as_ref()
+                    .map(TopicTemplate::subscribe_pattern),
+            );
+            result.push(format!("{}bridge/undiscover", prefix));
+            result.push(format!("{}bridge/pair", prefix));
+            result.push(format!("{}bridge/remove", prefix));
+            result.push(format!("{}bridge/rename", prefix));
+            result.push(format!("{}bridge/devices/get", prefix));
+            result.push(format!("{}group/+/set", prefix));
+            if !self.scenes.is_empty() {
+                result.push(format!("{}scene/+/set", prefix));
+            }
         }
         if let Some(disco) = self.discovery_listen_topic.as_ref() {
             result.push(disco.clone());
         }
+        if let Some(homie) = self.homie_topic_prefix.as_ref() {
+            result.push(format!("{}+/+/set", homie));
+        }
+        for subscription in self.extra_subscriptions.iter() {
+            if !result.contains(&subscription.topic) {
+                result.push(subscription.topic.clone());
+            }
+        }
         return result.into_iter();
     }
 
+    pub fn status_subscribe_pattern(&self) -> Option<String> {
+        self.status_topic
+            .as_ref()
+            .map(TopicTemplate::subscribe_pattern)
+    }
+
     pub fn parse_mqtt_topic(&self, topic: &str) -> Result<TopicType, Box<dyn Error>> {
         if self.is_discovery_listen_topic(topic) {
             Ok(TopicType::DiscoveryListenTopic())
+        } else if self.extra_subscriptions_for(topic).next().is_some() {
+            Ok(ExternalTopic(topic.to_string()))
+        } else if self.is_homie_topic(topic) {
+            let path_components = topic
+                .strip_prefix(self.homie_topic_prefix.as_ref().unwrap())
+                .unwrap()
+                .split("/")
+                .collect::<Vec<_>>();
+            match path_components[..] {
+                [node, property, "set"] => Ok(HomieSetTopic(
+                    homie::parse_node_id(node)
+                        .ok_or_else(|| simple_error!("Bad homie node: {}", node))?,
+                    property.to_string(),
+                )),
+                _ => Err(NotInterestingTopicError {}.into()),
+            }
         } else if self.is_discovery_topic(topic) {
             let suffix = topic
                 .strip_prefix(self.discovery_topic_prefix.as_ref().unwrap())
@@ -111,14 +695,18 @@ impl Config {
                 }
             };
 
-            Ok(DiscoveryTopic(
-                parsed.name("component").unwrap().as_str().into(),
-                parsed
-                    .name("device_id")
-                    .unwrap()
-                    .as_str()
-                    .parse_numberish()?,
-            ))
+            let component = parsed.name("component").unwrap().as_str().into();
+            let device_id = parsed
+                .name("device_id")
+                .unwrap()
+                .as_str()
+                .parse_numberish()?;
+            Ok(match parsed.name("object_id") {
+                Some(object_id) => {
+                    DiscoveryEntityTopic(component, device_id, object_id.as_str().into())
+                }
+                None => DiscoveryTopic(component, device_id),
+            })
         } else if self.is_interesting_topic(topic) {
             let path_components = topic
                 .strip_prefix(self.topic_prefix.as_ref().unwrap())
@@ -129,24 +717,27 @@ impl Config {
                 bail!("Invalid topic: {}", topic)
             }
 
-            if path_components.last().unwrap() == &"set"
-                && path_components.len() >= 2
-                && path_components.len() <= 3
-            {
-                let device_id =
-                    path_components.first().unwrap().parse::<u64>()? as crate::controller::DeviceId;
-
-                if let [_, rest, _] = path_components[..] {
-                    let attribute_id = rest.parse::<u64>()? as AttributeId;
-                    Ok(SetAttributeTopic(device_id, attribute_id))
-                } else {
-                    Ok(SetJsonTopic(device_id))
-                }
-            } else if path_components.last().unwrap() == &"status" && path_components.len() == 2 {
-                let device_id =
-                    path_components.first().unwrap().parse::<u64>()? as crate::controller::DeviceId;
+            let parse =
+                |template: &Option<TopicTemplate>| template.as_ref().and_then(|t| t.parse(topic));
 
+            if path_components.first().unwrap() == &"bridge" && path_components.len() >= 2 {
+                Ok(BridgeTopic(path_components[1..].join("/")))
+            } else if let ["group", group_id, "set"] = path_components[..] {
+                Ok(GroupSetTopic(group_id.parse_numberish()?))
+            } else if let ["group", group_id, "status"] = path_components[..] {
+                Ok(GroupStatusTopic(group_id.parse_numberish()?))
+            } else if let ["scene", scene_id, "set"] = path_components[..] {
+                Ok(SceneSetTopic(scene_id.parse_numberish()?))
+            } else if let Some((device_id, attribute_id)) = parse(&self.set_attribute_topic) {
+                Ok(SetAttributeTopic(device_id, attribute_id))
+            } else if let Some((device_id, _)) = parse(&self.set_topic) {
+                Ok(SetJsonTopic(device_id))
+            } else if let Some((device_id, _)) = parse(&self.status_topic) {
                 Ok(StatusTopic(device_id))
+            } else if let [device_id, "action"] = path_components[..] {
+                Ok(ActionTopic(device_id.parse_numberish()?))
+            } else if let [device_id, "attributes"] = path_components[..] {
+                Ok(AttributesTopic(device_id.parse_numberish()?))
             } else {
                 bail!("Bad internal topic: {}; {:?}", topic, path_components)
             }
@@ -157,22 +748,59 @@ impl Config {
     pub fn to_topic_string(&self, topic: &TopicType) -> Option<String> {
         match topic {
             SetJsonTopic(device_id) => self
-                .topic_prefix
+                .set_topic
                 .as_ref()
-                .map(|prefix| format!("{}{}/set", prefix, device_id)),
+                .map(|t| t.format(*device_id, &self.device_name(*device_id), 0)),
             SetAttributeTopic(device_id, attribute_id) => self
-                .topic_prefix
+                .set_attribute_topic
                 .as_ref()
-                .map(|prefix| format!("{}{}/{}/set", prefix, device_id, attribute_id)),
+                .map(|t| t.format(*device_id, &self.device_name(*device_id), *attribute_id)),
             StatusTopic(device_id) => self
-                .topic_prefix
+                .status_topic
                 .as_ref()
-                .map(|prefix| format!("{}{}/status", prefix, device_id)),
+                .map(|t| t.format(*device_id, &self.device_name(*device_id), 0)),
             DiscoveryTopic(device_type, device_id) => self
                 .discovery_topic_prefix
                 .as_ref()
                 .map(|prefix| format!("{}{}/wink_{}/config", prefix, device_type, device_id)),
+            DiscoveryEntityTopic(device_type, device_id, object_id) => {
+                self.discovery_topic_prefix.as_ref().map(|prefix| {
+                    format!(
+                        "{}{}/wink_{}/{}/config",
+                        prefix, device_type, device_id, object_id
+                    )
+                })
+            }
             TopicType::DiscoveryListenTopic() => self.discovery_listen_topic.clone(),
+            BridgeTopic(name) => self
+                .topic_prefix
+                .as_ref()
+                .map(|prefix| format!("{}bridge/{}", prefix, name)),
+            HomieSetTopic(device_id, property) => self
+                .homie_topic_prefix
+                .as_ref()
+                .map(|prefix| format!("{}{}/{}/set", prefix, homie::node_id(*device_id), property)),
+            ExternalTopic(topic) => Some(topic.clone()),
+            ActionTopic(device_id) => self
+                .topic_prefix
+                .as_ref()
+                .map(|prefix| format!("{}{}/action", prefix, device_id)),
+            AttributesTopic(device_id) => self
+                .topic_prefix
+                .as_ref()
+                .map(|prefix| format!("{}{}/attributes", prefix, device_id)),
+            GroupSetTopic(group_id) => self
+                .topic_prefix
+                .as_ref()
+                .map(|prefix| format!("{}group/{}/set", prefix, group_id)),
+            GroupStatusTopic(group_id) => self
+                .topic_prefix
+                .as_ref()
+                .map(|prefix| format!("{}group/{}/status", prefix, group_id)),
+            SceneSetTopic(scene_id) => self
+                .topic_prefix
+                .as_ref()
+                .map(|prefix| format!("{}scene/{}/set", prefix, scene_id)),
         }
     }
 }
@@ -180,6 +808,8 @@ impl Config {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::AttributeType;
+    use crate::test_utils;
 
     lazy_static! {
         static ref TEST_CASES: Vec<TopicType> = [
@@ -187,7 +817,16 @@ mod tests {
             SetAttributeTopic(1, 3),
             StatusTopic(1),
             DiscoveryTopic("light".to_string(), 1),
+            DiscoveryEntityTopic("switch".to_string(), 1, "On_Off2".to_string()),
             TopicType::DiscoveryListenTopic(),
+            BridgeTopic("reconciliation".to_string()),
+            BridgeTopic("pair".to_string()),
+            HomieSetTopic(2, "on-off".to_string()),
+            ActionTopic(4),
+            AttributesTopic(4),
+            GroupSetTopic(3),
+            GroupStatusTopic(3),
+            SceneSetTopic(5),
         ]
         .to_vec();
     }
@@ -209,9 +848,168 @@ mod tests {
         )
     }
 
+    #[test]
+    fn critical_targets() {
+        let mut config = Config::new(None, None, None, None, 10, None);
+        config.status_qos = QoS::AtMostOnce;
+        config.critical = vec![
+            Config::parse_critical_target("7").unwrap(),
+            Config::parse_critical_target("9/3").unwrap(),
+        ];
+        assert_eq!((9, Some(3)), config.critical[1]);
+        assert!(Config::parse_critical_target("x/3").is_err());
+
+        assert!(config.is_critical(7, Some(1)));
+        assert!(config.is_critical(9, Some(3)));
+        assert!(!config.is_critical(9, Some(4)));
+        assert!(!config.is_critical(8, None));
+        assert_eq!(QoS::AtLeastOnce, config.status_qos_for(9));
+        assert_eq!(QoS::AtMostOnce, config.status_qos_for(8));
+    }
+
+    #[test]
+    fn name_templates() {
+        let mut config = Config::new(None, None, None, None, 10, None);
+        assert_eq!("Bedroom Fan", config.discovery_name(2, "Bedroom Fan"));
+
+        config.name_template = "Wink {name}".to_string();
+        let (device_id, template) =
+            Config::parse_device_name_template("2={name} Light ({id})").unwrap();
+        config.device_name_templates.insert(device_id, template);
+        assert_eq!(
+            "Bedroom Fan Light (2)",
+            config.discovery_name(2, "Bedroom Fan")
+        );
+        assert_eq!("Wink Porch", config.discovery_name(3, "Porch"));
+        assert!(Config::parse_device_name_template("2").is_err());
+
+        config.set_overrides(
+            vec![(
+                3,
+                DeviceOverride {
+                    name: Some("Front Porch".to_string()),
+                    area: None,
+                    ..DeviceOverride::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!("Wink Front Porch", config.discovery_name(3, "Porch"));
+    }
+
+    #[test]
+    fn suggested_areas() {
+        let mut config = Config::new(None, None, None, None, 10, None);
+        config.areas = vec!["Living".to_string(), "Living Room".to_string()];
+        assert_eq!(
+            Some("Living Room".to_string()),
+            config.suggested_area(2, "living room lamp")
+        );
+        assert_eq!(
+            Some("Living".to_string()),
+            config.suggested_area(2, "Living")
+        );
+        assert_eq!(None, config.suggested_area(2, "Livingston Porch"));
+
+        config.set_overrides(
+            vec![(
+                2,
+                DeviceOverride {
+                    area: Some("Den".to_string()),
+                    ..DeviceOverride::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            Some("Den".to_string()),
+            config.suggested_area(2, "Living Room Lamp")
+        );
+    }
+
+    #[test]
+    fn poll_radios() {
+        let mut config = Config::new(None, None, None, None, 10, None);
+        assert!(config.polls_radio(Some("ZIGBEE")));
+        config.poll_radios = vec!["zwave".to_string()];
+        assert!(config.polls_radio(Some("ZWAVE")));
+        assert!(!config.polls_radio(Some("ZIGBEE")));
+        assert!(config.polls_radio(None));
+    }
+
+    #[test]
+    fn topic_templates() {
+        let mut config = Config::new(None, Some("home/wink"), None, None, 10, None);
+        assert_eq!(
+            Some("home/wink/+/status".to_string()),
+            config.status_subscribe_pattern()
+        );
+
+        config
+            .set_topic_templates(
+                "{prefix}{name}/{id}/state",
+                "{prefix}{name}/{id}/command",
+                "{prefix}{name}/{id}/{attribute_id}/command",
+            )
+            .unwrap();
+        config.remember_device_name(2, "Bedroom Fan");
+        assert_eq!(
+            Some("home/wink/Bedroom_Fan/2/state".to_string()),
+            config.to_topic_string(&StatusTopic(2))
+        );
+        assert_eq!(
+            Some("home/wink/+/+/state".to_string()),
+            config.status_subscribe_pattern()
+        );
+        for case in [StatusTopic(2), SetJsonTopic(2), SetAttributeTopic(2, 3)].iter() {
+            let topic = config.to_topic_string(case).unwrap();
+            assert_eq!(*case, config.parse_mqtt_topic(&topic).unwrap());
+        }
+        // The name is informational; the id decides which device a command is for.
+        assert_eq!(
+            SetJsonTopic(2),
+            config
+                .parse_mqtt_topic("home/wink/Old_Name/2/command")
+                .unwrap()
+        );
+
+        assert!(config
+            .set_topic_templates(
+                "{prefix}{name}/state",
+                "{prefix}{id}/set",
+                "{prefix}{id}/set"
+            )
+            .is_err());
+        assert!(config
+            .set_topic_templates(
+                "{prefix}{id}/status",
+                "{prefix}{id}/set",
+                "{prefix}{id}/set"
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn topic_patterns() {
+        assert!(topic_matches_pattern("a/+/set", "a/1/set"));
+        assert!(!topic_matches_pattern("a/+/set", "a/1/2/set"));
+        assert!(!topic_matches_pattern("a/+/set", "a/1"));
+        assert!(topic_matches_pattern("a/#", "a/1/2/set"));
+        assert!(topic_matches_pattern(
+            "homeassistant/status",
+            "homeassistant/status"
+        ));
+        assert!(!topic_matches_pattern(
+            "homeassistant/status",
+            "homeassistant/status/x"
+        ));
+    }
+
     #[test]
     fn full_config() {
-        let config = Config::new(
+        let mut config = Config::new(
             Some(MqttOptions::new("a", "localhost", 123)),
             Some("topic/prefix/"),
             Some("discovery/topic/prefix/"),
@@ -219,6 +1017,7 @@ mod tests {
             10,
             None,
         );
+        config.homie_topic_prefix = Some("homie/wink/".to_string());
 
         for case in TEST_CASES.iter() {
             let topic = config.to_topic_string(case).unwrap();
@@ -226,4 +1025,97 @@ mod tests {
             assert!(topic.find("//").is_none());
         }
     }
+
+    #[test]
+    fn extra_subscriptions() {
+        let mut config = Config::new(None, Some("home/wink/"), None, None, 10, None);
+        config.extra_subscriptions = serde_json::from_str(
+            r#"[
+                {"topic": "zigbee2mqtt/button1/action", "device_id": 4, "attribute_id": 1,
+                 "values": {"single": "TRUE", "double": "FALSE"}},
+                {"topic": "zigbee2mqtt/+", "device_id": 5, "attribute_id": 2,
+                 "value_key": "brightness"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert!(config
+            .mqtt_topic_subscribe_patterns()
+            .any(|t| t == "zigbee2mqtt/button1/action"));
+        assert_eq!(
+            ExternalTopic("zigbee2mqtt/button1/action".to_string()),
+            config
+                .parse_mqtt_topic("zigbee2mqtt/button1/action")
+                .unwrap()
+        );
+        assert_eq!(
+            1,
+            config
+                .extra_subscriptions_for("zigbee2mqtt/button1/action")
+                .count()
+        );
+
+        let button = &config.extra_subscriptions[0];
+        assert_eq!(Some("TRUE".to_string()), button.map_payload(b"single"));
+        assert_eq!(None, button.map_payload(b"hold"));
+        let dimmer = &config.extra_subscriptions[1];
+        assert_eq!(
+            Some("128".to_string()),
+            dimmer.map_payload(br#"{"brightness": 128}"#)
+        );
+        assert_eq!(None, dimmer.map_payload(b"128"));
+    }
+
+    #[test]
+    fn canary_config() {
+        let mut config = Config::new(None, None, Some("homeassistant"), None, 10, None);
+        assert!(config.canary_config().is_none());
+
+        config.canary_discovery_prefix = Some("canary/".to_string());
+        let canary = config.canary_config().unwrap();
+        assert!(canary.experimental_mappings);
+        assert!(!config.experimental_mappings);
+        assert_eq!(
+            Some("canary/light/wink_2/config".to_string()),
+            canary.to_topic_string(&DiscoveryTopic("light".to_string(), 2))
+        );
+    }
+
+    #[test]
+    fn command_topics() {
+        let commands = TEST_CASES
+            .iter()
+            .filter(|t| t.is_command())
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                SetJsonTopic(1),
+                SetAttributeTopic(1, 3),
+                BridgeTopic("pair".to_string()),
+                HomieSetTopic(2, "on-off".to_string()),
+                GroupSetTopic(3),
+                SceneSetTopic(5)
+            ],
+            commands
+        );
+    }
+
+    #[test]
+    fn attribute_synonyms() {
+        let mut config = test_utils::config();
+        config
+            .attribute_synonyms
+            .insert("Fan_Power".to_string(), "On_Off".to_string());
+        assert_eq!("On_Off", config.canonical_attribute_name("Fan_Power"));
+        assert_eq!("On_Off", config.canonical_attribute_name("OnOff"));
+
+        let mut device = test_utils::device(vec![test_utils::attribute(
+            1,
+            "Fan_Power",
+            AttributeType::Bool,
+        )]);
+        config.apply_attribute_overrides(&mut device);
+        assert_eq!(Some(1), device.attribute("On_Off").map(|a| a.id));
+    }
 }
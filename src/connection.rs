@@ -0,0 +1,215 @@
+use crate::utils::unix_timestamp;
+use serde::Serialize;
+use std::cmp::min;
+use std::collections::HashSet;
+use tokio::time::Duration;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Waiting for the broker to acknowledge our connection.
+    Connecting,
+    /// Connected, with our subscriptions in place.
+    Subscribed,
+    /// Connected, but the broker sent something we didn't expect.
+    Degraded,
+    /// The connection failed; waiting before trying again.
+    Backoff,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionEvent {
+    Subscribed,
+    Traffic,
+    UnexpectedPacket,
+    Disconnected,
+    ConnectionError,
+    BackoffElapsed,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    pub since: u64,
+    pub consecutive_failures: u32,
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct ConnectionStateMachine {
+    state: ConnectionState,
+    since: u64,
+    consecutive_failures: u32,
+}
+
+impl ConnectionStateMachine {
+    pub fn new() -> ConnectionStateMachine {
+        ConnectionStateMachine {
+            state: ConnectionState::Connecting,
+            since: unix_timestamp(),
+            consecutive_failures: 0,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        ConnectionStatus {
+            state: self.state,
+            since: self.since,
+            consecutive_failures: self.consecutive_failures,
+        }
+    }
+
+    fn next_state(&self, event: ConnectionEvent) -> ConnectionState {
+        match (self.state, event) {
+            (_, ConnectionEvent::ConnectionError) => ConnectionState::Backoff,
+            (ConnectionState::Backoff, ConnectionEvent::BackoffElapsed) => {
+                ConnectionState::Connecting
+            }
+            (ConnectionState::Backoff, _) => ConnectionState::Backoff,
+            (_, ConnectionEvent::Disconnected) => ConnectionState::Connecting,
+            (_, ConnectionEvent::Subscribed) => ConnectionState::Subscribed,
+            (ConnectionState::Subscribed, ConnectionEvent::UnexpectedPacket) => {
+                ConnectionState::Degraded
+            }
+            (ConnectionState::Degraded, ConnectionEvent::Traffic) => ConnectionState::Subscribed,
+            (state, _) => state,
+        }
+    }
+
+    /// Applies `event`, returning the new state if it changed.
+    pub fn handle(&mut self, event: ConnectionEvent) -> Option<ConnectionState> {
+        match event {
+            ConnectionEvent::ConnectionError => self.consecutive_failures += 1,
+            ConnectionEvent::Subscribed => self.consecutive_failures = 0,
+            _ => {}
+        };
+
+        let next = self.next_state(event);
+        if next == self.state {
+            return None;
+        }
+        self.state = next;
+        self.since = unix_timestamp();
+        Some(next)
+    }
+
+    /// How long to wait in the backoff state - doubles with every consecutive failure.
+    pub fn backoff_delay(&self) -> Duration {
+        let exponent = min(self.consecutive_failures.saturating_sub(1), 16);
+        min(MIN_BACKOFF * 2u32.pow(exponent), MAX_BACKOFF)
+    }
+}
+
+/// Tracks incoming QoS 2 publishes between their PUBLISH and PUBREL. The event loop sends the
+/// PUBREC/PUBCOMP replies itself, but hands us a publish every time the broker retransmits it;
+/// only the first one should be acted on.
+pub struct ExactlyOnceReceiver {
+    pending: HashSet<u16>,
+}
+
+impl ExactlyOnceReceiver {
+    pub fn new() -> ExactlyOnceReceiver {
+        ExactlyOnceReceiver {
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Returns whether the publish with this packet id is new (i.e. should be processed).
+    pub fn receive(&mut self, pkid: u16) -> bool {
+        self.pending.insert(pkid)
+    }
+
+    /// The broker released the packet id; it may be reused for a new publish.
+    pub fn release(&mut self, pkid: u16) {
+        self.pending.remove(&pkid);
+    }
+
+    /// Forgets all packet ids, for when the broker didn't keep our session.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn happy_path() {
+        let mut machine = ConnectionStateMachine::new();
+        assert_eq!(ConnectionState::Connecting, machine.state());
+        assert_eq!(
+            Some(ConnectionState::Subscribed),
+            machine.handle(ConnectionEvent::Subscribed)
+        );
+        assert_eq!(None, machine.handle(ConnectionEvent::Traffic));
+        assert_eq!(
+            Some(ConnectionState::Connecting),
+            machine.handle(ConnectionEvent::Disconnected)
+        );
+    }
+
+    #[test]
+    fn degraded_recovers_on_traffic() {
+        let mut machine = ConnectionStateMachine::new();
+        machine.handle(ConnectionEvent::Subscribed);
+        assert_eq!(
+            Some(ConnectionState::Degraded),
+            machine.handle(ConnectionEvent::UnexpectedPacket)
+        );
+        assert_eq!(None, machine.handle(ConnectionEvent::UnexpectedPacket));
+        assert_eq!(
+            Some(ConnectionState::Subscribed),
+            machine.handle(ConnectionEvent::Traffic)
+        );
+    }
+
+    #[test]
+    fn unexpected_packet_while_connecting() {
+        let mut machine = ConnectionStateMachine::new();
+        assert_eq!(None, machine.handle(ConnectionEvent::UnexpectedPacket));
+        assert_eq!(ConnectionState::Connecting, machine.state());
+    }
+
+    #[test]
+    fn backoff_grows_and_resets() {
+        let mut machine = ConnectionStateMachine::new();
+        assert_eq!(
+            Some(ConnectionState::Backoff),
+            machine.handle(ConnectionEvent::ConnectionError)
+        );
+        assert_eq!(MIN_BACKOFF, machine.backoff_delay());
+        assert_eq!(None, machine.handle(ConnectionEvent::Traffic));
+        assert_eq!(None, machine.handle(ConnectionEvent::ConnectionError));
+        assert_eq!(MIN_BACKOFF * 2, machine.backoff_delay());
+
+        for _ in 0..20 {
+            machine.handle(ConnectionEvent::ConnectionError);
+        }
+        assert_eq!(MAX_BACKOFF, machine.backoff_delay());
+
+        assert_eq!(
+            Some(ConnectionState::Connecting),
+            machine.handle(ConnectionEvent::BackoffElapsed)
+        );
+        machine.handle(ConnectionEvent::Subscribed);
+        assert_eq!(0, machine.status().consecutive_failures);
+    }
+
+    #[test]
+    fn exactly_once_deduplicates_until_released() {
+        let mut receiver = ExactlyOnceReceiver::new();
+        assert!(receiver.receive(1));
+        assert!(!receiver.receive(1));
+        assert!(receiver.receive(2));
+        receiver.release(1);
+        assert!(receiver.receive(1));
+        receiver.reset();
+        assert!(receiver.receive(2));
+    }
+}
@@ -1,30 +1,169 @@
+use crate::products::PRODUCTS;
+use crate::stats::{OperationRecorder, OperationStats};
+use crate::unknown_formats::UNKNOWN_FORMATS;
+use async_channel::Sender;
 use async_trait::async_trait;
 use std::convert::TryInto;
 use std::error::Error;
+use std::fmt;
 
-use crate::utils::Numberish;
+use crate::utils::{unix_timestamp, Numberish};
 use regex::Regex;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use simple_error::{bail, simple_error};
-use slog::{debug, error};
+use slog::{debug, error, warn};
 use slog_scope;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::process::Command;
 use tokio::sync::Mutex;
 
 pub type AttributeId = u32;
 pub type DeviceId = u32;
+pub type GroupId = u32;
+pub type SceneId = u32;
 pub type DeviceStatus = String;
 
 #[derive(Debug, Eq, PartialEq, Serialize)]
 pub struct ShortDevice {
     pub id: DeviceId,
     pub name: String,
+    /// The radio the device is paired over (e.g. ZWAVE, ZIGBEE or LUTRON), if known.
+    pub interconnect: Option<String>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupKind {
+    /// Groups of devices on one radio, set together by the radio itself.
+    Master,
+    /// Groups the hub sets one device at a time, e.g. across radios.
+    Control,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneTarget {
+    Device(DeviceId),
+    Group(GroupId),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SceneAction {
+    pub target: SceneTarget,
+    pub attribute_id: AttributeId,
+    pub value: serde_json::Value,
+}
+
+/// Writes stored on the hub (in the --scenes file) that are activated with one command, e.g.
+/// turning off every light group.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Scene {
+    pub id: SceneId,
+    pub name: String,
+    pub actions: Vec<SceneAction>,
+}
+
+/// A group of devices from the hub's database. The hub doesn't report the group's members or
+/// state, just that it exists.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Group {
+    pub id: GroupId,
+    pub kind: GroupKind,
+    pub name: String,
+    pub radio: Option<String>,
+}
+
+/// Radios `start_pairing` can listen on.
+pub const PAIRING_RADIOS: &[&str] = &["zwave", "zigbee", "lutron", "kidde"];
+/// How long pairing listens for new devices, unless asked otherwise.
+pub const DEFAULT_PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the device list changed while pairing or removing a device.
+#[derive(Debug, Serialize)]
+pub struct PairingResult {
+    pub added: Vec<ShortDevice>,
+    pub removed: Vec<ShortDevice>,
+    /// Whatever the controller printed along the way, for troubleshooting.
+    pub output: String,
+}
+
+impl PairingResult {
+    fn from_lists(
+        before: Vec<ShortDevice>,
+        after: Vec<ShortDevice>,
+        output: String,
+    ) -> PairingResult {
+        let before_ids = before.iter().map(|d| d.id).collect::<Vec<_>>();
+        let after_ids = after.iter().map(|d| d.id).collect::<Vec<_>>();
+        PairingResult {
+            added: after
+                .into_iter()
+                .filter(|d| !before_ids.contains(&d.id))
+                .collect(),
+            removed: before
+                .into_iter()
+                .filter(|d| !after_ids.contains(&d.id))
+                .collect(),
+            output,
+        }
+    }
+}
+
+/// How long a single Z-Wave maintenance command may take; neighbor updates on a big network
+/// take a while.
+pub const MAINTENANCE_STEP_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum ZwaveOperation {
+    /// A neighbor update for every Z-Wave node, one at a time.
+    HealNetwork,
+    /// Removes a node the controller has marked as failed, e.g. a dead device that can't be
+    /// excluded.
+    RemoveFailedNode { device_id: DeviceId },
+    /// Has the node rediscover its neighbors, e.g. after being moved.
+    NeighborUpdate { device_id: DeviceId },
+}
+
+/// How far along a Z-Wave maintenance operation is. The controller fills in the steps as it
+/// goes; whoever started the operation fills in when it ended.
+#[derive(Clone, Debug, Serialize)]
+pub struct MaintenanceProgress {
+    pub operation: ZwaveOperation,
+    pub started: u64,
+    pub finished: Option<u64>,
+    pub steps_done: usize,
+    pub steps_total: usize,
+    /// What each step printed (or why it failed).
+    pub output: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl MaintenanceProgress {
+    pub fn new(operation: ZwaveOperation) -> MaintenanceProgress {
+        MaintenanceProgress {
+            operation,
+            started: unix_timestamp(),
+            finished: None,
+            steps_done: 0,
+            steps_total: 1,
+            output: vec![],
+            error: None,
+        }
+    }
+
+    async fn step_done(progress: &Mutex<MaintenanceProgress>, output: String) {
+        let mut progress = progress.lock().await;
+        progress.steps_done += 1;
+        progress.output.push(output);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum AttributeType {
     Bool,
     String,
@@ -32,9 +171,17 @@ pub enum AttributeType {
     UInt16,
     UInt32,
     UInt64,
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    Float,
+    /// A string attribute that only takes these values (e.g. ON/OFF/TOGGLE). aprontest doesn't
+    /// report these; they come from the device's `enum_values` override.
+    Enum(Vec<String>),
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AttributeValue {
     NoValue,
     Bool(bool),
@@ -43,9 +190,19 @@ pub enum AttributeValue {
     UInt16(u16),
     UInt32(u32),
     UInt64(u64),
+    Int8(i8),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    Float(f64),
 }
 
 impl AttributeType {
+    /// Whether values of this type are strings on the wire.
+    pub fn is_string(&self) -> bool {
+        matches!(self, AttributeType::String | AttributeType::Enum(_))
+    }
+
     pub fn parse(&self, s: &str) -> Result<AttributeValue, Box<dyn Error>> {
         let payload_str = s.trim();
         Ok(match self {
@@ -53,7 +210,25 @@ impl AttributeType {
             AttributeType::UInt16 => AttributeValue::UInt16(payload_str.parse::<u16>()?),
             AttributeType::UInt32 => AttributeValue::UInt32(payload_str.parse::<u32>()?),
             AttributeType::UInt64 => AttributeValue::UInt64(payload_str.parse::<u64>()?),
+            AttributeType::Int8 => AttributeValue::Int8(payload_str.parse::<i8>()?),
+            AttributeType::Int16 => AttributeValue::Int16(payload_str.parse::<i16>()?),
+            AttributeType::Int32 => AttributeValue::Int32(payload_str.parse::<i32>()?),
+            AttributeType::Int64 => AttributeValue::Int64(payload_str.parse::<i64>()?),
+            AttributeType::Float => AttributeValue::Float(parse_float(payload_str)?),
             AttributeType::String => AttributeValue::String(payload_str.to_string()),
+            AttributeType::Enum(values) => AttributeValue::String(
+                values
+                    .iter()
+                    .find(|v| v.eq_ignore_ascii_case(payload_str))
+                    .ok_or_else(|| {
+                        simple_error!(
+                            "Bad value {}, expected one of: {}",
+                            payload_str,
+                            values.join(", ")
+                        )
+                    })?
+                    .clone(),
+            ),
             AttributeType::Bool => {
                 AttributeValue::Bool(match payload_str.to_ascii_lowercase().as_str() {
                     "true" | "1" | "yes" | "on" => true,
@@ -70,6 +245,7 @@ impl AttributeType {
                 AttributeValue::String(s.clone())
             }
             (v, AttributeType::String) => AttributeValue::String(v.to_string()),
+            (serde_json::Value::String(s), AttributeType::Enum(_)) => self.parse(s)?,
             (serde_json::Value::Number(n), AttributeType::UInt8) => AttributeValue::UInt8(
                 n.as_u64()
                     .ok_or_else(|| simple_error!("{} is not a u64", n))?
@@ -89,6 +265,29 @@ impl AttributeType {
                 n.as_u64()
                     .ok_or_else(|| simple_error!("{} is not a u64", n))?,
             ),
+            (serde_json::Value::Number(n), AttributeType::Int8) => AttributeValue::Int8(
+                n.as_i64()
+                    .ok_or_else(|| simple_error!("{} is not an i64", n))?
+                    .try_into()?,
+            ),
+            (serde_json::Value::Number(n), AttributeType::Int16) => AttributeValue::Int16(
+                n.as_i64()
+                    .ok_or_else(|| simple_error!("{} is not an i64", n))?
+                    .try_into()?,
+            ),
+            (serde_json::Value::Number(n), AttributeType::Int32) => AttributeValue::Int32(
+                n.as_i64()
+                    .ok_or_else(|| simple_error!("{} is not an i64", n))?
+                    .try_into()?,
+            ),
+            (serde_json::Value::Number(n), AttributeType::Int64) => AttributeValue::Int64(
+                n.as_i64()
+                    .ok_or_else(|| simple_error!("{} is not an i64", n))?,
+            ),
+            (serde_json::Value::Number(n), AttributeType::Float) => AttributeValue::Float(
+                n.as_f64()
+                    .ok_or_else(|| simple_error!("{} is not an f64", n))?,
+            ),
             (serde_json::Value::Bool(v), AttributeType::Bool) => AttributeValue::Bool(*v),
             (v, _) => {
                 bail!("unknown value for type {:?}: {}", self, v);
@@ -107,9 +306,48 @@ impl AttributeValue {
             AttributeValue::UInt16(_) => Some(AttributeType::UInt16),
             AttributeValue::UInt32(_) => Some(AttributeType::UInt32),
             AttributeValue::UInt64(_) => Some(AttributeType::UInt64),
+            AttributeValue::Int8(_) => Some(AttributeType::Int8),
+            AttributeValue::Int16(_) => Some(AttributeType::Int16),
+            AttributeValue::Int32(_) => Some(AttributeType::Int32),
+            AttributeValue::Int64(_) => Some(AttributeType::Int64),
+            AttributeValue::Float(_) => Some(AttributeType::Float),
+        }
+    }
+
+    /// Whether the value means "on"/"open" - i.e. anything but zero, false or off.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            AttributeValue::NoValue => false,
+            AttributeValue::Bool(b) => *b,
+            AttributeValue::UInt8(i) => *i != 0,
+            AttributeValue::UInt16(i) => *i != 0,
+            AttributeValue::UInt32(i) => *i != 0,
+            AttributeValue::UInt64(i) => *i != 0,
+            AttributeValue::Int8(i) => *i != 0,
+            AttributeValue::Int16(i) => *i != 0,
+            AttributeValue::Int32(i) => *i != 0,
+            AttributeValue::Int64(i) => *i != 0,
+            AttributeValue::Float(f) => *f != 0.0,
+            AttributeValue::String(s) => !["", "0", "OFF", "FALSE", "CLOSE", "CLOSED"]
+                .contains(&s.to_ascii_uppercase().as_str()),
         }
     }
 
+    /// A value without a known attribute type (e.g. for a group), taken from the json type.
+    pub fn from_untyped_json(value: &serde_json::Value) -> Result<AttributeValue, Box<dyn Error>> {
+        Ok(match value {
+            serde_json::Value::Bool(v) => AttributeValue::Bool(*v),
+            serde_json::Value::String(v) => AttributeValue::String(v.clone()),
+            serde_json::Value::Number(v) => match (v.as_u64(), v.as_i64(), v.as_f64()) {
+                (Some(v), _, _) => AttributeValue::UInt64(v),
+                (_, Some(v), _) => AttributeValue::Int64(v),
+                (_, _, Some(v)) => AttributeValue::Float(v),
+                _ => bail!("Bad value: {}", value),
+            },
+            _ => bail!("Bad value: {}", value),
+        })
+    }
+
     pub fn or<'a>(&'a self, other: &'a AttributeValue) -> &'a AttributeValue {
         if *self == AttributeValue::NoValue {
             other
@@ -126,6 +364,13 @@ impl AttributeValue {
             AttributeValue::UInt16(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
             AttributeValue::UInt32(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
             AttributeValue::UInt64(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            AttributeValue::Int8(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            AttributeValue::Int16(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            AttributeValue::Int32(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            AttributeValue::Int64(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            // Json has no NaN or infinity.
+            AttributeValue::Float(f) => serde_json::Number::from_f64(*f)
+                .map_or(serde_json::Value::Null, serde_json::Value::Number),
             AttributeValue::String(s) => serde_json::Value::String(s.clone()),
         }
     }
@@ -140,7 +385,7 @@ impl Serialize for AttributeValue {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct DeviceAttribute {
     pub id: AttributeId,
     pub description: String,
@@ -151,7 +396,44 @@ pub struct DeviceAttribute {
     pub setting_value: AttributeValue,
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize)]
+/// Values are serialized without their type; it takes the attribute type to read them back.
+#[derive(Deserialize)]
+struct SerializedDeviceAttribute {
+    id: AttributeId,
+    description: String,
+    attribute_type: AttributeType,
+    supports_write: bool,
+    supports_read: bool,
+    current_value: serde_json::Value,
+    setting_value: serde_json::Value,
+}
+
+impl<'de> Deserialize<'de> for DeviceAttribute {
+    fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let attribute = SerializedDeviceAttribute::deserialize(deserializer)?;
+        let parse = |value: &serde_json::Value| match value {
+            serde_json::Value::Null => Ok(AttributeValue::NoValue),
+            value => attribute
+                .attribute_type
+                .parse_json(value)
+                .map_err(serde::de::Error::custom),
+        };
+        Ok(DeviceAttribute {
+            current_value: parse(&attribute.current_value)?,
+            setting_value: parse(&attribute.setting_value)?,
+            id: attribute.id,
+            description: attribute.description,
+            attribute_type: attribute.attribute_type,
+            supports_write: attribute.supports_write,
+            supports_read: attribute.supports_read,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct LongDevice {
     // These probably don't change often
     pub gang_id: Option<u32>,
@@ -160,6 +442,16 @@ pub struct LongDevice {
     pub manufacturer_id: Option<u16>,
     pub product_type: Option<u16>,
     pub product_number: Option<u16>,
+    pub interconnect: Option<String>,
+    /// Battery powered devices print these above their attributes.
+    pub battery_level: Option<u8>,
+    /// Seconds between the times a sleeping device wakes up to take commands.
+    pub wakeup_interval: Option<u32>,
+    /// Link quality, from the device status line: a node that keeps failing transmissions or
+    /// hasn't been heard from in a while is likely out of range.
+    pub failed_tx: Option<u32>,
+    pub seconds_since_rx: Option<u32>,
+    pub polling_period: Option<u32>,
 
     pub id: DeviceId,
     pub status: DeviceStatus,
@@ -174,9 +466,77 @@ pub struct DeviceMeta {
     pub version: String,
 }
 
+lazy_static! {
+    /// Attribute names that mean the same thing across firmware versions, mapped to the name
+    /// the rest of the bridge uses. --attribute-synonym ones are applied by renaming instead.
+    static ref ATTRIBUTE_SYNONYMS: HashMap<&'static str, &'static str> = [
+        ("OnOff", "On_Off"),
+        ("On/Off", "On_Off"),
+        ("Switch", "On_Off"),
+        ("Barrier_Operator", "BarrierOperator"),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+}
+
+/// The name the bridge uses for an attribute, e.g. `On_Off` for `OnOff`.
+pub fn canonical_attribute_name(name: &str) -> &str {
+    ATTRIBUTE_SYNONYMS.get(name).cloned().unwrap_or(name)
+}
+
+impl DeviceAttribute {
+    pub fn canonical_name(&self) -> String {
+        canonical_attribute_name(&self.description).to_string()
+    }
+}
+
 impl LongDevice {
     pub fn attribute<'a>(&'a self, s: &str) -> Option<&'a DeviceAttribute> {
-        self.attributes.iter().find(|x| x.description == s)
+        let name = canonical_attribute_name(s);
+        self.attributes
+            .iter()
+            .find(|x| canonical_attribute_name(&x.description) == name)
+    }
+
+    /// Renames the attributes whose reported names are in `aliases`, unless the device already
+    /// has an attribute by the new name.
+    pub fn apply_attribute_aliases(&mut self, aliases: &BTreeMap<String, String>) {
+        for (reported, alias) in aliases.iter() {
+            let index = match self
+                .attributes
+                .iter()
+                .position(|a| &a.description == reported)
+            {
+                Some(index) if reported != alias => index,
+                _ => continue,
+            };
+            if self.attribute(alias).is_some() {
+                warn!(slog_scope::logger(), "attribute_alias_taken"; "device_id" => self.id, "attribute" => reported, "alias" => alias);
+                continue;
+            }
+            self.attributes[index].description = alias.clone();
+        }
+    }
+
+    /// Turns the string attributes named in `enum_values` into enumerations of the listed
+    /// values. Other attribute types are left alone: their values aren't written as strings.
+    pub fn apply_enum_values(&mut self, enum_values: &BTreeMap<String, Vec<String>>) {
+        let device_id = self.id;
+        for (name, values) in enum_values.iter() {
+            let name = canonical_attribute_name(name);
+            for attribute in self
+                .attributes
+                .iter_mut()
+                .filter(|a| canonical_attribute_name(&a.description) == name)
+            {
+                if attribute.attribute_type.is_string() {
+                    attribute.attribute_type = AttributeType::Enum(values.clone());
+                } else {
+                    warn!(slog_scope::logger(), "enum_values_for_non_string"; "device_id" => device_id, "attribute" => &attribute.description);
+                }
+            }
+        }
     }
 
     pub fn attribute_str<'a>(&'a self, s: &str) -> Option<&'a str> {
@@ -189,33 +549,86 @@ impl LongDevice {
         }
     }
 
+    /// The barrier attribute of a garage door opener (e.g. GoControl/Linear GD00Z).
+    pub fn garage_door_attribute(&self) -> Option<&DeviceAttribute> {
+        ["BarrierOperator", "Barrier_Operator", "GarageDoor"]
+            .iter()
+            .filter_map(|name| self.attribute(name))
+            .find(|a| a.supports_write)
+            .or_else(
+                || match (self.generic_device_type, self.specific_device_type) {
+                    // Z-Wave entry control / barrier operator
+                    (Some(0x40), Some(0x07)) => {
+                        self.attribute("GenericValue").filter(|a| a.supports_write)
+                    }
+                    _ => None,
+                },
+            )
+    }
+
+    /// The on/off attribute of a siren or alarm (e.g. Kidde smoke alarms and Z-Wave sirens).
+    pub fn siren_attribute(&self) -> Option<&DeviceAttribute> {
+        ["Siren", "Alarm", "Siren_Alarm", "AlarmActive"]
+            .iter()
+            .filter_map(|name| self.attribute(name))
+            .find(|a| a.supports_write)
+            .or_else(
+                || match (self.generic_device_type, self.specific_device_type) {
+                    // Z-Wave binary switch / siren
+                    (Some(0x10), Some(0x05)) => {
+                        self.attribute("GenericValue").filter(|a| a.supports_write)
+                    }
+                    _ => None,
+                },
+            )
+    }
+
+    /// Battery percentage, on battery powered devices.
+    pub fn battery_attribute(&self) -> Option<&DeviceAttribute> {
+        ["BatteryLevel", "Battery_Level", "Battery"]
+            .iter()
+            .filter_map(|name| self.attribute(name))
+            .find(|a| a.attribute_type != AttributeType::Bool && !a.attribute_type.is_string())
+    }
+
+    /// The lock/unlock attribute of a door lock.
+    pub fn lock_attribute(&self) -> Option<&DeviceAttribute> {
+        ["Locked", "Lock", "DoorLock"]
+            .iter()
+            .filter_map(|name| self.attribute(name))
+            .find(|a| a.supports_write)
+            .or_else(
+                || match (self.generic_device_type, self.specific_device_type) {
+                    // Z-Wave entry control / (secure keypad) door lock
+                    (Some(0x40), Some(0x01)) | (Some(0x40), Some(0x03)) => {
+                        self.attribute("GenericValue").filter(|a| a.supports_write)
+                    }
+                    _ => None,
+                },
+            )
+    }
+
     pub fn device_meta(&self) -> DeviceMeta {
         match (self.manufacturer_id, self.product_number, self.product_type) {
-            // You can get this information from e.g.
-            // http://www.openzwave.net/device-database/0063.3131.4944
-            (Some(0x0063), Some(0x3131), Some(0x4944)) => DeviceMeta {
-                manufacturer: "GE (Jasco Products)".to_string(),
-                product: "Fan Control Switch".to_string(),
-                version: "".to_string(),
-            },
-            (Some(0x0063), Some(0x3036), Some(0x4952)) => DeviceMeta {
-                manufacturer: "GE (Jasco Products)".to_string(),
-                product: "Switch".to_string(),
-                version: "".to_string(),
-            },
-            (Some(0x027a), Some(0xa001), Some(0xa000)) => DeviceMeta {
-                manufacturer: "Zooz".to_string(),
-                product: "S2 On Off Wall Switch".to_string(),
-                version: "".to_string(),
-            },
-            (Some(manufacturer_id), Some(product_number), Some(product_type)) => DeviceMeta {
-                manufacturer: format!("Unknown ({:04x})", manufacturer_id),
-                product: format!(
-                    "Unknown ({:04x}.{:04x}.{:04x})",
-                    manufacturer_id, product_number, product_type
-                ),
-                version: "".to_string(),
-            },
+            (Some(manufacturer_id), Some(product_number), Some(product_type)) => {
+                let products = PRODUCTS.read().unwrap_or_else(|e| e.into_inner());
+                DeviceMeta {
+                    manufacturer: products
+                        .manufacturer(manufacturer_id)
+                        .map(|x| x.to_string())
+                        .unwrap_or_else(|| format!("Unknown ({:04x})", manufacturer_id)),
+                    product: products
+                        .product(manufacturer_id, product_type, product_number)
+                        .map(|x| x.to_string())
+                        .unwrap_or_else(|| {
+                            format!(
+                                "Unknown ({:04x}.{:04x}.{:04x})",
+                                manufacturer_id, product_number, product_type
+                            )
+                        }),
+                    version: "".to_string(),
+                }
+            }
             (None, None, None) => DeviceMeta {
                 manufacturer: self
                     .attribute_str("ManufacturerName")
@@ -239,6 +652,22 @@ impl LongDevice {
     }
 }
 
+/// Failure modes that can be injected into a controller to exercise error handling.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct FaultConfig {
+    /// `set` always fails for these devices.
+    pub fail_set_devices: Vec<DeviceId>,
+    /// Probability (0.0 - 1.0) that any `set` call fails.
+    pub set_error_rate: f64,
+    /// These devices report an OFFLINE status, and sets to them fail.
+    pub offline_devices: Vec<DeviceId>,
+    /// Extra latency added to every `describe` call.
+    pub describe_latency_ms: u64,
+    /// Probability (0.0 - 1.0) that a `describe` call fails with a parse error.
+    pub parse_error_rate: f64,
+}
+
 #[async_trait]
 pub trait DeviceController: Send + Sync {
     async fn list(&self) -> Result<Vec<ShortDevice>, Box<dyn Error>>;
@@ -249,40 +678,386 @@ pub trait DeviceController: Send + Sync {
         attribute_id: AttributeId,
         value: &AttributeValue,
     ) -> Result<(), Box<dyn Error>>;
+
+    /// Sets several attributes of a device, in order. Backends that can do it in one go should
+    /// override this.
+    async fn set_many(
+        &self,
+        master_id: DeviceId,
+        values: &[(AttributeId, AttributeValue)],
+    ) -> Result<(), Box<dyn Error>> {
+        for (attribute_id, value) in values.iter() {
+            self.set(master_id, *attribute_id, value).await?;
+        }
+        Ok(())
+    }
+
+    /// The device and its attributes (ids, names, types, modes), for validating and routing
+    /// commands. Attribute values may be stale or missing; use `describe` for those. Backends
+    /// that can read the schema without a full state read should override this.
+    async fn attribute_schema(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        self.describe(master_id).await
+    }
+
+    /// One attribute with its current value, e.g. to check that a set took. Backends that can
+    /// read a single attribute without a full describe should override this.
+    async fn get(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+    ) -> Result<DeviceAttribute, Box<dyn Error>> {
+        take_attribute(self.describe(master_id).await?, attribute_id)
+    }
+
+    /// Counts and latencies of the operations run so far, by operation.
+    fn stats(&self) -> BTreeMap<String, OperationStats> {
+        BTreeMap::new()
+    }
+
+    /// Drops anything cached about the device, so the next `describe` reads it from the hub.
+    async fn forget_cached(&self, _master_id: DeviceId) {}
+
+    async fn describe_all(&self) -> Result<Vec<LongDevice>, Box<dyn Error>> {
+        let listed = self.list().await?;
+        let mut devices = Vec::with_capacity(listed.len());
+        for device in listed {
+            devices.push(self.describe(device.id).await?);
+        }
+        Ok(devices)
+    }
+
+    /// The unparsed output the controller got for the device, for debugging parse issues.
+    async fn describe_raw(&self, _master_id: DeviceId) -> Result<String, Box<dyn Error>> {
+        bail!("This controller does not support raw output")
+    }
+
+    async fn faults(&self) -> Result<FaultConfig, Box<dyn Error>> {
+        bail!("This controller does not support fault injection")
+    }
+    async fn set_faults(&self, _faults: FaultConfig) -> Result<(), Box<dyn Error>> {
+        bail!("This controller does not support fault injection")
+    }
+
+    /// Listens for new devices on `radio` (one of `PAIRING_RADIOS`) for `timeout`.
+    async fn start_pairing(
+        &self,
+        _radio: &str,
+        _timeout: Duration,
+    ) -> Result<PairingResult, Box<dyn Error>> {
+        bail!("This controller does not support pairing")
+    }
+
+    /// Unpairs the device and removes it from the hub.
+    async fn remove_device(&self, _master_id: DeviceId) -> Result<PairingResult, Box<dyn Error>> {
+        bail!("This controller does not support removing devices")
+    }
+
+    /// Changes the device's name on the hub (aprontest's USERNAME).
+    async fn rename(&self, _master_id: DeviceId, _name: &str) -> Result<(), Box<dyn Error>> {
+        bail!("This controller does not support renaming devices")
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, Box<dyn Error>> {
+        Ok(vec![])
+    }
+
+    /// Sets the attribute on every device in the group.
+    async fn set_group(
+        &self,
+        _group_id: GroupId,
+        _attribute_id: AttributeId,
+        _value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        bail!("This controller does not support groups")
+    }
+
+    /// Runs a Z-Wave maintenance operation, updating `progress` as it goes. These can take
+    /// minutes.
+    async fn zwave_maintenance(
+        &self,
+        _operation: ZwaveOperation,
+        _progress: &Mutex<MaintenanceProgress>,
+    ) -> Result<(), Box<dyn Error>> {
+        bail!("This controller does not support Z-Wave maintenance")
+    }
+
+    /// Runs the scene's writes in order. Device values are parsed with the attribute's type;
+    /// group values, lacking one, go by their json type.
+    async fn activate_scene(&self, scene: &Scene) -> Result<(), Box<dyn Error>> {
+        for action in scene.actions.iter() {
+            match action.target {
+                SceneTarget::Device(master_id) => {
+                    let device = self.attribute_schema(master_id).await?;
+                    let attribute = device
+                        .attributes
+                        .iter()
+                        .find(|a| a.id == action.attribute_id)
+                        .ok_or_else(|| {
+                            simple_error!(
+                                "No attribute {} on device {}",
+                                action.attribute_id,
+                                master_id
+                            )
+                        })?;
+                    let value = attribute.attribute_type.parse_json(&action.value)?;
+                    self.set(master_id, action.attribute_id, &value).await?;
+                }
+                SceneTarget::Group(group_id) => {
+                    let value = AttributeValue::from_untyped_json(&action.value)?;
+                    self.set_group(group_id, action.attribute_id, &value)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The attribute, out of a described device.
+pub fn take_attribute(
+    device: LongDevice,
+    attribute_id: AttributeId,
+) -> Result<DeviceAttribute, Box<dyn Error>> {
+    let master_id = device.id;
+    Ok(device
+        .attributes
+        .into_iter()
+        .find(|a| a.id == attribute_id)
+        .ok_or_else(|| simple_error!("No attribute {} on device {}", attribute_id, master_id))?)
+}
+
+// Names end up in a column of `aprontest -l` output; keep them to one non-empty line.
+fn validate_device_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if name.trim().is_empty() || name.contains(|c: char| c.is_control()) {
+        bail!("Bad device name {:?}", name)
+    }
+    Ok(())
 }
 
+// How long a describe result is reused. Sets invalidate it right away.
+const DESCRIBE_CACHE_TTL: Duration = Duration::from_secs(2);
+
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_COMMAND_RETRIES: u32 = 2;
+
+// Delay before the first retry of a failed read; doubled for every retry after that.
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+// aprontest errors that go away on their own, e.g. aprond's database being busy.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "database is locked",
+    "SQLITE_BUSY",
+    "Resource temporarily unavailable",
+];
+
+fn is_transient(error: &dyn Error) -> bool {
+    let message = error.to_string();
+    TRANSIENT_ERRORS.iter().any(|e| message.contains(e))
+}
+
+// How long a read waits before trying again after giving way to a set.
+const READ_YIELD: Duration = Duration::from_millis(10);
+
+type Runner = Box<
+    dyn for<'a> Fn(
+            &'a [&str],
+        )
+            -> Pin<Box<dyn Future<Output = Result<String, Box<dyn Error>>> + 'a + Send>>
+        + Send
+        + Sync,
+>;
+
 pub struct AprontestController {
-    runner: Box<
-        dyn for<'a> Fn(
-                &'a [&str],
-            )
-                -> Pin<Box<dyn Future<Output = Result<String, Box<dyn Error>>> + 'a + Send>>
-            + Send
-            + Sync,
-    >,
+    runner: Runner,
+    /// Recent describe results. Commands usually follow a describe of the same device (e.g. by
+    /// the http api), so this saves an aprontest run per command.
+    describe_cache: Mutex<HashMap<DeviceId, (Instant, LongDevice)>>,
+    /// Radios seen in the last list. `aprontest -l -m` doesn't print them.
+    interconnects: Mutex<HashMap<DeviceId, String>>,
+    /// Held while aprontest runs: concurrent runs race on aprond's socket and fail.
+    queue: Mutex<()>,
+    /// Sets waiting for `queue`. Reads give way to them.
+    waiting_sets: AtomicU32,
+    timeout: Duration,
+    /// How many times failed reads are retried.
+    retries: u32,
+    /// Hub reads and writes; describes answered from `describe_cache` aren't counted.
+    stats: OperationRecorder,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Priority {
+    /// Changes a device; someone's waiting on it.
+    Set,
+    /// Reads device state, e.g. for polling.
+    Read,
+}
+
+/// The raw result of an aprontest invocation, for debugging.
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandOutput {
+    pub command: String,
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
 }
 
+/// aprontest didn't finish in time; it's been killed. Usually means aprond is stuck.
+#[derive(Debug)]
+pub struct TimeoutError {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} didn't finish within {:?}",
+            self.command, self.timeout
+        )
+    }
+}
+impl Error for TimeoutError {}
+
 impl AprontestController {
-    pub fn new() -> AprontestController {
-        AprontestController {
-            runner: Box::new(|cmd| {
-                Box::pin((async move || {
-                    debug!(slog_scope::logger(), "running_command"; "cmd" => cmd.join(" "));
-                    let result = Command::new(cmd[0]).args(&cmd[1..]).output().await?;
-                    if !result.status.success() {
-                        bail!("Calling aprontest failed. Something went horribly wrong.\nCommand: {}\nStderr:\n{}", cmd.join(" "), std::str::from_utf8(&result.stderr)?)
+    /// Runs aprontest, sending the output of every command to `sink`. Kills commands that take
+    /// longer than `timeout` and retries transient read failures `retries` times.
+    pub fn with_options(
+        sink: Option<Sender<CommandOutput>>,
+        timeout: Duration,
+        retries: u32,
+    ) -> AprontestController {
+        let mut controller = Self::with_runner(Box::new(move |cmd| {
+            let sink = sink.clone();
+            Box::pin((async move || {
+                debug!(slog_scope::logger(), "running_command"; "cmd" => cmd.join(" "));
+                // Timeouts drop this future; don't leave the process behind.
+                let result = Command::new(cmd[0])
+                    .args(&cmd[1..])
+                    .kill_on_drop(true)
+                    .output()
+                    .await?;
+                if let Some(sink) = sink {
+                    // Never hold up device commands for debug output.
+                    let output = CommandOutput {
+                        command: cmd.join(" "),
+                        success: result.status.success(),
+                        stdout: String::from_utf8_lossy(&result.stdout).into_owned(),
+                        stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
                     };
-                    Ok(std::str::from_utf8(&result.stdout)?.to_string())
-                })())
-            }),
+                    if sink.try_send(output).is_err() {
+                        debug!(slog_scope::logger(), "dropped_command_output"; "cmd" => cmd.join(" "));
+                    }
+                }
+                if !result.status.success() {
+                    bail!("Calling aprontest failed. Something went horribly wrong.\nCommand: {}\nStderr:\n{}", cmd.join(" "), std::str::from_utf8(&result.stderr)?)
+                };
+                Ok(std::str::from_utf8(&result.stdout)?.to_string())
+            })())
+        }));
+        controller.timeout = timeout;
+        controller.retries = retries;
+        controller
+    }
+
+    fn with_runner(runner: Runner) -> AprontestController {
+        AprontestController {
+            runner,
+            describe_cache: Mutex::new(HashMap::new()),
+            interconnects: Mutex::new(HashMap::new()),
+            queue: Mutex::new(()),
+            waiting_sets: AtomicU32::new(0),
+            timeout: DEFAULT_COMMAND_TIMEOUT,
+            retries: DEFAULT_COMMAND_RETRIES,
+            stats: OperationRecorder::default(),
+        }
+    }
+
+    /// Runs aprontest, retrying reads that fail with a transient error. Sets aren't retried:
+    /// they may have gone through anyway.
+    async fn run(&self, cmd: &[&str], priority: Priority) -> Result<String, Box<dyn Error>> {
+        let mut attempt = 0;
+        loop {
+            let delay = match self.run_once(cmd, priority, self.timeout).await {
+                Err(e)
+                    if priority == Priority::Read
+                        && attempt < self.retries
+                        && is_transient(&*e) =>
+                {
+                    let delay = RETRY_BACKOFF * 2u32.pow(attempt);
+                    warn!(slog_scope::logger(), "retrying_aprontest"; "cmd" => cmd.join(" "), "attempt" => attempt + 1, "delay_ms" => delay.as_millis() as u64, "error" => %e);
+                    delay
+                }
+                result => return result,
+            };
+            tokio::time::delay_for(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Runs aprontest once nothing else is running it, with sets going ahead of queued reads.
+    async fn run_once(
+        &self,
+        cmd: &[&str],
+        priority: Priority,
+        timeout: Duration,
+    ) -> Result<String, Box<dyn Error>> {
+        let _guard = if priority == Priority::Set {
+            self.waiting_sets.fetch_add(1, Ordering::SeqCst);
+            let guard = self.queue.lock().await;
+            self.waiting_sets.fetch_sub(1, Ordering::SeqCst);
+            guard
+        } else {
+            loop {
+                let guard = self.queue.lock().await;
+                if self.waiting_sets.load(Ordering::SeqCst) == 0 {
+                    break guard;
+                }
+                drop(guard);
+                tokio::time::delay_for(READ_YIELD).await;
+            }
+        };
+        match tokio::time::timeout(timeout, (self.runner)(cmd)).await {
+            Ok(result) => result,
+            Err(_) => {
+                error!(slog_scope::logger(), "aprontest_timed_out"; "cmd" => cmd.join(" "), "timeout_secs" => timeout.as_secs());
+                Err(TimeoutError {
+                    command: cmd.join(" "),
+                    timeout,
+                }
+                .into())
+            }
         }
     }
+
+    async fn describe_uncached(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        let mut device = parse_long_device(master_id, &self.describe_raw(master_id).await?)?;
+        device.interconnect = self.interconnects.lock().await.get(&master_id).cloned();
+        Ok(device)
+    }
+
+    /// Runs one of aprontest's Z-Wave node operations (`-z <command> -m <id>`).
+    async fn zwave_node_command(
+        &self,
+        command: &str,
+        master_id: DeviceId,
+    ) -> Result<String, Box<dyn Error>> {
+        self.run_once(
+            &["aprontest", "-z", command, "-m", &format!("{}", master_id)],
+            Priority::Set,
+            MAINTENANCE_STEP_TIMEOUT,
+        )
+        .await
+    }
 }
 
 lazy_static! {
     static ref DEVICE_REGEX_STR: String = r"\s*(?P<id>\d+)\s*\|\s*(?P<interconnect>[^ |]*)\s*\|\s*(?P<name>[^\n]+)".to_owned();
     static ref LIST_REGEX: Regex = Regex::new(&(r"(?ms)^Found \d+ devices in .*MASTERID\s*\|\s*INTERCONNECT\s*\|\s*USERNAME(?P<devices>(?:".to_owned() + &DEVICE_REGEX_STR+ ")*)")).unwrap();
     static ref DEVICE_REGEX : Regex = Regex::new(&DEVICE_REGEX_STR).unwrap();
+    static ref GROUP_LIST_REGEX: Regex = Regex::new(r"(?m)^Found \d+ (?P<kind>master|control) groups in [^\n]*\n\s*GROUP ID\s*\|\s*NAME\s*\|\s*RADIO[ \t]*\|?[ \t]*(?P<groups>(?:\n[ \t]*\d+[ \t]*\|[^\n]*)*)").unwrap();
+    static ref GROUP_REGEX: Regex = Regex::new(r"(?m)^[ \t]*(?P<id>\d+)[ \t]*\|[ \t]*(?P<name>[^|\n]*?)[ \t]*\|[ \t]*(?P<radio>[^ \t|\n]*)").unwrap();
 
     static ref ATTRIBUTE_REGEX_STR: String = r"\s*(?P<id>\d+)\s*\|\s*(?P<description>[^\|]+)\s*\|\s*(?P<type>[^ ]+)\s*\|\s*(?P<mode>[^ ]+)\s*\|\s*(?P<get>[^ ]*)\s*\| *(?P<set>[^\n ]*)".to_owned();
     static ref LONG_DEVICE_REGEX : Regex = Regex::new(&((
@@ -293,16 +1068,27 @@ lazy_static! {
     // r"(?:[^\n]+\n)*" +
     r"(?:Manufacturer ID: (?P<manufacturer_id>(0x)?[0-9A-Fa-f]+) Product Type: (?P<product_type>(0x)?[0-9A-Fa-f]+) Product Number: (?P<product_number>(0x)?[0-9A-Fa-f]+)\n)?" +
     // r"(?:[^\n]+\n)*" +
-    r"(?:Device is (?P<device_status>[^,]+)[^\n]+\n)?" +
+    r"(?:Device is (?P<device_status>[^,\n]+)(?:, (?P<failed_tx>\d+) failed tx attempts)?(?:, (?P<seconds_since_rx>\d+) seconds since last msg rx'ed)?(?:, polling period (?P<polling_period>\d+) seconds)?[^\n]*\n)?" +
     r"(?:[^\n]+\n)*" +
     r"(?P<name>[^\n]+)\n" +
     r"\s*ATTRIBUTE\s*\|\s*DESCRIPTION\s*\|\s*TYPE\s*\|\s*MODE\s*\|\s*GET\s*\|\s*SET" +
     r"(?P<attributes>(?:").to_owned() + &ATTRIBUTE_REGEX_STR + ")*)"
     )).unwrap();
     static ref ATTRIBUTE_REGEX : Regex = Regex::new(&ATTRIBUTE_REGEX_STR).unwrap();
+    static ref BATTERY_REGEX: Regex = Regex::new(r"(?mi)^Battery level: *(?P<level>\d+)").unwrap();
+    static ref WAKEUP_REGEX: Regex = Regex::new(r"(?mi)^Wake ?up interval: *(?P<seconds>\d+)").unwrap();
+}
+
+// Rust happily parses "NaN" and "inf", which can't be sent anywhere as json.
+fn parse_float(v: &str) -> Result<f64, Box<dyn Error>> {
+    let f = v.parse::<f64>()?;
+    if !f.is_finite() {
+        bail!("Bad float value: {}", v)
+    }
+    Ok(f)
 }
 
-fn parse_attr_value(t: AttributeType, v: &str) -> Result<AttributeValue, Box<dyn Error>> {
+fn parse_attr_value(t: &AttributeType, v: &str) -> Result<AttributeValue, Box<dyn Error>> {
     Ok(match v {
         "" => AttributeValue::NoValue,
         v => match t {
@@ -310,113 +1096,264 @@ fn parse_attr_value(t: AttributeType, v: &str) -> Result<AttributeValue, Box<dyn
             AttributeType::UInt16 => AttributeValue::UInt16(v.parse()?),
             AttributeType::UInt32 => AttributeValue::UInt32(v.parse()?),
             AttributeType::UInt64 => AttributeValue::UInt64(v.parse()?),
+            AttributeType::Int8 => AttributeValue::Int8(v.parse()?),
+            AttributeType::Int16 => AttributeValue::Int16(v.parse()?),
+            AttributeType::Int32 => AttributeValue::Int32(v.parse()?),
+            AttributeType::Int64 => AttributeValue::Int64(v.parse()?),
+            AttributeType::Float => AttributeValue::Float(parse_float(v)?),
             AttributeType::Bool => AttributeValue::Bool(match v {
                 "TRUE" => true,
                 "FALSE" => false,
                 _ => bail!("Bad attribute value: {}", v),
             }),
-            AttributeType::String => AttributeValue::String(v.to_string()),
+            // Devices may report values outside the list; only writes are checked.
+            AttributeType::String | AttributeType::Enum(_) => AttributeValue::String(v.to_string()),
         },
     })
 }
 
+/// Parses the output of `aprontest -l -m <master_id>`.
+fn parse_long_device(master_id: DeviceId, stdout: &str) -> Result<LongDevice, Box<dyn Error>> {
+    let parsed = match LONG_DEVICE_REGEX.captures(stdout) {
+        Some(v) => v,
+        _ => bail!("Output does not match regex:\n{}", stdout),
+    };
+
+    Ok(LongDevice {
+        gang_id: parsed
+            .name("gang_id")
+            .map(|v| v.as_str().parse_numberish())
+            .transpose()?,
+        generic_device_type: parsed
+            .name("generic_device_type")
+            .map(|v| v.as_str().parse_numberish())
+            .transpose()?,
+        specific_device_type: parsed
+            .name("specific_device_type")
+            .map(|v| v.as_str().parse_numberish())
+            .transpose()?,
+        manufacturer_id: parsed
+            .name("manufacturer_id")
+            .map(|v| v.as_str().parse_numberish())
+            .transpose()?,
+        product_type: parsed
+            .name("product_type")
+            .map(|v| v.as_str().parse_numberish())
+            .transpose()?,
+        product_number: parsed
+            .name("product_number")
+            .map(|v| v.as_str().parse_numberish())
+            .transpose()?,
+        interconnect: None,
+        // Nice to have, so these don't fail the whole device if they're odd.
+        battery_level: BATTERY_REGEX
+            .captures(stdout)
+            .and_then(|m| m.name("level").unwrap().as_str().parse().ok()),
+        wakeup_interval: WAKEUP_REGEX
+            .captures(stdout)
+            .and_then(|m| m.name("seconds").unwrap().as_str().parse().ok()),
+        failed_tx: parsed
+            .name("failed_tx")
+            .map(|v| v.as_str().parse())
+            .transpose()?,
+        seconds_since_rx: parsed
+            .name("seconds_since_rx")
+            .map(|v| v.as_str().parse())
+            .transpose()?,
+        polling_period: parsed
+            .name("polling_period")
+            .map(|v| v.as_str().parse())
+            .transpose()?,
+        id: master_id,
+        status: parsed
+            .name("device_status")
+            .map_or("", |v| v.as_str())
+            .to_string(),
+        name: parsed.name("name").map_or("", |v| v.as_str()).to_string(),
+        attributes: ATTRIBUTE_REGEX
+            .captures_iter(parsed.name("attributes").unwrap().as_str())
+            .map(|m| -> Result<DeviceAttribute, Box<dyn Error>> {
+                let attribute_type = match m.name("type").unwrap().as_str() {
+                    "UINT8" => AttributeType::UInt8,
+                    "UINT16" => AttributeType::UInt16,
+                    "UINT32" => AttributeType::UInt32,
+                    "UINT64" => AttributeType::UInt64,
+                    "INT8" => AttributeType::Int8,
+                    "INT16" => AttributeType::Int16,
+                    "INT32" => AttributeType::Int32,
+                    "INT64" => AttributeType::Int64,
+                    "FLOAT" | "DOUBLE" => AttributeType::Float,
+                    "BOOL" => AttributeType::Bool,
+                    "STRING" => AttributeType::String,
+                    other => {
+                        UNKNOWN_FORMATS.record(other, master_id, m.get(0).unwrap().as_str().trim());
+                        bail!("Bad attribute type: {}", other)
+                    }
+                };
+                Ok(DeviceAttribute {
+                    id: m.name("id").unwrap().as_str().parse()?,
+                    description: m.name("description").unwrap().as_str().trim().to_string(),
+                    supports_write: m.name("mode").unwrap().as_str().contains("W"),
+                    supports_read: m.name("mode").unwrap().as_str().contains("R"),
+                    current_value: parse_attr_value(
+                        &attribute_type,
+                        m.name("get").unwrap().as_str().trim(),
+                    )?,
+                    setting_value: parse_attr_value(
+                        &attribute_type,
+                        m.name("set").unwrap().as_str().trim(),
+                    )?,
+                    attribute_type,
+                })
+            })
+            .filter_map(|v| match v {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    error!(slog_scope::logger(), "failed_to_parse_attribute"; "error" => ?e);
+                    None
+                }
+            })
+            .collect::<Vec<DeviceAttribute>>(),
+    })
+}
+
+fn parse_device_list(stdout: &str) -> Result<Vec<ShortDevice>, Box<dyn Error>> {
+    let devices = match LIST_REGEX.captures(stdout) {
+        Some(v) => v,
+        _ => bail!("Output doesn't match regex:\n{}", stdout),
+    }
+    .name("devices")
+    .unwrap()
+    .as_str();
+
+    Ok(DEVICE_REGEX
+        .captures_iter(devices)
+        .map(|m| ShortDevice {
+            id: m.name("id").unwrap().as_str().parse().unwrap(),
+            name: m.name("name").unwrap().as_str().to_string(),
+            interconnect: Some(m.name("interconnect").unwrap().as_str().trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string()),
+        })
+        .collect())
+}
+
+// How aprontest's -v takes the value.
+fn aprontest_value(value: &AttributeValue) -> Result<String, Box<dyn Error>> {
+    Ok(match value {
+        AttributeValue::NoValue => bail!("Invalid attribute value: none"),
+        AttributeValue::UInt8(v) => format!("{}", v),
+        AttributeValue::UInt16(v) => format!("{}", v),
+        AttributeValue::UInt32(v) => format!("{}", v),
+        AttributeValue::UInt64(v) => format!("{}", v),
+        AttributeValue::Int8(v) => format!("{}", v),
+        AttributeValue::Int16(v) => format!("{}", v),
+        AttributeValue::Int32(v) => format!("{}", v),
+        AttributeValue::Int64(v) => format!("{}", v),
+        AttributeValue::Float(v) => format!("{}", v),
+        AttributeValue::Bool(v) => if *v { "TRUE" } else { "FALSE" }.to_string(),
+        AttributeValue::String(v) => v.clone(),
+    })
+}
+
 #[async_trait]
 impl DeviceController for AprontestController {
     async fn list(&self) -> Result<Vec<ShortDevice>, Box<dyn Error>> {
-        let stdout = (self.runner)(&["aprontest", "-l"]).await?;
-        let devices = match LIST_REGEX.captures(&stdout) {
-            Some(v) => v,
-            _ => bail!("Output doesn't match regex:\n{}", stdout),
-        }
-        .name("devices")
-        .unwrap()
-        .as_str();
-
-        Ok(DEVICE_REGEX
-            .captures_iter(devices)
-            .map(|m| ShortDevice {
-                id: m.name("id").unwrap().as_str().parse().unwrap(),
-                name: m.name("name").unwrap().as_str().to_string(),
-            })
-            .collect())
+        let started = Instant::now();
+        let devices = {
+            let result = self
+                .run(&["aprontest", "-l"], Priority::Read)
+                .await
+                .and_then(|stdout| parse_device_list(&stdout));
+            self.stats.record("list", result.is_ok(), started.elapsed());
+            result?
+        };
+        *self.interconnects.lock().await = devices
+            .iter()
+            .filter_map(|d| d.interconnect.clone().map(|i| (d.id, i)))
+            .collect();
+        Ok(devices)
     }
 
     async fn describe(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
-        let stdout = (self.runner)(&["aprontest", "-l", "-m", &format!("{}", master_id)]).await?;
+        if let Some((at, device)) = self.describe_cache.lock().await.get(&master_id) {
+            if at.elapsed() < DESCRIBE_CACHE_TTL {
+                return Ok(device.clone());
+            }
+        }
+        let started = Instant::now();
+        let device = {
+            let result = self.describe_uncached(master_id).await;
+            self.stats
+                .record("describe", result.is_ok(), started.elapsed());
+            result?
+        };
+        self.describe_cache
+            .lock()
+            .await
+            .insert(master_id, (Instant::now(), device.clone()));
+        Ok(device)
+    }
 
-        let parsed = match LONG_DEVICE_REGEX.captures(&stdout) {
-            Some(v) => v,
-            _ => bail!("Output does not match regex:\n{}", stdout),
+    async fn get(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+    ) -> Result<DeviceAttribute, Box<dyn Error>> {
+        if let Some((at, device)) = self.describe_cache.lock().await.get(&master_id) {
+            if at.elapsed() < DESCRIBE_CACHE_TTL {
+                if let Some(attribute) = device.attributes.iter().find(|a| a.id == attribute_id) {
+                    return Ok(attribute.clone());
+                }
+            }
+        }
+        // With -t, aprontest only reads that attribute. Builds that don't know it print the whole
+        // device, which parses just the same.
+        let started = Instant::now();
+        let result = self
+            .run(
+                &[
+                    "aprontest",
+                    "-l",
+                    "-m",
+                    &format!("{}", master_id),
+                    "-t",
+                    &format!("{}", attribute_id),
+                ],
+                Priority::Read,
+            )
+            .await
+            .and_then(|stdout| parse_long_device(master_id, &stdout))
+            .map(|device| device.attributes.into_iter().find(|a| a.id == attribute_id))
+            .map_err(|e| e.to_string());
+        self.stats.record("get", result.is_ok(), started.elapsed());
+        let attribute = match result {
+            Ok(attribute) => attribute,
+            Err(e) => {
+                debug!(slog_scope::logger(), "targeted_get_failed"; "device_id" => master_id, "attribute_id" => attribute_id, "error" => %e);
+                None
+            }
         };
+        match attribute {
+            Some(attribute) => Ok(attribute),
+            None => take_attribute(self.describe(master_id).await?, attribute_id),
+        }
+    }
 
-        Ok(LongDevice {
-            gang_id: parsed
-                .name("gang_id")
-                .map(|v| v.as_str().parse_numberish())
-                .transpose()?,
-            generic_device_type: parsed
-                .name("generic_device_type")
-                .map(|v| v.as_str().parse_numberish())
-                .transpose()?,
-            specific_device_type: parsed
-                .name("specific_device_type")
-                .map(|v| v.as_str().parse_numberish())
-                .transpose()?,
-            manufacturer_id: parsed
-                .name("manufacturer_id")
-                .map(|v| v.as_str().parse_numberish())
-                .transpose()?,
-            product_type: parsed
-                .name("product_type")
-                .map(|v| v.as_str().parse_numberish())
-                .transpose()?,
-            product_number: parsed
-                .name("product_number")
-                .map(|v| v.as_str().parse_numberish())
-                .transpose()?,
-            id: master_id,
-            status: parsed
-                .name("device_status")
-                .map_or("", |v| v.as_str())
-                .to_string(),
-            name: parsed.name("name").map_or("", |v| v.as_str()).to_string(),
-            attributes: ATTRIBUTE_REGEX
-                .captures_iter(parsed.name("attributes").unwrap().as_str())
-                .map(|m| -> Result<DeviceAttribute, Box<dyn Error>> {
-                    let attribute_type = match m.name("type").unwrap().as_str() {
-                        "UINT8" => AttributeType::UInt8,
-                        "UINT16" => AttributeType::UInt16,
-                        "UINT32" => AttributeType::UInt32,
-                        "UINT64" => AttributeType::UInt64,
-                        "BOOL" => AttributeType::Bool,
-                        "STRING" => AttributeType::String,
-                        _ => bail!("Bad attribute type: {}", m.name("type").unwrap().as_str()),
-                    };
-                    Ok(DeviceAttribute {
-                        id: m.name("id").unwrap().as_str().parse()?,
-                        description: m.name("description").unwrap().as_str().trim().to_string(),
-                        attribute_type,
-                        supports_write: m.name("mode").unwrap().as_str().contains("W"),
-                        supports_read: m.name("mode").unwrap().as_str().contains("R"),
-                        current_value: parse_attr_value(
-                            attribute_type,
-                            m.name("get").unwrap().as_str().trim(),
-                        )?,
-                        setting_value: parse_attr_value(
-                            attribute_type,
-                            m.name("set").unwrap().as_str().trim(),
-                        )?,
-                    })
-                })
-                .filter_map(|v| match v {
-                    Ok(v) => Some(v),
-                    Err(e) => {
-                        error!(slog_scope::logger(), "failed_to_parse_attribute"; "error" => ?e);
-                        None
-                    }
-                })
-                .collect::<Vec<DeviceAttribute>>(),
-        })
+    fn stats(&self) -> BTreeMap<String, OperationStats> {
+        self.stats.snapshot()
+    }
+
+    async fn forget_cached(&self, master_id: DeviceId) {
+        self.describe_cache.lock().await.remove(&master_id);
+    }
+
+    async fn describe_raw(&self, master_id: DeviceId) -> Result<String, Box<dyn Error>> {
+        self.run(
+            &["aprontest", "-l", "-m", &format!("{}", master_id)],
+            Priority::Read,
+        )
+        .await
     }
 
     async fn set(
@@ -425,170 +1362,641 @@ impl DeviceController for AprontestController {
         attribute_id: AttributeId,
         value: &AttributeValue,
     ) -> Result<(), Box<dyn Error>> {
-        let value = match value {
-            AttributeValue::NoValue => bail!("Invalid attribute value: none"),
-            AttributeValue::UInt8(v) => format!("{}", v),
-            AttributeValue::UInt16(v) => format!("{}", v),
-            AttributeValue::UInt32(v) => format!("{}", v),
-            AttributeValue::UInt64(v) => format!("{}", v),
-            AttributeValue::Bool(v) => if *v { "TRUE" } else { "FALSE" }.to_string(),
-            AttributeValue::String(v) => v.clone(),
-        };
-        (self.runner)(&[
-            "aprontest",
-            "-u",
-            "-m",
-            &format!("{}", master_id),
-            "-t",
-            &format!("{}", attribute_id),
-            "-v",
-            &value,
-        ])
-        .await?;
+        self.set_many(master_id, &[(attribute_id, value.clone())])
+            .await
+    }
+
+    /// aprontest takes any number of `-t`/`-v` pairs, so this is a single run.
+    async fn set_many(
+        &self,
+        master_id: DeviceId,
+        values: &[(AttributeId, AttributeValue)],
+    ) -> Result<(), Box<dyn Error>> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let mut args = vec![
+            "aprontest".to_string(),
+            "-u".to_string(),
+            "-m".to_string(),
+            format!("{}", master_id),
+        ];
+        for (attribute_id, value) in values.iter() {
+            args.push("-t".to_string());
+            args.push(format!("{}", attribute_id));
+            args.push("-v".to_string());
+            args.push(aprontest_value(value)?);
+        }
+        let args = args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>();
+        let started = Instant::now();
+        let result = self
+            .run(&args, Priority::Set)
+            .await
+            .map_err(|e| e.to_string());
+        self.stats.record("set", result.is_ok(), started.elapsed());
+        self.describe_cache.lock().await.remove(&master_id);
+        result?;
+        Ok(())
+    }
+
+    async fn start_pairing(
+        &self,
+        radio: &str,
+        timeout: Duration,
+    ) -> Result<PairingResult, Box<dyn Error>> {
+        if !PAIRING_RADIOS.contains(&radio) {
+            bail!("Unknown radio {}", radio)
+        }
+        let before = self.list().await?;
+        // aprontest listens for the whole time; only kill it if it runs well past that.
+        let output = self
+            .run_once(
+                &[
+                    "aprontest",
+                    "-a",
+                    &format!("{}", timeout.as_secs()),
+                    "-r",
+                    radio,
+                ],
+                Priority::Set,
+                timeout + self.timeout,
+            )
+            .await?;
+        Ok(PairingResult::from_lists(
+            before,
+            self.list().await?,
+            output,
+        ))
+    }
+
+    async fn remove_device(&self, master_id: DeviceId) -> Result<PairingResult, Box<dyn Error>> {
+        let before = self.list().await?;
+        if !before.iter().any(|d| d.id == master_id) {
+            bail!("Device id {} not found", master_id)
+        }
+        let result = self
+            .run(
+                &["aprontest", "-d", "-m", &format!("{}", master_id)],
+                Priority::Set,
+            )
+            .await
+            .map_err(|e| e.to_string());
+        self.describe_cache.lock().await.remove(&master_id);
+        Ok(PairingResult::from_lists(
+            before,
+            self.list().await?,
+            result?,
+        ))
+    }
+
+    async fn rename(&self, master_id: DeviceId, name: &str) -> Result<(), Box<dyn Error>> {
+        validate_device_name(name)?;
+        let result = self
+            .run(
+                &[
+                    "aprontest",
+                    "-u",
+                    "-m",
+                    &format!("{}", master_id),
+                    "-n",
+                    name.trim(),
+                ],
+                Priority::Set,
+            )
+            .await
+            .map_err(|e| e.to_string());
+        self.describe_cache.lock().await.remove(&master_id);
+        result?;
         Ok(())
     }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, Box<dyn Error>> {
+        let stdout = self.run(&["aprontest", "-l"], Priority::Read).await?;
+        let mut groups = Vec::new();
+        for section in GROUP_LIST_REGEX.captures_iter(&stdout) {
+            let kind = match section.name("kind").unwrap().as_str() {
+                "master" => GroupKind::Master,
+                _ => GroupKind::Control,
+            };
+            for m in GROUP_REGEX.captures_iter(section.name("groups").unwrap().as_str()) {
+                groups.push(Group {
+                    id: m.name("id").unwrap().as_str().parse()?,
+                    kind,
+                    name: m.name("name").unwrap().as_str().to_string(),
+                    radio: Some(m.name("radio").unwrap().as_str())
+                        .filter(|v| !v.is_empty())
+                        .map(|v| v.to_string()),
+                });
+            }
+        }
+        Ok(groups)
+    }
+
+    async fn set_group(
+        &self,
+        group_id: GroupId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        let value = aprontest_value(value)?;
+        let result = self
+            .run(
+                &[
+                    "aprontest",
+                    "-u",
+                    "-x",
+                    &format!("{}", group_id),
+                    "-t",
+                    &format!("{}", attribute_id),
+                    "-v",
+                    &value,
+                ],
+                Priority::Set,
+            )
+            .await
+            .map_err(|e| e.to_string());
+        // Any device might be in the group.
+        self.describe_cache.lock().await.clear();
+        result?;
+        Ok(())
+    }
+
+    async fn zwave_maintenance(
+        &self,
+        operation: ZwaveOperation,
+        progress: &Mutex<MaintenanceProgress>,
+    ) -> Result<(), Box<dyn Error>> {
+        match operation {
+            ZwaveOperation::HealNetwork => {
+                let nodes = self
+                    .list()
+                    .await?
+                    .into_iter()
+                    .filter(|d| d.interconnect.as_deref() == Some("ZWAVE"))
+                    .collect::<Vec<_>>();
+                progress.lock().await.steps_total = nodes.len();
+                // Keep going past failures: one unreachable node shouldn't stop the rest.
+                for node in nodes {
+                    let output = match self.zwave_node_command("neighbor_update", node.id).await {
+                        Ok(output) => output,
+                        Err(e) => format!("Node {} failed: {}", node.id, e),
+                    };
+                    MaintenanceProgress::step_done(progress, output).await;
+                }
+            }
+            ZwaveOperation::RemoveFailedNode { device_id } => {
+                let result = self
+                    .zwave_node_command("remove_failed", device_id)
+                    .await
+                    .map_err(|e| e.to_string());
+                self.describe_cache.lock().await.remove(&device_id);
+                MaintenanceProgress::step_done(progress, result?).await;
+            }
+            ZwaveOperation::NeighborUpdate { device_id } => {
+                let output = self
+                    .zwave_node_command("neighbor_update", device_id)
+                    .await?;
+                MaintenanceProgress::step_done(progress, output).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the fake controller has without a scenario file: a fan and a light.
+fn default_fake_devices() -> Vec<LongDevice> {
+    let attribute =
+        |id, description: &str, attribute_type, value: AttributeValue| DeviceAttribute {
+            id,
+            description: description.to_string(),
+            attribute_type,
+            supports_write: true,
+            supports_read: value != AttributeValue::NoValue,
+            current_value: value.clone(),
+            setting_value: value,
+        };
+    vec![
+        LongDevice {
+            gang_id: Some(0x03),
+            generic_device_type: Some(0x11),
+            specific_device_type: Some(0x08),
+            manufacturer_id: Some(0x63),
+            product_type: Some(0x4944),
+            product_number: Some(0x3131),
+            interconnect: Some("ZWAVE".to_string()),
+            battery_level: None,
+            wakeup_interval: None,
+            failed_tx: None,
+            seconds_since_rx: None,
+            polling_period: None,
+            id: 2,
+            status: "ONLINE".to_string(),
+            name: "Bedroom Fan".to_string(),
+            attributes: vec![
+                attribute(
+                    1,
+                    "GenericValue",
+                    AttributeType::UInt8,
+                    AttributeValue::UInt8(0),
+                ),
+                attribute(3, "Level", AttributeType::UInt8, AttributeValue::UInt8(0)),
+                attribute(4, "Up_Down", AttributeType::Bool, AttributeValue::NoValue),
+                attribute(
+                    5,
+                    "StopMovement",
+                    AttributeType::Bool,
+                    AttributeValue::NoValue,
+                ),
+            ],
+        },
+        LongDevice {
+            gang_id: None,
+            generic_device_type: None,
+            specific_device_type: None,
+            manufacturer_id: None,
+            product_type: None,
+            product_number: None,
+            interconnect: Some("ZWAVE".to_string()),
+            battery_level: None,
+            wakeup_interval: None,
+            failed_tx: None,
+            seconds_since_rx: None,
+            polling_period: None,
+            id: 4,
+            status: "".to_string(),
+            name: "Bedroom Light".to_string(),
+            attributes: vec![attribute(
+                1,
+                "On_Off",
+                AttributeType::Bool,
+                AttributeValue::Bool(false),
+            )],
+        },
+    ]
+}
+
+/// Devices for the fake controller to pretend to have, e.g. from `--fake-devices`. Each device's
+/// `raw` is its `aprontest -l -m` output, so a support bundle works as a scenario as is.
+#[derive(Debug, Deserialize)]
+pub struct FakeScenario {
+    pub devices: Vec<FakeScenarioDevice>,
+    /// Failures to inject from the start; the same as posting them to `/api/debug/faults`.
+    #[serde(default)]
+    pub faults: FaultConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FakeScenarioDevice {
+    pub id: DeviceId,
+    pub name: String,
+    #[serde(default)]
+    pub interconnect: Option<String>,
+    /// Usually the output as a string; support bundles have an error object here for devices
+    /// that couldn't be described.
+    pub raw: serde_json::Value,
 }
 
 pub struct FakeController {
+    /// The devices with their initial values, along with their raw output if they have any.
+    devices: Mutex<Vec<(LongDevice, Option<String>)>>,
     attr_values: Mutex<HashMap<(DeviceId, AttributeId), AttributeValue>>,
+    faults: Mutex<FaultConfig>,
+    rng_state: AtomicU32,
+    /// Groups along with their members.
+    groups: Vec<(Group, Vec<DeviceId>)>,
 }
 
 impl FakeController {
     pub fn new() -> FakeController {
         FakeController {
+            groups: vec![(
+                Group {
+                    id: 1,
+                    kind: GroupKind::Master,
+                    name: "Bedroom".to_string(),
+                    radio: Some("ZWAVE".to_string()),
+                },
+                vec![4],
+            )],
+            ..Self::with_devices(
+                default_fake_devices()
+                    .into_iter()
+                    .map(|device| (device, None))
+                    .collect(),
+            )
+        }
+    }
+
+    /// A fake controller with the devices from a scenario file (see `FakeScenario`).
+    pub fn from_scenario(path: &str) -> Result<FakeController, Box<dyn Error>> {
+        let scenario: FakeScenario = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| simple_error!("Bad scenario file {}: {}", path, e))?;
+        let mut devices = Vec::new();
+        for device in scenario.devices.into_iter() {
+            let id = device.id;
+            let raw = match device.raw {
+                serde_json::Value::String(raw) => raw,
+                other => {
+                    warn!(slog_scope::logger(), "skipping_fake_device"; "device_id" => id, "raw" => %other);
+                    continue;
+                }
+            };
+            let mut parsed = parse_long_device(id, &raw)
+                .map_err(|e| simple_error!("Bad output for device {}: {}", id, e))?;
+            parsed.name = device.name;
+            parsed.interconnect = device.interconnect;
+            devices.push((parsed, Some(raw)));
+        }
+        Ok(FakeController {
+            faults: Mutex::new(scenario.faults),
+            ..Self::with_devices(devices)
+        })
+    }
+
+    fn with_devices(devices: Vec<(LongDevice, Option<String>)>) -> FakeController {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        FakeController {
+            devices: Mutex::new(devices),
             attr_values: Mutex::new(HashMap::new()),
+            faults: Mutex::new(FaultConfig::default()),
+            rng_state: AtomicU32::new(seed | 1),
+            groups: vec![],
         }
     }
+
+    async fn device(
+        &self,
+        master_id: DeviceId,
+    ) -> Result<(LongDevice, Option<String>), Box<dyn Error>> {
+        self.devices
+            .lock()
+            .await
+            .iter()
+            .find(|(device, _)| device.id == master_id)
+            .cloned()
+            .ok_or_else(|| simple_error!("Device id {} not found", master_id).into())
+    }
+
+    // xorshift32 - plenty random for failure injection.
+    fn next_random(&self) -> f64 {
+        let mut x = self.rng_state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state.store(x, Ordering::Relaxed);
+        x as f64 / u32::MAX as f64
+    }
+
+    async fn inject_describe_faults(&self, master_id: DeviceId) -> Result<(), Box<dyn Error>> {
+        let faults = self.faults.lock().await.clone();
+        if faults.describe_latency_ms > 0 {
+            tokio::time::delay_for(std::time::Duration::from_millis(faults.describe_latency_ms))
+                .await;
+        }
+        if faults.parse_error_rate > 0.0 && self.next_random() < faults.parse_error_rate {
+            bail!(
+                "Injected fault: output for {} does not match regex",
+                master_id
+            )
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl DeviceController for FakeController {
     async fn list(&self) -> Result<Vec<ShortDevice>, Box<dyn Error>> {
-        Ok(vec![
-            ShortDevice {
-                id: 2,
-                name: "Bedroom Fan".to_string(),
-            },
-            ShortDevice {
-                id: 4,
-                name: "Bedroom Light".to_string(),
-            },
-        ])
+        Ok(self
+            .devices
+            .lock()
+            .await
+            .iter()
+            .map(|(device, _)| ShortDevice {
+                id: device.id,
+                name: device.name.clone(),
+                interconnect: device.interconnect.clone(),
+            })
+            .collect())
     }
 
     async fn describe(&self, master_id: u32) -> Result<LongDevice, Box<dyn Error>> {
+        self.inject_describe_faults(master_id).await?;
+        let mut device = self.device(master_id).await?.0;
+        if self
+            .faults
+            .lock()
+            .await
+            .offline_devices
+            .contains(&master_id)
+        {
+            device.status = "OFFLINE".to_string();
+        }
         let attr_values = self.attr_values.lock().await;
-        match master_id {
-            2 => Ok(LongDevice {
-                gang_id: Some(0x03),
-                generic_device_type: Some(0x11),
-                specific_device_type: Some(0x08),
-                manufacturer_id: Some(0x63),
-                product_type: Some(0x4944),
-                product_number: Some(0x3131),
-                id: master_id,
-                status: "ONLINE".to_string(),
-                name: "Bedroom Fan".to_string(),
-                attributes: vec![
-                    DeviceAttribute {
+        for attribute in device.attributes.iter_mut() {
+            if let Some(value) = attr_values.get(&(master_id, attribute.id)) {
+                attribute.current_value = value.clone();
+                attribute.setting_value = value.clone();
+            }
+        }
+        Ok(device)
+    }
+
+    async fn describe_raw(&self, master_id: DeviceId) -> Result<String, Box<dyn Error>> {
+        match self.device(master_id).await?.1 {
+            Some(raw) => Ok(raw),
+            None => bail!("This controller does not support raw output"),
+        }
+    }
+
+    async fn set(
+        &self,
+        master_id: u32,
+        attribute_id: u32,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        let known = self.device(master_id).await.map_or(false, |(device, _)| {
+            device.attributes.iter().any(|a| a.id == attribute_id)
+        });
+        if !known || *value == AttributeValue::NoValue {
+            bail!("Invalid set inputs: {}/{}", master_id, attribute_id)
+        }
+        let faults = self.faults.lock().await.clone();
+        if faults.offline_devices.contains(&master_id) {
+            bail!("Injected fault: device {} is offline", master_id)
+        }
+        if faults.fail_set_devices.contains(&master_id)
+            || (faults.set_error_rate > 0.0 && self.next_random() < faults.set_error_rate)
+        {
+            bail!("Injected fault: set failed for device {}", master_id)
+        }
+        self.attr_values
+            .lock()
+            .await
+            .insert((master_id, attribute_id), value.clone());
+        Ok(())
+    }
+
+    async fn faults(&self) -> Result<FaultConfig, Box<dyn Error>> {
+        Ok(self.faults.lock().await.clone())
+    }
+
+    async fn set_faults(&self, faults: FaultConfig) -> Result<(), Box<dyn Error>> {
+        *self.faults.lock().await = faults;
+        Ok(())
+    }
+
+    /// Pairs a new on/off switch right away, rather than waiting out `timeout`.
+    async fn start_pairing(
+        &self,
+        radio: &str,
+        _timeout: Duration,
+    ) -> Result<PairingResult, Box<dyn Error>> {
+        if !PAIRING_RADIOS.contains(&radio) {
+            bail!("Unknown radio {}", radio)
+        }
+        let before = self.list().await?;
+        {
+            let mut devices = self.devices.lock().await;
+            let id = devices.iter().map(|(d, _)| d.id).max().unwrap_or(0) + 1;
+            devices.push((
+                LongDevice {
+                    gang_id: None,
+                    generic_device_type: None,
+                    specific_device_type: None,
+                    manufacturer_id: None,
+                    product_type: None,
+                    product_number: None,
+                    interconnect: Some(radio.to_ascii_uppercase()),
+                    battery_level: None,
+                    wakeup_interval: None,
+                    failed_tx: None,
+                    seconds_since_rx: None,
+                    polling_period: None,
+                    id,
+                    status: "ONLINE".to_string(),
+                    name: format!("New {} Device", radio),
+                    attributes: vec![DeviceAttribute {
                         id: 1,
-                        description: "GenericValue".to_string(),
-                        attribute_type: AttributeType::UInt8,
-                        supports_write: true,
-                        supports_read: true,
-                        current_value: attr_values
-                            .get(&(master_id, 1 as AttributeId))
-                            .unwrap_or(&AttributeValue::UInt8(0))
-                            .clone(),
-                        setting_value: attr_values
-                            .get(&(master_id, 1 as AttributeId))
-                            .unwrap_or(&AttributeValue::UInt8(0))
-                            .clone(),
-                    },
-                    DeviceAttribute {
-                        id: 3,
-                        description: "Level".to_string(),
-                        attribute_type: AttributeType::UInt8,
-                        supports_write: true,
-                        supports_read: true,
-                        current_value: attr_values
-                            .get(&(master_id, 3 as AttributeId))
-                            .unwrap_or(&AttributeValue::UInt8(0))
-                            .clone(),
-                        setting_value: attr_values
-                            .get(&(master_id, 3 as AttributeId))
-                            .unwrap_or(&AttributeValue::UInt8(0))
-                            .clone(),
-                    },
-                    DeviceAttribute {
-                        id: 4,
-                        description: "Up_Down".to_string(),
-                        attribute_type: AttributeType::Bool,
-                        supports_write: true,
-                        supports_read: false,
-                        current_value: AttributeValue::NoValue,
-                        setting_value: AttributeValue::NoValue,
-                    },
-                    DeviceAttribute {
-                        id: 5,
-                        description: "StopMovement".to_string(),
+                        description: "On_Off".to_string(),
                         attribute_type: AttributeType::Bool,
                         supports_write: true,
-                        supports_read: false,
-                        current_value: AttributeValue::NoValue,
-                        setting_value: AttributeValue::NoValue,
-                    },
-                ],
-            }),
+                        supports_read: true,
+                        current_value: AttributeValue::Bool(false),
+                        setting_value: AttributeValue::Bool(false),
+                    }],
+                },
+                None,
+            ));
+        }
+        Ok(PairingResult::from_lists(
+            before,
+            self.list().await?,
+            "".to_string(),
+        ))
+    }
+
+    async fn remove_device(&self, master_id: DeviceId) -> Result<PairingResult, Box<dyn Error>> {
+        let before = self.list().await?;
+        self.device(master_id).await?;
+        self.devices
+            .lock()
+            .await
+            .retain(|(device, _)| device.id != master_id);
+        self.attr_values
+            .lock()
+            .await
+            .retain(|(device_id, _), _| *device_id != master_id);
+        Ok(PairingResult::from_lists(
+            before,
+            self.list().await?,
+            "".to_string(),
+        ))
+    }
+
+    async fn rename(&self, master_id: DeviceId, name: &str) -> Result<(), Box<dyn Error>> {
+        validate_device_name(name)?;
+        let mut devices = self.devices.lock().await;
+        let (device, _) = devices
+            .iter_mut()
+            .find(|(device, _)| device.id == master_id)
+            .ok_or_else(|| simple_error!("Device id {} not found", master_id))?;
+        device.name = name.trim().to_string();
+        Ok(())
+    }
 
-            4 => Ok(LongDevice {
-                gang_id: None,
-                generic_device_type: None,
-                specific_device_type: None,
-                manufacturer_id: None,
-                product_type: None,
-                product_number: None,
-                id: master_id,
-                status: "".to_string(),
-                name: "Bedroom Light".to_string(),
-                attributes: vec![DeviceAttribute {
-                    id: 1,
-                    description: "On_Off".to_string(),
-                    attribute_type: AttributeType::Bool,
-                    supports_write: true,
-                    supports_read: true,
-                    current_value: attr_values
-                        .get(&(master_id, 1 as AttributeId))
-                        .unwrap_or(&AttributeValue::Bool(false))
-                        .clone(),
-                    setting_value: attr_values
-                        .get(&(master_id, 1 as AttributeId))
-                        .unwrap_or(&AttributeValue::Bool(false))
-                        .clone(),
-                }],
-            }),
+    async fn list_groups(&self) -> Result<Vec<Group>, Box<dyn Error>> {
+        Ok(self.groups.iter().map(|(group, _)| group.clone()).collect())
+    }
 
-            _ => bail!("Device id {} not found", master_id),
+    /// Sets the attribute on the members that have it, like the radio would.
+    async fn set_group(
+        &self,
+        group_id: GroupId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        let (_, members) = self
+            .groups
+            .iter()
+            .find(|(group, _)| group.id == group_id)
+            .ok_or_else(|| simple_error!("Group id {} not found", group_id))?;
+        for member in members.iter() {
+            let has_attribute = self.device(*member).await.map_or(false, |(device, _)| {
+                device.attributes.iter().any(|a| a.id == attribute_id)
+            });
+            if has_attribute {
+                self.set(*member, attribute_id, value).await?;
+            }
         }
+        Ok(())
     }
 
-    async fn set(
+    /// Offline devices count as failed nodes: they can be removed, but not updated.
+    async fn zwave_maintenance(
         &self,
-        master_id: u32,
-        attribute_id: u32,
-        value: &AttributeValue,
+        operation: ZwaveOperation,
+        progress: &Mutex<MaintenanceProgress>,
     ) -> Result<(), Box<dyn Error>> {
-        if (master_id != 2 && master_id != 4)
-            || attribute_id < 1
-            || attribute_id > 5
-            || *value == AttributeValue::NoValue
-        {
-            bail!("Invalid set inputs: {}/{}", master_id, attribute_id)
+        let offline = self.faults.lock().await.offline_devices.clone();
+        match operation {
+            ZwaveOperation::HealNetwork => {
+                let nodes = self
+                    .list()
+                    .await?
+                    .into_iter()
+                    .filter(|d| d.interconnect.as_deref() == Some("ZWAVE"))
+                    .collect::<Vec<_>>();
+                progress.lock().await.steps_total = nodes.len();
+                for node in nodes {
+                    let output = if offline.contains(&node.id) {
+                        format!("Node {} failed: no response", node.id)
+                    } else {
+                        format!("Node {} updated", node.id)
+                    };
+                    MaintenanceProgress::step_done(progress, output).await;
+                }
+            }
+            ZwaveOperation::RemoveFailedNode { device_id } => {
+                self.device(device_id).await?;
+                if !offline.contains(&device_id) {
+                    bail!("Node {} has not failed", device_id)
+                }
+                self.remove_device(device_id).await?;
+                MaintenanceProgress::step_done(progress, format!("Node {} removed", device_id))
+                    .await;
+            }
+            ZwaveOperation::NeighborUpdate { device_id } => {
+                self.device(device_id).await?;
+                if offline.contains(&device_id) {
+                    bail!("Node {} failed: no response", device_id)
+                }
+                MaintenanceProgress::step_done(progress, format!("Node {} updated", device_id))
+                    .await;
+            }
         }
-        self.attr_values
-            .lock()
-            .await
-            .insert((master_id, attribute_id), value.clone());
         Ok(())
     }
 }
@@ -596,6 +2004,7 @@ impl DeviceController for FakeController {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils;
     use std::sync::Arc;
 
     const TEST_LIST_STRING: &str = r###"
@@ -613,12 +2022,10 @@ GROUP ID |             NAME |            RADIO |
 
     fn controller_with_output(output: &str) -> AprontestController {
         let output = Arc::new(output.to_string());
-        AprontestController {
-            runner: Box::new(move |_| {
-                let output = output.clone();
-                Box::pin((async move || Ok((*output).clone()))())
-            }),
-        }
+        AprontestController::with_runner(Box::new(move |_| {
+            let output = output.clone();
+            Box::pin(async move { Ok((*output).clone()) })
+        }))
     }
 
     #[tokio::test]
@@ -629,17 +2036,91 @@ GROUP ID |             NAME |            RADIO |
             vec![
                 ShortDevice {
                     id: 2,
-                    name: "Bedroom Fan".to_string()
+                    name: "Bedroom Fan".to_string(),
+                    interconnect: Some("ZWAVE".to_string()),
                 },
                 ShortDevice {
                     id: 4,
-                    name: "Bedroom Lights".to_string()
+                    name: "Bedroom Lights".to_string(),
+                    interconnect: Some("ZWAVE".to_string()),
                 }
             ],
             controller.list().await.unwrap()
         )
     }
 
+    #[tokio::test]
+    async fn operation_stats() {
+        let controller = controller_with_output(TEST_LIST_STRING);
+        controller.list().await.unwrap();
+        controller.list().await.unwrap();
+        controller
+            .set(2, 4, &AttributeValue::UInt8(3))
+            .await
+            .unwrap();
+
+        let stats = controller.stats();
+        assert_eq!(2, stats["list"].successes);
+        assert_eq!(1, stats["set"].successes);
+        assert_eq!(0, stats["set"].failures);
+        assert!(!stats.contains_key("describe"));
+
+        let controller = controller_with_output("Segmentation fault");
+        assert!(controller.list().await.is_err());
+        assert_eq!(1, controller.stats()["list"].failures);
+    }
+
+    const TEST_GROUPS_LIST_STRING: &str = r###"
+Found 1 devices in database...
+MASTERID |     INTERCONNECT |                         USERNAME
+       2 |           ZIGBEE |                      Living Lamp
+
+Found 2 master groups in database...
+GROUP ID |             NAME |            RADIO |
+       1 |      Living Room |           ZIGBEE |
+       3 |          Hallway |            ZWAVE |
+
+Found 1 control groups in database...
+GROUP ID |             NAME |            RADIO |
+       2 |       Everything |                  |
+"###;
+
+    #[tokio::test]
+    async fn list_groups() {
+        let controller = controller_with_output(TEST_GROUPS_LIST_STRING);
+        assert_eq!(1, controller.list().await.unwrap().len());
+        assert_eq!(
+            vec![
+                Group {
+                    id: 1,
+                    kind: GroupKind::Master,
+                    name: "Living Room".to_string(),
+                    radio: Some("ZIGBEE".to_string()),
+                },
+                Group {
+                    id: 3,
+                    kind: GroupKind::Master,
+                    name: "Hallway".to_string(),
+                    radio: Some("ZWAVE".to_string()),
+                },
+                Group {
+                    id: 2,
+                    kind: GroupKind::Control,
+                    name: "Everything".to_string(),
+                    radio: None,
+                },
+            ],
+            controller.list_groups().await.unwrap()
+        );
+        assert_eq!(
+            Vec::<Group>::new(),
+            controller_with_output(TEST_LIST_STRING)
+                .list_groups()
+                .await
+                .unwrap()
+        );
+    }
+
     const TEST_DESCRIBE_STRING: &str = r###"
 Gang ID: 0x00000003
 Generic/Specific device types: 0x11/0x08
@@ -654,6 +2135,63 @@ Bedroom Fan
            5 |                        StopMovement |   BOOL |    W |                                  |
 "###;
 
+    const TEST_SIGNED_DESCRIBE_STRING: &str = r###"
+Device is ONLINE, 0 failed tx attempts, 6 seconds since last msg rx'ed, polling period 10 seconds
+Device has 3 attributes...
+Thermostat
+   ATTRIBUTE |                         DESCRIPTION |   TYPE | MODE |                              GET |                              SET
+           1 |                         Temperature |  FLOAT |    R |                            21.25 |
+           2 |                   TemperatureOffset |  INT16 |  R/W |                               -3 |                               -3
+           3 |                            Humidity |  FLOAT |    R |                              nan |
+"###;
+
+    const TEST_BATTERY_DESCRIBE_STRING: &str = r###"
+Generic/Specific device types: 0x20/0x01
+Manufacturer ID: 0x014A Product Type: 0x0001 Product Number: 0x0001
+Device is ONLINE, 0 failed tx attempts, 1200 seconds since last msg rx'ed, polling period 0 seconds
+Battery level: 85%
+Wakeup interval: 3600 seconds
+Device has 1 attributes...
+Front Door
+   ATTRIBUTE |                         DESCRIPTION |   TYPE | MODE |                              GET |                              SET
+           1 |                              Opened |   BOOL |    R |                            FALSE |
+"###;
+
+    #[tokio::test]
+    async fn battery_and_wakeup() {
+        let device = controller_with_output(TEST_BATTERY_DESCRIBE_STRING)
+            .describe(3)
+            .await
+            .unwrap();
+        assert_eq!(Some(85), device.battery_level);
+        assert_eq!(Some(3600), device.wakeup_interval);
+        assert_eq!("Front Door", device.name);
+        assert_eq!(1, device.attributes.len());
+    }
+
+    #[tokio::test]
+    async fn signed_and_float_attributes() {
+        let controller = controller_with_output(TEST_SIGNED_DESCRIBE_STRING);
+        let device = controller.describe(7).await.unwrap();
+        // NaN doesn't make it through json, so that attribute is dropped.
+        assert_eq!(2, device.attributes.len());
+        assert_eq!(AttributeType::Float, device.attributes[0].attribute_type);
+        assert_eq!(
+            AttributeValue::Float(21.25),
+            device.attributes[0].current_value
+        );
+        assert_eq!(AttributeType::Int16, device.attributes[1].attribute_type);
+        assert_eq!(
+            AttributeValue::Int16(-3),
+            device.attributes[1].setting_value
+        );
+        assert_eq!(
+            serde_json::json!(-3),
+            device.attributes[1].current_value.to_json()
+        );
+        assert!(AttributeType::Int8.parse("200").is_err());
+    }
+
     #[tokio::test]
     async fn describe() {
         let controller = controller_with_output(TEST_DESCRIBE_STRING);
@@ -666,6 +2204,12 @@ Bedroom Fan
                 manufacturer_id: Some(0x63),
                 product_type: Some(0x4944),
                 product_number: Some(0x3131),
+                interconnect: None,
+                battery_level: None,
+                wakeup_interval: None,
+                failed_tx: Some(0),
+                seconds_since_rx: Some(6),
+                polling_period: Some(10),
                 id: 2,
                 status: "ONLINE".to_string(),
                 name: "Bedroom Fan".to_string(),
@@ -712,6 +2256,224 @@ Bedroom Fan
         )
     }
 
+    #[tokio::test]
+    async fn describe_raw() {
+        let controller = controller_with_output(TEST_DESCRIBE_STRING);
+
+        assert_eq!(
+            TEST_DESCRIBE_STRING,
+            controller.describe_raw(2).await.unwrap()
+        );
+        assert!(FakeController::new().describe_raw(2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn one_aprontest_at_a_time() {
+        let running = Arc::new(AtomicU32::new(0));
+        let max_running = Arc::new(AtomicU32::new(0));
+        let controller = AprontestController::with_runner(Box::new({
+            let running = running.clone();
+            let max_running = max_running.clone();
+            move |_| {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                Box::pin(async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::delay_for(Duration::from_millis(5)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(TEST_DESCRIBE_STRING.to_string())
+                })
+            }
+        }));
+        futures::future::join_all((2..6).map(|id| controller.describe(id))).await;
+        controller
+            .set(2, 1, &AttributeValue::String("TRUE".into()))
+            .await
+            .unwrap();
+        assert_eq!(1, max_running.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn command_timeout() {
+        let mut controller = AprontestController::with_runner(Box::new(|_| {
+            Box::pin((async move || {
+                tokio::time::delay_for(Duration::from_secs(10)).await;
+                Ok("".to_string())
+            })())
+        }));
+        controller.timeout = Duration::from_millis(10);
+        let error = controller.list().await.unwrap_err();
+        assert_eq!(
+            "aprontest -l",
+            error.downcast_ref::<TimeoutError>().unwrap().command
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let controller = AprontestController::with_runner(Box::new({
+            let runs = runs.clone();
+            move |_| {
+                let run = runs.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    let result: Result<String, Box<dyn Error>> = if run == 0 {
+                        Err(simple_error!("aprontest failed.\nStderr:\ndatabase is locked").into())
+                    } else {
+                        Ok(TEST_LIST_STRING.to_string())
+                    };
+                    result
+                })
+            }
+        }));
+        assert_eq!(2, controller.list().await.unwrap().len());
+        assert_eq!(2, runs.load(Ordering::SeqCst));
+
+        // Sets might have gone through, so they're not retried.
+        runs.store(0, Ordering::SeqCst);
+        assert!(controller
+            .set(2, 1, &AttributeValue::String("TRUE".into()))
+            .await
+            .is_err());
+        assert_eq!(1, runs.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn get() {
+        let commands = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let controller = AprontestController::with_runner(Box::new({
+            let commands = commands.clone();
+            move |cmd| {
+                commands.lock().unwrap().push(cmd.join(" "));
+                // Like a build that ignores -t and prints the whole device.
+                Box::pin((async move || Ok(TEST_DESCRIBE_STRING.to_string()))())
+            }
+        }));
+
+        let attribute = controller.get(2, 3).await.unwrap();
+        assert_eq!("Level", attribute.description);
+        assert_eq!(AttributeValue::UInt8(0), attribute.current_value);
+        assert_eq!(vec!["aprontest -l -m 2 -t 3"], *commands.lock().unwrap());
+
+        // Not in the targeted output: falls back to a full describe.
+        assert!(controller.get(2, 9).await.is_err());
+        assert_eq!(
+            vec![
+                "aprontest -l -m 2 -t 3",
+                "aprontest -l -m 2 -t 9",
+                "aprontest -l -m 2"
+            ],
+            *commands.lock().unwrap()
+        );
+        // Answered from the describe cache.
+        controller.get(2, 1).await.unwrap();
+        assert_eq!(3, commands.lock().unwrap().len());
+        assert_eq!(2, controller.stats()["get"].successes);
+    }
+
+    #[tokio::test]
+    async fn set_many() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let controller = AprontestController::with_runner(Box::new({
+            let runs = runs.clone();
+            move |cmd| {
+                runs.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(
+                    vec![
+                        "aprontest",
+                        "-u",
+                        "-m",
+                        "2",
+                        "-t",
+                        "4",
+                        "-v",
+                        "3",
+                        "-t",
+                        "1",
+                        "-v",
+                        "TRUE"
+                    ],
+                    cmd.to_vec()
+                );
+                Box::pin((async move || Ok(String::new()))())
+            }
+        }));
+        controller
+            .set_many(
+                2,
+                &[
+                    (4, AttributeValue::UInt16(3)),
+                    (1, AttributeValue::Bool(true)),
+                ],
+            )
+            .await
+            .unwrap();
+        controller.set_many(2, &[]).await.unwrap();
+        assert_eq!(1, runs.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn describe_cache() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let controller = AprontestController::with_runner(Box::new({
+            let runs = runs.clone();
+            move |_| {
+                runs.fetch_add(1, Ordering::SeqCst);
+                Box::pin((async move || Ok(TEST_DESCRIBE_STRING.to_string()))())
+            }
+        }));
+        controller.describe(2).await.unwrap();
+        controller.describe(2).await.unwrap();
+        assert_eq!(1, runs.load(Ordering::SeqCst));
+
+        controller
+            .set(2, 1, &AttributeValue::String("TRUE".into()))
+            .await
+            .unwrap();
+        controller.describe(2).await.unwrap();
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+
+        controller.forget_cached(2).await;
+        controller.describe(2).await.unwrap();
+        assert_eq!(4, runs.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn pairing() {
+        let paired = Arc::new(AtomicU32::new(0));
+        let controller = AprontestController::with_runner(Box::new({
+            let paired = paired.clone();
+            move |cmd| {
+                let output = if cmd[1] == "-a" {
+                    assert_eq!(vec!["aprontest", "-a", "60", "-r", "zwave"], cmd.to_vec());
+                    paired.store(1, Ordering::SeqCst);
+                    "Added 2 devices".to_string()
+                } else if paired.load(Ordering::SeqCst) == 1 {
+                    TEST_OLD_LIST_STRING.to_string()
+                } else {
+                    TEST_LIST_STRING.to_string()
+                };
+                Box::pin((async move || Ok(output))())
+            }
+        }));
+        assert!(controller
+            .start_pairing("bluetooth", Duration::from_secs(60))
+            .await
+            .is_err());
+
+        let result = controller
+            .start_pairing("zwave", Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![1, 3],
+            result.added.iter().map(|d| d.id).collect::<Vec<_>>()
+        );
+        assert!(result.removed.is_empty());
+        assert_eq!("Added 2 devices", result.output);
+    }
+
     #[tokio::test]
     async fn device_meta() {
         let controller = controller_with_output(TEST_DESCRIBE_STRING);
@@ -742,19 +2504,23 @@ MASTERID |     INTERCONNECT |                         USERNAME
             vec![
                 ShortDevice {
                     id: 1,
-                    name: "LV_Lamp1".to_string()
+                    name: "LV_Lamp1".to_string(),
+                    interconnect: Some("ZIGBEE".to_string()),
                 },
                 ShortDevice {
                     id: 2,
-                    name: "LV_Lamp2".to_string()
+                    name: "LV_Lamp2".to_string(),
+                    interconnect: Some("ZIGBEE".to_string()),
                 },
                 ShortDevice {
                     id: 3,
-                    name: "Fireplace-L".to_string()
+                    name: "Fireplace-L".to_string(),
+                    interconnect: Some("ZIGBEE".to_string()),
                 },
                 ShortDevice {
                     id: 4,
-                    name: "Fireplace-R".to_string()
+                    name: "Fireplace-R".to_string(),
+                    interconnect: Some("ZIGBEE".to_string()),
                 }
             ],
             controller.list().await.unwrap()
@@ -781,6 +2547,12 @@ ATTRIBUTE |               DESCRIPTION |   TYPE | MODE |          GET |     SET
                 manufacturer_id: None,
                 product_type: None,
                 product_number: None,
+                interconnect: None,
+                battery_level: None,
+                wakeup_interval: None,
+                failed_tx: None,
+                seconds_since_rx: None,
+                polling_period: None,
                 id: 2,
                 status: "".to_string(),
                 name: "LV_Lamp1".to_string(),
@@ -870,6 +2642,61 @@ New HA Dimmable Light
         )
     }
 
+    #[test]
+    fn enum_values() {
+        let mut device = default_fake_devices().remove(0);
+        device.attributes[0].attribute_type = AttributeType::String;
+        device.apply_enum_values(
+            &vec![
+                (
+                    "GenericValue".to_string(),
+                    vec!["ON".to_string(), "TOGGLE".to_string()],
+                ),
+                ("Level".to_string(), vec!["1".to_string()]),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        // Only string attributes become enums.
+        assert_eq!(AttributeType::UInt8, device.attributes[1].attribute_type);
+
+        let mode = &device.attributes[0].attribute_type;
+        assert_eq!(
+            AttributeValue::String("TOGGLE".to_string()),
+            mode.parse("toggle").unwrap()
+        );
+        assert_eq!(
+            AttributeValue::String("ON".to_string()),
+            mode.parse_json(&serde_json::json!("ON")).unwrap()
+        );
+        assert!(mode.parse("OFF").is_err());
+        assert!(mode.parse_json(&serde_json::json!(1)).is_err());
+    }
+
+    #[test]
+    fn attribute_aliases() {
+        let mut device = default_fake_devices().remove(0);
+        device.apply_attribute_aliases(
+            &vec![
+                ("GenericValue".to_string(), "Brightness".to_string()),
+                // The fan already has a Level.
+                ("StopMovement".to_string(), "Level".to_string()),
+                ("Missing".to_string(), "On_Off".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            vec!["Brightness", "Level", "Up_Down", "StopMovement"],
+            device
+                .attributes
+                .iter()
+                .map(|a| a.description.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, device.attribute("Brightness").unwrap().id);
+    }
+
     #[tokio::test]
     async fn test_json_serialization() {
         let tests = [
@@ -884,6 +2711,12 @@ New HA Dimmable Light
             AttributeValue::UInt16(u16::MAX),
             AttributeValue::UInt32(u32::MAX),
             AttributeValue::UInt64(u64::MAX),
+            AttributeValue::Int8(i8::MIN),
+            AttributeValue::Int16(-40),
+            AttributeValue::Int32(i32::MIN),
+            AttributeValue::Int64(i64::MAX),
+            AttributeValue::Float(21.5),
+            AttributeValue::Float(-3.0),
         ];
 
         for test in tests.iter() {
@@ -905,4 +2738,304 @@ New HA Dimmable Light
 
         assert_eq!(serde_json::Value::Null, AttributeValue::NoValue.to_json());
     }
+
+    #[tokio::test]
+    async fn fake_controller_scenario() {
+        let path = std::env::temp_dir().join(format!("wink-scenario-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            serde_json::to_vec(&serde_json::json!({
+                "devices": [
+                    {"id": 2, "name": "Bedroom Fan", "raw": TEST_DESCRIBE_STRING},
+                    {"id": 3, "name": "Broken", "raw": {"error": "timed out"}},
+                ]
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+        let controller = FakeController::from_scenario(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(1, controller.list().await.unwrap().len());
+        assert_eq!(
+            TEST_DESCRIBE_STRING,
+            controller.describe_raw(2).await.unwrap()
+        );
+        let device = controller.describe(2).await.unwrap();
+        assert_eq!("Bedroom Fan", device.name);
+        let attribute_id = device.attributes[0].id;
+        controller
+            .set(2, attribute_id, &AttributeValue::UInt8(7))
+            .await
+            .unwrap();
+        assert_eq!(
+            AttributeValue::UInt8(7),
+            controller.describe(2).await.unwrap().attributes[0].current_value
+        );
+        assert!(controller
+            .set(3, 1, &AttributeValue::UInt8(7))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn fake_controller_faults() {
+        let controller = FakeController::new();
+        controller
+            .set_faults(FaultConfig {
+                fail_set_devices: vec![4],
+                describe_latency_ms: 0,
+                parse_error_rate: 1.0,
+                ..FaultConfig::default()
+            })
+            .await
+            .unwrap();
+
+        assert!(controller
+            .set(4, 1, &AttributeValue::Bool(true))
+            .await
+            .is_err());
+        assert!(controller
+            .set(2, 3, &AttributeValue::UInt8(5))
+            .await
+            .is_ok());
+        assert!(controller.describe(2).await.is_err());
+
+        controller.set_faults(FaultConfig::default()).await.unwrap();
+        assert!(controller
+            .set(4, 1, &AttributeValue::Bool(true))
+            .await
+            .is_ok());
+        assert!(controller.describe(2).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fake_controller_offline_and_random_faults() {
+        let controller = FakeController::new();
+        controller
+            .set_faults(FaultConfig {
+                offline_devices: vec![4],
+                set_error_rate: 1.0,
+                ..FaultConfig::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!("OFFLINE", controller.describe(4).await.unwrap().status);
+        assert_eq!("ONLINE", controller.describe(2).await.unwrap().status);
+        assert!(controller
+            .set(4, 1, &AttributeValue::Bool(true))
+            .await
+            .is_err());
+        assert!(controller
+            .set(2, 3, &AttributeValue::UInt8(5))
+            .await
+            .is_err());
+
+        controller
+            .set_faults(FaultConfig {
+                offline_devices: vec![4],
+                ..FaultConfig::default()
+            })
+            .await
+            .unwrap();
+        assert!(controller
+            .set(2, 3, &AttributeValue::UInt8(5))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn fake_controller_groups() {
+        let controller = FakeController::new();
+        assert_eq!(
+            vec![1],
+            controller
+                .list_groups()
+                .await
+                .unwrap()
+                .iter()
+                .map(|g| g.id)
+                .collect::<Vec<_>>()
+        );
+        controller
+            .set_group(1, 1, &AttributeValue::Bool(true))
+            .await
+            .unwrap();
+        assert_eq!(
+            AttributeValue::Bool(true),
+            controller.describe(4).await.unwrap().attributes[0].current_value
+        );
+        assert!(controller
+            .set_group(2, 1, &AttributeValue::Bool(true))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn activate_scene() {
+        let controller = FakeController::new();
+        let scene: Scene = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "name": "Bedroom Off",
+            "actions": [
+                {"target": {"device": 2}, "attribute_id": 3, "value": 0},
+                {"target": {"group": 1}, "attribute_id": 1, "value": false},
+            ],
+        }))
+        .unwrap();
+        controller
+            .set(2, 3, &AttributeValue::UInt8(255))
+            .await
+            .unwrap();
+        controller
+            .set(4, 1, &AttributeValue::Bool(true))
+            .await
+            .unwrap();
+        controller.activate_scene(&scene).await.unwrap();
+        assert_eq!(
+            AttributeValue::UInt8(0),
+            controller.describe(2).await.unwrap().attributes[1].current_value
+        );
+        assert_eq!(
+            AttributeValue::Bool(false),
+            controller.describe(4).await.unwrap().attributes[0].current_value
+        );
+
+        let bad = Scene {
+            actions: vec![SceneAction {
+                target: SceneTarget::Device(2),
+                attribute_id: 9,
+                value: serde_json::json!(1),
+            }],
+            ..scene
+        };
+        assert!(controller.activate_scene(&bad).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn heal_network() {
+        let commands = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let controller = AprontestController::with_runner(Box::new({
+            let commands = commands.clone();
+            move |cmd| {
+                commands.lock().unwrap().push(cmd.join(" "));
+                let output = if cmd[1] == "-l" {
+                    TEST_LIST_STRING.to_string()
+                } else {
+                    "Done".to_string()
+                };
+                Box::pin((async move || Ok(output))())
+            }
+        }));
+        let progress = Mutex::new(MaintenanceProgress::new(ZwaveOperation::HealNetwork));
+        controller
+            .zwave_maintenance(ZwaveOperation::HealNetwork, &progress)
+            .await
+            .unwrap();
+        let progress = progress.into_inner();
+        assert_eq!((2, 2), (progress.steps_done, progress.steps_total));
+        assert_eq!(vec!["Done", "Done"], progress.output);
+        assert_eq!(
+            vec![
+                "aprontest -l",
+                "aprontest -z neighbor_update -m 2",
+                "aprontest -z neighbor_update -m 4"
+            ],
+            *commands.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn fake_controller_maintenance() {
+        let controller = FakeController::new();
+        controller
+            .set_faults(FaultConfig {
+                offline_devices: vec![4],
+                ..FaultConfig::default()
+            })
+            .await
+            .unwrap();
+        let run = |operation| {
+            let controller = &controller;
+            async move {
+                let progress = Mutex::new(MaintenanceProgress::new(operation));
+                let result = controller.zwave_maintenance(operation, &progress).await;
+                result.map(|_| progress.into_inner())
+            }
+        };
+
+        let heal = run(ZwaveOperation::HealNetwork).await.unwrap();
+        assert_eq!(
+            vec!["Node 2 updated", "Node 4 failed: no response"],
+            heal.output
+        );
+        assert!(run(ZwaveOperation::NeighborUpdate { device_id: 4 })
+            .await
+            .is_err());
+        assert!(run(ZwaveOperation::RemoveFailedNode { device_id: 2 })
+            .await
+            .is_err());
+        run(ZwaveOperation::RemoveFailedNode { device_id: 4 })
+            .await
+            .unwrap();
+        assert!(controller.describe(4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fake_controller_pairing() {
+        let controller = FakeController::new();
+        let result = controller
+            .start_pairing("zigbee", Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(1, result.added.len());
+        let new_id = result.added[0].id;
+        assert_eq!(5, new_id);
+        assert_eq!(
+            "New zigbee Device",
+            controller.describe(new_id).await.unwrap().name
+        );
+
+        let result = controller.remove_device(2).await.unwrap();
+        assert_eq!(
+            vec![2],
+            result.removed.iter().map(|d| d.id).collect::<Vec<_>>()
+        );
+        assert!(controller.describe(2).await.is_err());
+        assert!(controller.remove_device(2).await.is_err());
+        assert_eq!(
+            vec![4, 5],
+            controller
+                .list()
+                .await
+                .unwrap()
+                .iter()
+                .map(|d| d.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let controller = controller_with_output("");
+        assert!(controller.rename(2, " ").await.is_err());
+        assert!(controller.rename(2, "Fan\nLight").await.is_err());
+        controller.rename(2, "Ceiling Fan").await.unwrap();
+
+        let controller = FakeController::new();
+        controller.rename(2, " Ceiling Fan ").await.unwrap();
+        assert_eq!("Ceiling Fan", controller.describe(2).await.unwrap().name);
+        assert!(controller.rename(7, "Nothing").await.is_err());
+    }
+
+    #[test]
+    fn attribute_synonyms() {
+        let device =
+            test_utils::device(vec![test_utils::attribute(1, "OnOff", AttributeType::Bool)]);
+        assert_eq!(Some(1), device.attribute("On_Off").map(|a| a.id));
+        assert_eq!(Some(1), device.attribute("OnOff").map(|a| a.id));
+        assert_eq!("On_Off", device.attributes[0].canonical_name());
+        assert_eq!(None, device.attribute("Fan_Power"));
+    }
 }
@@ -1,21 +1,195 @@
-use crate::controller::{AttributeType, LongDevice};
-use serde_json::{json, Value};
+use crate::controller::{
+    AttributeType, AttributeValue, DeviceAttribute, DeviceId, LongDevice, Scene,
+};
+use clap::crate_version;
+use regex::Regex;
+use serde_json::{json, Map, Value};
 use simple_error::{bail, simple_error};
+use slog::warn;
 use std::error::Error;
 
 use crate::config::{Config, TopicType};
+use crate::overrides::{BrightnessZero, Component, ComponentOverride, DeviceOverride};
 use crate::utils::ResultExtensions;
 
 pub struct AutodiscoveryMessage {
     pub component: &'static str,
+    /// Distinguishes multiple entities of the same component on one device.
+    pub object_id: Option<String>,
     pub discovery_info: Value,
 }
 
+impl AutodiscoveryMessage {
+    pub fn topic(&self, device_id: DeviceId) -> TopicType {
+        match &self.object_id {
+            Some(object_id) => {
+                TopicType::DiscoveryEntityTopic(self.component.into(), device_id, object_id.clone())
+            }
+            None => TopicType::DiscoveryTopic(self.component.into(), device_id),
+        }
+    }
+}
+
+/// Identifies the synthetic hub device that every discovered device is linked to (`via_device`).
+const HUB_IDENTIFIER: &str = "wink_hub";
+
+/// Every component we might publish discovery for; used to clean up after devices whose
+/// discovery topics we no longer remember.
+pub const DISCOVERY_COMPONENTS: &[&str] = &[
+    "binary_sensor",
+    "climate",
+    "cover",
+    "device_automation",
+    "light",
+    "lock",
+    "select",
+    "sensor",
+    "siren",
+    "switch",
+];
+
+// Thermostat attribute names seen across firmware versions.
+const THERMOSTAT_MODE_ATTRIBUTES: &[&str] = &["ThermostatMode", "Mode"];
+const THERMOSTAT_FAN_MODE_ATTRIBUTES: &[&str] = &["ThermostatFanMode", "FanMode"];
+const HEATING_SETPOINT_ATTRIBUTES: &[&str] =
+    &["HeatingSetpoint", "Heating_Setpoint", "HeatSetpoint"];
+const COOLING_SETPOINT_ATTRIBUTES: &[&str] =
+    &["CoolingSetpoint", "Cooling_Setpoint", "CoolSetpoint"];
+const TEMPERATURE_ATTRIBUTES: &[&str] = &["Temperature", "CurrentTemperature"];
+
+// Siren tone selection, and the (read-only) number of tones the siren has. Tones are numbered from 1.
+const SIREN_TONE_ATTRIBUTES: &[&str] = &["Tone", "Siren_Tone", "AlarmTone"];
+const SIREN_TONE_COUNT_ATTRIBUTES: &[&str] = &["ToneCount", "Tone_Count", "NumberOfTones"];
+
+// Z-Wave thermostat mode and fan mode values, by Home Assistant mode.
+const THERMOSTAT_MODES: &[(&str, u8)] = &[("off", 0), ("heat", 1), ("cool", 2), ("auto", 3)];
+const THERMOSTAT_FAN_MODES: &[(&str, u8)] = &[("auto", 0), ("on", 1)];
+
+/// Firmware and version attributes. Their diagnostic sensors are disabled by default so they
+/// don't clutter dashboards.
+const FIRMWARE_ATTRIBUTES: &[&str] = &[
+    "HWVersion",
+    "ApplicationVersion",
+    "StackVersion",
+    "ZCLVersion",
+    "DateCode",
+    "FirmwareVersion",
+];
+
+/// Signal strength attributes. Like firmware versions, these go in the entity attributes rather
+/// than getting entities of their own.
+const SIGNAL_ATTRIBUTES: &[&str] = &["LinkQuality", "LQI", "RSSI", "SignalStrength"];
+
+/// Smoke and CO alarm attributes (e.g. of Kidde detectors), by name. These are binary sensors
+/// whatever their type; any value other than off/0/false means the alarm is sounding.
+const ALARM_ATTRIBUTES: &[(&str, &str)] = &[
+    ("Smoke", "smoke"),
+    ("SmokeAlarm", "smoke"),
+    ("Smoke_Alarm", "smoke"),
+    ("CO", "carbon_monoxide"),
+    ("CoAlarm", "carbon_monoxide"),
+    ("CO_Alarm", "carbon_monoxide"),
+    ("CarbonMonoxide", "carbon_monoxide"),
+    ("Carbon_Monoxide", "carbon_monoxide"),
+];
+
+/// Home Assistant binary sensor device classes, by a (lowercase) piece of the attribute name.
+/// Checked in order, so e.g. `Tamper_Open` is a tamper sensor.
+const BINARY_SENSOR_CLASSES: &[(&str, &str)] = &[
+    ("tamper", "tamper"),
+    ("battery", "battery"),
+    ("motion", "motion"),
+    ("occupancy", "occupancy"),
+    ("vibration", "vibration"),
+    ("smoke", "smoke"),
+    ("carbon", "carbon_monoxide"),
+    ("water", "moisture"),
+    ("leak", "moisture"),
+    ("window", "window"),
+    ("door", "door"),
+    ("contact", "opening"),
+    ("open", "opening"),
+];
+
+/// A sensor for a metering attribute, with what Home Assistant's energy dashboard needs to use it.
+struct MeteringSensor {
+    attributes: &'static [&'static str],
+    name: &'static str,
+    object_id: &'static str,
+    device_class: &'static str,
+    state_class: &'static str,
+    unit: &'static str,
+}
+
+const METERING_SENSORS: &[MeteringSensor] = &[
+    MeteringSensor {
+        attributes: &["Power", "Watts", "InstantaneousDemand"],
+        name: "Power",
+        object_id: "power",
+        device_class: "power",
+        state_class: "measurement",
+        unit: "W",
+    },
+    MeteringSensor {
+        attributes: &["Energy", "Energy_kWh", "kWh", "CurrentSummationDelivered"],
+        name: "Energy",
+        object_id: "energy",
+        device_class: "energy",
+        state_class: "total_increasing",
+        unit: "kWh",
+    },
+];
+
+lazy_static! {
+    static ref SOCKET_ATTRIBUTE_REGEX: Regex = Regex::new("^On_Off_?(?P<socket>[0-9]+)$").unwrap();
+    static ref BUTTON_ATTRIBUTE_REGEX: Regex = Regex::new("^Button_?(?P<button>[0-9]+)$").unwrap();
+}
+
+/// Home Assistant builds entity ids from `object_id`, so e.g. "Bedroom Fan" becomes
+/// `light.bedroom_fan`.
+fn object_id(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            result.push(c.to_ascii_lowercase());
+        } else if !result.is_empty() && !result.ends_with('_') {
+            result.push('_');
+        }
+    }
+    result.trim_end_matches('_').to_string()
+}
+
+/// The unique_id of one of the device's entities (None for the main one). By default these are
+/// based on the topic prefix and device id, which change if the prefix does or the device is
+/// re-paired. With --stable-unique-ids they're based on the hub's gang id for the device instead,
+/// falling back to the default for devices without one.
+fn entity_unique_id(
+    config: &Config,
+    device: &LongDevice,
+    entity: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let base = match device.gang_id {
+        Some(gang_id) if config.stable_unique_ids => format!("wink_gang_{:08x}", gang_id),
+        _ => format!(
+            "{}/{}",
+            config
+                .topic_prefix
+                .as_ref()
+                .ok_or_else(|| simple_error!("No topic prefix defined"))?,
+            device.id
+        ),
+    };
+    Ok(match entity {
+        Some(entity) => format!("{}/{}", base, entity),
+        None => base,
+    })
+}
+
 fn device_description(config: &Config, device: &LongDevice) -> Value {
     let device_meta = device.device_meta();
 
-    return json!({
-        "name": device.name,
+    let mut description = json!({
+        "name": config.discovery_name(device.id, &device.name),
         "identifiers": [format!("wink_{}", device.id)],
         "connections": [["mqtt", config.to_topic_string(&TopicType::SetJsonTopic(device.id)).unwrap()]],
         "manufacturer": device_meta.manufacturer,
@@ -23,118 +197,2629 @@ fn device_description(config: &Config, device: &LongDevice) -> Value {
             "" => device_meta.product,
             version => format!("{} (v{})", device_meta.product, version)
         },
+        // The bridge's version, since that's what decides how the device shows up.
+        "sw_version": crate_version!(),
+        "via_device": HUB_IDENTIFIER,
     });
+    if let Some(area) = config.suggested_area(device.id, &device.name) {
+        description["suggested_area"] = json!(area);
+    }
+    // Home Assistant has no field for the radio; the hardware version is the closest fit.
+    if let Some(interconnect) = &device.interconnect {
+        description["hw_version"] = json!(interconnect);
+    }
+    description
 }
 
 pub fn device_to_discovery_payload(
     config: &Config,
     device: &LongDevice,
-) -> Option<AutodiscoveryMessage> {
+) -> Vec<AutodiscoveryMessage> {
+    let component_override = config.component_override(device.id);
+    if component_override.as_ref().map(|o| o.component) == Some(Component::None) {
+        return vec![];
+    }
+    let mut messages = match component_override {
+        Some(component_override) => {
+            overridden_discovery_payloads(config, device, &component_override)
+                .log_failing_result("overridden_discovery_failed")
+                .unwrap_or_default()
+        }
+        None => entity_discovery_payloads(config, device),
+    };
+    // Attribute ids that already have an entity of their own.
+    let mut used = Vec::new();
+    if messages.iter().any(|m| m.component == "binary_sensor") {
+        used.extend(binary_sensor_attributes(device).iter().map(|a| a.id));
+    }
+    if messages.iter().any(|m| m.component == "device_automation") {
+        used.extend(remote_buttons(device).iter().map(|(_, a)| a.id));
+    }
+    // Prefer the attribute; some devices only print their battery level above the attributes.
+    let battery_key = match device.battery_attribute() {
+        Some(battery) => {
+            used.push(battery.id);
+            Some(battery.canonical_name())
+        }
+        None => device.battery_level.map(|_| "battery_level".to_string()),
+    };
+    if let Some(battery_key) = battery_key {
+        messages.extend(
+            battery_to_discovery_payload(config, device, &battery_key)
+                .log_failing_result("battery_discovery_failed"),
+        );
+    }
+    for sensor in METERING_SENSORS.iter() {
+        if let Some(attribute) = first_attribute(device, sensor.attributes) {
+            used.push(attribute.id);
+            messages.extend(
+                metering_to_discovery_payload(config, device, sensor, attribute)
+                    .log_failing_result("metering_discovery_failed"),
+            );
+        }
+    }
+    for attribute in device.attributes.iter().filter(|a| a.supports_write) {
+        if let AttributeType::Enum(options) = &attribute.attribute_type {
+            used.push(attribute.id);
+            messages.extend(
+                select_to_discovery_payload(config, device, attribute, options)
+                    .log_failing_result("select_discovery_failed"),
+            );
+        }
+    }
+    if config.diagnostic_sensors {
+        for attribute in device
+            .attributes
+            .iter()
+            .filter(|a| !a.supports_write && !used.contains(&a.id))
+        {
+            messages.extend(
+                diagnostic_to_discovery_payload(config, device, attribute)
+                    .log_failing_result("diagnostic_discovery_failed"),
+            );
+        }
+    }
+    if config.expose_notes && !messages.is_empty() {
+        messages.extend(
+            notes_to_discovery_payload(config, device).log_failing_result("notes_discovery_failed"),
+        );
+    }
+    let attributes_topic = config.to_topic_string(&TopicType::AttributesTopic(device.id));
+    for message in messages.iter_mut() {
+        message.discovery_info["origin"] = origin();
+        // Device triggers don't have attributes.
+        if let (Some(topic), false) = (&attributes_topic, message.component == "device_automation")
+        {
+            message.discovery_info["json_attributes_topic"] = json!(topic);
+        }
+    }
+    messages
+}
+
+/// What's published to the device's attributes topic: things worth seeing in Home Assistant
+/// that aren't worth an entity, like the device's status, signal strength, link quality and
+/// versions.
+pub fn extended_attributes(device: &LongDevice) -> Value {
+    let mut result = Map::new();
+    result.insert("status".into(), json!(device.status));
+    for (key, value) in [
+        ("failed_tx", device.failed_tx),
+        ("seconds_since_rx", device.seconds_since_rx),
+        ("polling_period", device.polling_period),
+    ]
+    .iter()
+    {
+        if let Some(value) = value {
+            result.insert(key.to_string(), json!(value));
+        }
+    }
+    for attribute in device.attributes.iter().filter(|a| {
+        is_firmware_attribute(a) || SIGNAL_ATTRIBUTES.contains(&a.canonical_name().as_str())
+    }) {
+        result.insert(
+            attribute.canonical_name(),
+            attribute
+                .setting_value
+                .or(&attribute.current_value)
+                .to_json(),
+        );
+    }
+    Value::Object(result)
+}
+
+/// Home Assistant only creates devices that have entities, so the hub device gets one: the
+/// bridge's MQTT connection state, from `bridge/state`. Returns the discovery topic and config.
+pub fn hub_discovery_payload(config: &Config) -> Option<(String, Value)> {
+    let prefix = config.discovery_topic_prefix.as_ref()?;
+    let topic_prefix = config.topic_prefix.as_ref()?;
+    let state_topic = config.to_topic_string(&TopicType::BridgeTopic("state".to_string()))?;
+    Some((
+        format!("{}sensor/{}/connection/config", prefix, HUB_IDENTIFIER),
+        json!({
+            "platform": "mqtt",
+            "unique_id": format!("{}/bridge/connection", topic_prefix),
+            "name": "Wink Hub Connection",
+            "object_id": "wink_hub_connection",
+            "device": hub_device_info(),
+            "entity_category": "diagnostic",
+            "state_topic": state_topic,
+            "value_template": "{{ value_json.state }}",
+            "origin": origin(),
+        }),
+    ))
+}
+
+/// Scenes belong to the hub device, since they aren't tied to a single device. Returns the
+/// discovery topic and config.
+pub fn scene_discovery_payload(config: &Config, scene: &Scene) -> Option<(String, Value)> {
+    let prefix = config.discovery_topic_prefix.as_ref()?;
+    let topic_prefix = config.topic_prefix.as_ref()?;
+    let command_topic = config.to_topic_string(&TopicType::SceneSetTopic(scene.id))?;
+    Some((
+        format!(
+            "{}scene/{}/scene_{}/config",
+            prefix, HUB_IDENTIFIER, scene.id
+        ),
+        json!({
+            "platform": "mqtt",
+            "unique_id": format!("{}scene/{}", topic_prefix, scene.id),
+            "name": scene.name,
+            "object_id": format!("wink_scene_{}", scene.id),
+            "device": hub_device_info(),
+            "command_topic": command_topic,
+            "payload_on": "ON",
+            "origin": origin(),
+        }),
+    ))
+}
+
+fn hub_device_info() -> Value {
+    json!({
+        "name": "Wink Hub",
+        "identifiers": [HUB_IDENTIFIER],
+        "manufacturer": "Wink",
+        "model": "Hub",
+        "sw_version": crate_version!(),
+    })
+}
+
+/// Tells Home Assistant which bridge (and version) published a discovery config.
+fn origin() -> Value {
+    json!({
+        "name": "wink-mqtt-rs",
+        "sw_version": crate_version!(),
+        "support_url": "https://github.com/mikekap/wink-mqtt-rs",
+    })
+}
+
+/// Discovery for a device whose component was given by the user instead of guessed.
+fn overridden_discovery_payloads(
+    config: &Config,
+    device: &LongDevice,
+    component_override: &ComponentOverride,
+) -> Result<Vec<AutodiscoveryMessage>, Box<dyn Error>> {
+    let attribute = match &component_override.attribute {
+        Some(name) => Some(
+            device
+                .attribute(name)
+                .ok_or_else(|| simple_error!("Device {} has no attribute {}", device.id, name))?,
+        ),
+        None => None,
+    };
+    let writable_generic_value = || {
+        device
+            .attribute("GenericValue")
+            .filter(|a| a.supports_write)
+    };
+    let missing = |what: &str| {
+        simple_error!(
+            "Device {} has no {} attribute; set one with component_attribute",
+            device.id,
+            what
+        )
+    };
+    Ok(match component_override.component {
+        Component::None => vec![],
+        Component::BinarySensor => {
+            let sensors = match attribute {
+                Some(attribute) => vec![attribute],
+                None => binary_sensor_attributes(device),
+            };
+            let single = sensors.len() == 1;
+            sensors
+                .into_iter()
+                .map(|attribute| {
+                    binary_sensor_to_discovery_payload(config, device, attribute, single)
+                })
+                .collect::<Result<_, _>>()?
+        }
+        Component::Climate => {
+            vec![climate_to_discovery_payload(config, device)?.ok_or_else(|| missing("setpoint"))?]
+        }
+        Component::Cover => vec![shade_to_discovery_payload(
+            config,
+            device,
+            attribute.or_else(|| device.attribute("Up_Down")),
+        )?],
+        Component::Light => {
+            if device.attribute("Level").is_none() {
+                return Err(missing("Level").into());
+            }
+            vec![dimmer_to_discovery_payload(config, device)?]
+        }
+        Component::Lock => vec![lock_to_discovery_payload(
+            config,
+            device,
+            attribute
+                .or_else(|| device.lock_attribute())
+                .or_else(writable_generic_value)
+                .ok_or_else(|| missing("lock"))?,
+        )?],
+        Component::Siren => vec![siren_to_discovery_payload(
+            config,
+            device,
+            attribute
+                .or_else(|| device.siren_attribute())
+                .or_else(writable_generic_value)
+                .ok_or_else(|| missing("siren"))?,
+        )?],
+        Component::Switch => vec![switch_to_discovery_payload(
+            config,
+            device,
+            attribute
+                .or_else(|| device.attribute("On_Off"))
+                .or_else(writable_generic_value)
+                .ok_or_else(|| missing("On_Off"))?,
+        )?],
+    })
+}
+
+fn entity_discovery_payloads(config: &Config, device: &LongDevice) -> Vec<AutodiscoveryMessage> {
+    if let Some(attribute) = device.garage_door_attribute() {
+        return garage_door_to_discovery_payload(config, device, attribute)
+            .log_failing_result("garage_door_discovery_failed")
+            .into_iter()
+            .collect();
+    }
+    if let Some(attribute) = device.lock_attribute() {
+        return lock_to_discovery_payload(config, device, attribute)
+            .log_failing_result("lock_discovery_failed")
+            .into_iter()
+            .collect();
+    }
+    if let Some(attribute) = device.siren_attribute() {
+        return siren_to_discovery_payload(config, device, attribute)
+            .log_failing_result("siren_discovery_failed")
+            .into_iter()
+            .collect();
+    }
+    if let Some(climate) = climate_to_discovery_payload(config, device)
+        .log_failing_result("climate_discovery_failed")
+        .flatten()
+    {
+        return vec![climate];
+    }
+    let up_down = device.attribute("Up_Down").filter(|a| a.supports_write);
+    if up_down.is_some() || is_zwave_motor(device) {
+        return shade_to_discovery_payload(config, device, up_down)
+            .log_failing_result("shade_discovery_failed")
+            .into_iter()
+            .collect();
+    }
+    let sockets = power_strip_sockets(device);
+    if !sockets.is_empty() {
+        return sockets
+            .into_iter()
+            .filter_map(|(socket, attribute)| {
+                socket_to_discovery_payload(config, device, socket, attribute)
+                    .log_failing_result("socket_discovery_failed")
+            })
+            .collect();
+    }
     if device.attribute("Level").is_some() {
         return dimmer_to_discovery_payload(&config, device)
-            .log_failing_result("dimmer_discovery_failed");
+            .log_failing_result("dimmer_discovery_failed")
+            .into_iter()
+            .collect();
+    }
+    if let Some(on_off) = device.attribute("On_Off") {
+        return switch_to_discovery_payload(&config, device, on_off)
+            .log_failing_result("switch_discovery_failed")
+            .into_iter()
+            .collect();
     }
-    if device.attribute("On_Off").is_some() {
-        return switch_to_discovery_payload(&config, device)
-            .log_failing_result("switch_discovery_failed");
+    let buttons = remote_buttons(device);
+    if !buttons.is_empty() {
+        return buttons
+            .into_iter()
+            .flat_map(|(button, _)| {
+                BUTTON_TRIGGERS.iter().filter_map(move |(trigger, action)| {
+                    trigger_to_discovery_payload(config, device, button, trigger, action)
+                        .log_failing_result("trigger_discovery_failed")
+                })
+            })
+            .collect();
     }
-    return None;
+    let sensors = binary_sensor_attributes(device);
+    let single = sensors.len() == 1;
+    sensors
+        .into_iter()
+        .filter_map(|attribute| {
+            binary_sensor_to_discovery_payload(config, device, attribute, single)
+                .log_failing_result("binary_sensor_discovery_failed")
+        })
+        .collect()
 }
 
-fn switch_to_discovery_payload(
+/// Home Assistant trigger types for remote buttons, and the action suffix published for them.
+const BUTTON_TRIGGERS: &[(&str, &str)] = &[
+    ("button_short_press", "press"),
+    ("button_short_release", "release"),
+];
+
+/// Buttons of remotes like Lutron Picos: read-only Button1, Button2, ... attributes.
+fn remote_buttons(device: &LongDevice) -> Vec<(&str, &DeviceAttribute)> {
+    device
+        .attributes
+        .iter()
+        .filter(|a| !a.supports_write)
+        .filter_map(|a| {
+            BUTTON_ATTRIBUTE_REGEX
+                .captures(&a.description)
+                .map(|c| (c.name("button").unwrap().as_str(), a))
+        })
+        .collect()
+}
+
+/// The action to publish on the device's action topic when a remote button attribute changes to
+/// `value`, e.g. `button_1_press`. None if the attribute isn't a button.
+pub fn button_action(device: &LongDevice, attribute: &str, value: &Value) -> Option<String> {
+    let (button, _) = remote_buttons(device)
+        .into_iter()
+        .find(|(_, a)| a.canonical_name() == attribute)?;
+    let pressed = match value {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_u64() != Some(0),
+        Value::String(s) => {
+            !["", "0", "FALSE", "OFF", "RELEASED"].contains(&s.to_uppercase().as_str())
+        }
+        _ => false,
+    };
+    let (_, action) = BUTTON_TRIGGERS[if pressed { 0 } else { 1 }];
+    Some(format!("button_{}_{}", button, action))
+}
+
+fn trigger_to_discovery_payload(
     config: &Config,
     device: &LongDevice,
+    button: &str,
+    trigger: &str,
+    action: &str,
 ) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
-    let on_off = device.attribute("On_Off").unwrap();
+    let payload = format!("button_{}_{}", button, action);
+    let topic = config
+        .to_topic_string(&TopicType::ActionTopic(device.id))
+        .ok_or_else(|| simple_error!("No topic prefix defined"))?;
 
-    let (payload_on, payload_off) = match on_off.attribute_type {
+    Ok(AutodiscoveryMessage {
+        component: "device_automation",
+        object_id: Some(payload.clone()),
+        discovery_info: json!({
+            "platform": "mqtt",
+            "automation_type": "trigger",
+            "device": device_description(config, device),
+            "topic": topic,
+            "type": trigger,
+            "subtype": format!("button_{}", button),
+            "payload": payload,
+        }),
+    })
+}
+
+/// Z-Wave motor controls (shades, blinds, ...) and window coverings. These have a Level like
+/// dimmers, but it's a position.
+fn is_zwave_motor(device: &LongDevice) -> bool {
+    match (device.generic_device_type, device.specific_device_type) {
+        // Multilevel switch / motor multiposition, motor control class A, B and C
+        (Some(0x11), Some(0x03)) | (Some(0x11), Some(0x05..=0x07)) => device
+            .attribute("Level")
+            .filter(|a| a.supports_write)
+            .is_some(),
+        // Window covering
+        (Some(0x09), _) => device
+            .attribute("Level")
+            .filter(|a| a.supports_write)
+            .is_some(),
+        _ => false,
+    }
+}
+
+/// The device class of a Z-Wave sensor, for attributes whose names don't say.
+fn zwave_binary_sensor_class(device: &LongDevice) -> Option<&'static str> {
+    match (device.generic_device_type, device.specific_device_type) {
+        // Alarm sensor / (basic) routing, zensor and advanced zensor smoke sensors
+        (Some(0xA1), Some(0x06..=0x0A)) => Some("smoke"),
+        (Some(0xA1), _) => Some("safety"),
+        _ => None,
+    }
+}
+
+fn alarm_device_class(attribute: &DeviceAttribute) -> Option<&'static str> {
+    let name = attribute.canonical_name();
+    ALARM_ATTRIBUTES
+        .iter()
+        .find(|(alarm, _)| alarm.eq_ignore_ascii_case(&name))
+        .map(|(_, class)| *class)
+}
+
+/// Read-only booleans (contact/motion/leak sensors and the like). Z-Wave binary and alarm sensors
+/// report their state in GenericValue, and smoke/CO detectors in their alarm attributes, whatever
+/// their type.
+fn binary_sensor_attributes(device: &LongDevice) -> Vec<&DeviceAttribute> {
+    let is_zwave_binary_sensor =
+        device.generic_device_type == Some(0x20) || device.generic_device_type == Some(0xA1);
+    device
+        .attributes
+        .iter()
+        .filter(|a| !a.supports_write)
+        .filter(|a| {
+            a.attribute_type == AttributeType::Bool
+                || (is_zwave_binary_sensor && a.canonical_name() == "GenericValue")
+                || alarm_device_class(a).is_some()
+        })
+        .collect()
+}
+
+fn binary_sensor_device_class(attribute: &DeviceAttribute) -> Option<&'static str> {
+    if let Some(class) = alarm_device_class(attribute) {
+        return Some(class);
+    }
+    let name = attribute.canonical_name().to_ascii_lowercase();
+    BINARY_SENSOR_CLASSES
+        .iter()
+        .find(|(fragment, _)| name.contains(fragment))
+        .map(|(_, class)| *class)
+}
+
+/// Power strips expose one On_Off attribute per socket (On_Off1, On_Off2, ...).
+fn power_strip_sockets(device: &LongDevice) -> Vec<(&str, &DeviceAttribute)> {
+    device
+        .attributes
+        .iter()
+        .filter(|a| a.supports_write)
+        .filter_map(|a| {
+            SOCKET_ATTRIBUTE_REGEX
+                .captures(&a.description)
+                .map(|c| (c.name("socket").unwrap().as_str(), a))
+        })
+        .collect()
+}
+
+fn on_off_payloads(attribute_type: &AttributeType) -> (&'static str, String) {
+    match attribute_type {
         AttributeType::UInt8 => ("0", format!("{}", u8::MAX)),
         AttributeType::UInt16 => ("0", format!("{}", u16::MAX)),
         AttributeType::UInt32 => ("0", format!("{}", u32::MAX)),
         AttributeType::UInt64 => ("0", format!("{}", u64::MAX)),
+        AttributeType::Int8 => ("0", format!("{}", i8::MAX)),
+        AttributeType::Int16 => ("0", format!("{}", i16::MAX)),
+        AttributeType::Int32 => ("0", format!("{}", i32::MAX)),
+        AttributeType::Int64 => ("0", format!("{}", i64::MAX)),
+        AttributeType::Float => ("0", "1".into()),
         AttributeType::Bool => ("TRUE", "FALSE".into()),
-        AttributeType::String => ("ON", "OFF".into()),
+        AttributeType::String | AttributeType::Enum(_) => ("ON", "OFF".into()),
+    }
+}
+
+fn notes_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let name = format!("{} Notes", config.discovery_name(device.id, &device.name));
+    let unique_id = entity_unique_id(config, device, Some("notes"))?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+
+    Ok(AutodiscoveryMessage {
+        component: "sensor",
+        object_id: Some("notes".into()),
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "entity_category": "diagnostic",
+            "icon": "mdi:note-text",
+            "state_topic": &state_topic,
+            // Sensor states are limited to 255 characters
+            "value_template": "{{ value_json.notes | truncate(255) }}",
+            "json_attributes_topic": &state_topic,
+            "json_attributes_template": "{{ {'labels': value_json.labels} | tojson }}",
+        }),
+    })
+}
+
+fn binary_sensor_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    attribute: &DeviceAttribute,
+    only_sensor: bool,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let device_name = config.discovery_name(device.id, &device.name);
+    let name = if only_sensor {
+        device_name
+    } else {
+        format!(
+            "{} {}",
+            device_name,
+            attribute.description.replace('_', " ")
+        )
     };
+    let unique_id = entity_unique_id(config, device, Some(&attribute.description))?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
 
-    let unique_id = format!(
-        "{}/{}",
-        config
-            .topic_prefix
-            .as_ref()
-            .ok_or_else(|| simple_error!("No topic prefix defined"))?,
-        device.id
+    Ok(AutodiscoveryMessage {
+        component: "binary_sensor",
+        object_id: Some(attribute.description.clone()),
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "device_class": binary_sensor_device_class(attribute)
+                .or_else(|| zwave_binary_sensor_class(device)),
+            "state_topic": state_topic,
+            "value_template": format!(
+                "{{{{ 'OFF' if value_json.{} in [none, false, 0, 'FALSE', 'CLOSED', 'OFF', 'IDLE', 'CLEAR'] else 'ON' }}}}",
+                attribute.canonical_name()
+            ),
+            "payload_on": "ON",
+            "payload_off": "OFF",
+        }),
+    })
+}
+
+fn is_firmware_attribute(attribute: &DeviceAttribute) -> bool {
+    FIRMWARE_ATTRIBUTES.contains(&attribute.canonical_name().as_str())
+}
+
+/// A read-only attribute shown as-is, e.g. ZCLVersion or DateCode.
+fn diagnostic_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    attribute: &DeviceAttribute,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let name = format!(
+        "{} {}",
+        config.discovery_name(device.id, &device.name),
+        attribute.description.replace('_', " ")
     );
+    let unique_id = entity_unique_id(
+        config,
+        device,
+        Some(&format!("diagnostic/{}", attribute.description)),
+    )?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+
+    Ok(AutodiscoveryMessage {
+        component: "sensor",
+        object_id: Some(format!("diagnostic_{}", attribute.description)),
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "entity_category": "diagnostic",
+            "enabled_by_default": !is_firmware_attribute(attribute),
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} }}}}", attribute.canonical_name()),
+        }),
+    })
+}
+
+/// Writable enum attributes, as a select of their values.
+fn select_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    attribute: &DeviceAttribute,
+    options: &[String],
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let name = format!(
+        "{} {}",
+        config.discovery_name(device.id, &device.name),
+        attribute.description.replace('_', " ")
+    );
+    let unique_id = entity_unique_id(
+        config,
+        device,
+        Some(&format!("select/{}", attribute.description)),
+    )?;
     let state_topic = config
         .to_topic_string(&TopicType::StatusTopic(device.id))
         .unwrap();
     let command_topic = config
-        .to_topic_string(&TopicType::SetAttributeTopic(device.id, on_off.id))
+        .to_topic_string(&TopicType::SetAttributeTopic(device.id, attribute.id))
         .unwrap();
 
     Ok(AutodiscoveryMessage {
-        component: "switch",
+        component: "select",
+        object_id: Some(format!("select_{}", attribute.description)),
         discovery_info: json!({
             "platform": "mqtt",
             "unique_id": unique_id,
             "device": device_description(config, device),
-            "name": device.name,
+            "name": &name,
+            "object_id": object_id(&name),
             "state_topic": state_topic,
-            "value_template": "{{ value_json.On_Off | upper }}",
+            "value_template": format!("{{{{ value_json.{} }}}}", attribute.canonical_name()),
             "command_topic": command_topic,
-            "payload_on": payload_on,
-            "payload_off": payload_off,
+            "options": options,
         }),
     })
 }
 
-fn dimmer_to_discovery_payload(
+fn first_attribute<'a>(device: &'a LongDevice, names: &[&str]) -> Option<&'a DeviceAttribute> {
+    names
+        .iter()
+        .filter_map(|name| device.attribute(name))
+        .next()
+}
+
+/// Templates translating between Home Assistant's mode names and the attribute's values: the
+/// Z-Wave numbers for numeric attributes, lowercase names as-is for string ones.
+fn mode_templates(attribute: &DeviceAttribute, modes: &[(&str, u8)]) -> (String, String) {
+    if attribute.attribute_type.is_string() {
+        return (
+            "{{ value }}".to_string(),
+            format!(
+                "{{{{ value_json.{} | lower }}}}",
+                attribute.canonical_name()
+            ),
+        );
+    }
+    let to_value = modes
+        .iter()
+        .map(|(name, value)| format!("'{}': {}", name, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let from_value = modes
+        .iter()
+        .map(|(name, value)| format!("{}: '{}'", value, name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    (
+        format!("{{{{ {{{}}}[value] }}}}", to_value),
+        format!(
+            "{{{{ {{{}}}.get(value_json.{}, '{}') }}}}",
+            from_value,
+            attribute.canonical_name(),
+            modes[0].0
+        ),
+    )
+}
+
+/// Thermostats, if the device looks like one (a Z-Wave thermostat or a writable setpoint).
+fn climate_to_discovery_payload(
     config: &Config,
     device: &LongDevice,
-) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
-    let level = device.attribute("Level").unwrap();
-    let scale: u64 = match level.attribute_type {
-        AttributeType::UInt8 => u8::MAX as u64,
-        AttributeType::UInt16 => u16::MAX as u64,
-        AttributeType::UInt32 => u32::MAX as u64,
-        AttributeType::UInt64 => u64::MAX,
-        AttributeType::Bool => 1,
-        AttributeType::String => {
-            bail!("A string level type! Please report with `aprontest -l` output!")
+) -> Result<Option<AutodiscoveryMessage>, Box<dyn Error>> {
+    let heat = first_attribute(device, HEATING_SETPOINT_ATTRIBUTES).filter(|a| a.supports_write);
+    let cool = first_attribute(device, COOLING_SETPOINT_ATTRIBUTES).filter(|a| a.supports_write);
+    let is_zwave_thermostat = device.generic_device_type == Some(0x08);
+    if heat.is_none() && cool.is_none() && !is_zwave_thermostat {
+        return Ok(None);
+    }
+
+    let name = config.discovery_name(device.id, &device.name);
+    let unique_id = entity_unique_id(config, device, None)?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+    let set_topic = |attribute: &DeviceAttribute| {
+        config
+            .to_topic_string(&TopicType::SetAttributeTopic(device.id, attribute.id))
+            .unwrap()
+    };
+    let value_template = |attribute: &DeviceAttribute| {
+        format!("{{{{ value_json.{} }}}}", attribute.canonical_name())
+    };
+
+    let mut discovery_info = json!({
+        "platform": "mqtt",
+        "unique_id": unique_id,
+        "device": device_description(config, device),
+        "name": &name,
+        "object_id": object_id(&name),
+        "precision": 1.0,
+    });
+    if let Some(mode) = first_attribute(device, THERMOSTAT_MODE_ATTRIBUTES) {
+        let (command_template, state_template) = mode_templates(mode, THERMOSTAT_MODES);
+        discovery_info["modes"] =
+            json!(THERMOSTAT_MODES.iter().map(|(m, _)| m).collect::<Vec<_>>());
+        discovery_info["mode_state_topic"] = json!(state_topic);
+        discovery_info["mode_state_template"] = json!(state_template);
+        if mode.supports_write {
+            discovery_info["mode_command_topic"] = json!(set_topic(mode));
+            discovery_info["mode_command_template"] = json!(command_template);
+        }
+    }
+    if let Some(fan_mode) = first_attribute(device, THERMOSTAT_FAN_MODE_ATTRIBUTES) {
+        let (command_template, state_template) = mode_templates(fan_mode, THERMOSTAT_FAN_MODES);
+        discovery_info["fan_modes"] = json!(THERMOSTAT_FAN_MODES
+            .iter()
+            .map(|(m, _)| m)
+            .collect::<Vec<_>>());
+        discovery_info["fan_mode_state_topic"] = json!(state_topic);
+        discovery_info["fan_mode_state_template"] = json!(state_template);
+        if fan_mode.supports_write {
+            discovery_info["fan_mode_command_topic"] = json!(set_topic(fan_mode));
+            discovery_info["fan_mode_command_template"] = json!(command_template);
         }
+    }
+    if let Some(temperature) = first_attribute(device, TEMPERATURE_ATTRIBUTES) {
+        discovery_info["current_temperature_topic"] = json!(state_topic);
+        discovery_info["current_temperature_template"] = json!(value_template(temperature));
+    }
+    // Setpoints are integers; Home Assistant sends e.g. "21.5".
+    let setpoints = match (heat, cool) {
+        (Some(heat), Some(cool)) => vec![("temperature_low", heat), ("temperature_high", cool)],
+        (Some(setpoint), None) | (None, Some(setpoint)) => vec![("temperature", setpoint)],
+        (None, None) => vec![],
     };
+    for (key, setpoint) in setpoints.into_iter() {
+        discovery_info[format!("{}_state_topic", key)] = json!(state_topic);
+        discovery_info[format!("{}_state_template", key)] = json!(value_template(setpoint));
+        discovery_info[format!("{}_command_topic", key)] = json!(set_topic(setpoint));
+        discovery_info[format!("{}_command_template", key)] = json!("{{ value | round | int }}");
+    }
 
-    let unique_id = format!(
-        "{}/{}",
-        config
-            .topic_prefix
-            .as_ref()
-            .ok_or_else(|| simple_error!("No topic prefix defined"))?,
-        device.id
+    Ok(Some(AutodiscoveryMessage {
+        component: "climate",
+        object_id: None,
+        discovery_info,
+    }))
+}
+
+/// `key` is where the level is in the status json.
+fn battery_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    key: &str,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let name = format!("{} Battery", config.discovery_name(device.id, &device.name));
+    let unique_id = entity_unique_id(config, device, Some("battery"))?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+
+    Ok(AutodiscoveryMessage {
+        component: "sensor",
+        object_id: Some("battery".into()),
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "device_class": "battery",
+            "entity_category": "diagnostic",
+            "unit_of_measurement": "%",
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} }}}}", key),
+        }),
+    })
+}
+
+fn metering_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    sensor: &MeteringSensor,
+    attribute: &DeviceAttribute,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let name = format!(
+        "{} {}",
+        config.discovery_name(device.id, &device.name),
+        sensor.name
     );
+    let unique_id = entity_unique_id(config, device, Some(sensor.object_id))?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+
+    Ok(AutodiscoveryMessage {
+        component: "sensor",
+        object_id: Some(sensor.object_id.into()),
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "device_class": sensor.device_class,
+            "state_class": sensor.state_class,
+            "unit_of_measurement": sensor.unit,
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} }}}}", attribute.canonical_name()),
+        }),
+    })
+}
+
+fn garage_door_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    attribute: &DeviceAttribute,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let (payload_open, payload_close) = match attribute.attribute_type {
+        AttributeType::Bool => ("TRUE", "FALSE"),
+        AttributeType::String | AttributeType::Enum(_) => ("OPEN", "CLOSE"),
+        _ => ("255", "0"),
+    };
+
+    let name = config.discovery_name(device.id, &device.name);
+    let unique_id = entity_unique_id(config, device, None)?;
     let state_topic = config
         .to_topic_string(&TopicType::StatusTopic(device.id))
         .unwrap();
     let command_topic = config
-        .to_topic_string(&TopicType::SetAttributeTopic(device.id, level.id))
+        .to_topic_string(&TopicType::SetAttributeTopic(device.id, attribute.id))
         .unwrap();
 
     Ok(AutodiscoveryMessage {
-        component: "light",
+        component: "cover",
+        object_id: None,
         discovery_info: json!({
             "platform": "mqtt",
             "unique_id": unique_id,
-            "name": device.name,
             "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "device_class": "garage",
             "state_topic": state_topic,
-            "state_value_template": "{% if value_json.Level > 0 %}1{% else %}0{% endif %}",
+            // Z-Wave barrier states: 0 closed, 252 closing, 253 stopped, 254 opening, 255 open
+            "value_template": format!(
+                "{{% set v = value_json.{} %}}{{% if v in [0, false, 'CLOSED', 'FALSE'] %}}closed{{% elif v == 252 %}}closing{{% elif v == 254 %}}opening{{% else %}}open{{% endif %}}",
+                attribute.canonical_name()
+            ),
             "command_topic": command_topic,
-            "on_command_type": "brightness",
-            "payload_off": "0",
-            "payload_on": "1",
-            "brightness_state_topic": state_topic,
-            "brightness_command_topic": command_topic,
-            "brightness_value_template": "{{value_json.Level}}",
-            "brightness_scale": scale,
+            "payload_open": payload_open,
+            "payload_close": payload_close,
+            "payload_stop": null,
         }),
     })
 }
+
+/// How a lock attribute of the given type spells locked and unlocked, respectively.
+fn lock_payloads(attribute_type: &AttributeType) -> (&'static str, &'static str) {
+    match attribute_type {
+        AttributeType::Bool => ("TRUE", "FALSE"),
+        AttributeType::String | AttributeType::Enum(_) => ("LOCKED", "UNLOCKED"),
+        // Z-Wave door lock modes: 255 secured, 0 unsecured
+        _ => ("255", "0"),
+    }
+}
+
+/// Lets lock commands say LOCK/UNLOCK no matter how the device represents them. Other payloads
+/// are passed through.
+pub fn lock_command_payload<'a>(attribute_type: &AttributeType, payload: &'a str) -> &'a str {
+    let (locked, unlocked) = lock_payloads(attribute_type);
+    match payload.trim().to_ascii_uppercase().as_str() {
+        "LOCK" | "LOCKED" => locked,
+        "UNLOCK" | "UNLOCKED" => unlocked,
+        _ => payload,
+    }
+}
+
+fn lock_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    attribute: &DeviceAttribute,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let (payload_lock, payload_unlock) = lock_payloads(&attribute.attribute_type);
+
+    let name = config.discovery_name(device.id, &device.name);
+    let unique_id = entity_unique_id(config, device, None)?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+    let command_topic = config
+        .to_topic_string(&TopicType::SetAttributeTopic(device.id, attribute.id))
+        .unwrap();
+
+    Ok(AutodiscoveryMessage {
+        component: "lock",
+        object_id: None,
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "state_topic": state_topic,
+            "value_template": format!(
+                "{{% set v = value_json.{} %}}{{% if v in [true, 255, 'TRUE', 'LOCKED', 'SECURED'] %}}LOCKED{{% else %}}UNLOCKED{{% endif %}}",
+                attribute.canonical_name()
+            ),
+            "command_topic": command_topic,
+            "payload_lock": payload_lock,
+            "payload_unlock": payload_unlock,
+            // Never assume a lock command worked; wait for the device to report it.
+            "optimistic": false,
+        }),
+    })
+}
+
+/// The values that turn a siren attribute on and off, respectively.
+fn siren_values(attribute_type: &AttributeType) -> Result<(Value, Value), Box<dyn Error>> {
+    Ok(match attribute_type {
+        AttributeType::String | AttributeType::Enum(_) => (json!("ON"), json!("OFF")),
+        AttributeType::Bool => (json!(true), json!(false)),
+        t => (json!(level_scale(t)?), json!(0)),
+    })
+}
+
+/// The tones a siren can play, if it has a tone attribute and says how many tones there are.
+fn siren_tones(device: &LongDevice) -> Option<(&DeviceAttribute, Vec<String>)> {
+    let tone = first_attribute(device, SIREN_TONE_ATTRIBUTES).filter(|a| a.supports_write)?;
+    let count = first_attribute(device, SIREN_TONE_COUNT_ATTRIBUTES)?
+        .current_value
+        .to_json()
+        .as_u64()?;
+    if count == 0 || tone.attribute_type == AttributeType::Bool {
+        return None;
+    }
+    Some((tone, (1..=count.min(255)).map(|t| t.to_string()).collect()))
+}
+
+/// Sirens and alarms. Commands go through the JSON set topic so a tone can be set along with
+/// turning the siren on.
+fn siren_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    attribute: &DeviceAttribute,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let (on, off) = siren_values(&attribute.attribute_type)?;
+    let key = attribute.canonical_name();
+
+    let name = config.discovery_name(device.id, &device.name);
+    let unique_id = entity_unique_id(config, device, None)?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+    let command_topic = config
+        .to_topic_string(&TopicType::SetJsonTopic(device.id))
+        .unwrap();
+
+    let tones = siren_tones(device);
+    let tone_command = match &tones {
+        Some((tone, _)) => format!(
+            "{{% if tone is defined %}}, \"{}\": {}{{% endif %}}",
+            tone.canonical_name(),
+            match tone.attribute_type {
+                AttributeType::String | AttributeType::Enum(_) => "{{ tone | tojson }}",
+                _ => "{{ tone }}",
+            }
+        ),
+        None => "".to_string(),
+    };
+    let mut discovery_info = json!({
+        "platform": "mqtt",
+        "unique_id": unique_id,
+        "device": device_description(config, device),
+        "name": &name,
+        "object_id": object_id(&name),
+        "state_topic": state_topic,
+        "state_value_template": format!(
+            "{{% set v = value_json.{} %}}{{% if v in [false, 0, 'FALSE', 'OFF'] %}}OFF{{% else %}}ON{{% endif %}}",
+            key
+        ),
+        "command_topic": command_topic,
+        "payload_on": "ON",
+        "payload_off": "OFF",
+        "command_template": format!(
+            "{{% if value == 'ON' %}}{{\"{key}\": {on}{tone}}}{{% else %}}{{\"{key}\": {off}}}{{% endif %}}",
+            key = key,
+            on = on,
+            off = off,
+            tone = tone_command
+        ),
+        "support_duration": false,
+        "support_volume_set": false,
+    });
+    if let Some((_, tones)) = tones {
+        discovery_info["available_tones"] = json!(tones);
+    }
+
+    Ok(AutodiscoveryMessage {
+        component: "siren",
+        object_id: None,
+        discovery_info,
+    })
+}
+
+/// The values that move an Up_Down attribute up and down, respectively.
+fn up_down_values(attribute_type: &AttributeType) -> Result<(Value, Value), Box<dyn Error>> {
+    Ok(match attribute_type {
+        AttributeType::String | AttributeType::Enum(_) => (json!("UP"), json!("DOWN")),
+        AttributeType::Bool => (json!(true), json!(false)),
+        t => (json!(level_scale(t)?), json!(0)),
+    })
+}
+
+/// Shades and other motor controllers. Open/close/stop are different attributes, so the commands
+/// go through the JSON set topic; position comes from Level when the device has one. Z-Wave
+/// motors without Up_Down are opened and closed by setting Level.
+fn shade_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    up_down: Option<&DeviceAttribute>,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let (key, (up, down)) = match up_down {
+        Some(up_down) => (
+            up_down.canonical_name(),
+            up_down_values(&up_down.attribute_type)?,
+        ),
+        None => {
+            let level = device
+                .attribute("Level")
+                .ok_or_else(|| simple_error!("Motor without a Level attribute"))?;
+            (
+                level.canonical_name(),
+                (json!(level_scale(&level.attribute_type)?), json!(0)),
+            )
+        }
+    };
+    let payload_stop = match device
+        .attribute("StopMovement")
+        .filter(|a| a.supports_write)
+    {
+        Some(stop) => {
+            let (stop_value, _) = up_down_values(&stop.attribute_type)?;
+            json!(json!({ stop.canonical_name(): stop_value }).to_string())
+        }
+        None => Value::Null,
+    };
+
+    let name = config.discovery_name(device.id, &device.name);
+    let unique_id = entity_unique_id(config, device, None)?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+    let command_topic = config
+        .to_topic_string(&TopicType::SetJsonTopic(device.id))
+        .unwrap();
+
+    let mut discovery_info = json!({
+        "platform": "mqtt",
+        "unique_id": unique_id,
+        "device": device_description(config, device),
+        "name": &name,
+        "object_id": object_id(&name),
+        "device_class": "shade",
+        "command_topic": command_topic,
+        "payload_open": json!({ key.as_str(): up }).to_string(),
+        "payload_close": json!({ key.as_str(): down }).to_string(),
+        "payload_stop": payload_stop,
+    });
+    if let Some(level) = device.attribute("Level") {
+        let scale = level_scale(&level.attribute_type)?;
+        discovery_info["position_topic"] = json!(state_topic);
+        discovery_info["position_template"] =
+            json!(format!("{{{{ value_json.{} }}}}", level.canonical_name()));
+        discovery_info["position_open"] = json!(scale);
+        discovery_info["position_closed"] = json!(0);
+        if level.supports_write {
+            discovery_info["set_position_topic"] = json!(config
+                .to_topic_string(&TopicType::SetAttributeTopic(device.id, level.id))
+                .unwrap());
+        }
+    }
+
+    Ok(AutodiscoveryMessage {
+        component: "cover",
+        object_id: None,
+        discovery_info,
+    })
+}
+
+fn socket_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    socket: &str,
+    attribute: &DeviceAttribute,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let (payload_on, payload_off) = on_off_payloads(&attribute.attribute_type);
+
+    let name = format!(
+        "{} Socket {}",
+        config.discovery_name(device.id, &device.name),
+        socket
+    );
+    let unique_id = entity_unique_id(config, device, Some(&attribute.description))?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+    let command_topic = config
+        .to_topic_string(&TopicType::SetAttributeTopic(device.id, attribute.id))
+        .unwrap();
+
+    Ok(AutodiscoveryMessage {
+        component: "switch",
+        object_id: Some(attribute.description.clone()),
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} | upper }}}}", attribute.canonical_name()),
+            "command_topic": command_topic,
+            "payload_on": payload_on,
+            "payload_off": payload_off,
+        }),
+    })
+}
+
+fn switch_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+    on_off: &DeviceAttribute,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let (payload_on, payload_off) = on_off_payloads(&on_off.attribute_type);
+
+    let name = config.discovery_name(device.id, &device.name);
+    let unique_id = entity_unique_id(config, device, None)?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+    let command_topic = config
+        .to_topic_string(&TopicType::SetAttributeTopic(device.id, on_off.id))
+        .unwrap();
+
+    Ok(AutodiscoveryMessage {
+        component: "switch",
+        object_id: None,
+        discovery_info: json!({
+            "platform": "mqtt",
+            "unique_id": unique_id,
+            "device": device_description(config, device),
+            "name": &name,
+            "object_id": object_id(&name),
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} | upper }}}}", on_off.canonical_name()),
+            "command_topic": command_topic,
+            "payload_on": payload_on,
+            "payload_off": payload_off,
+        }),
+    })
+}
+
+fn level_scale(attribute_type: &AttributeType) -> Result<u64, Box<dyn Error>> {
+    Ok(match attribute_type {
+        AttributeType::UInt8 => u8::MAX as u64,
+        AttributeType::UInt16 => u16::MAX as u64,
+        AttributeType::UInt32 => u32::MAX as u64,
+        AttributeType::UInt64 => u64::MAX,
+        AttributeType::Int8 => i8::MAX as u64,
+        AttributeType::Int16 => i16::MAX as u64,
+        AttributeType::Int32 => i32::MAX as u64,
+        AttributeType::Int64 => i64::MAX as u64,
+        AttributeType::Bool => 1,
+        AttributeType::Float => {
+            bail!("A float level type! Please report with `aprontest -l` output!")
+        }
+        AttributeType::String | AttributeType::Enum(_) => {
+            bail!("A string level type! Please report with `aprontest -l` output!")
+        }
+    })
+}
+
+/// The color attributes of a color bulb: hue/saturation, or else CIE x/y.
+fn color_attributes(
+    device: &LongDevice,
+) -> Option<(&'static str, &DeviceAttribute, &DeviceAttribute)> {
+    if let (Some(hue), Some(saturation)) = (device.attribute("Hue"), device.attribute("Saturation"))
+    {
+        return Some(("hs", hue, saturation));
+    }
+    match (
+        first_attribute(device, &["ColorX", "Color_X", "CurrentX"]),
+        first_attribute(device, &["ColorY", "Color_Y", "CurrentY"]),
+    ) {
+        (Some(x), Some(y)) => Some(("xy", x, y)),
+        _ => None,
+    }
+}
+
+/// Color temperature attribute of a tunable white bulb, and whether it's in mireds (otherwise it's
+/// in kelvin, like the Wink API).
+fn color_temperature_attribute(device: &LongDevice) -> Option<(&DeviceAttribute, bool)> {
+    if let Some(mireds) = first_attribute(device, &["ColorTemperatureMireds", "ColorTempMireds"]) {
+        return Some((mireds, true));
+    }
+    first_attribute(
+        device,
+        &["ColorTemperature", "Color_Temperature", "ColorTemp"],
+    )
+    .map(|kelvin| (kelvin, false))
+}
+
+/// Light effects, by name, and their Zigbee (identify "trigger effect") values. Bulbs with a
+/// string effect attribute get the name itself.
+const LIGHT_EFFECTS: &[(&str, u8)] = &[
+    ("blink", 0x00),
+    ("breathe", 0x01),
+    ("okay", 0x02),
+    ("channel_change", 0x0B),
+    ("finish_effect", 0xFE),
+    ("stop_effect", 0xFF),
+];
+
+fn effect_attribute(device: &LongDevice) -> Option<&DeviceAttribute> {
+    first_attribute(device, &["Effect", "LightEffect", "Light_Effect", "Mode"])
+        .filter(|a| a.supports_write)
+}
+
+fn effect_name(attribute: &DeviceAttribute) -> Option<String> {
+    let value = attribute
+        .setting_value
+        .or(&attribute.current_value)
+        .to_json();
+    match value {
+        Value::String(name) if !name.is_empty() => Some(name),
+        value => {
+            let value = value.as_u64()?;
+            LIGHT_EFFECTS
+                .iter()
+                .find(|(_, v)| *v as u64 == value)
+                .map(|(name, _)| name.to_string())
+        }
+    }
+}
+
+fn effect_value(attribute: &DeviceAttribute, name: &str) -> Result<Value, Box<dyn Error>> {
+    let value = LIGHT_EFFECTS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| *v)
+        .ok_or_else(|| simple_error!("Unknown effect: {}", name))?;
+    Ok(match attribute.attribute_type {
+        AttributeType::String | AttributeType::Enum(_) => json!(name),
+        _ => json!(value),
+    })
+}
+
+// The range Home Assistant offers: 6500K to 2000K.
+const MIN_MIREDS: u64 = 153;
+const MAX_MIREDS: u64 = 500;
+
+/// Converts between mireds and kelvin; the conversion is its own inverse.
+fn mireds_kelvin(value: f64) -> f64 {
+    if value <= 0.0 {
+        0.0
+    } else {
+        (1_000_000.0 / value).round()
+    }
+}
+
+/// Full scale of each color component in Home Assistant's units, by color mode.
+fn color_component_scales(mode: &str) -> (&'static str, f64, &'static str, f64) {
+    match mode {
+        "hs" => ("h", 360.0, "s", 100.0),
+        _ => ("x", 1.0, "y", 1.0),
+    }
+}
+
+/// The attribute's value as a fraction of its full scale.
+fn attribute_fraction(attribute: &DeviceAttribute) -> Option<f64> {
+    let value = attribute
+        .setting_value
+        .or(&attribute.current_value)
+        .to_json()
+        .as_f64()?;
+    Some(value / level_scale(&attribute.attribute_type).ok()? as f64)
+}
+
+/// Dimmers use the JSON schema (https://www.home-assistant.io/integrations/light.mqtt/#json-schema),
+/// so their status carries `state`/`brightness` and their commands go through
+/// `json_light_command`.
+fn dimmer_to_discovery_payload(
+    config: &Config,
+    device: &LongDevice,
+) -> Result<AutodiscoveryMessage, Box<dyn Error>> {
+    let level = device.attribute("Level").unwrap();
+    let options = config.device_options(device.id);
+    let scale = if options.brightness_percent == Some(true) {
+        100
+    } else {
+        light_scale(level, &options)?
+    };
+
+    let name = config.discovery_name(device.id, &device.name);
+    let unique_id = entity_unique_id(config, device, None)?;
+    let state_topic = config
+        .to_topic_string(&TopicType::StatusTopic(device.id))
+        .unwrap();
+    let command_topic = config
+        .to_topic_string(&TopicType::SetJsonTopic(device.id))
+        .unwrap();
+
+    let mut message = AutodiscoveryMessage {
+        component: "light",
+        object_id: None,
+        discovery_info: json!({
+            "platform": "mqtt",
+            "schema": "json",
+            "unique_id": unique_id,
+            "name": &name,
+            "object_id": object_id(&name),
+            "device": device_description(config, device),
+            "state_topic": state_topic,
+            "command_topic": command_topic,
+            "brightness": true,
+            "brightness_scale": scale,
+            "transition": transition_attribute(device).is_some(),
+        }),
+    };
+    let mut color_modes = Vec::new();
+    if let Some((mode, _, _)) = color_attributes(device) {
+        color_modes.push(mode);
+    }
+    if color_temperature_attribute(device).is_some() {
+        color_modes.push("color_temp");
+        message.discovery_info["min_mireds"] = json!(MIN_MIREDS);
+        message.discovery_info["max_mireds"] = json!(MAX_MIREDS);
+    }
+    if !color_modes.is_empty() {
+        message.discovery_info["color_mode"] = json!(true);
+        message.discovery_info["supported_color_modes"] = json!(color_modes);
+    }
+    if effect_attribute(device).is_some() {
+        message.discovery_info["effect"] = json!(true);
+        message.discovery_info["effect_list"] = json!(LIGHT_EFFECTS
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>());
+    }
+    Ok(message)
+}
+
+/// Bulbs that can fade take the fade time (in tenths of a second) of the next Level change in
+/// WK_TransitionTime.
+fn transition_attribute(device: &LongDevice) -> Option<&DeviceAttribute> {
+    device
+        .attribute("WK_TransitionTime")
+        .filter(|a| a.supports_write)
+}
+
+/// The Level of a light at full brightness: its `brightness_scale` override, or the maximum of
+/// its attribute type.
+fn light_scale(level: &DeviceAttribute, options: &DeviceOverride) -> Result<u64, Box<dyn Error>> {
+    match options.brightness_scale {
+        Some(scale) => Ok(scale),
+        None => level_scale(&level.attribute_type),
+    }
+}
+
+/// A Level as a percentage of `scale`. A light that's on never rounds down to 0%.
+fn level_to_percent(level: f64, scale: u64) -> u64 {
+    let percent = (level * 100.0 / scale as f64).round().min(100.0) as u64;
+    if level > 0.0 {
+        percent.max(1)
+    } else {
+        percent
+    }
+}
+
+fn percent_to_level(percent: f64, scale: u64) -> u64 {
+    (percent.clamp(0.0, 100.0) * scale as f64 / 100.0).round() as u64
+}
+
+/// Whether the device is discovered as a JSON schema light, and so needs `json_light_state` in
+/// its status.
+pub fn is_json_light(config: &Config, device: &LongDevice) -> bool {
+    device_to_discovery_payload(config, device)
+        .iter()
+        .any(|m| m.component == "light")
+}
+
+/// Whether an On_Off value reads as on, by the same payloads switches are discovered with.
+fn on_off_is_on(attribute_type: &AttributeType, value: &AttributeValue) -> bool {
+    let (payload_on, _) = on_off_payloads(attribute_type);
+    let rendered = match value.to_json() {
+        Value::String(s) => s,
+        v => v.to_string(),
+    };
+    rendered.to_uppercase() == payload_on
+}
+
+/// The On_Off value that turns a light on or off, by the same payloads switches are discovered
+/// with.
+fn on_off_command_value(attribute_type: &AttributeType, on: bool) -> Result<Value, Box<dyn Error>> {
+    let (payload_on, payload_off) = on_off_payloads(attribute_type);
+    let payload = if on {
+        payload_on.to_string()
+    } else {
+        payload_off
+    };
+    Ok(if attribute_type.is_string() {
+        json!(payload)
+    } else {
+        serde_json::from_str(&payload.to_lowercase())?
+    })
+}
+
+/// The `state`/`brightness` keys JSON schema lights expect in the status payload. A light that's
+/// on at level 0 is reported the way `brightness_zero` would have set it.
+pub fn json_light_state(
+    device: &LongDevice,
+    options: &DeviceOverride,
+) -> Option<Map<String, Value>> {
+    let level = device.attribute("Level")?;
+    let level_value = level.setting_value.or(&level.current_value);
+    let on_off = device.attribute("On_Off");
+    let on_off_value = on_off.unwrap_or(level);
+    let on_off_value = on_off_value.setting_value.or(&on_off_value.current_value);
+    let mut on = match (&options.on_off_values, on_off) {
+        (Some(values), _) => on_off_value.to_json() != values.off,
+        (None, Some(on_off)) => on_off_is_on(&on_off.attribute_type, on_off_value),
+        (None, None) => level_value.is_truthy(),
+    };
+    let mut brightness = level_value.to_json();
+    if options.brightness_percent == Some(true) {
+        if let (Some(value), Ok(scale)) = (brightness.as_f64(), light_scale(level, options)) {
+            brightness = json!(level_to_percent(value, scale));
+        }
+    }
+    if on && brightness == json!(0) {
+        match options.brightness_zero.unwrap_or_default() {
+            BrightnessZero::Off => on = false,
+            BrightnessZero::Minimum => brightness = json!(1),
+        }
+    }
+    let mut result = Map::new();
+    result.insert("state".into(), json!(if on { "ON" } else { "OFF" }));
+    result.insert("brightness".into(), brightness);
+    if let Some((attribute, in_mireds)) = color_temperature_attribute(device) {
+        if let Some(value) = attribute
+            .setting_value
+            .or(&attribute.current_value)
+            .to_json()
+            .as_f64()
+        {
+            let mireds = if in_mireds {
+                value
+            } else {
+                mireds_kelvin(value)
+            };
+            result.insert("color_mode".into(), json!("color_temp"));
+            result.insert("color_temp".into(), json!(mireds as u64));
+        }
+    }
+    if let Some(effect) = effect_attribute(device).and_then(effect_name) {
+        result.insert("effect".into(), json!(effect));
+    }
+    // Without a way to tell which mode the bulb is in, color wins over color temperature.
+    if let Some((mode, first, second)) = color_attributes(device) {
+        let (first_key, first_scale, second_key, second_scale) = color_component_scales(mode);
+        if let (Some(a), Some(b)) = (attribute_fraction(first), attribute_fraction(second)) {
+            let round = |v: f64| (v * 10000.0).round() / 10000.0;
+            result.insert("color_mode".into(), json!(mode));
+            result.insert(
+                "color".into(),
+                json!({ first_key: round(a * first_scale), second_key: round(b * second_scale) }),
+            );
+        }
+    }
+    Some(result)
+}
+
+/// Translates the `state`/`brightness`/`transition`/`effect` keys of a JSON schema light command
+/// into wink attributes. Other keys are passed through untouched.
+pub fn json_light_command(
+    device: &LongDevice,
+    mut command: Map<String, Value>,
+    options: &DeviceOverride,
+) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let brightness_zero = options.brightness_zero.unwrap_or_default();
+    let level = match device.attribute("Level") {
+        Some(v) => v,
+        None => return Ok(command),
+    };
+    let state = command.remove("state");
+    let mut brightness = command.remove("brightness");
+    if let (Some(percent), Some(true)) = (&brightness, options.brightness_percent) {
+        let percent = percent
+            .as_f64()
+            .ok_or_else(|| simple_error!("Bad brightness: {}", percent))?;
+        brightness = Some(json!(percent_to_level(
+            percent,
+            light_scale(level, options)?
+        )));
+    }
+    let transition = command.remove("transition");
+    let color = command.remove("color");
+    let color_temp = command.remove("color_temp");
+    let effect = command.remove("effect");
+
+    if let Some(effect) = effect {
+        let attribute = effect_attribute(device)
+            .ok_or_else(|| simple_error!("Device {} doesn't support effects", device.id))?;
+        let name = effect
+            .as_str()
+            .ok_or_else(|| simple_error!("Bad effect: {}", effect))?;
+        command.insert(
+            attribute.description.clone(),
+            effect_value(attribute, name)?,
+        );
+    }
+
+    if let Some(color_temp) = color_temp {
+        let (attribute, in_mireds) = color_temperature_attribute(device).ok_or_else(|| {
+            simple_error!("Device {} doesn't support color temperature", device.id)
+        })?;
+        let mireds = color_temp
+            .as_f64()
+            .ok_or_else(|| simple_error!("Bad color_temp: {}", color_temp))?;
+        let value = if in_mireds {
+            mireds.round()
+        } else {
+            mireds_kelvin(mireds)
+        };
+        command.insert(attribute.description.clone(), json!(value as u64));
+    }
+
+    if let Some(color) = color {
+        let (mode, first, second) = color_attributes(device)
+            .ok_or_else(|| simple_error!("Device {} doesn't support colors", device.id))?;
+        let (first_key, first_scale, second_key, second_scale) = color_component_scales(mode);
+        for (key, scale, attribute) in [
+            (first_key, first_scale, first),
+            (second_key, second_scale, second),
+        ]
+        .iter()
+        {
+            let value = color[*key]
+                .as_f64()
+                .ok_or_else(|| simple_error!("Bad {} color (expected {}): {}", mode, key, color))?;
+            let attribute_scale = level_scale(&attribute.attribute_type)? as f64;
+            command.insert(
+                attribute.description.clone(),
+                json!(((value / scale).clamp(0.0, 1.0) * attribute_scale).round() as u64),
+            );
+        }
+    }
+
+    if let Some(transition) = transition {
+        if let Some(attribute) = transition_attribute(device) {
+            let seconds = transition
+                .as_f64()
+                .ok_or_else(|| simple_error!("Bad transition: {}", transition))?;
+            command.insert(
+                attribute.description.clone(),
+                json!((seconds * 10.0).round() as u64),
+            );
+        }
+    }
+
+    let mut turn_on = match state.as_ref().and_then(Value::as_str) {
+        Some("ON") => Some(true),
+        Some("OFF") => Some(false),
+        Some(other) => bail!("Bad light state: {}", other),
+        None => None,
+    };
+    let brightness = match brightness {
+        Some(b) if b.as_u64() == Some(0) && turn_on != Some(false) => {
+            warn!(slog_scope::logger(), "brightness_zero_command"; "device_id" => device.id, "mapped_to" => ?brightness_zero);
+            match brightness_zero {
+                BrightnessZero::Off => {
+                    turn_on = Some(false);
+                    None
+                }
+                BrightnessZero::Minimum => Some(json!(1)),
+            }
+        }
+        b => b,
+    };
+    match device.attribute("On_Off").filter(|a| a.supports_write) {
+        Some(on_off) => {
+            if let Some(on) = turn_on {
+                let value = match (&options.on_off_values, &on_off.attribute_type) {
+                    (Some(values), _) => {
+                        if on {
+                            values.on.clone()
+                        } else {
+                            values.off.clone()
+                        }
+                    }
+                    (None, t) => on_off_command_value(t, on)?,
+                };
+                command.insert(on_off.description.clone(), value);
+            }
+            if let Some(brightness) = brightness {
+                command.insert(level.description.clone(), brightness);
+            }
+        }
+        None => match (turn_on, brightness) {
+            (Some(false), _) => {
+                let off = match &options.on_off_values {
+                    Some(values) => values.off.clone(),
+                    None => json!(0),
+                };
+                command.insert(level.description.clone(), off);
+            }
+            (_, Some(brightness)) => {
+                command.insert(level.description.clone(), brightness);
+            }
+            (Some(true), None) => {
+                let on = match &options.on_off_values {
+                    Some(values) => values.on.clone(),
+                    None => json!(light_scale(level, options)?),
+                };
+                command.insert(level.description.clone(), on);
+            }
+            (None, None) => {}
+        },
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::AttributeValue;
+    use crate::overrides::OnOffValues;
+    use crate::test_utils::{attribute, config, device};
+
+    #[test]
+    fn power_strip() {
+        let config = config();
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![
+                attribute(1, "On_Off1", AttributeType::String),
+                attribute(2, "On_Off2", AttributeType::String),
+            ]),
+        );
+        assert_eq!(2, messages.len());
+        assert_eq!(
+            Some("homeassistant/switch/wink_5/On_Off2/config".to_string()),
+            config.to_topic_string(&messages[1].topic(5))
+        );
+        assert_eq!(
+            "home/wink/5/2/set",
+            messages[1].discovery_info["command_topic"]
+        );
+        assert_eq!("Strip Socket 2", messages[1].discovery_info["name"]);
+        assert_eq!(
+            "{{ value_json.On_Off2 | upper }}",
+            messages[1].discovery_info["value_template"]
+        );
+        assert_ne!(
+            messages[0].discovery_info["unique_id"],
+            messages[1].discovery_info["unique_id"]
+        );
+    }
+
+    #[test]
+    fn garage_door() {
+        let config = config();
+        let mut garage = device(vec![attribute(1, "GenericValue", AttributeType::UInt8)]);
+        garage.generic_device_type = Some(0x40);
+        garage.specific_device_type = Some(0x07);
+        let messages = device_to_discovery_payload(&config, &garage);
+        assert_eq!(1, messages.len());
+        assert_eq!("cover", messages[0].component);
+        assert_eq!("garage", messages[0].discovery_info["device_class"]);
+        assert_eq!("255", messages[0].discovery_info["payload_open"]);
+        assert_eq!("0", messages[0].discovery_info["payload_close"]);
+    }
+
+    #[test]
+    fn binary_sensors() {
+        let config = config();
+        let read_only = |id, description| {
+            let mut a = attribute(id, description, AttributeType::Bool);
+            a.supports_write = false;
+            a
+        };
+        let sensor = device(vec![read_only(1, "Opened"), read_only(2, "Tamper_Alarm")]);
+        let messages = device_to_discovery_payload(&config, &sensor);
+        assert_eq!(2, messages.len());
+        assert_eq!("binary_sensor", messages[0].component);
+        assert_eq!("opening", messages[0].discovery_info["device_class"]);
+        assert_eq!("tamper", messages[1].discovery_info["device_class"]);
+        assert_eq!("Strip Tamper Alarm", messages[1].discovery_info["name"]);
+        assert_eq!(
+            Some("homeassistant/binary_sensor/wink_5/Opened/config".to_string()),
+            config.to_topic_string(&messages[0].topic(5))
+        );
+
+        let motion = device(vec![read_only(1, "Motion")]);
+        let messages = device_to_discovery_payload(&config, &motion);
+        assert_eq!("motion", messages[0].discovery_info["device_class"]);
+        assert_eq!("Strip", messages[0].discovery_info["name"]);
+    }
+
+    #[test]
+    fn multiple_entities() {
+        let config = config();
+        let dimmer = LongDevice {
+            name: "Dimmer".to_string(),
+            ..device(vec![
+                attribute(1, "On_Off", AttributeType::String),
+                attribute(2, "Level", AttributeType::UInt8),
+                attribute(3, "Watts", AttributeType::UInt16),
+                attribute(4, "Energy", AttributeType::UInt32),
+            ])
+        };
+        let messages = device_to_discovery_payload(&config, &dimmer);
+        assert_eq!(
+            vec!["light", "sensor", "sensor"],
+            messages.iter().map(|m| m.component).collect::<Vec<_>>()
+        );
+        assert_eq!("power", messages[1].discovery_info["device_class"]);
+        assert_eq!("Dimmer Energy", messages[2].discovery_info["name"]);
+        assert_eq!(
+            messages[0].discovery_info["device"],
+            messages[2].discovery_info["device"]
+        );
+    }
+
+    #[test]
+    fn smoke_detector() {
+        let config = config();
+        let mut smoke = attribute(1, "SmokeAlarm", AttributeType::UInt8);
+        smoke.supports_write = false;
+        let mut co = attribute(2, "CO_Alarm", AttributeType::String);
+        co.supports_write = false;
+        let detector = device(vec![smoke, co]);
+        let messages = device_to_discovery_payload(&config, &detector);
+        assert_eq!(2, messages.len());
+        assert_eq!("binary_sensor", messages[0].component);
+        assert_eq!("smoke", messages[0].discovery_info["device_class"]);
+        assert_eq!(
+            "carbon_monoxide",
+            messages[1].discovery_info["device_class"]
+        );
+        assert_eq!(
+            "{{ 'OFF' if value_json.CO_Alarm in [none, false, 0, 'FALSE', 'CLOSED', 'OFF', 'IDLE', 'CLEAR'] else 'ON' }}",
+            messages[1].discovery_info["value_template"]
+        );
+    }
+
+    #[test]
+    fn battery() {
+        let config = config();
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![
+                attribute(1, "On_Off", AttributeType::String),
+                attribute(2, "BatteryLevel", AttributeType::UInt8),
+            ]),
+        );
+        assert_eq!(2, messages.len());
+        assert_eq!("sensor", messages[1].component);
+        assert_eq!("battery", messages[1].discovery_info["device_class"]);
+        assert_eq!("diagnostic", messages[1].discovery_info["entity_category"]);
+        assert_eq!(
+            "{{ value_json.BatteryLevel }}",
+            messages[1].discovery_info["value_template"]
+        );
+        assert_eq!(
+            Some("homeassistant/sensor/wink_5/battery/config".to_string()),
+            config.to_topic_string(&messages[1].topic(5))
+        );
+
+        let mut sensor = device(vec![attribute(1, "On_Off", AttributeType::String)]);
+        sensor.battery_level = Some(85);
+        let messages = device_to_discovery_payload(&config, &sensor);
+        assert_eq!(2, messages.len());
+        assert_eq!("battery", messages[1].discovery_info["device_class"]);
+        assert_eq!(
+            "{{ value_json.battery_level }}",
+            messages[1].discovery_info["value_template"]
+        );
+    }
+
+    #[test]
+    fn metering() {
+        let config = config();
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![
+                attribute(1, "On_Off", AttributeType::String),
+                attribute(2, "Watts", AttributeType::UInt16),
+                attribute(3, "Energy", AttributeType::UInt32),
+            ]),
+        );
+        assert_eq!(3, messages.len());
+        assert_eq!("power", messages[1].discovery_info["device_class"]);
+        assert_eq!("measurement", messages[1].discovery_info["state_class"]);
+        assert_eq!("W", messages[1].discovery_info["unit_of_measurement"]);
+        assert_eq!(
+            "{{ value_json.Watts }}",
+            messages[1].discovery_info["value_template"]
+        );
+        assert_eq!("Strip Energy", messages[2].discovery_info["name"]);
+        assert_eq!(
+            "total_increasing",
+            messages[2].discovery_info["state_class"]
+        );
+        assert_eq!(
+            Some("homeassistant/sensor/wink_5/energy/config".to_string()),
+            config.to_topic_string(&messages[2].topic(5))
+        );
+    }
+
+    #[test]
+    fn diagnostic_sensors() {
+        let mut config = config();
+        let mut version = attribute(2, "ZCLVersion", AttributeType::UInt8);
+        version.supports_write = false;
+        let mut battery = attribute(3, "BatteryLevel", AttributeType::UInt8);
+        battery.supports_write = false;
+        let bulb = device(vec![
+            attribute(1, "On_Off", AttributeType::String),
+            version,
+            battery,
+        ]);
+        assert_eq!(2, device_to_discovery_payload(&config, &bulb).len());
+
+        config.diagnostic_sensors = true;
+        let messages = device_to_discovery_payload(&config, &bulb);
+        assert_eq!(3, messages.len());
+        let info = &messages[2].discovery_info;
+        assert_eq!("Strip ZCLVersion", info["name"]);
+        assert_eq!("diagnostic", info["entity_category"]);
+        assert_eq!("{{ value_json.ZCLVersion }}", info["value_template"]);
+        assert_eq!(false, info["enabled_by_default"]);
+        assert_eq!(
+            Some("homeassistant/sensor/wink_5/diagnostic_ZCLVersion/config".to_string()),
+            config.to_topic_string(&messages[2].topic(5))
+        );
+    }
+
+    #[test]
+    fn enum_select() {
+        let config = config();
+        let mut switch = device(vec![
+            attribute(1, "On_Off", AttributeType::String),
+            attribute(2, "Schedule", AttributeType::String),
+        ]);
+        assert_eq!(1, device_to_discovery_payload(&config, &switch).len());
+
+        config.set_overrides(
+            vec![(
+                5,
+                DeviceOverride {
+                    enum_values: Some(
+                        vec![(
+                            "Schedule".to_string(),
+                            vec!["ON".to_string(), "OFF".to_string(), "TOGGLE".to_string()],
+                        )]
+                        .into_iter()
+                        .collect(),
+                    ),
+                    ..DeviceOverride::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        config.apply_attribute_overrides(&mut switch);
+        let messages = device_to_discovery_payload(&config, &switch);
+        assert_eq!(2, messages.len());
+        let info = &messages[1].discovery_info;
+        assert_eq!("select", messages[1].component);
+        assert_eq!("Strip Schedule", info["name"]);
+        assert_eq!(json!(["ON", "OFF", "TOGGLE"]), info["options"]);
+        assert_eq!("home/wink/5/2/set", info["command_topic"]);
+        assert_eq!("{{ value_json.Schedule }}", info["value_template"]);
+    }
+
+    #[test]
+    fn thermostat() {
+        let config = config();
+        let mut thermostat = device(vec![
+            attribute(1, "ThermostatMode", AttributeType::UInt8),
+            attribute(2, "HeatingSetpoint", AttributeType::UInt8),
+            attribute(3, "CoolingSetpoint", AttributeType::UInt8),
+            attribute(4, "Temperature", AttributeType::UInt8),
+        ]);
+        thermostat.attributes[3].supports_write = false;
+        let messages = device_to_discovery_payload(&config, &thermostat);
+        assert_eq!(1, messages.len());
+        assert_eq!("climate", messages[0].component);
+        let info = &messages[0].discovery_info;
+        assert_eq!("home/wink/5/1/set", info["mode_command_topic"]);
+        assert_eq!(
+            "{{ {'off': 0, 'heat': 1, 'cool': 2, 'auto': 3}[value] }}",
+            info["mode_command_template"]
+        );
+        assert_eq!(
+            "{{ {0: 'off', 1: 'heat', 2: 'cool', 3: 'auto'}.get(value_json.ThermostatMode, 'off') }}",
+            info["mode_state_template"]
+        );
+        assert_eq!("home/wink/5/2/set", info["temperature_low_command_topic"]);
+        assert_eq!("home/wink/5/3/set", info["temperature_high_command_topic"]);
+        assert_eq!(
+            "{{ value_json.Temperature }}",
+            info["current_temperature_template"]
+        );
+        assert_eq!(Value::Null, info["fan_mode_command_topic"]);
+    }
+
+    #[test]
+    fn lock() {
+        let config = config();
+        let mut lock = device(vec![attribute(1, "GenericValue", AttributeType::UInt8)]);
+        lock.generic_device_type = Some(0x40);
+        lock.specific_device_type = Some(0x03);
+        let messages = device_to_discovery_payload(&config, &lock);
+        assert_eq!(1, messages.len());
+        assert_eq!("lock", messages[0].component);
+        assert_eq!("255", messages[0].discovery_info["payload_lock"]);
+        assert_eq!("0", messages[0].discovery_info["payload_unlock"]);
+        assert_eq!(
+            "home/wink/5/1/set",
+            messages[0].discovery_info["command_topic"]
+        );
+
+        assert_eq!("0", lock_command_payload(&AttributeType::UInt8, "unlock"));
+        assert_eq!("TRUE", lock_command_payload(&AttributeType::Bool, "LOCK"));
+        assert_eq!("17", lock_command_payload(&AttributeType::UInt8, "17"));
+    }
+
+    #[test]
+    fn siren() {
+        let config = config();
+        let mut siren = device(vec![attribute(1, "GenericValue", AttributeType::UInt8)]);
+        siren.generic_device_type = Some(0x10);
+        siren.specific_device_type = Some(0x05);
+        let messages = device_to_discovery_payload(&config, &siren);
+        assert_eq!(1, messages.len());
+        assert_eq!("siren", messages[0].component);
+        let info = &messages[0].discovery_info;
+        assert_eq!("home/wink/5/set", info["command_topic"]);
+        assert_eq!(
+            "{% if value == 'ON' %}{\"GenericValue\": 255}{% else %}{\"GenericValue\": 0}{% endif %}",
+            info["command_template"]
+        );
+        assert_eq!(Value::Null, info["available_tones"]);
+
+        let mut tone_count = attribute(3, "ToneCount", AttributeType::UInt8);
+        tone_count.supports_write = false;
+        tone_count.current_value = AttributeValue::UInt8(3);
+        let siren = device(vec![
+            attribute(1, "Siren", AttributeType::Bool),
+            attribute(2, "Tone", AttributeType::UInt8),
+            tone_count,
+        ]);
+        let messages = device_to_discovery_payload(&config, &siren);
+        let info = &messages[0].discovery_info;
+        assert_eq!(json!(["1", "2", "3"]), info["available_tones"]);
+        assert_eq!(
+            "{% if value == 'ON' %}{\"Siren\": true{% if tone is defined %}, \"Tone\": {{ tone }}{% endif %}}{% else %}{\"Siren\": false}{% endif %}",
+            info["command_template"]
+        );
+    }
+
+    #[test]
+    fn shade() {
+        let config = config();
+        let mut shade = device(vec![
+            attribute(1, "Up_Down", AttributeType::Bool),
+            attribute(2, "StopMovement", AttributeType::Bool),
+            attribute(3, "Level", AttributeType::UInt8),
+        ]);
+        let messages = device_to_discovery_payload(&config, &shade);
+        assert_eq!(1, messages.len());
+        assert_eq!("cover", messages[0].component);
+        let info = &messages[0].discovery_info;
+        assert_eq!("home/wink/5/set", info["command_topic"]);
+        assert_eq!(r#"{"Up_Down":true}"#, info["payload_open"]);
+        assert_eq!(r#"{"Up_Down":false}"#, info["payload_close"]);
+        assert_eq!(r#"{"StopMovement":true}"#, info["payload_stop"]);
+        assert_eq!("{{ value_json.Level }}", info["position_template"]);
+        assert_eq!(255, info["position_open"]);
+        assert_eq!("home/wink/5/3/set", info["set_position_topic"]);
+
+        shade.attributes.truncate(1);
+        let messages = device_to_discovery_payload(&config, &shade);
+        assert_eq!(Value::Null, messages[0].discovery_info["payload_stop"]);
+        assert_eq!(Value::Null, messages[0].discovery_info["position_topic"]);
+    }
+
+    #[test]
+    fn component_overrides() {
+        let mut config = config();
+        let strip = device(vec![
+            attribute(1, "Level", AttributeType::UInt8),
+            attribute(2, "Backlight", AttributeType::String),
+            attribute(3, "BatteryLevel", AttributeType::UInt8),
+        ]);
+        assert_eq!(
+            "light",
+            device_to_discovery_payload(&config, &strip)[0].component
+        );
+
+        config.component_overrides.insert(
+            5,
+            ComponentOverride {
+                component: Component::Cover,
+                attribute: None,
+            },
+        );
+        let messages = device_to_discovery_payload(&config, &strip);
+        assert_eq!(2, messages.len());
+        assert_eq!("cover", messages[0].component);
+
+        config.component_overrides.insert(
+            5,
+            ComponentOverride {
+                component: Component::Switch,
+                attribute: Some("Backlight".to_string()),
+            },
+        );
+        let messages = device_to_discovery_payload(&config, &strip);
+        assert_eq!("switch", messages[0].component);
+        assert_eq!(
+            "{{ value_json.Backlight | upper }}",
+            messages[0].discovery_info["value_template"]
+        );
+
+        config.component_overrides.insert(
+            5,
+            ComponentOverride {
+                component: Component::Lock,
+                attribute: Some("Nope".to_string()),
+            },
+        );
+        assert_eq!(1, device_to_discovery_payload(&config, &strip).len());
+
+        config.component_overrides.insert(
+            5,
+            ComponentOverride {
+                component: Component::None,
+                attribute: None,
+            },
+        );
+        assert!(device_to_discovery_payload(&config, &strip).is_empty());
+    }
+
+    #[test]
+    fn zwave_device_types() {
+        let config = config();
+        // A motor control looks like a dimmer, but is a cover.
+        let mut motor = device(vec![attribute(1, "Level", AttributeType::UInt8)]);
+        motor.generic_device_type = Some(0x11);
+        motor.specific_device_type = Some(0x07);
+        let messages = device_to_discovery_payload(&config, &motor);
+        assert_eq!("cover", messages[0].component);
+        assert_eq!(
+            r#"{"Level":255}"#,
+            messages[0].discovery_info["payload_open"]
+        );
+        assert_eq!(
+            r#"{"Level":0}"#,
+            messages[0].discovery_info["payload_close"]
+        );
+        motor.specific_device_type = Some(0x01);
+        let messages = device_to_discovery_payload(&config, &motor);
+        assert_eq!("light", messages[0].component);
+
+        let mut smoke = device(vec![attribute(1, "GenericValue", AttributeType::UInt8)]);
+        smoke.attributes[0].supports_write = false;
+        smoke.generic_device_type = Some(0xA1);
+        smoke.specific_device_type = Some(0x07);
+        let messages = device_to_discovery_payload(&config, &smoke);
+        assert_eq!("binary_sensor", messages[0].component);
+        assert_eq!("smoke", messages[0].discovery_info["device_class"]);
+    }
+
+    #[test]
+    fn unique_ids() {
+        let mut config = config();
+        let mut switch = device(vec![attribute(1, "On_Off", AttributeType::String)]);
+        switch.gang_id = Some(0x7ce8f9f9);
+        assert_eq!(
+            "home/wink//5",
+            entity_unique_id(&config, &switch, None).unwrap()
+        );
+
+        config.stable_unique_ids = true;
+        assert_eq!(
+            "wink_gang_7ce8f9f9/battery",
+            entity_unique_id(&config, &switch, Some("battery")).unwrap()
+        );
+        let messages = device_to_discovery_payload(&config, &switch);
+        assert_eq!(
+            "wink_gang_7ce8f9f9",
+            messages[0].discovery_info["unique_id"]
+        );
+
+        switch.gang_id = None;
+        assert_eq!(
+            "home/wink//5",
+            entity_unique_id(&config, &switch, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn pico() {
+        let config = config();
+        let mut buttons = vec![
+            attribute(1, "Button1", AttributeType::Bool),
+            attribute(2, "Button_2", AttributeType::Bool),
+        ];
+        for button in buttons.iter_mut() {
+            button.supports_write = false;
+        }
+        let pico = device(buttons);
+        let messages = device_to_discovery_payload(&config, &pico);
+        assert_eq!(4, messages.len());
+        assert_eq!("device_automation", messages[0].component);
+        let info = &messages[3].discovery_info;
+        assert_eq!("home/wink/5/action", info["topic"]);
+        assert_eq!("button_short_release", info["type"]);
+        assert_eq!("button_2", info["subtype"]);
+        assert_eq!("button_2_release", info["payload"]);
+        assert_eq!(
+            Some("homeassistant/device_automation/wink_5/button_2_release/config".to_string()),
+            config.to_topic_string(&messages[3].topic(5))
+        );
+
+        assert_eq!(
+            Some("button_1_press".to_string()),
+            button_action(&pico, "Button1", &json!(true))
+        );
+        assert_eq!(
+            Some("button_2_release".to_string()),
+            button_action(&pico, "Button_2", &json!(false))
+        );
+        assert_eq!(None, button_action(&pico, "Level", &json!(1)));
+    }
+
+    #[test]
+    fn origin_and_version() {
+        let config = config();
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![attribute(1, "On_Off", AttributeType::String)]),
+        );
+        let info = &messages[0].discovery_info;
+        assert_eq!("wink-mqtt-rs", info["origin"]["name"]);
+        assert_eq!(crate_version!(), info["origin"]["sw_version"]);
+        assert_eq!(crate_version!(), info["device"]["sw_version"]);
+        assert_eq!(Value::Null, info["device"]["hw_version"]);
+
+        let mut zigbee = device(vec![attribute(1, "On_Off", AttributeType::String)]);
+        zigbee.interconnect = Some("ZIGBEE".to_string());
+        let messages = device_to_discovery_payload(&config, &zigbee);
+        assert_eq!("ZIGBEE", messages[0].discovery_info["device"]["hw_version"]);
+    }
+
+    #[test]
+    fn hub_device() {
+        let config = config();
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![attribute(1, "On_Off", AttributeType::String)]),
+        );
+        assert_eq!(
+            "wink_hub",
+            messages[0].discovery_info["device"]["via_device"]
+        );
+
+        let (topic, info) = hub_discovery_payload(&config).unwrap();
+        assert_eq!("homeassistant/sensor/wink_hub/connection/config", topic);
+        assert_eq!(json!(["wink_hub"]), info["device"]["identifiers"]);
+        assert_eq!("home/wink/bridge/state", info["state_topic"]);
+    }
+
+    #[test]
+    fn scene() {
+        let config = config();
+        let scene = Scene {
+            id: 3,
+            name: "All Off".to_string(),
+            actions: vec![],
+        };
+        let (topic, info) = scene_discovery_payload(&config, &scene).unwrap();
+        assert_eq!("homeassistant/scene/wink_hub/scene_3/config", topic);
+        assert_eq!("home/wink/scene/3/set", info["command_topic"]);
+        assert_eq!("All Off", info["name"]);
+        assert_eq!(json!(["wink_hub"]), info["device"]["identifiers"]);
+    }
+
+    #[test]
+    fn json_attributes() {
+        let config = config();
+        let mut on_off = attribute(1, "On_Off", AttributeType::String);
+        on_off.current_value = AttributeValue::String("TRUE".to_string());
+        let mut lqi = attribute(2, "LQI", AttributeType::UInt8);
+        lqi.supports_write = false;
+        lqi.current_value = AttributeValue::UInt8(200);
+        let mut version = attribute(3, "ZCLVersion", AttributeType::UInt8);
+        version.supports_write = false;
+        version.current_value = AttributeValue::UInt8(1);
+        let mut switch = device(vec![on_off, lqi, version]);
+        switch.status = "ONLINE".to_string();
+        switch.failed_tx = Some(3);
+        switch.seconds_since_rx = Some(40);
+
+        let messages = device_to_discovery_payload(&config, &switch);
+        assert_eq!(
+            "home/wink/5/attributes",
+            messages[0].discovery_info["json_attributes_topic"]
+        );
+        assert_eq!(
+            json!({"status": "ONLINE", "failed_tx": 3, "seconds_since_rx": 40, "LQI": 200, "ZCLVersion": 1}),
+            extended_attributes(&switch)
+        );
+
+        let mut button = attribute(1, "Button1", AttributeType::Bool);
+        button.supports_write = false;
+        let pico = device(vec![button]);
+        for message in device_to_discovery_payload(&config, &pico).iter() {
+            assert_eq!(Value::Null, message.discovery_info["json_attributes_topic"]);
+        }
+    }
+
+    #[test]
+    fn names() {
+        let mut config = config();
+        config.name_template = "{name} Light".to_string();
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![attribute(1, "Level", AttributeType::UInt8)]),
+        );
+        assert_eq!("Strip Light", messages[0].discovery_info["name"]);
+        assert_eq!("strip_light", messages[0].discovery_info["object_id"]);
+        assert_eq!("Strip Light", messages[0].discovery_info["device"]["name"]);
+    }
+
+    #[test]
+    fn notes_sensor() {
+        let mut config = config();
+        let dimmer = device(vec![attribute(2, "Level", AttributeType::UInt8)]);
+        assert_eq!(1, device_to_discovery_payload(&config, &dimmer).len());
+
+        config.expose_notes = true;
+        let messages = device_to_discovery_payload(&config, &dimmer);
+        assert_eq!(2, messages.len());
+        assert_eq!("sensor", messages[1].component);
+        assert_eq!("diagnostic", messages[1].discovery_info["entity_category"]);
+        assert_eq!(
+            Some("homeassistant/sensor/wink_5/notes/config".to_string()),
+            config.to_topic_string(&messages[1].topic(5))
+        );
+    }
+
+    #[test]
+    fn dimmer() {
+        let config = config();
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![
+                attribute(1, "On_Off", AttributeType::String),
+                attribute(2, "Level", AttributeType::UInt8),
+            ]),
+        );
+        assert_eq!(1, messages.len());
+        assert_eq!("light", messages[0].component);
+        assert_eq!(
+            Some("homeassistant/light/wink_5/config".to_string()),
+            config.to_topic_string(&messages[0].topic(5))
+        );
+        assert_eq!("json", messages[0].discovery_info["schema"]);
+        assert_eq!(false, messages[0].discovery_info["transition"]);
+
+        let messages = device_to_discovery_payload(
+            &config,
+            &device(vec![
+                attribute(2, "Level", AttributeType::UInt8),
+                attribute(3, "WK_TransitionTime", AttributeType::UInt16),
+            ]),
+        );
+        assert_eq!(true, messages[0].discovery_info["transition"]);
+    }
+
+    fn command(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn json_light_commands() {
+        let dimmer = device(vec![
+            attribute(2, "Level", AttributeType::UInt8),
+            attribute(3, "WK_TransitionTime", AttributeType::UInt16),
+        ]);
+        assert_eq!(
+            command(json!({"Level": 128, "WK_TransitionTime": 25})),
+            json_light_command(
+                &dimmer,
+                command(json!({"state": "ON", "brightness": 128, "transition": 2.5})),
+                &DeviceOverride::default()
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            command(json!({"Level": 0})),
+            json_light_command(
+                &dimmer,
+                command(json!({"state": "OFF"})),
+                &DeviceOverride::default()
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            command(json!({"Level": 255})),
+            json_light_command(
+                &dimmer,
+                command(json!({"state": "ON"})),
+                &DeviceOverride::default()
+            )
+            .unwrap()
+        );
+
+        let with_switch = device(vec![
+            attribute(1, "On_Off", AttributeType::String),
+            attribute(2, "Level", AttributeType::UInt8),
+        ]);
+        assert_eq!(
+            command(json!({"On_Off": "ON"})),
+            json_light_command(
+                &with_switch,
+                command(json!({"state": "ON"})),
+                &DeviceOverride::default()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn brightness_scale() {
+        let config = config();
+        let dimmer = device(vec![attribute(2, "Level", AttributeType::UInt8)]);
+        let options = DeviceOverride {
+            brightness_scale: Some(99),
+            ..DeviceOverride::default()
+        };
+        config.set_overrides(vec![(5, options.clone())].into_iter().collect());
+        let messages = device_to_discovery_payload(&config, &dimmer);
+        assert_eq!(99, messages[0].discovery_info["brightness_scale"]);
+        assert_eq!(
+            command(json!({"Level": 99})),
+            json_light_command(&dimmer, command(json!({"state": "ON"})), &options).unwrap()
+        );
+
+        let mut with_switch = device(vec![
+            attribute(1, "On_Off", AttributeType::UInt8),
+            attribute(2, "Level", AttributeType::UInt8),
+        ]);
+        let options = DeviceOverride {
+            on_off_values: Some(OnOffValues {
+                on: json!(1),
+                off: json!(0),
+            }),
+            ..DeviceOverride::default()
+        };
+        assert_eq!(
+            command(json!({"On_Off": 1})),
+            json_light_command(&with_switch, command(json!({"state": "ON"})), &options).unwrap()
+        );
+        with_switch.attributes[0].current_value = AttributeValue::UInt8(1);
+        with_switch.attributes[1].current_value = AttributeValue::UInt8(40);
+        assert_eq!(
+            json!("ON"),
+            json_light_state(&with_switch, &options).unwrap()["state"]
+        );
+
+        // Without on_off_values, integer On_Off reads like it does for switches: 0 is on.
+        let options = DeviceOverride::default();
+        assert_eq!(
+            command(json!({"On_Off": 0})),
+            json_light_command(&with_switch, command(json!({"state": "ON"})), &options).unwrap()
+        );
+        assert_eq!(
+            command(json!({"On_Off": 255})),
+            json_light_command(&with_switch, command(json!({"state": "OFF"})), &options).unwrap()
+        );
+        assert_eq!(
+            json!("OFF"),
+            json_light_state(&with_switch, &options).unwrap()["state"]
+        );
+        with_switch.attributes[0].current_value = AttributeValue::UInt8(0);
+        assert_eq!(
+            json!("ON"),
+            json_light_state(&with_switch, &options).unwrap()["state"]
+        );
+    }
+
+    #[test]
+    fn brightness_percent() {
+        let mut config = config();
+        config.brightness_percent = true;
+        let mut dimmer = device(vec![attribute(2, "Level", AttributeType::UInt8)]);
+        let messages = device_to_discovery_payload(&config, &dimmer);
+        assert_eq!(100, messages[0].discovery_info["brightness_scale"]);
+
+        let options = config.device_options(5);
+        assert_eq!(
+            command(json!({"Level": 128})),
+            json_light_command(&dimmer, command(json!({"brightness": 50})), &options).unwrap()
+        );
+        dimmer.attributes[0].current_value = AttributeValue::UInt8(255);
+        assert_eq!(
+            json!(100),
+            json_light_state(&dimmer, &options).unwrap()["brightness"]
+        );
+        // Dim but on isn't 0%.
+        dimmer.attributes[0].current_value = AttributeValue::UInt8(1);
+        assert_eq!(
+            json!(1),
+            json_light_state(&dimmer, &options).unwrap()["brightness"]
+        );
+
+        // Per device overrides win.
+        config.set_overrides(
+            vec![(
+                5,
+                DeviceOverride {
+                    brightness_percent: Some(false),
+                    ..DeviceOverride::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let messages = device_to_discovery_payload(&config, &dimmer);
+        assert_eq!(255, messages[0].discovery_info["brightness_scale"]);
+    }
+
+    #[test]
+    fn color_light() {
+        let config = config();
+        let mut bulb = device(vec![
+            attribute(1, "On_Off", AttributeType::String),
+            attribute(2, "Level", AttributeType::UInt8),
+            attribute(3, "Hue", AttributeType::UInt8),
+            attribute(4, "Saturation", AttributeType::UInt8),
+        ]);
+        let messages = device_to_discovery_payload(&config, &bulb);
+        assert_eq!(
+            json!(["hs"]),
+            messages[0].discovery_info["supported_color_modes"]
+        );
+
+        assert_eq!(
+            command(json!({"Hue": 128, "Saturation": 255})),
+            json_light_command(
+                &bulb,
+                command(json!({"color": {"h": 180.7, "s": 100}})),
+                &DeviceOverride::default()
+            )
+            .unwrap()
+        );
+
+        bulb.attributes[2].current_value = AttributeValue::UInt8(51);
+        bulb.attributes[3].current_value = AttributeValue::UInt8(255);
+        let state = json_light_state(&bulb, &DeviceOverride::default()).unwrap();
+        assert_eq!(json!("hs"), state["color_mode"]);
+        assert_eq!(json!({"h": 72.0, "s": 100.0}), state["color"]);
+
+        let white = device(vec![attribute(2, "Level", AttributeType::UInt8)]);
+        assert!(json_light_command(
+            &white,
+            command(json!({"color": {"h": 0, "s": 0}})),
+            &DeviceOverride::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn color_temperature() {
+        let config = config();
+        let mut bulb = device(vec![
+            attribute(2, "Level", AttributeType::UInt8),
+            attribute(3, "ColorTemperature", AttributeType::UInt16),
+        ]);
+        let messages = device_to_discovery_payload(&config, &bulb);
+        assert_eq!(
+            json!(["color_temp"]),
+            messages[0].discovery_info["supported_color_modes"]
+        );
+        assert_eq!(153, messages[0].discovery_info["min_mireds"]);
+
+        assert_eq!(
+            command(json!({"ColorTemperature": 2703})),
+            json_light_command(
+                &bulb,
+                command(json!({"color_temp": 370})),
+                &DeviceOverride::default()
+            )
+            .unwrap()
+        );
+
+        bulb.attributes[1].current_value = AttributeValue::UInt16(4000);
+        let state = json_light_state(&bulb, &DeviceOverride::default()).unwrap();
+        assert_eq!(json!("color_temp"), state["color_mode"]);
+        assert_eq!(json!(250), state["color_temp"]);
+    }
+
+    #[test]
+    fn effects() {
+        let config = config();
+        let mut bulb = device(vec![
+            attribute(2, "Level", AttributeType::UInt8),
+            attribute(3, "Effect", AttributeType::UInt8),
+        ]);
+        let messages = device_to_discovery_payload(&config, &bulb);
+        assert_eq!(true, messages[0].discovery_info["effect"]);
+        assert_eq!("breathe", messages[0].discovery_info["effect_list"][1]);
+
+        let options = DeviceOverride::default();
+        assert_eq!(
+            command(json!({"Effect": 1})),
+            json_light_command(&bulb, command(json!({"effect": "breathe"})), &options).unwrap()
+        );
+        assert!(json_light_command(&bulb, command(json!({"effect": "disco"})), &options).is_err());
+        bulb.attributes[1].current_value = AttributeValue::UInt8(0xFF);
+        assert_eq!(
+            json!("stop_effect"),
+            json_light_state(&bulb, &options).unwrap()["effect"]
+        );
+
+        bulb.attributes[1].attribute_type = AttributeType::String;
+        assert_eq!(
+            command(json!({"Effect": "okay"})),
+            json_light_command(&bulb, command(json!({"effect": "okay"})), &options).unwrap()
+        );
+    }
+
+    #[test]
+    fn brightness_zero() {
+        let with_switch = device(vec![
+            attribute(1, "On_Off", AttributeType::String),
+            attribute(2, "Level", AttributeType::UInt8),
+        ]);
+        let zero = || command(json!({"state": "ON", "brightness": 0}));
+        let minimum = DeviceOverride {
+            brightness_zero: Some(BrightnessZero::Minimum),
+            ..DeviceOverride::default()
+        };
+        assert_eq!(
+            command(json!({"On_Off": "OFF"})),
+            json_light_command(&with_switch, zero(), &DeviceOverride::default()).unwrap()
+        );
+        assert_eq!(
+            command(json!({"On_Off": "ON", "Level": 1})),
+            json_light_command(&with_switch, zero(), &minimum).unwrap()
+        );
+
+        let mut on_at_zero = with_switch;
+        on_at_zero.attributes[0].current_value = AttributeValue::String("ON".into());
+        on_at_zero.attributes[1].current_value = AttributeValue::UInt8(0);
+        assert_eq!(
+            command(json!({"state": "OFF", "brightness": 0})),
+            json_light_state(&on_at_zero, &DeviceOverride::default()).unwrap()
+        );
+        assert_eq!(
+            command(json!({"state": "ON", "brightness": 1})),
+            json_light_state(&on_at_zero, &minimum).unwrap()
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Topics and payloads for the Homie 4.0 convention (https://homieiot.github.io/specification/).
+//!
+//! The hub is a single Homie device; every wink device is a node on it and every attribute of
+//! that device is a property of the node.
+use crate::controller::{AttributeType, AttributeValue, DeviceAttribute, DeviceId, LongDevice};
+
+pub const HOMIE_VERSION: &str = "4.0";
+
+/// Homie ids may only contain lowercase letters, digits and hyphens.
+pub fn homie_id(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() {
+            result.push(c.to_ascii_lowercase());
+        } else if !result.is_empty() && !result.ends_with('-') {
+            result.push('-');
+        }
+    }
+    result.trim_end_matches('-').to_string()
+}
+
+pub fn node_id(device_id: DeviceId) -> String {
+    format!("device-{}", device_id)
+}
+
+pub fn parse_node_id(node_id: &str) -> Option<DeviceId> {
+    node_id.strip_prefix("device-")?.parse().ok()
+}
+
+pub fn property_id(attribute: &DeviceAttribute) -> String {
+    match homie_id(&attribute.canonical_name()) {
+        id if id.is_empty() => format!("attribute-{}", attribute.id),
+        id => id,
+    }
+}
+
+fn datatype(attribute_type: &AttributeType) -> &'static str {
+    match attribute_type {
+        AttributeType::Bool => "boolean",
+        AttributeType::String => "string",
+        AttributeType::Enum(_) => "enum",
+        AttributeType::UInt8
+        | AttributeType::UInt16
+        | AttributeType::UInt32
+        | AttributeType::UInt64
+        | AttributeType::Int8
+        | AttributeType::Int16
+        | AttributeType::Int32
+        | AttributeType::Int64 => "integer",
+        AttributeType::Float => "float",
+    }
+}
+
+fn value_format(attribute_type: &AttributeType) -> Option<String> {
+    match attribute_type {
+        AttributeType::UInt8 => Some("0:255".to_string()),
+        AttributeType::UInt16 => Some("0:65535".to_string()),
+        AttributeType::Int8 => Some("-128:127".to_string()),
+        AttributeType::Int16 => Some("-32768:32767".to_string()),
+        AttributeType::Enum(values) => Some(values.join(",")),
+        _ => None,
+    }
+}
+
+/// The payload for a property value, or None if the attribute has no value yet.
+pub fn format_value(value: &AttributeValue) -> Option<String> {
+    match value {
+        AttributeValue::NoValue => None,
+        AttributeValue::Bool(b) => Some(b.to_string()),
+        AttributeValue::String(s) => Some(s.clone()),
+        AttributeValue::UInt8(v) => Some(v.to_string()),
+        AttributeValue::UInt16(v) => Some(v.to_string()),
+        AttributeValue::UInt32(v) => Some(v.to_string()),
+        AttributeValue::UInt64(v) => Some(v.to_string()),
+        AttributeValue::Int8(v) => Some(v.to_string()),
+        AttributeValue::Int16(v) => Some(v.to_string()),
+        AttributeValue::Int32(v) => Some(v.to_string()),
+        AttributeValue::Int64(v) => Some(v.to_string()),
+        AttributeValue::Float(v) => Some(v.to_string()),
+    }
+}
+
+/// Device level attributes. `prefix` is the `homie/{device_id}/` topic prefix.
+pub fn device_messages(prefix: &str, name: &str, nodes: &[String]) -> Vec<(String, String)> {
+    vec![
+        (format!("{}$homie", prefix), HOMIE_VERSION.to_string()),
+        (format!("{}$name", prefix), name.to_string()),
+        (format!("{}$nodes", prefix), nodes.join(",")),
+        (format!("{}$extensions", prefix), "".to_string()),
+    ]
+}
+
+/// The node and property attributes describing a single wink device.
+pub fn node_messages(prefix: &str, device: &LongDevice) -> Vec<(String, String)> {
+    let node = format!("{}{}/", prefix, node_id(device.id));
+    let mut result = vec![
+        (format!("{}$name", node), device.name.clone()),
+        (format!("{}$type", node), "wink-device".to_string()),
+        (
+            format!("{}$properties", node),
+            device
+                .attributes
+                .iter()
+                .map(property_id)
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+    ];
+    for attribute in device.attributes.iter() {
+        let property = format!("{}{}/", node, property_id(attribute));
+        result.push((format!("{}$name", property), attribute.description.clone()));
+        result.push((
+            format!("{}$datatype", property),
+            datatype(&attribute.attribute_type).to_string(),
+        ));
+        result.push((
+            format!("{}$settable", property),
+            attribute.supports_write.to_string(),
+        ));
+        if let Some(format) = value_format(&attribute.attribute_type) {
+            result.push((format!("{}$format", property), format));
+        }
+    }
+    result
+}
+
+/// The current value of every property of the device.
+pub fn property_value_messages(prefix: &str, device: &LongDevice) -> Vec<(String, String)> {
+    device
+        .attributes
+        .iter()
+        .filter_map(|attribute| {
+            format_value(attribute.current_value.or(&attribute.setting_value)).map(|value| {
+                (
+                    format!(
+                        "{}{}/{}",
+                        prefix,
+                        node_id(device.id),
+                        property_id(attribute)
+                    ),
+                    value,
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, attribute};
+
+    fn device() -> LongDevice {
+        LongDevice {
+            id: 2,
+            name: "Bedroom Fan".to_string(),
+            status: "ONLINE".to_string(),
+            ..test_utils::device(vec![DeviceAttribute {
+                current_value: AttributeValue::Bool(true),
+                ..attribute(1, "On_Off", AttributeType::Bool)
+            }])
+        }
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!("on-off", homie_id("On_Off"));
+        assert_eq!("level", homie_id("__Level__"));
+        assert_eq!("device-7", node_id(7));
+        assert_eq!(Some(7), parse_node_id("device-7"));
+        assert_eq!(None, parse_node_id("bridge"));
+    }
+
+    #[test]
+    fn messages() {
+        let device = device();
+        let nodes = node_messages("homie/wink/", &device);
+        assert!(nodes.contains(&(
+            "homie/wink/device-2/$properties".to_string(),
+            "on-off".to_string()
+        )));
+        assert!(nodes.contains(&(
+            "homie/wink/device-2/on-off/$datatype".to_string(),
+            "boolean".to_string()
+        )));
+        assert_eq!(
+            vec![("homie/wink/device-2/on-off".to_string(), "true".to_string())],
+            property_value_messages("homie/wink/", &device)
+        );
+    }
+}
@@ -1,13 +1,26 @@
+use crate::audit::{self, AuditEvent};
 use crate::config::Config;
-use crate::controller::{AttributeId, DeviceController, DeviceId};
+#[cfg(debug_assertions)]
+use crate::controller::FaultConfig;
+use crate::controller::{
+    AttributeId, DeviceController, DeviceId, MaintenanceProgress, ZwaveOperation,
+    DEFAULT_PAIRING_TIMEOUT,
+};
+use crate::notes::{DeviceNotesPatch, NotesStore};
+use crate::overrides::{save_overrides, validate_overrides, DeviceOverride};
+use crate::read_only::READ_ONLY_ERROR;
+use crate::stats::{Stats, StatsReport};
+use crate::support_bundle;
 use crate::syncer::DeviceSyncer;
-use crate::utils::{Numberish, ResultExtensions};
+use crate::unknown_formats::UNKNOWN_FORMATS;
+use crate::utils::{unix_timestamp, Numberish, ResultExtensions};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server};
 use regex::Regex;
 use rust_embed::RustEmbed;
 use simple_error::{bail, simple_error};
 use slog::{debug, error, info};
+use std::collections::HashMap;
 use std::error::Error;
 use std::ffi::OsStr;
 use std::net::SocketAddr;
@@ -15,12 +28,17 @@ use std::path::Path;
 use std::sync::Arc;
 use tokio::process::Command;
 use tokio::sync::oneshot::Sender;
+use tokio::sync::Mutex;
 
 pub struct HttpServer {
     config: Config,
     controller: Arc<dyn DeviceController>,
     shutdown_signal: Sender<()>,
     syncer: Option<Arc<DeviceSyncer>>,
+    notes: Arc<NotesStore>,
+    stats: Arc<Stats>,
+    /// The running (or last) Z-Wave maintenance operation.
+    maintenance: Mutex<Option<Arc<Mutex<MaintenanceProgress>>>>,
 }
 
 #[derive(RustEmbed)]
@@ -30,6 +48,23 @@ struct Assets;
 lazy_static! {
     static ref SET_DEVICE_ATTRIBUTE_REGEX: Regex =
         Regex::new("/api/devices/(?P<device_id>[0-9]+)/(?P<attribute_id>[0-9]+)").unwrap();
+    static ref DEVICE_REGEX: Regex = Regex::new("^/api/devices/(?P<device_id>[0-9]+)$").unwrap();
+    static ref DEVICE_ATTRIBUTE_REGEX: Regex =
+        Regex::new("^/api/devices/(?P<device_id>[0-9]+)/(?P<attribute_id>[0-9]+)$").unwrap();
+    static ref RAW_DEVICE_REGEX: Regex =
+        Regex::new("^/api/devices/(?P<device_id>[0-9]+)/raw$").unwrap();
+    static ref DEVICE_META_REGEX: Regex =
+        Regex::new("^/api/devices/(?P<device_id>[0-9]+)/meta$").unwrap();
+    static ref UNDISCOVER_DEVICE_REGEX: Regex =
+        Regex::new("^/api/devices/(?P<device_id>[0-9]+)/undiscover$").unwrap();
+    static ref REMOVE_DEVICE_REGEX: Regex =
+        Regex::new("^/api/devices/(?P<device_id>[0-9]+)/remove$").unwrap();
+    static ref RENAME_DEVICE_REGEX: Regex =
+        Regex::new("^/api/devices/(?P<device_id>[0-9]+)/rename$").unwrap();
+    static ref ZWAVE_NODE_REGEX: Regex = Regex::new(
+        "^/api/devices/(?P<device_id>[0-9]+)/(?P<operation>remove_failed|neighbor_update)$"
+    )
+    .unwrap();
 }
 
 impl HttpServer {
@@ -37,6 +72,8 @@ impl HttpServer {
         config: &Config,
         controller: Arc<dyn DeviceController>,
         syncer: Option<Arc<DeviceSyncer>>,
+        notes: Arc<NotesStore>,
+        stats: Arc<Stats>,
     ) -> Arc<HttpServer> {
         let (tx, rx) = tokio::sync::oneshot::channel::<()>();
 
@@ -44,7 +81,10 @@ impl HttpServer {
             config: config.clone(),
             controller,
             syncer,
+            notes,
+            stats,
             shutdown_signal: tx,
+            maintenance: Mutex::new(None),
         });
 
         let that = this.clone();
@@ -112,43 +152,194 @@ impl HttpServer {
             .unwrap()
     }
 
-    fn json_error_response(err: &Box<dyn Error>) -> Response<Body> {
+    fn json_error_response(stats: &Stats, operation: &str, err: &Box<dyn Error>) -> Response<Body> {
+        stats.record_failure(operation);
         Self::json_response(500, serde_json::json!({ "error": format!("{:?}", err) }))
     }
 
+    /// Requests that would actuate devices or change the hub's device list, refused in
+    /// read-only mode. Bridge settings (overrides, hidden devices, notes) can still change.
+    fn changes_hub(request: &Request<Body>) -> bool {
+        let path = request.uri().path();
+        request.method() == Method::POST
+            && (REMOVE_DEVICE_REGEX.is_match(path)
+                || RENAME_DEVICE_REGEX.is_match(path)
+                || ZWAVE_NODE_REGEX.is_match(path)
+                || SET_DEVICE_ATTRIBUTE_REGEX.is_match(path)
+                || path == "/api/zwave/heal"
+                || path == "/api/devices/discovery"
+                || path == "/api/aprontest")
+    }
+
     async fn handler(
         self: Arc<Self>,
         request: Request<Body>,
     ) -> Result<Response<Body>, hyper::Error> {
         debug!(slog_scope::logger(), "http_request"; "method" => %request.method(), "uri" => %request.uri());
 
+        // Safe mode only serves status and diagnostics; nothing that changes devices or config.
+        if self.config.safe_mode && request.method() != Method::GET {
+            return Ok(Self::json_response(
+                503,
+                serde_json::json!({ "error": "Safe mode: only GET requests are served" }),
+            ));
+        }
+        if self.config.read_only && Self::changes_hub(&request) {
+            info!(slog_scope::logger(), "read_only_refusing_request"; "uri" => %request.uri());
+            return Ok(Self::json_response(
+                403,
+                serde_json::json!({ "error": READ_ONLY_ERROR }),
+            ));
+        }
+
+        let stats = self.stats.clone();
         match (request.method(), request.uri().path()) {
             (&Method::GET, "/") => Ok(Self::static_response("index.html")),
             (&Method::GET, "/static/index.js") => Ok(Self::static_response("index.js")),
             (&Method::GET, "/api/devices") => self.devices_list().await.or_else(|e| {
                 error!(slog_scope::logger(), "device_list_failed"; "error" => ?e);
-                Ok(Self::json_error_response(&e))
+                Ok(Self::json_error_response(&stats, "device_list", &e))
             }),
             (&Method::GET, "/api/events") => self.last_messages().await.or_else(|e| {
                 error!(slog_scope::logger(), "last_messages_failed"; "error" => ?e);
-                Ok(Self::json_error_response(&e))
+                Ok(Self::json_error_response(&stats, "last_messages", &e))
+            }),
+            (&Method::GET, "/api/mqtt/status") => self.mqtt_status().await.or_else(|e| {
+                error!(slog_scope::logger(), "mqtt_status_failed"; "error" => ?e);
+                Ok(Self::json_error_response(&stats, "mqtt_status", &e))
+            }),
+            (&Method::GET, "/metrics") => self.metrics().await.or_else(|e| {
+                error!(slog_scope::logger(), "metrics_failed"; "error" => ?e);
+                Ok(Self::json_error_response(&stats, "metrics", &e))
+            }),
+            (&Method::GET, path) if DEVICE_REGEX.is_match(path) => {
+                self.device_details(path).await.or_else(|e| {
+                    error!(slog_scope::logger(), "device_details_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "device_details", &e))
+                })
+            }
+            (&Method::GET, path) if DEVICE_ATTRIBUTE_REGEX.is_match(path) => {
+                self.device_attribute(path).await.or_else(|e| {
+                    error!(slog_scope::logger(), "device_attribute_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "device_attribute", &e))
+                })
+            }
+            (&Method::GET, path) if RAW_DEVICE_REGEX.is_match(path) => {
+                self.device_raw(path).await.or_else(|e| {
+                    error!(slog_scope::logger(), "device_raw_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "device_raw", &e))
+                })
+            }
+            (&Method::GET, "/api/stats") => Ok(Self::json_response(
+                200,
+                serde_json::to_value(StatsReport {
+                    counters: self.stats.snapshot(),
+                    controller: self.controller.stats(),
+                })
+                .unwrap(),
+            )),
+            (&Method::GET, "/api/support-bundle") => Ok(self.support_bundle().await),
+            (&Method::GET, "/api/diagnostics/unknown-formats") => Ok(Self::json_response(
+                200,
+                serde_json::json!({ "unknown_formats": UNKNOWN_FORMATS.snapshot() }),
+            )),
+            (&Method::POST, "/api/stats/reset") => Ok(Self::json_response(
+                200,
+                serde_json::to_value(self.stats.reset()).unwrap(),
+            )),
+            (&Method::GET, "/api/devices/hidden") => self.hidden_devices().await.or_else(|e| {
+                error!(slog_scope::logger(), "hidden_devices_failed"; "error" => ?e);
+                Ok(Self::json_error_response(&stats, "hidden_devices", &e))
             }),
+            (&Method::POST, "/api/devices/hide_excess") => {
+                self.hide_excess_devices().await.or_else(|e| {
+                    error!(slog_scope::logger(), "hide_excess_devices_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "hide_excess_devices", &e))
+                })
+            }
+            (&Method::POST, "/api/devices/unhide") => self.unhide_devices().await.or_else(|e| {
+                error!(slog_scope::logger(), "unhide_devices_failed"; "error" => ?e);
+                Ok(Self::json_error_response(&stats, "unhide_devices", &e))
+            }),
+            (&Method::PATCH, path) if DEVICE_META_REGEX.is_match(path) => {
+                return self.update_device_meta(request).await.or_else(|e| {
+                    error!(slog_scope::logger(), "update_device_meta_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "update_device_meta", &e))
+                })
+            }
+            (&Method::GET, "/api/overrides") => Ok(Self::json_response(
+                200,
+                serde_json::to_value(self.config.overrides())
+                    .unwrap_or_else(|e| serde_json::json!({ "error": format!("{:?}", e) })),
+            )),
+            (&Method::PUT, "/api/overrides") => self.put_overrides(request).await.or_else(|e| {
+                error!(slog_scope::logger(), "put_overrides_failed"; "error" => ?e);
+                Ok(Self::json_error_response(&stats, "put_overrides", &e))
+            }),
+            (&Method::POST, path) if UNDISCOVER_DEVICE_REGEX.is_match(path) => {
+                return self.undiscover_device(request).await.or_else(|e| {
+                    error!(slog_scope::logger(), "undiscover_device_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "undiscover_device", &e))
+                })
+            }
+            (&Method::POST, path) if REMOVE_DEVICE_REGEX.is_match(path) => {
+                return self.remove_device(request).await.or_else(|e| {
+                    error!(slog_scope::logger(), "remove_device_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "remove_device", &e))
+                })
+            }
+            (&Method::POST, path) if RENAME_DEVICE_REGEX.is_match(path) => {
+                return self.rename_device(request).await.or_else(|e| {
+                    error!(slog_scope::logger(), "rename_device_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "rename_device", &e))
+                })
+            }
+            (&Method::POST, path) if ZWAVE_NODE_REGEX.is_match(path) => {
+                return self.zwave_node_maintenance(path).await.or_else(|e| {
+                    error!(slog_scope::logger(), "zwave_maintenance_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "zwave_maintenance", &e))
+                })
+            }
+            (&Method::POST, "/api/zwave/heal") => self
+                .start_maintenance(ZwaveOperation::HealNetwork)
+                .await
+                .or_else(|e| {
+                    error!(slog_scope::logger(), "zwave_maintenance_failed"; "error" => ?e);
+                    Ok(Self::json_error_response(&stats, "zwave_maintenance", &e))
+                }),
+            (&Method::GET, "/api/zwave/maintenance") => {
+                let progress = match self.maintenance.lock().await.as_ref() {
+                    Some(progress) => serde_json::to_value(&*progress.lock().await).unwrap(),
+                    None => serde_json::Value::Null,
+                };
+                Ok(Self::json_response(200, progress))
+            }
             (&Method::POST, path) if SET_DEVICE_ATTRIBUTE_REGEX.is_match(path) => {
                 return self.set_attribute(request).await.or_else(|e| {
                     error!(slog_scope::logger(), "set_attribute_failed"; "error" => ?e);
-                    Ok(Self::json_error_response(&e))
+                    Ok(Self::json_error_response(&stats, "set_attribute", &e))
                 })
             }
             (&Method::POST, "/api/devices/discovery") => {
                 return self.do_discovery(request).await.or_else(|e| {
                     error!(slog_scope::logger(), "discovery_failed"; "error" => ?e);
-                    Ok(Self::json_error_response(&e))
+                    Ok(Self::json_error_response(&stats, "discovery", &e))
                 })
             }
+            #[cfg(debug_assertions)]
+            (&Method::GET, "/api/debug/faults") => self.get_faults().await.or_else(|e| {
+                error!(slog_scope::logger(), "get_faults_failed"; "error" => ?e);
+                Ok(Self::json_error_response(&stats, "get_faults", &e))
+            }),
+            #[cfg(debug_assertions)]
+            (&Method::POST, "/api/debug/faults") => self.set_faults(request).await.or_else(|e| {
+                error!(slog_scope::logger(), "set_faults_failed"; "error" => ?e);
+                Ok(Self::json_error_response(&stats, "set_faults", &e))
+            }),
             (&Method::POST, "/api/aprontest") => {
                 return self.do_run_raw(request).await.or_else(|e| {
                     error!(slog_scope::logger(), "run_raw_failed"; "error" => ?e);
-                    Ok(Self::json_error_response(&e))
+                    Ok(Self::json_error_response(&stats, "run_raw", &e))
                 })
             }
             _ => Ok(Response::builder()
@@ -175,6 +366,241 @@ impl HttpServer {
         ))
     }
 
+    async fn mqtt_status(self: Arc<Self>) -> Result<Response<Body>, Box<dyn Error>> {
+        let syncer = self
+            .syncer
+            .as_ref()
+            .ok_or_else(|| simple_error!("No MQTT syncer!"))?;
+        Ok(Self::json_response(
+            200,
+            serde_json::json!({
+                "connection": syncer.status().await,
+                "subscriptions": syncer.subscription_stats().await,
+            }),
+        ))
+    }
+
+    fn escape_label(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    async fn metrics(self: Arc<Self>) -> Result<Response<Body>, Box<dyn Error>> {
+        let mut output = String::new();
+        if let Some(syncer) = self.syncer.as_ref() {
+            let subscriptions = syncer.subscription_stats().await;
+            output.push_str("# TYPE wink_mqtt_subscription_messages_total counter\n");
+            for (pattern, stats) in subscriptions.iter() {
+                output.push_str(&format!(
+                    "wink_mqtt_subscription_messages_total{{pattern=\"{}\"}} {}\n",
+                    Self::escape_label(pattern),
+                    stats.messages
+                ));
+            }
+            output.push_str("# TYPE wink_mqtt_subscription_last_message_timestamp_seconds gauge\n");
+            for (pattern, stats) in subscriptions.iter() {
+                if let Some(last_received) = stats.last_received {
+                    output.push_str(&format!(
+                        "wink_mqtt_subscription_last_message_timestamp_seconds{{pattern=\"{}\"}} {}\n",
+                        Self::escape_label(pattern),
+                        last_received
+                    ));
+                }
+            }
+        }
+
+        Ok(Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .header("Cache-Control", "no-cache, no-store")
+            .header("Connection", "close")
+            .body(Body::from(output))
+            .unwrap())
+    }
+
+    fn require_syncer(&self) -> Result<&Arc<DeviceSyncer>, Box<dyn Error>> {
+        Ok(self
+            .syncer
+            .as_ref()
+            .ok_or_else(|| simple_error!("No MQTT syncer!"))?)
+    }
+
+    async fn hidden_devices(self: Arc<Self>) -> Result<Response<Body>, Box<dyn Error>> {
+        let syncer = self.require_syncer()?;
+        let hidden = syncer.hidden_devices().await;
+        Ok(Self::json_response(
+            200,
+            serde_json::json!({ "hidden": hidden }),
+        ))
+    }
+
+    async fn hide_excess_devices(self: Arc<Self>) -> Result<Response<Body>, Box<dyn Error>> {
+        let syncer = self.require_syncer()?;
+        let hidden = syncer.hide_excess_devices().await?;
+        Ok(Self::json_response(
+            200,
+            serde_json::json!({ "hidden": hidden }),
+        ))
+    }
+
+    async fn unhide_devices(self: Arc<Self>) -> Result<Response<Body>, Box<dyn Error>> {
+        let syncer = self.require_syncer()?;
+        syncer.unhide_devices().await?;
+        Ok(Self::json_response(200, serde_json::json!({})))
+    }
+
+    /// The device along with when each of its attributes last changed value.
+    async fn device_details(self: Arc<Self>, path: &str) -> Result<Response<Body>, Box<dyn Error>> {
+        let device_id = DEVICE_REGEX
+            .captures(path)
+            .ok_or_else(|| simple_error!("Bad URL"))?
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        let device = self.controller.describe(device_id).await?;
+        let last_changed = match self.syncer.as_ref() {
+            Some(syncer) => syncer.last_changed(device_id).await,
+            None => Default::default(),
+        };
+        Ok(Self::json_response(
+            200,
+            serde_json::json!({ "device": device, "last_changed": last_changed }),
+        ))
+    }
+
+    async fn support_bundle(self: Arc<Self>) -> Response<Body> {
+        let bundle = support_bundle::build(
+            self.controller.as_ref(),
+            &self.stats,
+            self.syncer.as_deref(),
+        )
+        .await;
+        let mut response = Self::json_response(200, bundle);
+        response.headers_mut().insert(
+            "Content-Disposition",
+            "attachment; filename=\"wink-mqtt-rs-support.json\""
+                .parse()
+                .unwrap(),
+        );
+        response
+    }
+
+    /// Just one attribute, read fresh, without describing the whole device.
+    async fn device_attribute(
+        self: Arc<Self>,
+        path: &str,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let components = DEVICE_ATTRIBUTE_REGEX
+            .captures(path)
+            .ok_or_else(|| simple_error!("Bad URL"))?;
+        let device_id = components
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        let attribute_id = components
+            .name("attribute_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as AttributeId;
+        let attribute = self.controller.get(device_id, attribute_id).await?;
+        Ok(Self::json_response(200, serde_json::to_value(attribute)?))
+    }
+
+    async fn device_raw(self: Arc<Self>, path: &str) -> Result<Response<Body>, Box<dyn Error>> {
+        let device_id = RAW_DEVICE_REGEX
+            .captures(path)
+            .ok_or_else(|| simple_error!("Bad URL"))?
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        let raw = self.controller.describe_raw(device_id).await?;
+        // The raw output is most useful exactly when parsing fails, so don't bail on that.
+        let parsed = match self.controller.describe(device_id).await {
+            Ok(device) => serde_json::to_value(device)?,
+            Err(e) => serde_json::json!({ "error": format!("{:?}", e) }),
+        };
+        Ok(Self::json_response(
+            200,
+            serde_json::json!({ "raw": raw, "parsed": parsed }),
+        ))
+    }
+
+    async fn update_device_meta(
+        self: Arc<Self>,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let device_id = DEVICE_META_REGEX
+            .captures(request.uri().path())
+            .ok_or_else(|| simple_error!("Bad URL"))?
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        let patch: DeviceNotesPatch =
+            serde_json::from_slice(&hyper::body::to_bytes(request.into_body()).await?)?;
+        let notes = self.notes.update(device_id, patch).await?;
+        if let (true, Some(syncer)) = (self.config.expose_notes, self.syncer.as_ref()) {
+            syncer.repoll_device(device_id).await?;
+        }
+        Ok(Self::json_response(200, serde_json::to_value(notes)?))
+    }
+
+    /// Replaces every device override at once, persisting them to --data-dir.
+    async fn put_overrides(
+        self: Arc<Self>,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let overrides: HashMap<DeviceId, DeviceOverride> = match serde_json::from_slice(&body) {
+            Ok(v) => v,
+            Err(e) => {
+                return Ok(Self::json_response(
+                    400,
+                    serde_json::json!({ "error": e.to_string() }),
+                ))
+            }
+        };
+        if let Err(e) = validate_overrides(&overrides) {
+            return Ok(Self::json_response(
+                400,
+                serde_json::json!({ "error": e.to_string() }),
+            ));
+        }
+
+        if let Some(data_dir) = self.config.data_dir.as_ref() {
+            save_overrides(data_dir, &overrides)?;
+        }
+        info!(slog_scope::logger(), "replaced_overrides"; "count" => overrides.len());
+        self.config.set_overrides(overrides);
+        if let Some(syncer) = self.syncer.as_ref() {
+            syncer.clone().rebroadcast_discovery().await;
+        }
+        Ok(Self::json_response(
+            200,
+            serde_json::to_value(self.config.overrides())?,
+        ))
+    }
+
+    async fn undiscover_device(
+        self: Arc<Self>,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let device_id = UNDISCOVER_DEVICE_REGEX
+            .captures(request.uri().path())
+            .ok_or_else(|| simple_error!("Bad URL"))?
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        let syncer = self.require_syncer()?;
+        let cleared = syncer.undiscover_device(device_id).await;
+        Ok(Self::json_response(
+            200,
+            serde_json::json!({ "cleared": cleared }),
+        ))
+    }
+
     async fn run_command_output(
         self: Arc<Self>,
         mut command: Command,
@@ -224,6 +650,26 @@ impl HttpServer {
         self.run_command_output(command).await
     }
 
+    #[cfg(debug_assertions)]
+    async fn get_faults(self: Arc<Self>) -> Result<Response<Body>, Box<dyn Error>> {
+        Ok(Self::json_response(
+            200,
+            serde_json::to_value(self.controller.faults().await?)?,
+        ))
+    }
+
+    #[cfg(debug_assertions)]
+    async fn set_faults(
+        self: Arc<Self>,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let faults: FaultConfig = serde_json::from_slice(&body)?;
+        info!(slog_scope::logger(), "setting_faults"; "faults" => ?faults);
+        self.controller.set_faults(faults).await?;
+        Ok(Self::json_response(200, serde_json::json!({})))
+    }
+
     async fn do_discovery(
         self: Arc<Self>,
         request: Request<Body>,
@@ -231,19 +677,126 @@ impl HttpServer {
         let body = hyper::body::to_bytes(request.into_body()).await?;
         let json: serde_json::Value = serde_json::from_slice(&body)?;
         let radio = match &json["radio"] {
-            serde_json::Value::String(r)
-                if ["zwave", "zigbee", "lutron", "kidde"].contains(&r.as_str()) =>
-            {
-                r
-            }
+            serde_json::Value::String(r) => r,
             _ => bail!("Bad input"),
         };
 
         info!(slog_scope::logger(), "running_discovery"; "radio" => &radio);
+        let result = self
+            .controller
+            .start_pairing(radio, DEFAULT_PAIRING_TIMEOUT)
+            .await?;
+        info!(slog_scope::logger(), "discovery_finished"; "radio" => &radio, "added" => ?result.added);
+        Ok(Self::json_response(200, serde_json::to_value(result)?))
+    }
 
-        let mut command = Command::new("aprontest");
-        command.arg("-a").arg("60").arg("-r").arg(radio);
-        self.run_command_output(command).await
+    async fn remove_device(
+        self: Arc<Self>,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let device_id = REMOVE_DEVICE_REGEX
+            .captures(request.uri().path())
+            .ok_or_else(|| simple_error!("Bad URL"))?
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        info!(slog_scope::logger(), "removing_device"; "device_id" => device_id);
+        let result = self.controller.remove_device(device_id).await?;
+        Ok(Self::json_response(200, serde_json::to_value(result)?))
+    }
+
+    async fn zwave_node_maintenance(
+        self: Arc<Self>,
+        path: &str,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let captures = ZWAVE_NODE_REGEX
+            .captures(path)
+            .ok_or_else(|| simple_error!("Bad URL"))?;
+        let device_id = captures
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        let operation = match captures.name("operation").unwrap().as_str() {
+            "remove_failed" => ZwaveOperation::RemoveFailedNode { device_id },
+            _ => ZwaveOperation::NeighborUpdate { device_id },
+        };
+        self.start_maintenance(operation).await
+    }
+
+    /// Starts the operation in the background and returns right away; its progress is at
+    /// `/api/zwave/maintenance`. Only one operation runs at a time.
+    async fn start_maintenance(
+        self: Arc<Self>,
+        operation: ZwaveOperation,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let progress = {
+            let mut current = self.maintenance.lock().await;
+            if let Some(current) = current.as_ref() {
+                let current = current.lock().await;
+                if current.finished.is_none() {
+                    return Ok(Self::json_response(
+                        409,
+                        serde_json::json!({
+                            "error": "Another maintenance operation is running",
+                            "progress": &*current,
+                        }),
+                    ));
+                }
+            }
+            let progress = Arc::new(Mutex::new(MaintenanceProgress::new(operation)));
+            *current = Some(progress.clone());
+            progress
+        };
+
+        info!(slog_scope::logger(), "starting_zwave_maintenance"; "operation" => ?operation);
+        tokio::task::spawn({
+            let controller = self.controller.clone();
+            let progress = progress.clone();
+            async move {
+                let result = controller
+                    .zwave_maintenance(operation, &progress)
+                    .await
+                    .map_err(|e| e.to_string());
+                let mut progress = progress.lock().await;
+                progress.finished = Some(unix_timestamp());
+                match result {
+                    Ok(()) => {
+                        info!(slog_scope::logger(), "zwave_maintenance_finished"; "operation" => ?operation)
+                    }
+                    Err(e) => {
+                        error!(slog_scope::logger(), "zwave_maintenance_failed"; "operation" => ?operation, "error" => &e);
+                        progress.error = Some(e);
+                    }
+                }
+            }
+        });
+        let body = serde_json::to_value(&*progress.lock().await)?;
+        Ok(Self::json_response(202, body))
+    }
+
+    async fn rename_device(
+        self: Arc<Self>,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Box<dyn Error>> {
+        let device_id = RENAME_DEVICE_REGEX
+            .captures(request.uri().path())
+            .ok_or_else(|| simple_error!("Bad URL"))?
+            .name("device_id")
+            .unwrap()
+            .as_str()
+            .parse_numberish::<u64>()? as DeviceId;
+        let body: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(request.into_body()).await?)?;
+        let name = body["name"]
+            .as_str()
+            .ok_or_else(|| simple_error!("Bad input"))?;
+        match self.syncer.as_ref() {
+            Some(syncer) => syncer.clone().rename_device(device_id, name).await?,
+            None => self.controller.rename(device_id, name).await?,
+        }
+        Ok(Self::json_response(200, serde_json::json!({})))
     }
 
     async fn set_attribute(
@@ -264,13 +817,14 @@ impl HttpServer {
             .as_str()
             .parse_numberish::<u64>()? as AttributeId;
 
+        self.stats.record_command();
         let device_data_future = self.controller.as_ref().describe(device_id);
 
         let body: serde_json::Value =
             serde_json::from_slice(&hyper::body::to_bytes(request.into_body()).await?)?;
 
-        let attribute = device_data_future
-            .await?
+        let device = device_data_future.await?;
+        let attribute = device
             .attributes
             .into_iter()
             .find(|a| a.id == attribute_id)
@@ -289,6 +843,12 @@ impl HttpServer {
         self.controller
             .set(device_id, attribute_id, &attribute_value)
             .await?;
+        audit::record(&AuditEvent::new(
+            "http",
+            device_id,
+            &attribute,
+            &attribute_value,
+        ));
 
         // TODO(mikekap): Force the syncer to rescan.
 
@@ -296,21 +856,12 @@ impl HttpServer {
     }
 
     async fn devices_list(self: Arc<Self>) -> Result<Response<Body>, Box<dyn Error>> {
-        let device_futures: Vec<_> = self
-            .controller
-            .list()
-            .await?
-            .into_iter()
-            .map(|d| self.controller.describe(d.id))
-            .collect();
-        let mut devices = Vec::with_capacity(device_futures.len());
-        for f in device_futures {
-            devices.push(f.await?)
-        }
+        let devices = self.controller.describe_all().await?;
+        let notes = self.notes.all().await;
 
         Ok(Self::json_response(
             200,
-            serde_json::json!({ "devices": devices }),
+            serde_json::json!({ "devices": devices, "notes": notes }),
         ))
     }
 }
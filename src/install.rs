@@ -0,0 +1,168 @@
+//! `install`/`uninstall` subcommands: set wink-mqtt-rs up as a monit-managed service on a rooted
+//! hub. These do the same thing as `release/setup.sh` and `release/uninstall.sh`.
+use crate::utils::write_file_atomically;
+use simple_error::{bail, simple_error};
+use slog::{info, warn};
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+const INSTALL_DIR: &str = "/opt/wink-mqtt-rs";
+const BINARY_PATH: &str = "/opt/wink-mqtt-rs/wink-mqtt-rs";
+const CONFIG_PATH: &str = "/opt/wink-mqtt-rs/config";
+const INIT_SCRIPT_PATH: &str = "/etc/rc.d/init.d/wink-mqtt-rs";
+const MONITRC_PATH: &str = "/etc/monitrc";
+const LOG_PATH: &str = "/var/log/wink-mqtt-rs.log";
+const PID_PATH: &str = "/var/run/wink-mqtt-rs.pid";
+
+const INIT_SCRIPT: &str = include_str!("../release/mqtt.sh");
+const MONIT_CONFIG: &str = include_str!("../release/monit");
+const MONIT_CHECK_LINE: &str = "check process wink-mqtt-rs ";
+
+/// The config file is the command line arguments to the process, one per line. The init script
+/// passes it through a shell, so every argument is quoted.
+pub fn config_file_contents(args: &[&str]) -> Result<String, Box<dyn Error>> {
+    if let Some(arg) = args.iter().find(|arg| arg.contains('\'')) {
+        bail!("Options can't contain single quotes: {}", arg)
+    }
+    Ok(args.iter().map(|arg| format!("'{}'\n", arg)).collect())
+}
+
+/// `monitrc` with our check appended, or None if it's already there.
+pub fn add_monit_config(monitrc: &str) -> Option<String> {
+    if monitrc.contains(MONIT_CHECK_LINE) {
+        return None;
+    }
+    let mut result = monitrc.to_string();
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str(MONIT_CONFIG);
+    Some(result)
+}
+
+/// `monitrc` without our check (the `check process` line and its indented settings).
+pub fn remove_monit_config(monitrc: &str) -> String {
+    let mut in_our_check = false;
+    monitrc
+        .lines()
+        .filter(|line| {
+            if line.starts_with(MONIT_CHECK_LINE) {
+                in_our_check = true;
+            } else if !line.starts_with(char::is_whitespace) {
+                in_our_check = false;
+            }
+            !in_our_check
+        })
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let status = Command::new(program).args(args).status()?;
+    if !status.success() {
+        bail!("{} {} failed: {}", program, args.join(" "), status)
+    }
+    Ok(())
+}
+
+pub fn install(config_args: &[&str]) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(INSTALL_DIR)?;
+
+    let current = std::env::current_exe()?;
+    if current != Path::new(BINARY_PATH) {
+        info!(slog_scope::logger(), "installing_binary"; "from" => %current.display(), "to" => BINARY_PATH);
+        let tmp = format!("{}.tmp", BINARY_PATH);
+        fs::copy(&current, &tmp)?;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o755))?;
+        fs::rename(&tmp, BINARY_PATH)?;
+    }
+
+    if !config_args.is_empty() {
+        write_file_atomically(
+            Path::new(CONFIG_PATH),
+            config_file_contents(config_args)?.as_bytes(),
+        )?;
+        info!(slog_scope::logger(), "wrote_config"; "path" => CONFIG_PATH);
+    } else if !Path::new(CONFIG_PATH).exists() {
+        bail!(
+            "No config at {}; pass the options to run with, e.g. install -- -s mqtt://broker:1883/",
+            CONFIG_PATH
+        )
+    }
+
+    write_file_atomically(Path::new(INIT_SCRIPT_PATH), INIT_SCRIPT.as_bytes())?;
+    fs::set_permissions(INIT_SCRIPT_PATH, fs::Permissions::from_mode(0o755))?;
+
+    let monitrc = fs::read_to_string(MONITRC_PATH)?;
+    match add_monit_config(&monitrc) {
+        Some(updated) => {
+            fs::write(format!("{}.bak", MONITRC_PATH), &monitrc)?;
+            write_file_atomically(Path::new(MONITRC_PATH), updated.as_bytes())?;
+            run("monit", &["reload"])?;
+        }
+        None => info!(slog_scope::logger(), "monit_already_configured"),
+    }
+
+    // Pick up the new binary/config if we were already running.
+    if Path::new(PID_PATH).exists() {
+        run(INIT_SCRIPT_PATH, &["restart"])?;
+    } else {
+        run(INIT_SCRIPT_PATH, &["start"])?;
+    }
+    println!(
+        "Installed wink-mqtt-rs. Options are in {}; logs are in {}.",
+        CONFIG_PATH, LOG_PATH
+    );
+    Ok(())
+}
+
+pub fn uninstall() -> Result<(), Box<dyn Error>> {
+    let monitrc = fs::read_to_string(MONITRC_PATH)?;
+    let updated = remove_monit_config(&monitrc);
+    if updated != monitrc {
+        write_file_atomically(Path::new(MONITRC_PATH), updated.as_bytes())?;
+        run("monit", &["reload"])?;
+    }
+
+    if Path::new(INIT_SCRIPT_PATH).exists() {
+        run(INIT_SCRIPT_PATH, &["stop"])
+            .unwrap_or_else(|e| warn!(slog_scope::logger(), "stop_failed"; "error" => ?e));
+    }
+    for path in [INIT_SCRIPT_PATH, LOG_PATH, PID_PATH].iter() {
+        if Path::new(path).exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    fs::remove_dir_all(INSTALL_DIR)
+        .map_err(|e| simple_error!("Couldn't remove {}: {}", INSTALL_DIR, e))?;
+    println!("Removed wink-mqtt-rs.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONITRC: &str = "set daemon 30\n\ncheck process aprond with pidfile /var/run/aprond.pid\n    start program = \"/etc/rc.d/init.d/aprond start\"\n";
+
+    #[test]
+    fn monit_config() {
+        let added = add_monit_config(MONITRC).unwrap();
+        assert!(added.starts_with(MONITRC));
+        assert!(added.contains("check process wink-mqtt-rs with pidfile"));
+        assert_eq!(None, add_monit_config(&added));
+        assert_eq!(MONITRC, remove_monit_config(&added));
+    }
+
+    #[test]
+    fn config_contents() {
+        assert_eq!(
+            "'-s'\n'mqtt://broker:1883/'\n'-t'\n'home/wink/'\n",
+            config_file_contents(&["-s", "mqtt://broker:1883/", "-t", "home/wink/"]).unwrap()
+        );
+        assert!(config_file_contents(&["-t", "it's/"]).is_err());
+    }
+}
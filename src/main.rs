@@ -3,29 +3,57 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::io::{BufReader, Read};
 
-use crate::config::Config;
+use crate::attribute_overrides::AttributeOverridesController;
+use crate::composite::CompositeController;
+use crate::config::{Config, DEFAULT_SET_ATTRIBUTE_TOPIC, DEFAULT_SET_TOPIC, DEFAULT_STATUS_TOPIC};
+use crate::controller::DeviceController;
 use crate::http::HttpServer;
+use crate::notes::NotesStore;
+use crate::read_only::ReadOnlyController;
+use crate::registry::DeviceRegistry;
+use crate::stats::Stats;
+use crate::utils::ResultExtensions;
+use crate::watcher::{Watcher, DEFAULT_WATCH_PATTERN};
 use clap::{crate_version, App, Arg, ArgMatches, ErrorKind};
-use rumqttc::MqttOptions;
-use simple_error::bail;
-use slog::{info, o, trace, Drain};
+use rumqttc::{ClientConfig, LastWill, MqttOptions, QoS};
+use simple_error::{bail, simple_error};
+use slog::{debug, error, info, o, trace, warn, Drain};
 use slog_scope::GlobalLoggerGuard;
 use slog_term;
 use std::sync::Arc;
 use tokio::{self, time::Duration};
 use url::Url;
 
+mod attribute_overrides;
+mod audit;
+mod changes;
+mod composite;
 mod config;
+mod connection;
 mod controller;
 mod converter;
+mod homie;
 mod http;
+mod install;
+mod notes;
+mod overrides;
+mod products;
+mod read_only;
+mod registry;
+mod safe_mode;
+mod stats;
+mod support_bundle;
 mod syncer;
+#[cfg(test)]
+mod test_utils;
+mod unknown_formats;
 mod utils;
+mod watcher;
 
 fn init_logger(args: &ArgMatches) -> GlobalLoggerGuard {
     let min_log_level = match args.occurrences_of("verbose") {
@@ -48,7 +76,58 @@ fn init_logger(args: &ArgMatches) -> GlobalLoggerGuard {
     scope_guard
 }
 
-fn init_mqtt_client(a: &ArgMatches) -> Result<Option<MqttOptions>, Box<dyn Error>> {
+const DEFAULT_MQTT_QUEUE_SIZE: usize = 100;
+#[cfg(target_arch = "arm")]
+const DEFAULT_DATA_DIR: &str = "/opt/wink-mqtt-rs/data";
+#[cfg(not(target_arch = "arm"))]
+const DEFAULT_DATA_DIR: &str = "data";
+// Only the hub has aprontest; elsewhere the fake controller simulates some devices.
+#[cfg(target_arch = "arm")]
+const DEFAULT_CONTROLLER: &str = "aprontest";
+#[cfg(not(target_arch = "arm"))]
+const DEFAULT_CONTROLLER: &str = "fake";
+
+// Where the common distributions (and the wink hub itself) keep their CA bundles.
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+fn read_file(path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut pem = BufReader::new(fs::File::open(path)?);
+    let mut data = Vec::new();
+    pem.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The system CA bundle, if there is one.
+fn system_root_certs() -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let from_env = std::env::var("SSL_CERT_FILE").ok();
+    let path = match from_env
+        .iter()
+        .map(|x| x.as_str())
+        .chain(SYSTEM_CA_BUNDLES.iter().cloned())
+        .find(|path| std::path::Path::new(path).is_file())
+    {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    debug!(slog_scope::logger(), "using_system_root_certs"; "path" => path);
+    Ok(Some(read_file(path)?))
+}
+
+/// TLS settings trusting the Mozilla root certificates built into the binary.
+fn bundled_root_certs() -> Arc<ClientConfig> {
+    debug!(slog_scope::logger(), "using_bundled_root_certs");
+    let mut tls = ClientConfig::new();
+    tls.root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    Arc::new(tls)
+}
+
+fn init_mqtt_client(a: &ArgMatches) -> Result<Option<(MqttOptions, usize)>, Box<dyn Error>> {
     let mqtt_uri = match a.value_of("mqtt-uri") {
         Some(v) => v,
         None => return Ok(None),
@@ -83,7 +162,38 @@ fn init_mqtt_client(a: &ArgMatches) -> Result<Option<MqttOptions>, Box<dyn Error
         bail!("Invalid client id: {}", client_id)
     }
 
+    let clean_session = match hash_query.get("clean_session").map(|x| x.as_str()) {
+        None | Some("true") | Some("1") => true,
+        Some("false") | Some("0") => false,
+        Some(v) => bail!("Invalid clean_session value: {}", v),
+    };
+
     let mut options = MqttOptions::new(client_id, host, port);
+    options.set_clean_session(clean_session);
+
+    if let Some(keep_alive) = hash_query.get("keep_alive") {
+        let keep_alive = keep_alive.parse::<u16>()?;
+        if keep_alive < 5 {
+            bail!("keep_alive must be at least 5 seconds, got {}", keep_alive)
+        }
+        options.set_keep_alive(keep_alive);
+    }
+
+    if let Some(inflight) = hash_query.get("inflight") {
+        let inflight = inflight.parse::<u16>()?;
+        if inflight == 0 {
+            bail!("inflight must be positive")
+        }
+        options.set_inflight(inflight);
+    }
+
+    let queue_size = match hash_query.get("queue_size") {
+        Some(v) => match v.parse::<usize>()? {
+            0 => bail!("queue_size must be positive"),
+            v => v,
+        },
+        None => DEFAULT_MQTT_QUEUE_SIZE,
+    };
 
     if parsed.username() != "" {
         let password = parsed.password().unwrap_or("");
@@ -91,18 +201,47 @@ fn init_mqtt_client(a: &ArgMatches) -> Result<Option<MqttOptions>, Box<dyn Error
     }
 
     if "mqtts" == parsed.scheme() {
-        if let Some(cert) = hash_query.get("tls_root_cert") {
-            let mut pem = BufReader::new(fs::File::open(cert)?);
-            let mut data = Vec::new();
-            pem.read_to_end(&mut data)?;
-            options.set_ca(data);
-            ()
-        } else {
-            bail!("Missing root cert for mqtts")
-        }
+        let data = match hash_query.get("tls_root_cert") {
+            Some(cert) => Some(read_file(cert)?),
+            None => system_root_certs()?,
+        };
+        match data {
+            Some(data) => options.set_ca(data),
+            None => options.set_tls_client_config(bundled_root_certs()),
+        };
     }
 
-    Ok(Some(options))
+    Ok(Some((options, queue_size)))
+}
+
+async fn import_wink_export(
+    file: &str,
+    config: &Config,
+    controller: &dyn DeviceController,
+) -> Result<(), Box<dyn Error>> {
+    let data_dir = config
+        .data_dir
+        .as_ref()
+        .ok_or_else(|| simple_error!("Importing requires --data-dir"))?;
+    let export = serde_json::from_slice(&read_file(file)?)?;
+    let result = overrides::import_wink_export(&export, &controller.list().await?)?;
+
+    let mut device_overrides = overrides::load_overrides(Some(data_dir))?;
+    for (device_id, imported) in result.overrides.iter() {
+        info!(slog_scope::logger(), "imported_device"; "device_id" => device_id, "name" => ?imported.name, "area" => ?imported.area);
+    }
+    for name in result.unmatched.iter() {
+        warn!(slog_scope::logger(), "unmatched_exported_device"; "name" => name);
+    }
+    let imported = result.overrides.len();
+    device_overrides.extend(result.overrides);
+    overrides::save_overrides(data_dir, &device_overrides)?;
+    println!(
+        "Imported {} devices ({} not found on this hub). Restart wink-mqtt-rs to use them.",
+        imported,
+        result.unmatched.len()
+    );
+    Ok(())
 }
 
 #[tokio::main]
@@ -131,23 +270,312 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
             .short('t')
             .about("Prefix for the mqtt topic used for device status/control")
             .default_value("home/wink/"))
+        .arg(Arg::new("status-topic")
+            .required(false)
+            .takes_value(true)
+            .long("--status-topic")
+            .about("Topic layout for device status. Must start with {prefix} and contain {id}; {name} is replaced with the device name.")
+            .default_value(DEFAULT_STATUS_TOPIC))
+        .arg(Arg::new("set-topic")
+            .required(false)
+            .takes_value(true)
+            .long("--set-topic")
+            .about("Topic layout for json commands to a device. Same placeholders as --status-topic.")
+            .default_value(DEFAULT_SET_TOPIC))
+        .arg(Arg::new("set-attribute-topic")
+            .required(false)
+            .takes_value(true)
+            .long("--set-attribute-topic")
+            .about("Topic layout for commands to a single attribute. Same placeholders as --status-topic, plus {attribute_id}.")
+            .default_value(DEFAULT_SET_ATTRIBUTE_TOPIC))
         .arg(Arg::new("discovery-prefix")
             .short('d')
             .takes_value(true)
             .about("Prefix (applied independently of --topic-prefix) to broadcast mqtt discovery information (see https://www.home-assistant.io/docs/mqtt/discovery/)")
             .required(false))
+        .arg(Arg::new("canary-discovery-prefix")
+            .required(false)
+            .takes_value(true)
+            .long("--canary-discovery-prefix")
+            .about("Also publish discovery information built with experimental device mappings under this prefix, e.g. for a test Home Assistant. Requires --discovery-prefix."))
         .arg(Arg::new("discovery-listen-topic")
             .required(false)
             .takes_value(true)
             .long("--discovery-listen-topic")
             .about("Topic to listen to in order to (re)broadcast discovery information. Only applies if --discovery-prefix is set.")
             .default_value("homeassistant/status"))
+        .arg(Arg::new("no-retain-discovery")
+            .required(false)
+            .takes_value(false)
+            .long("--no-retain-discovery")
+            .about("Publish Home Assistant discovery configs without the retain flag. They're then only (re)sent when Home Assistant comes online."))
         .arg(Arg::new("http-port")
             .required(false)
             .takes_value(true)
             .long("--http-port")
             .about("If you'd like an http server, this is the port on which to start it")
             .default_value("3000"))
+        .arg(Arg::new("reconcile-retained-state")
+            .required(false)
+            .takes_value(false)
+            .long("--reconcile-retained-state")
+            .about("On startup, compare retained status messages on the broker with the actual device state and report/correct any differences"))
+        .arg(Arg::new("ignore-retained-commands")
+            .required(false)
+            .takes_value(false)
+            .long("--ignore-retained-commands")
+            .about("Ignore retained messages on set topics. Otherwise the broker replays them on every (re)connect, flipping devices back."))
+        .arg(Arg::new("read-only")
+            .required(false)
+            .takes_value(false)
+            .long("--read-only")
+            .about("Never change anything on the hub: report device state as usual, but refuse MQTT and HTTP commands and only log sets. For monitoring a hub you don't want to actuate."))
+        .arg(Arg::new("publish-empty-status")
+            .required(false)
+            .takes_value(false)
+            .long("--publish-empty-status")
+            .about("Publish an empty status when a device reports no attributes. By default this is treated as a transient failure and the last status is kept (marked stale)."))
+        .arg(Arg::new("safe-mode-after")
+            .required(false)
+            .takes_value(true)
+            .long("--safe-mode-after")
+            .default_value("5")
+            .about("Start in safe mode (no commands or discovery, read-only http api) after this many starts in a row that crashed within 10 minutes. Needs --data-dir to count them; 0 disables safe mode."))
+        .arg(Arg::new("status-last-changed")
+            .required(false)
+            .takes_value(false)
+            .long("--status-last-changed")
+            .about("Include when each attribute last changed value in status messages, as a `last_changed` map of unix timestamps."))
+        .arg(Arg::new("publish-rate-limit")
+            .required(false)
+            .takes_value(true)
+            .long("--publish-rate-limit")
+            .about("Maximum number of messages per second to publish to the mqtt server. Unlimited if not set."))
+        .arg(Arg::new("publish-burst")
+            .required(false)
+            .takes_value(true)
+            .long("--publish-burst")
+            .about("Number of messages that can be published in a burst before --publish-rate-limit kicks in")
+            .default_value("10"))
+        .arg(Arg::new("controller")
+            .required(false)
+            .takes_value(true)
+            .long("--controller")
+            .possible_values(&["aprontest", "fake"])
+            .about("Where devices come from: aprontest (the hub's devices) or fake (simulated devices, for testing)")
+            .default_value(DEFAULT_CONTROLLER))
+        .arg(Arg::new("fake-devices")
+            .required(false)
+            .takes_value(true)
+            .long("--fake-devices")
+            .about("Json file with the devices for --controller fake to simulate; a support bundle works"))
+        .arg(Arg::new("aprontest-timeout")
+            .required(false)
+            .takes_value(true)
+            .long("--aprontest-timeout")
+            .about("Seconds to wait for an aprontest command before killing it")
+            .default_value("30"))
+        .arg(Arg::new("aprontest-retries")
+            .required(false)
+            .takes_value(true)
+            .long("--aprontest-retries")
+            .about("Times to retry reading devices when aprontest fails with a transient error (e.g. a locked database)")
+            .default_value("2"))
+        .arg(Arg::new("poll-concurrency")
+            .required(false)
+            .takes_value(true)
+            .long("--poll-concurrency")
+            .about("Number of devices to poll at the same time")
+            .default_value("3"))
+        .arg(Arg::new("poll-radio")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("--poll-radio")
+            .about("Only poll devices paired over this radio (e.g. ZWAVE, ZIGBEE or LUTRON). Can be given more than once; all devices are polled by default."))
+        .arg(Arg::new("status-qos")
+            .required(false)
+            .takes_value(true)
+            .long("--status-qos")
+            .possible_values(&["0", "1"])
+            .about("MQTT QoS level for device status messages. Critical devices always use 1.")
+            .default_value("1"))
+        .arg(Arg::new("critical")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("--critical")
+            .about("Mark a device (e.g. 7) or a device attribute (e.g. 7/3) as critical: commands are verified right after they're sent and the result is published to <topic-prefix>bridge/command_result"))
+        .arg(Arg::new("set-debounce")
+            .required(false)
+            .takes_value(true)
+            .long("--set-debounce")
+            .about("Milliseconds to wait for more commands to the same attribute before applying only the latest one (e.g. while dragging a brightness slider). 0 disables.")
+            .default_value("0"))
+        .arg(Arg::new("max-command-age")
+            .required(false)
+            .takes_value(true)
+            .long("--max-command-age")
+            .about("Drop json commands whose \"ts\" field (unix time) is more than this many seconds old, e.g. ones queued during a broker outage. 0 disables.")
+            .default_value("60"))
+        .arg(Arg::new("stats-interval")
+            .required(false)
+            .takes_value(true)
+            .long("--stats-interval")
+            .about("Seconds between publishing bridge counters to <topic-prefix>bridge/stats. 0 disables.")
+            .default_value("60"))
+        .arg(Arg::new("max-devices")
+            .required(false)
+            .takes_value(true)
+            .long("--max-devices")
+            .about("Safety limit on the number of devices. Devices beyond it (highest ids first) are polled much less often and reported on <topic-prefix>bridge/warnings."))
+        .arg(Arg::new("allow-garage-open")
+            .required(false)
+            .takes_value(false)
+            .long("--allow-garage-open")
+            .about("Accept commands over MQTT that open garage doors. Without this, only closing is allowed."))
+        .arg(Arg::new("name-template")
+            .required(false)
+            .takes_value(true)
+            .long("--name-template")
+            .about("Name for devices in discovery messages, which Home Assistant also derives entity ids from. {name} and {id} are replaced with the wink name and device id.")
+            .default_value("{name}"))
+        .arg(Arg::new("device-name")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("--device-name")
+            .about("Name template for a single device, as device_id=template (e.g. '2={name} Light'). Overrides --name-template."))
+        .arg(Arg::new("device-override")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("--device-override")
+            .about("Discover a device as a specific Home Assistant component, as device_id=component or device_id=component:attribute (e.g. '7=cover' or '12=switch:On_Off2'). Components: binary_sensor, climate, cover, light, lock, siren, switch, none."))
+        .arg(Arg::new("product-db")
+            .required(false)
+            .takes_value(true)
+            .long("--product-db")
+            .about("A json file of Z-Wave manufacturer and product names to use in addition to the built in ones"))
+        .arg(Arg::new("area")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("--area")
+            .about("An area (room) name; devices whose names start with it (e.g. 'Bedroom Fan' for 'Bedroom') are suggested to Home Assistant as being in that area. The `area` device override takes precedence."))
+        .arg(Arg::new("attribute-synonym")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("--attribute-synonym")
+            .about("Treat an attribute name reported by some firmware as another, as alias=name (e.g. 'Power=On_Off')"))
+        .arg(Arg::new("audit-to-mqtt")
+            .required(false)
+            .takes_value(false)
+            .long("--audit-to-mqtt")
+            .about("Publish an audit record for every command sent to a device to <topic-prefix>bridge/audit"))
+        .arg(Arg::new("extra-subscriptions")
+            .required(false)
+            .takes_value(true)
+            .long("--extra-subscriptions")
+            .about("Path to a json file of extra topics to subscribe to and the device attributes they set (see the README)"))
+        .arg(Arg::new("scenes")
+            .required(false)
+            .takes_value(true)
+            .long("--scenes")
+            .about("Path to a json file of scenes: device and group writes that are activated together and discovered as Home Assistant scenes (see the README)"))
+        .arg(Arg::new("composite-devices")
+            .required(false)
+            .takes_value(true)
+            .long("--composite-devices")
+            .about("Path to a json file of virtual devices made of several devices (e.g. a light made of three bulbs) that are set and reported together (see the README)"))
+        .arg(Arg::new("watch-command")
+            .required(false)
+            .takes_value(true)
+            .long("--watch-command")
+            .conflicts_with("watch-file")
+            .about("Shell command printing hub log lines (e.g. 'logread -f'); devices mentioned in them are repolled right away instead of on the next poll"))
+        .arg(Arg::new("watch-file")
+            .required(false)
+            .takes_value(true)
+            .long("--watch-file")
+            .about("Log file to follow for device reports, like --watch-command (e.g. /var/log/messages)"))
+        .arg(Arg::new("watch-pattern")
+            .required(false)
+            .takes_value(true)
+            .long("--watch-pattern")
+            .about("Regex finding the device id in watched log lines, with the id in an `id` group")
+            .default_value(DEFAULT_WATCH_PATTERN))
+        .arg(Arg::new("data-dir")
+            .required(false)
+            .takes_value(true)
+            .long("--data-dir")
+            .about("Directory to keep state (e.g. device notes) in")
+            .default_value(DEFAULT_DATA_DIR))
+        .arg(Arg::new("expose-notes")
+            .required(false)
+            .takes_value(false)
+            .long("--expose-notes")
+            .about("Publish device notes and labels, and expose them in Home Assistant as a diagnostic sensor"))
+        .arg(Arg::new("stable-unique-ids")
+            .required(false)
+            .takes_value(false)
+            .long("--stable-unique-ids")
+            .about("Base Home Assistant unique ids on the hub's gang ids, so they survive changing the topic prefix. Turning this on re-creates the entities in Home Assistant once."))
+        .arg(Arg::new("diagnostic-sensors")
+            .required(false)
+            .takes_value(false)
+            .long("--diagnostic-sensors")
+            .about("Expose every otherwise unused read-only attribute in Home Assistant as a diagnostic sensor"))
+        .arg(Arg::new("brightness-percent")
+            .required(false)
+            .takes_value(false)
+            .long("--brightness-percent")
+            .about("Publish and accept light brightness as a percentage (0-100) instead of the raw Level"))
+        .arg(Arg::new("debug-aprontest")
+            .required(false)
+            .takes_value(false)
+            .long("--debug-aprontest")
+            .about("Publish the raw output of every aprontest command to <topic-prefix>bridge/debug/aprontest"))
+        .arg(Arg::new("convention")
+            .required(false)
+            .takes_value(true)
+            .long("--convention")
+            .possible_values(&["wink", "homie"])
+            .about("Topic convention to publish in. homie additionally publishes the Homie 4.0 topology under homie/<homie-device-id>/.")
+            .default_value("wink"))
+        .arg(Arg::new("homie-device-id")
+            .required(false)
+            .takes_value(true)
+            .long("--homie-device-id")
+            .about("Homie device id for the hub, with --convention homie")
+            .default_value("wink"))
+        .subcommand(App::new("install")
+            .about("Install (or update) wink-mqtt-rs as a service on the hub, starting it on boot")
+            .arg(Arg::new("options")
+                .multiple(true)
+                .last(true)
+                .about("Options to run with, saved to /opt/wink-mqtt-rs/config (e.g. -- -s mqtt://broker:1883/). Keeps the existing config if empty.")))
+        .subcommand(App::new("uninstall")
+            .about("Stop wink-mqtt-rs and remove it from the hub"))
+        .subcommand(App::new("support-bundle")
+            .about("Write everything useful for a bug report (redacted options, raw aprontest output, ...) as json")
+            .arg(Arg::new("output")
+                .required(false)
+                .index(1)
+                .about("File to write to. Defaults to stdout.")))
+        .subcommand(App::new("import-wink-export")
+            .about("Import device names and rooms from a Wink cloud export into --data-dir")
+            .arg(Arg::new("file")
+                .required(true)
+                .index(1)
+                .about("The exported json (e.g. the response of /users/me/wink_devices)")))
         .get_matches();
 
     let resync_interval: u64 = matches
@@ -165,12 +593,98 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
             }
         });
 
+    let publish_rate_limit = matches
+        .value_of_t::<f64>("publish-rate-limit")
+        .map(|t| Some(t))
+        .unwrap_or_else(|e| {
+            if e.kind == ErrorKind::ArgumentNotFound {
+                None
+            } else {
+                e.exit()
+            }
+        });
+    if publish_rate_limit.map_or(false, |rate: f64| rate <= 0.0) {
+        bail!("--publish-rate-limit must be positive")
+    }
+    let publish_burst: u32 = matches
+        .value_of_t("publish-burst")
+        .unwrap_or_else(|e| e.exit());
+    let poll_concurrency: usize = matches
+        .value_of_t("poll-concurrency")
+        .unwrap_or_else(|e| e.exit());
+    if poll_concurrency == 0 {
+        bail!("--poll-concurrency must be positive")
+    }
+    let aprontest_timeout: u64 = matches
+        .value_of_t("aprontest-timeout")
+        .unwrap_or_else(|e| e.exit());
+    if aprontest_timeout == 0 {
+        bail!("--aprontest-timeout must be positive")
+    }
+    let aprontest_retries: u32 = matches
+        .value_of_t("aprontest-retries")
+        .unwrap_or_else(|e| e.exit());
+
+    let set_debounce_ms: u64 = matches
+        .value_of_t("set-debounce")
+        .unwrap_or_else(|e| e.exit());
+    let stats_interval: u64 = matches
+        .value_of_t("stats-interval")
+        .unwrap_or_else(|e| e.exit());
+    let max_command_age: u64 = matches
+        .value_of_t("max-command-age")
+        .unwrap_or_else(|e| e.exit());
+    let safe_mode_after: u32 = matches
+        .value_of_t("safe-mode-after")
+        .unwrap_or_else(|e| e.exit());
+
+    let max_devices = matches
+        .value_of_t::<usize>("max-devices")
+        .map(|t| Some(t))
+        .unwrap_or_else(|e| {
+            if e.kind == ErrorKind::ArgumentNotFound {
+                None
+            } else {
+                e.exit()
+            }
+        });
+
+    let homie_topic_prefix = match matches.value_of("convention") {
+        Some("homie") => {
+            let device_id = homie::homie_id(matches.value_of("homie-device-id").unwrap());
+            if device_id.is_empty() {
+                bail!("--homie-device-id must contain letters or digits");
+            }
+            Some(format!("homie/{}/", device_id))
+        }
+        _ => None,
+    };
+
     let _guard = init_logger(&matches);
 
     info!(slog_scope::logger(), "starting"; "version" => crate_version!());
 
-    let options = init_mqtt_client(&matches)?;
-    let config = Config::new(
+    if let Some(install) = matches.subcommand_matches("install") {
+        let options = install
+            .values_of("options")
+            .map(|v| v.collect::<Vec<_>>())
+            .unwrap_or_default();
+        return install::install(&options);
+    }
+    if matches.subcommand_matches("uninstall").is_some() {
+        return install::uninstall();
+    }
+
+    let (mut options, mqtt_queue_size) = match init_mqtt_client(&matches)? {
+        Some((options, queue_size)) => (Some(options), queue_size),
+        None => (None, DEFAULT_MQTT_QUEUE_SIZE),
+    };
+    if let (Some(options), Some(prefix)) = (options.as_mut(), homie_topic_prefix.as_ref()) {
+        let mut last_will = LastWill::new(format!("{}$state", prefix), QoS::AtLeastOnce, "lost");
+        last_will.retain = true;
+        options.set_last_will(last_will);
+    }
+    let mut config = Config::new(
         options,
         matches.value_of("topic-prefix"),
         matches.value_of("discovery-prefix"),
@@ -178,19 +692,240 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         resync_interval,
         http_port,
     );
-    #[cfg(target_arch = "arm")]
-    let controller = controller::AprontestController::new();
-    #[cfg(not(target_arch = "arm"))]
-    let controller = controller::FakeController::new();
-    let controller = Arc::new(controller);
+    config.set_topic_templates(
+        matches.value_of("status-topic").unwrap(),
+        matches.value_of("set-topic").unwrap(),
+        matches.value_of("set-attribute-topic").unwrap(),
+    )?;
+    config.reconcile_retained_state = matches.is_present("reconcile-retained-state");
+    config.publish_empty_status = matches.is_present("publish-empty-status");
+    config.status_last_changed = matches.is_present("status-last-changed");
+    config.ignore_retained_commands = matches.is_present("ignore-retained-commands");
+    config.read_only = matches.is_present("read-only");
+    config.retain_discovery = !matches.is_present("no-retain-discovery");
+    config.canary_discovery_prefix = match matches.value_of("canary-discovery-prefix") {
+        Some(_) if config.discovery_topic_prefix.is_none() => {
+            bail!("--canary-discovery-prefix requires --discovery-prefix")
+        }
+        Some(prefix) => {
+            let prefix = Config::normalize_topic_prefix(prefix);
+            if Some(&prefix) == config.discovery_topic_prefix.as_ref() {
+                bail!("--canary-discovery-prefix must differ from --discovery-prefix")
+            }
+            Some(prefix)
+        }
+        None => None,
+    };
+    config.mqtt_queue_size = mqtt_queue_size;
+    config.publish_rate_limit = publish_rate_limit;
+    config.publish_burst = publish_burst;
+    config.poll_concurrency = poll_concurrency;
+    config.poll_radios = matches
+        .values_of("poll-radio")
+        .map(|values| values.map(|x| x.to_string()).collect())
+        .unwrap_or_default();
+    config.status_qos = match matches.value_of("status-qos") {
+        Some("0") => QoS::AtMostOnce,
+        _ => QoS::AtLeastOnce,
+    };
+    config.set_debounce_ms = set_debounce_ms;
+    config.stats_interval = stats_interval;
+    config.max_command_age = max_command_age;
+    config.max_devices = max_devices;
+    config.allow_garage_open = matches.is_present("allow-garage-open");
+    config.homie_topic_prefix = homie_topic_prefix;
+    config.data_dir = matches.value_of("data-dir").map(|x| x.to_string());
+    config.expose_notes = matches.is_present("expose-notes");
+    config.diagnostic_sensors = matches.is_present("diagnostic-sensors");
+    config.brightness_percent = matches.is_present("brightness-percent");
+    config.stable_unique_ids = matches.is_present("stable-unique-ids");
+    config.debug_aprontest = matches.is_present("debug-aprontest");
+    config.audit_to_mqtt = matches.is_present("audit-to-mqtt");
+    config.set_overrides(overrides::load_overrides(config.data_dir.as_deref())?);
+    config.name_template = matches.value_of("name-template").unwrap().to_string();
+    config.device_name_templates = matches
+        .values_of("device-name")
+        .map(|values| {
+            values
+                .map(Config::parse_device_name_template)
+                .collect::<Result<HashMap<_, _>, _>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    config.component_overrides = matches
+        .values_of("device-override")
+        .map(|values| {
+            values
+                .map(overrides::parse_component_override)
+                .collect::<Result<HashMap<_, _>, _>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    config.areas = matches
+        .values_of("area")
+        .map(|values| values.map(|x| x.to_string()).collect())
+        .unwrap_or_default();
+    for synonym in matches.values_of("attribute-synonym").into_iter().flatten() {
+        let mut parts = synonym.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(alias), Some(name)) if !alias.is_empty() && !name.is_empty() => {
+                config
+                    .attribute_synonyms
+                    .insert(alias.trim().to_string(), name.trim().to_string());
+            }
+            _ => bail!(
+                "Expected alias=name for --attribute-synonym, got {}",
+                synonym
+            ),
+        }
+    }
+    if let Some(path) = matches.value_of("product-db") {
+        products::load_product_db(path)?;
+    }
+    if let Some(path) = matches.value_of("extra-subscriptions") {
+        config.extra_subscriptions = serde_json::from_slice(&read_file(path)?)
+            .map_err(|e| simple_error!("Bad --extra-subscriptions file {}: {}", path, e))?;
+    }
+    if let Some(path) = matches.value_of("scenes") {
+        config.scenes = serde_json::from_slice(&read_file(path)?)
+            .map_err(|e| simple_error!("Bad --scenes file {}: {}", path, e))?;
+        let mut ids = HashSet::new();
+        if let Some(scene) = config.scenes.iter().find(|s| !ids.insert(s.id)) {
+            bail!("Scene id {} is used more than once in {}", scene.id, path)
+        }
+    }
+    let watch_pattern = matches.value_of("watch-pattern").unwrap();
+    let watcher = match (
+        matches.value_of("watch-command"),
+        matches.value_of("watch-file"),
+    ) {
+        (Some(command), _) => Some(Watcher::new(command, watch_pattern)?),
+        (None, Some(path)) => Some(Watcher::for_file(path, watch_pattern)?),
+        (None, None) => None,
+    };
+    config.critical = matches
+        .values_of("critical")
+        .map(|values| {
+            values
+                .map(Config::parse_critical_target)
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let (command_output_sender, command_output_receiver) = if config.debug_aprontest {
+        let (sender, receiver) = async_channel::bounded(100);
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+    let mut controller: Arc<dyn DeviceController> = match matches.value_of("controller").unwrap() {
+        "aprontest" => {
+            if matches.is_present("fake-devices") {
+                bail!("--fake-devices requires --controller fake")
+            }
+            Arc::new(controller::AprontestController::with_options(
+                command_output_sender,
+                Duration::from_secs(aprontest_timeout),
+                aprontest_retries,
+            ))
+        }
+        _ => {
+            // The fake controller doesn't run any commands.
+            drop(command_output_sender);
+            Arc::new(match matches.value_of("fake-devices") {
+                Some(path) => controller::FakeController::from_scenario(path)?,
+                None => controller::FakeController::new(),
+            })
+        }
+    };
+    if let Some(path) = matches.value_of("composite-devices") {
+        let composites = serde_json::from_slice(&read_file(path)?)
+            .map_err(|e| simple_error!("Bad --composite-devices file {}: {}", path, e))?;
+        controller = Arc::new(
+            CompositeController::new(controller, composites)
+                .map_err(|e| simple_error!("Bad --composite-devices file {}: {}", path, e))?,
+        );
+    }
+    controller = Arc::new(AttributeOverridesController::new(controller, &config));
+    if config.read_only {
+        info!(slog_scope::logger(), "read_only_mode");
+        controller = Arc::new(ReadOnlyController::new(controller));
+    }
+
+    if let Some(import) = matches.subcommand_matches("import-wink-export") {
+        return import_wink_export(
+            import.value_of("file").unwrap(),
+            &config,
+            controller.as_ref(),
+        )
+        .await;
+    }
+
+    if let Some(bundle) = matches.subcommand_matches("support-bundle") {
+        let contents = serde_json::to_string_pretty(
+            &support_bundle::build(controller.as_ref(), &Stats::new(), None).await,
+        )?;
+        match bundle.value_of("output") {
+            Some(path) => fs::write(path, contents)?,
+            None => println!("{}", contents),
+        }
+        return Ok(());
+    }
+
+    let crash_counter = safe_mode::CrashCounter::new(config.data_dir.as_deref());
+    let unstable_starts = crash_counter.record_start()?;
+    if safe_mode::should_enter_safe_mode(unstable_starts, safe_mode_after) {
+        error!(slog_scope::logger(), "starting_in_safe_mode"; "unstable_starts" => unstable_starts);
+        config.safe_mode = true;
+    }
+    tokio::task::spawn(async move {
+        tokio::time::delay_for(safe_mode::STABLE_AFTER).await;
+        crash_counter
+            .reset()
+            .log_failing_result("reset_crash_counter_failed");
+    });
+
+    let notes = Arc::new(NotesStore::load(config.data_dir.as_deref())?);
+    let registry = Arc::new(DeviceRegistry::load(config.data_dir.as_deref()));
+    let stats = Arc::new(Stats::new());
 
     let syncer = if config.has_mqtt() {
-        Some(syncer::DeviceSyncer::new(&config, controller.clone()))
+        Some(syncer::DeviceSyncer::new(
+            &config,
+            controller.clone(),
+            notes.clone(),
+            registry,
+            stats.clone(),
+        ))
     } else {
         None
     };
+    if let (Some(syncer), Some(receiver)) = (syncer.as_ref(), command_output_receiver) {
+        tokio::task::spawn(syncer.clone().mirror_command_output(receiver));
+    }
+    if let Some(syncer) = syncer.as_ref() {
+        let (sender, receiver) = async_channel::bounded(10);
+        unknown_formats::UNKNOWN_FORMATS.set_sink(sender);
+        tokio::task::spawn(syncer.clone().mirror_unknown_formats(receiver));
+    }
+    if let (true, Some(syncer)) = (config.audit_to_mqtt, syncer.as_ref()) {
+        let (sender, receiver) = async_channel::bounded(100);
+        audit::set_sink(sender);
+        tokio::task::spawn(syncer.clone().mirror_audit_events(receiver));
+    }
+    if let (Some(watcher), Some(syncer)) = (watcher, syncer.as_ref()) {
+        let (sender, receiver) = async_channel::bounded(100);
+        watcher.spawn(sender);
+        tokio::task::spawn(syncer.clone().repoll_watched_devices(receiver));
+    }
     let _http = if http_port.is_some() {
-        Some(HttpServer::new(&config, controller.clone(), syncer))
+        Some(HttpServer::new(
+            &config,
+            controller.clone(),
+            syncer,
+            notes,
+            stats,
+        ))
     } else {
         None
     };
@@ -0,0 +1,135 @@
+use crate::controller::DeviceId;
+use crate::utils::write_file_atomically;
+use serde::{Deserialize, Serialize};
+use slog::info;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+const NOTES_FILE: &str = "device_notes.json";
+
+/// Free-form user notes about a device ("flaky, replace battery in March").
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DeviceNotes {
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl DeviceNotes {
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty() && self.labels.is_empty()
+    }
+}
+
+/// A partial update; missing fields are left alone.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DeviceNotesPatch {
+    pub notes: Option<String>,
+    pub labels: Option<Vec<String>>,
+}
+
+/// Device notes, persisted as json in the data directory (or only in memory without one).
+pub struct NotesStore {
+    path: Option<PathBuf>,
+    notes: Mutex<HashMap<DeviceId, DeviceNotes>>,
+}
+
+impl NotesStore {
+    pub fn load(data_dir: Option<&str>) -> Result<NotesStore, Box<dyn Error>> {
+        let path = data_dir.map(|dir| PathBuf::from(dir).join(NOTES_FILE));
+        let notes = match path.as_ref() {
+            Some(path) if path.exists() => serde_json::from_slice(&fs::read(path)?)?,
+            _ => HashMap::new(),
+        };
+        Ok(NotesStore {
+            path,
+            notes: Mutex::new(notes),
+        })
+    }
+
+    pub async fn get(&self, device_id: DeviceId) -> DeviceNotes {
+        self.notes
+            .lock()
+            .await
+            .get(&device_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn all(&self) -> HashMap<DeviceId, DeviceNotes> {
+        self.notes.lock().await.clone()
+    }
+
+    pub async fn update(
+        &self,
+        device_id: DeviceId,
+        patch: DeviceNotesPatch,
+    ) -> Result<DeviceNotes, Box<dyn Error>> {
+        let mut notes = self.notes.lock().await;
+        let entry = notes.entry(device_id).or_default();
+        if let Some(text) = patch.notes {
+            entry.notes = text;
+        }
+        if let Some(labels) = patch.labels {
+            entry.labels = labels;
+        }
+        let result = entry.clone();
+        if result.is_empty() {
+            notes.remove(&device_id);
+        }
+
+        if let Some(path) = self.path.as_ref() {
+            write_file_atomically(path, &serde_json::to_vec_pretty(&*notes)?)?;
+        }
+        info!(slog_scope::logger(), "updated_device_notes"; "device_id" => device_id, "notes" => ?result);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persists_notes() {
+        let dir = std::env::temp_dir().join(format!("wink-notes-{}", std::process::id()));
+        let dir_str = dir.to_str().unwrap();
+
+        let store = NotesStore::load(Some(dir_str)).unwrap();
+        assert_eq!(DeviceNotes::default(), store.get(2).await);
+        store
+            .update(
+                2,
+                DeviceNotesPatch {
+                    notes: Some("replace battery in March".to_string()),
+                    labels: None,
+                },
+            )
+            .await
+            .unwrap();
+        store
+            .update(
+                2,
+                DeviceNotesPatch {
+                    notes: None,
+                    labels: Some(vec!["flaky".to_string()]),
+                },
+            )
+            .await
+            .unwrap();
+
+        let reloaded = NotesStore::load(Some(dir_str)).unwrap();
+        assert_eq!(
+            DeviceNotes {
+                notes: "replace battery in March".to_string(),
+                labels: vec!["flaky".to_string()],
+            },
+            reloaded.get(2).await
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+use crate::controller::{DeviceId, ShortDevice};
+use crate::utils::{write_file_atomically, Numberish};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use simple_error::{bail, simple_error};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+const OVERRIDES_FILE: &str = "device_overrides.json";
+
+/// User provided details about a device that take precedence over what the hub reports.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub area: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness_zero: Option<BrightnessZero>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plain_payload: Option<PlainPayload>,
+    /// The Level a light reports at full brightness, for dimmers that use e.g. 0-99 instead of
+    /// the full range of their attribute type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness_scale: Option<u64>,
+    /// The values that turn a light on and off, if they aren't what its attribute type suggests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_off_values: Option<OnOffValues>,
+    /// Whether the light's `brightness` is a percentage (0-100) instead of its raw Level.
+    /// Defaults to --brightness-percent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness_percent: Option<bool>,
+    /// Discover the device as this component instead of guessing from its attributes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub component: Option<Component>,
+    /// The attribute backing `component`, for components that map a single attribute (switches,
+    /// locks, sirens, binary sensors and the open/close attribute of covers).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub component_attribute: Option<String>,
+    /// The only values some string attributes take, by attribute name (e.g.
+    /// `{"Mode": ["ON", "OFF", "TOGGLE"]}`). Other values are refused, and writable ones are
+    /// discovered as selects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enum_values: Option<BTreeMap<String, Vec<String>>>,
+    /// Names for attributes the device's firmware misnames, by the name it reports (e.g.
+    /// `{"GenericValue": "Level"}`), so it's discovered and commanded like others of its kind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attribute_aliases: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnOffValues {
+    pub on: Value,
+    pub off: Value,
+}
+
+/// Home Assistant components a device can be forced to be discovered as.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    BinarySensor,
+    Climate,
+    Cover,
+    Light,
+    Lock,
+    Siren,
+    Switch,
+    /// Don't discover the device at all.
+    None,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComponentOverride {
+    pub component: Component,
+    pub attribute: Option<String>,
+}
+
+/// Parses `device_id=component` or `device_id=component:attribute`, e.g. `7=cover` or
+/// `12=switch:On_Off2`.
+pub fn parse_component_override(s: &str) -> Result<(DeviceId, ComponentOverride), Box<dyn Error>> {
+    let mut parts = s.splitn(2, '=');
+    let device_id = parts.next().unwrap().trim().parse_numberish()?;
+    let mut mapping = parts
+        .next()
+        .ok_or_else(|| simple_error!("Expected device_id=component, got {}", s))?
+        .splitn(2, ':');
+    let component = mapping.next().unwrap().trim();
+    let component = serde_json::from_value(Value::String(component.to_string()))
+        .map_err(|_| simple_error!("Unknown component {}", component))?;
+    Ok((
+        device_id,
+        ComponentOverride {
+            component,
+            attribute: mapping.next().map(|a| a.trim().to_string()),
+        },
+    ))
+}
+
+/// Lets controllers that can't send json (OpenHAB, simple NodeRED flows) use the json set topic:
+/// plain payloads become a single-key json command. E.g. with `{"text": "state", "number":
+/// "brightness"}`, `ON` becomes `{"state": "ON"}` and `50` becomes `{"brightness": 50}`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlainPayload {
+    /// Key for payloads that are numbers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number: Option<String>,
+    /// Key for any other payload that isn't a json object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl PlainPayload {
+    /// The json command for a plain payload, or None if it's json already (or there's no key
+    /// configured for this kind of payload).
+    pub fn to_json_command(&self, payload: &str) -> Option<Map<String, Value>> {
+        let payload = payload.trim();
+        let (key, value) = match serde_json::from_str::<Value>(payload) {
+            Ok(Value::Object(_)) => return None,
+            Ok(Value::Number(n)) => (self.number.as_ref()?, Value::Number(n)),
+            _ => (self.text.as_ref()?, Value::String(payload.to_string())),
+        };
+        let mut command = Map::new();
+        command.insert(key.clone(), value);
+        Some(command)
+    }
+}
+
+/// What a light command with brightness 0 (but not `"state": "OFF"`) means. Some Home Assistant
+/// setups send these, and passing them through leaves a dimmer that's "on" at level 0.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrightnessZero {
+    /// Turn the light off.
+    Off,
+    /// Turn the light on at its lowest level.
+    Minimum,
+}
+
+impl Default for BrightnessZero {
+    fn default() -> Self {
+        BrightnessZero::Off
+    }
+}
+
+fn overrides_path(data_dir: &str) -> PathBuf {
+    PathBuf::from(data_dir).join(OVERRIDES_FILE)
+}
+
+pub fn load_overrides(
+    data_dir: Option<&str>,
+) -> Result<HashMap<DeviceId, DeviceOverride>, Box<dyn Error>> {
+    match data_dir.map(overrides_path) {
+        Some(path) if path.exists() => Ok(serde_json::from_slice(&fs::read(path)?)?),
+        _ => Ok(HashMap::new()),
+    }
+}
+
+pub fn save_overrides(
+    data_dir: &str,
+    overrides: &HashMap<DeviceId, DeviceOverride>,
+) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(
+        &overrides_path(data_dir),
+        &serde_json::to_vec_pretty(overrides)?,
+    )?;
+    Ok(())
+}
+
+/// Checks a full set of overrides before it replaces the current one.
+pub fn validate_overrides(
+    overrides: &HashMap<DeviceId, DeviceOverride>,
+) -> Result<(), Box<dyn Error>> {
+    for (device_id, o) in overrides.iter() {
+        if *device_id == 0 {
+            bail!("0 is not a valid device id")
+        }
+        for (field, value) in [("name", &o.name), ("area", &o.area)].iter() {
+            if let Some(value) = value {
+                if value.trim().is_empty() {
+                    bail!(
+                        "Device {}: {} can't be blank (leave it out instead)",
+                        device_id,
+                        field
+                    )
+                }
+            }
+        }
+        for (attribute, values) in o.enum_values.iter().flatten() {
+            if values.is_empty() || values.iter().any(|v| v.trim().is_empty()) {
+                bail!(
+                    "Device {}: enum_values for {} need at least one value, and no blank ones",
+                    device_id,
+                    attribute
+                )
+            }
+        }
+        for (attribute, alias) in o.attribute_aliases.iter().flatten() {
+            if alias.trim().is_empty() {
+                bail!(
+                    "Device {}: the attribute alias for {} can't be blank",
+                    device_id,
+                    attribute
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+fn string_or_number(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Results of importing a Wink export.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportResult {
+    pub overrides: HashMap<DeviceId, DeviceOverride>,
+    /// Names of exported devices that don't map to a device on this hub.
+    pub unmatched: Vec<String>,
+}
+
+/// Maps the devices of a Wink cloud export (either the raw API response with a `data` list, or
+/// just the list) to hub devices. Wink's `local_id` is the hub's master id, so that's what we
+/// match on; rooms become areas.
+pub fn import_wink_export(
+    export: &Value,
+    devices: &[ShortDevice],
+) -> Result<ImportResult, Box<dyn Error>> {
+    let exported = match export {
+        Value::Array(v) => v,
+        Value::Object(o) => match o.get("data") {
+            Some(Value::Array(v)) => v,
+            _ => bail!("Export has no device list under \"data\""),
+        },
+        _ => bail!("Export is not a device list"),
+    };
+
+    let mut result = ImportResult::default();
+    for device in exported.iter() {
+        let name = device["name"].as_str().map(|s| s.to_string());
+        let local_id = device
+            .get("local_id")
+            .and_then(string_or_number)
+            .and_then(|id| id.parse::<DeviceId>().ok());
+        let area = match &device["room"] {
+            Value::Object(room) => room.get("name").and_then(string_or_number),
+            other => string_or_number(other),
+        }
+        .or_else(|| device.get("room_name").and_then(string_or_number));
+
+        match local_id.filter(|id| devices.iter().any(|d| d.id == *id)) {
+            Some(id) => {
+                result.overrides.insert(
+                    id,
+                    DeviceOverride {
+                        name,
+                        area,
+                        ..DeviceOverride::default()
+                    },
+                );
+            }
+            None => result
+                .unmatched
+                .push(name.unwrap_or_else(|| device.to_string())),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn plain_payloads() {
+        let format: PlainPayload =
+            serde_json::from_value(json!({"text": "state", "number": "brightness"})).unwrap();
+        assert_eq!(
+            Some(json!({"state": "ON"})),
+            format.to_json_command("ON\n").map(Value::Object)
+        );
+        assert_eq!(
+            Some(json!({"brightness": 50})),
+            format.to_json_command("50").map(Value::Object)
+        );
+        assert_eq!(None, format.to_json_command(r#"{"state": "OFF"}"#));
+
+        let numbers_only = PlainPayload {
+            number: Some("Level".to_string()),
+            text: None,
+        };
+        assert_eq!(None, numbers_only.to_json_command("ON"));
+    }
+
+    #[test]
+    fn wink_export() {
+        let devices = vec![
+            ShortDevice {
+                id: 2,
+                name: "Bedroom Fan".to_string(),
+                interconnect: None,
+            },
+            ShortDevice {
+                id: 4,
+                name: "Bedroom Light".to_string(),
+                interconnect: None,
+            },
+        ];
+        let export = json!({"data": [
+            {"name": "Ceiling Fan", "local_id": "2", "room": {"name": "Bedroom"}},
+            {"name": "Lamp", "local_id": 4},
+            {"name": "Gone", "local_id": "9"},
+        ]});
+
+        let result = import_wink_export(&export, &devices).unwrap();
+        assert_eq!(
+            Some(&DeviceOverride {
+                name: Some("Ceiling Fan".to_string()),
+                area: Some("Bedroom".to_string()),
+                ..DeviceOverride::default()
+            }),
+            result.overrides.get(&2)
+        );
+        assert_eq!(
+            Some(&DeviceOverride {
+                name: Some("Lamp".to_string()),
+                area: None,
+                ..DeviceOverride::default()
+            }),
+            result.overrides.get(&4)
+        );
+        assert_eq!(vec!["Gone".to_string()], result.unmatched);
+        assert!(import_wink_export(&json!("nope"), &devices).is_err());
+    }
+
+    #[test]
+    fn validation() {
+        let parse = |v: Value| serde_json::from_value::<HashMap<DeviceId, DeviceOverride>>(v);
+        let overrides = parse(json!({"2": {"name": "Fan", "area": "Bedroom"}})).unwrap();
+        assert!(validate_overrides(&overrides).is_ok());
+        assert!(validate_overrides(&parse(json!({"2": {"name": " "}})).unwrap()).is_err());
+        assert!(validate_overrides(&parse(json!({"0": {"name": "Fan"}})).unwrap()).is_err());
+        assert!(validate_overrides(
+            &parse(json!({"2": {"enum_values": {"Mode": ["ON", "OFF"]}}})).unwrap()
+        )
+        .is_ok());
+        assert!(
+            validate_overrides(&parse(json!({"2": {"enum_values": {"Mode": []}}})).unwrap())
+                .is_err()
+        );
+        assert!(validate_overrides(
+            &parse(json!({"2": {"attribute_aliases": {"GenericValue": "Level"}}})).unwrap()
+        )
+        .is_ok());
+        assert!(validate_overrides(
+            &parse(json!({"2": {"attribute_aliases": {"GenericValue": ""}}})).unwrap()
+        )
+        .is_err());
+        assert!(parse(json!({"2": {"nmae": "Fan"}})).is_err());
+    }
+
+    #[test]
+    fn component_overrides() {
+        assert_eq!(
+            (
+                7,
+                ComponentOverride {
+                    component: Component::Cover,
+                    attribute: None
+                }
+            ),
+            parse_component_override("7=cover").unwrap()
+        );
+        assert_eq!(
+            (
+                12,
+                ComponentOverride {
+                    component: Component::BinarySensor,
+                    attribute: Some("Opened".to_string())
+                }
+            ),
+            parse_component_override("12=binary_sensor:Opened").unwrap()
+        );
+        assert!(parse_component_override("7=toaster").is_err());
+        assert!(parse_component_override("7").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Names of Z-Wave manufacturers and products, by id. A small database is built in; more can be
+//! loaded from a json file with --product-db, in the same format as `products.json`:
+//!
+//! ```json
+//! {
+//!   "manufacturers": {"0x0063": "GE (Jasco Products)"},
+//!   "products": [
+//!     {"manufacturer_id": "0x0063", "product_type": "0x4944", "product_number": "0x3131", "product": "Fan Control Switch"}
+//!   ]
+//! }
+//! ```
+//!
+//! You can get this information from e.g. http://www.openzwave.net/device-database/0063.3131.4944
+use serde::Deserialize;
+use simple_error::simple_error;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::RwLock;
+
+const BUILTIN_PRODUCTS: &str = include_str!("products.json");
+
+lazy_static! {
+    pub static ref PRODUCTS: RwLock<ProductDb> = RwLock::new(
+        ProductDb::parse(BUILTIN_PRODUCTS).expect("built in product database is invalid")
+    );
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProductFile {
+    #[serde(default)]
+    manufacturers: HashMap<String, String>,
+    #[serde(default)]
+    products: Vec<ProductEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProductEntry {
+    manufacturer_id: String,
+    product_type: String,
+    product_number: String,
+    product: String,
+}
+
+/// (manufacturer id, product type, product number)
+type ProductKey = (u16, u16, u16);
+
+#[derive(Debug, Default)]
+pub struct ProductDb {
+    manufacturers: HashMap<u16, String>,
+    products: HashMap<ProductKey, String>,
+}
+
+/// Ids are hex, with or without a 0x prefix.
+fn parse_id(id: &str) -> Result<u16, Box<dyn Error>> {
+    let id = id.trim();
+    Ok(u16::from_str_radix(id.strip_prefix("0x").unwrap_or(id), 16)
+        .map_err(|e| simple_error!("Bad id {}: {}", id, e))?)
+}
+
+impl ProductDb {
+    pub fn parse(contents: &str) -> Result<ProductDb, Box<dyn Error>> {
+        let file: ProductFile = serde_json::from_str(contents)?;
+        let mut db = ProductDb::default();
+        for (id, name) in file.manufacturers.into_iter() {
+            db.manufacturers.insert(parse_id(&id)?, name);
+        }
+        for entry in file.products.into_iter() {
+            db.products.insert(
+                (
+                    parse_id(&entry.manufacturer_id)?,
+                    parse_id(&entry.product_type)?,
+                    parse_id(&entry.product_number)?,
+                ),
+                entry.product,
+            );
+        }
+        Ok(db)
+    }
+
+    /// Adds (or replaces) the entries of another database.
+    pub fn extend(&mut self, other: ProductDb) {
+        self.manufacturers.extend(other.manufacturers);
+        self.products.extend(other.products);
+    }
+
+    pub fn manufacturer(&self, manufacturer_id: u16) -> Option<&str> {
+        self.manufacturers.get(&manufacturer_id).map(|x| x.as_str())
+    }
+
+    pub fn product(
+        &self,
+        manufacturer_id: u16,
+        product_type: u16,
+        product_number: u16,
+    ) -> Option<&str> {
+        self.products
+            .get(&(manufacturer_id, product_type, product_number))
+            .map(|x| x.as_str())
+    }
+}
+
+/// Adds the products in a --product-db file to the built in ones.
+pub fn load_product_db(path: &str) -> Result<(), Box<dyn Error>> {
+    let db = ProductDb::parse(&fs::read_to_string(path)?)
+        .map_err(|e| simple_error!("Couldn't load {}: {}", path, e))?;
+    PRODUCTS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .extend(db);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_extend() {
+        let mut db = ProductDb::parse(BUILTIN_PRODUCTS).unwrap();
+        assert_eq!(Some("Zooz"), db.manufacturer(0x027a));
+        assert_eq!(None, db.product(0x027a, 0xa000, 0x0001));
+
+        db.extend(
+            ProductDb::parse(
+                r#"{"products": [{"manufacturer_id": "027A", "product_type": "0xa000", "product_number": "0x0001", "product": "Dimmer"}]}"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(Some("Dimmer"), db.product(0x027a, 0xa000, 0x0001));
+        assert_eq!(
+            Some("S2 On Off Wall Switch"),
+            db.product(0x027a, 0xa000, 0xa001)
+        );
+        assert!(ProductDb::parse(r#"{"manufacturers": {"0xzz": "Nope"}}"#).is_err());
+    }
+}
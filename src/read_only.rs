@@ -0,0 +1,156 @@
+//! `--read-only`: watch a production hub without any risk of actuating its devices. Reads go
+//! through; sets are logged and dropped, and everything else that changes the hub fails.
+use crate::controller::{
+    AttributeId, AttributeValue, DeviceAttribute, DeviceController, DeviceId, FaultConfig, Group,
+    GroupId, LongDevice, MaintenanceProgress, PairingResult, Scene, ShortDevice, ZwaveOperation,
+};
+use crate::stats::OperationStats;
+use async_trait::async_trait;
+use simple_error::bail;
+use slog::info;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Why a write was refused, for MQTT and HTTP clients.
+pub const READ_ONLY_ERROR: &str =
+    "The bridge is running with --read-only, so it doesn't change anything on the hub";
+
+pub struct ReadOnlyController {
+    inner: Arc<dyn DeviceController>,
+}
+
+impl ReadOnlyController {
+    pub fn new(inner: Arc<dyn DeviceController>) -> ReadOnlyController {
+        ReadOnlyController { inner }
+    }
+}
+
+#[async_trait]
+impl DeviceController for ReadOnlyController {
+    async fn list(&self) -> Result<Vec<ShortDevice>, Box<dyn Error>> {
+        self.inner.list().await
+    }
+
+    async fn describe(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        self.inner.describe(master_id).await
+    }
+
+    async fn set(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        self.set_many(master_id, &[(attribute_id, value.clone())])
+            .await
+    }
+
+    async fn set_many(
+        &self,
+        master_id: DeviceId,
+        values: &[(AttributeId, AttributeValue)],
+    ) -> Result<(), Box<dyn Error>> {
+        info!(slog_scope::logger(), "read_only_set_skipped"; "device_id" => master_id, "values" => ?values);
+        Ok(())
+    }
+
+    async fn attribute_schema(&self, master_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        self.inner.attribute_schema(master_id).await
+    }
+
+    async fn get(
+        &self,
+        master_id: DeviceId,
+        attribute_id: AttributeId,
+    ) -> Result<DeviceAttribute, Box<dyn Error>> {
+        self.inner.get(master_id, attribute_id).await
+    }
+
+    fn stats(&self) -> BTreeMap<String, OperationStats> {
+        self.inner.stats()
+    }
+
+    async fn forget_cached(&self, master_id: DeviceId) {
+        self.inner.forget_cached(master_id).await
+    }
+
+    async fn describe_raw(&self, master_id: DeviceId) -> Result<String, Box<dyn Error>> {
+        self.inner.describe_raw(master_id).await
+    }
+
+    async fn faults(&self) -> Result<FaultConfig, Box<dyn Error>> {
+        self.inner.faults().await
+    }
+
+    async fn set_faults(&self, faults: FaultConfig) -> Result<(), Box<dyn Error>> {
+        self.inner.set_faults(faults).await
+    }
+
+    async fn start_pairing(
+        &self,
+        _radio: &str,
+        _timeout: Duration,
+    ) -> Result<PairingResult, Box<dyn Error>> {
+        bail!(READ_ONLY_ERROR)
+    }
+
+    async fn remove_device(&self, _master_id: DeviceId) -> Result<PairingResult, Box<dyn Error>> {
+        bail!(READ_ONLY_ERROR)
+    }
+
+    async fn rename(&self, _master_id: DeviceId, _name: &str) -> Result<(), Box<dyn Error>> {
+        bail!(READ_ONLY_ERROR)
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, Box<dyn Error>> {
+        self.inner.list_groups().await
+    }
+
+    async fn set_group(
+        &self,
+        group_id: GroupId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        info!(slog_scope::logger(), "read_only_group_set_skipped"; "group_id" => group_id, "attribute_id" => attribute_id, "value" => ?value);
+        Ok(())
+    }
+
+    async fn zwave_maintenance(
+        &self,
+        _operation: ZwaveOperation,
+        _progress: &Mutex<MaintenanceProgress>,
+    ) -> Result<(), Box<dyn Error>> {
+        bail!(READ_ONLY_ERROR)
+    }
+
+    async fn activate_scene(&self, scene: &Scene) -> Result<(), Box<dyn Error>> {
+        info!(slog_scope::logger(), "read_only_scene_skipped"; "scene_id" => scene.id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::FakeController;
+
+    #[tokio::test]
+    async fn writes_skipped() {
+        let fake = Arc::new(FakeController::new());
+        let controller = ReadOnlyController::new(fake.clone());
+
+        let before = controller.describe(4).await.unwrap();
+        let on_off = before.attribute("On_Off").unwrap();
+        let value = AttributeValue::Bool(!on_off.current_value.is_truthy());
+        controller.set(4, on_off.id, &value).await.unwrap();
+        assert_eq!(before, fake.describe(4).await.unwrap());
+
+        assert!(controller.rename(4, "Porch").await.is_err());
+        assert!(controller.remove_device(4).await.is_err());
+        assert_eq!(fake.list().await.unwrap(), controller.list().await.unwrap());
+    }
+}
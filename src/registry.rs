@@ -0,0 +1,136 @@
+use crate::controller::{DeviceId, LongDevice};
+use crate::utils::write_file_atomically;
+use slog::{info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const REGISTRY_FILE: &str = "device_registry.json";
+
+/// Values change on most polls; don't wear out the hub's flash rewriting them every time.
+const SAVE_INTERVAL: Duration = Duration::from_secs(600);
+
+struct RegistryState {
+    devices: BTreeMap<DeviceId, LongDevice>,
+    dirty: bool,
+    last_saved: Option<Instant>,
+}
+
+/// The devices (names, attribute schemas and last values) seen by the last polls, persisted in
+/// the data directory so a restarted bridge can publish discovery and retained state before its
+/// first full poll finishes.
+pub struct DeviceRegistry {
+    path: Option<PathBuf>,
+    state: Mutex<RegistryState>,
+}
+
+impl DeviceRegistry {
+    /// Starts empty if there's nothing saved, or if it can't be read: it's only a cache.
+    pub fn load(data_dir: Option<&str>) -> DeviceRegistry {
+        let path = data_dir.map(|dir| PathBuf::from(dir).join(REGISTRY_FILE));
+        let devices = match path.as_ref() {
+            Some(path) if path.exists() => Self::read(path).unwrap_or_else(|e| {
+                warn!(slog_scope::logger(), "device_registry_load_failed"; "path" => ?path, "error" => ?e);
+                BTreeMap::new()
+            }),
+            _ => BTreeMap::new(),
+        };
+        info!(slog_scope::logger(), "loaded_device_registry"; "devices" => devices.len());
+        DeviceRegistry {
+            path,
+            state: Mutex::new(RegistryState {
+                devices,
+                dirty: false,
+                last_saved: None,
+            }),
+        }
+    }
+
+    fn read(path: &PathBuf) -> Result<BTreeMap<DeviceId, LongDevice>, Box<dyn Error>> {
+        let devices: Vec<LongDevice> = serde_json::from_slice(&fs::read(path)?)?;
+        Ok(devices.into_iter().map(|d| (d.id, d)).collect())
+    }
+
+    pub async fn get(&self, device_id: DeviceId) -> Option<LongDevice> {
+        self.state.lock().await.devices.get(&device_id).cloned()
+    }
+
+    pub async fn devices(&self) -> Vec<LongDevice> {
+        self.state.lock().await.devices.values().cloned().collect()
+    }
+
+    /// Remembers the result of describing a device.
+    pub async fn record(&self, device: &LongDevice) {
+        let mut state = self.state.lock().await;
+        if state.devices.get(&device.id) != Some(device) {
+            state.devices.insert(device.id, device.clone());
+            state.dirty = true;
+        }
+    }
+
+    /// Forgets devices that are no longer paired.
+    pub async fn retain(&self, device_ids: &HashSet<DeviceId>) {
+        let mut state = self.state.lock().await;
+        let before = state.devices.len();
+        state.devices.retain(|id, _| device_ids.contains(id));
+        if state.devices.len() != before {
+            state.dirty = true;
+        }
+    }
+
+    /// Writes the registry out if it changed, at most every `SAVE_INTERVAL`.
+    pub async fn save_if_due(&self) -> Result<(), Box<dyn Error>> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().await;
+        let due = state
+            .last_saved
+            .map_or(true, |last| last.elapsed() >= SAVE_INTERVAL);
+        if !state.dirty || !due {
+            return Ok(());
+        }
+        let devices = state.devices.values().collect::<Vec<_>>();
+        write_file_atomically(path, &serde_json::to_vec(&devices)?)?;
+        state.dirty = false;
+        state.last_saved = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{DeviceController, FakeController};
+
+    #[tokio::test]
+    async fn persists_devices() {
+        let dir = std::env::temp_dir().join(format!("wink-registry-{}", std::process::id()));
+        let dir_str = dir.to_str().unwrap();
+        let controller = FakeController::new();
+
+        let registry = DeviceRegistry::load(Some(dir_str));
+        assert_eq!(Vec::<LongDevice>::new(), registry.devices().await);
+        let mut devices = vec![];
+        for device in controller.list().await.unwrap() {
+            let device = controller.describe(device.id).await.unwrap();
+            registry.record(&device).await;
+            devices.push(device);
+        }
+        devices.sort_by_key(|d| d.id);
+        registry.save_if_due().await.unwrap();
+
+        let reloaded = DeviceRegistry::load(Some(dir_str));
+        assert_eq!(devices, reloaded.devices().await);
+
+        let first = devices[0].id;
+        reloaded.retain(&[first].iter().cloned().collect()).await;
+        assert_eq!(vec![devices[0].clone()], reloaded.devices().await);
+        assert_eq!(None, reloaded.get(devices[1].id).await);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
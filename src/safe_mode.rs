@@ -0,0 +1,86 @@
+//! Crash loop detection. Every start bumps a counter in --data-dir and staying up for a while
+//! resets it, so a high count means the bridge keeps dying shortly after starting (and monit keeps
+//! restarting it). Past --safe-mode-after such starts the bridge comes up in safe mode: no
+//! commands and no discovery, just status publishing and the read-only http API.
+use crate::utils::{unix_timestamp, write_file_atomically};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const CRASH_COUNTER_FILE: &str = "crash_counter.json";
+
+/// How long the bridge has to stay up for a start to count as a good one.
+pub const STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CrashCounterState {
+    unstable_starts: u32,
+    last_start: Option<u64>,
+}
+
+/// Without a data directory nothing is persisted, so every start looks like the first one.
+pub struct CrashCounter {
+    path: Option<PathBuf>,
+}
+
+impl CrashCounter {
+    pub fn new(data_dir: Option<&str>) -> CrashCounter {
+        CrashCounter {
+            path: data_dir.map(|dir| PathBuf::from(dir).join(CRASH_COUNTER_FILE)),
+        }
+    }
+
+    /// Counts this start. Returns the number of starts in a row, including this one, that didn't
+    /// last `STABLE_AFTER`.
+    pub fn record_start(&self) -> Result<u32, Box<dyn Error>> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(1),
+        };
+        let mut state: CrashCounterState = match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        state.unstable_starts += 1;
+        state.last_start = Some(unix_timestamp());
+        write_file_atomically(path, &serde_json::to_vec(&state)?)?;
+        Ok(state.unstable_starts)
+    }
+
+    /// Marks the current start as a good one.
+    pub fn reset(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = self.path.as_ref() {
+            write_file_atomically(path, &serde_json::to_vec(&CrashCounterState::default())?)?;
+        }
+        Ok(())
+    }
+}
+
+/// `threshold` crashes in a row are tolerated; 0 disables safe mode.
+pub fn should_enter_safe_mode(unstable_starts: u32, threshold: u32) -> bool {
+    threshold > 0 && unstable_starts > threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_unstable_starts() {
+        let dir = std::env::temp_dir().join(format!("wink-crashes-{}", std::process::id()));
+        let counter = CrashCounter::new(Some(dir.to_str().unwrap()));
+        assert_eq!(1, counter.record_start().unwrap());
+        assert_eq!(2, counter.record_start().unwrap());
+        assert_eq!(3, counter.record_start().unwrap());
+        assert!(!should_enter_safe_mode(3, 3));
+        assert!(should_enter_safe_mode(4, 3));
+        assert!(!should_enter_safe_mode(4, 0));
+
+        counter.reset().unwrap();
+        assert_eq!(1, counter.record_start().unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use crate::utils::unix_timestamp;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Counters {
+    /// When the counters were last reset.
+    pub since: u64,
+    pub commands: u64,
+    pub publishes: u64,
+    pub reconnects: u64,
+    /// Failures by operation (e.g. `poll_device`, `set_attribute`).
+    pub failures: BTreeMap<String, u64>,
+}
+
+/// Bridge counters. One instance is created at startup and shared by the syncer and http server.
+pub struct Stats {
+    counters: Mutex<Counters>,
+}
+
+/// Upper bounds of the latency buckets, in milliseconds. aprontest usually takes tens of
+/// milliseconds; seconds mean aprond is struggling.
+pub const LATENCY_BUCKETS_MS: &[u64] = &[10, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Counts and latencies of one kind of controller operation (e.g. `describe`).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OperationStats {
+    pub successes: u64,
+    pub failures: u64,
+    pub total_ms: u64,
+    /// How many calls took at most each of `LATENCY_BUCKETS_MS`, but more than the one before;
+    /// the last bucket has the slower ones.
+    pub latency_buckets: Vec<u64>,
+}
+
+impl Default for OperationStats {
+    fn default() -> Self {
+        OperationStats {
+            successes: 0,
+            failures: 0,
+            total_ms: 0,
+            latency_buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+        }
+    }
+}
+
+impl OperationStats {
+    pub fn record(&mut self, success: bool, elapsed: Duration) {
+        if success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+        let ms = elapsed.as_millis() as u64;
+        self.total_ms += ms;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket] += 1;
+    }
+}
+
+/// Per-operation stats for a controller, since the bridge started.
+#[derive(Default)]
+pub struct OperationRecorder {
+    operations: Mutex<BTreeMap<String, OperationStats>>,
+}
+
+impl OperationRecorder {
+    pub fn record(&self, operation: &str, success: bool, elapsed: Duration) {
+        self.operations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(operation.to_string())
+            .or_default()
+            .record(success, elapsed)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, OperationStats> {
+        self.operations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// What's published to `bridge/stats` and served at `/api/stats`.
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    #[serde(flatten)]
+    pub counters: Counters,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub controller: BTreeMap<String, OperationStats>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            counters: Mutex::new(Counters {
+                since: unix_timestamp(),
+                ..Counters::default()
+            }),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Counters)>(&self, f: F) {
+        // A panic while holding the lock can't leave the counters inconsistent.
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut counters)
+    }
+
+    pub fn record_command(&self) {
+        self.update(|c| c.commands += 1)
+    }
+
+    pub fn record_publish(&self) {
+        self.update(|c| c.publishes += 1)
+    }
+
+    pub fn record_reconnect(&self) {
+        self.update(|c| c.reconnects += 1)
+    }
+
+    pub fn record_failure(&self, operation: &str) {
+        self.update(|c| *c.failures.entry(operation.to_string()).or_default() += 1)
+    }
+
+    pub fn snapshot(&self) -> Counters {
+        self.counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Zeroes every counter; returns their values from before the reset.
+    pub fn reset(&self) -> Counters {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(
+            &mut *counters,
+            Counters {
+                since: unix_timestamp(),
+                ..Counters::default()
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::ResultExtensions;
+
+    #[test]
+    fn counters() {
+        let stats = Stats::new();
+        stats.record_command();
+        stats.record_publish();
+        stats.record_publish();
+        stats.record_failure("poll_device");
+
+        let snapshot = stats.snapshot();
+        assert_eq!(1, snapshot.commands);
+        assert_eq!(2, snapshot.publishes);
+        assert_eq!(Some(&1), snapshot.failures.get("poll_device"));
+
+        assert_eq!(snapshot, stats.reset());
+        assert_eq!(0, stats.snapshot().publishes);
+        assert!(stats.snapshot().failures.is_empty());
+    }
+
+    #[test]
+    fn failing_results() {
+        let stats = Stats::new();
+        assert_eq!(
+            None,
+            Err::<(), _>("boom").record_failing_result(&stats, "poll_all")
+        );
+        assert_eq!(
+            Some(1),
+            Ok::<_, &str>(1).record_failing_result(&stats, "poll_all")
+        );
+        assert_eq!(
+            None,
+            Err::<(), _>("boom").record_failing_result(&stats, "poll_all")
+        );
+        assert_eq!(Some(&2), stats.snapshot().failures.get("poll_all"));
+    }
+
+    #[test]
+    fn operations() {
+        let recorder = OperationRecorder::default();
+        recorder.record("describe", true, Duration::from_millis(30));
+        recorder.record("describe", false, Duration::from_millis(50));
+        recorder.record("describe", true, Duration::from_secs(20));
+
+        let describe = recorder.snapshot().remove("describe").unwrap();
+        assert_eq!(2, describe.successes);
+        assert_eq!(1, describe.failures);
+        assert_eq!(20080, describe.total_ms);
+        assert_eq!(vec![0, 2, 0, 0, 0, 0, 0, 0, 0, 1], describe.latency_buckets);
+    }
+}
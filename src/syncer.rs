@@ -1,18 +1,42 @@
-use crate::config::{Config, NotInterestingTopicError, TopicType};
-use crate::controller::{AttributeId, DeviceController, DeviceId};
-use crate::converter::device_to_discovery_payload;
-use crate::utils::ResultExtensions;
+use crate::audit::{self, AuditEvent};
+use crate::changes::ChangeTracker;
+use crate::config::{topic_matches_pattern, Config, NotInterestingTopicError, TopicType};
+use crate::connection::{
+    ConnectionEvent, ConnectionState, ConnectionStateMachine, ConnectionStatus, ExactlyOnceReceiver,
+};
+use crate::controller::{
+    AttributeId, AttributeValue, CommandOutput, DeviceAttribute, DeviceController, DeviceId,
+    GroupId, LongDevice, PairingResult, SceneId, ShortDevice, DEFAULT_PAIRING_TIMEOUT,
+};
+use crate::converter::{
+    button_action, device_to_discovery_payload, extended_attributes, hub_discovery_payload,
+    is_json_light, json_light_command, json_light_state, lock_command_payload,
+    scene_discovery_payload, DISCOVERY_COMPONENTS,
+};
+use crate::homie;
+use crate::notes::NotesStore;
+use crate::read_only::READ_ONLY_ERROR;
+use crate::registry::DeviceRegistry;
+use crate::stats::{Stats, StatsReport};
+use crate::unknown_formats::UnknownFormat;
+use crate::utils::{
+    unix_timestamp, write_file_atomically, Numberish, ResultExtensions, TokenBucket,
+};
 use async_channel::{bounded, Receiver, Sender};
 use futures::future::join_all;
-use rumqttc::{Event, EventLoop, Incoming, Publish, Request, Subscribe};
+use futures::stream::{self, StreamExt};
+use rumqttc::{Event, EventLoop, Incoming, Publish, Request, Subscribe, Unsubscribe};
 use serde::{Serialize, Serializer};
 use serde_json::value::Value::Object;
 use simple_error::{bail, simple_error};
-use slog::{crit, debug, error, info, trace, warn};
+use slog::{debug, error, info, trace, warn};
 use slog_scope;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fs;
 use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::time::Duration;
@@ -26,6 +50,12 @@ pub struct MaybeJsonString {
 pub enum LoggedMessage {
     OutgoingMessage(String, MaybeJsonString),
     IncomingMessage(String, MaybeJsonString),
+    AttributeChanged {
+        device_id: DeviceId,
+        attribute: String,
+        value: serde_json::Value,
+        at: u64,
+    },
     Connected,
     Disconnected,
 }
@@ -54,28 +84,195 @@ impl Serialize for MaybeJsonString {
     }
 }
 
+#[derive(Debug, Default, Serialize)]
+pub struct ReconciliationReport {
+    pub matched: Vec<DeviceId>,
+    pub mismatched: Vec<DeviceId>,
+    pub missing_retained: Vec<DeviceId>,
+    pub unknown_devices: Vec<DeviceId>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SubscriptionStats {
+    pub messages: u64,
+    pub last_received: Option<u64>,
+}
+
+/// Details about the MQTT client that don't fit the connection state machine.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct EventLoopDetails {
+    pub last_connack: Option<u64>,
+    /// Publishes sent but not acknowledged by the broker yet.
+    pub inflight: u16,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+}
+
+/// Everything about the syncer's MQTT connection; served by the http api and published (retained)
+/// to `bridge/state`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct SyncerStatus {
+    #[serde(flatten)]
+    pub connection: ConnectionStatus,
+    #[serde(flatten)]
+    pub details: EventLoopDetails,
+    /// Commands and discovery are disabled after a crash loop; see `safe_mode`.
+    pub safe_mode: bool,
+}
+
+// Upper bound on messages kept around while disconnected; one per topic.
+const MAX_OUTBOX_SIZE: usize = 1000;
+
+// Devices over --max-devices only get polled on every Nth full poll.
+const SHED_DEVICE_POLL_EVERY: u32 = 10;
+
+// Groups rarely change and listing them is another aprontest run, so they're only listed on
+// every Nth full poll.
+const GROUP_POLL_EVERY: u32 = 10;
+
+const HOMIE_DEVICE_NAME: &str = "Wink Hub";
+
+// Devices hidden through the http api, kept in --data-dir so they stay hidden after a restart.
+const HIDDEN_DEVICES_FILE: &str = "hidden_devices.json";
+
+// What Home Assistant publishes to its status topic when it starts.
+const HA_BIRTH_PAYLOAD: &[u8] = b"online";
+
+// How long to collect retained status messages before comparing them to the devices.
+const RECONCILIATION_WINDOW: Duration = Duration::from_secs(5);
+
+// The event loop sends a ping every keep-alive interval, so a poll that takes this many
+// intervals to complete means it's stuck.
+const WATCHDOG_KEEP_ALIVES: u32 = 3;
+
+/// Starts with nothing hidden if there's no saved list, or if it can't be read.
+fn load_hidden_devices(path: Option<&PathBuf>) -> HashSet<DeviceId> {
+    let path = match path {
+        Some(path) if path.exists() => path,
+        _ => return HashSet::new(),
+    };
+    let read = || -> Result<HashSet<DeviceId>, Box<dyn Error>> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    };
+    read().unwrap_or_else(|e| {
+        warn!(slog_scope::logger(), "hidden_devices_load_failed"; "path" => ?path, "error" => ?e);
+        HashSet::new()
+    })
+}
+
+fn save_hidden_devices(
+    path: Option<&PathBuf>,
+    hidden: &HashSet<DeviceId>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = path {
+        let mut ids = hidden.iter().cloned().collect::<Vec<_>>();
+        ids.sort();
+        write_file_atomically(path, &serde_json::to_vec_pretty(&ids)?)?;
+    }
+    Ok(())
+}
+
+pub fn device_status_payload(config: &Config, device: &LongDevice) -> serde_json::Value {
+    let mut payload = device
+        .attributes
+        .iter()
+        .map(|x| {
+            (
+                x.canonical_name(),
+                x.setting_value.or(&x.current_value).to_json(),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+    if is_json_light(config, device) {
+        if let Some(light_state) = json_light_state(device, &config.device_options(device.id)) {
+            payload.extend(light_state);
+        }
+    }
+    if let Some(interconnect) = &device.interconnect {
+        payload.insert(
+            "interconnect".into(),
+            serde_json::Value::String(interconnect.clone()),
+        );
+    }
+    if let Some(battery_level) = device.battery_level {
+        payload.insert("battery_level".into(), battery_level.into());
+    }
+    if let Some(wakeup_interval) = device.wakeup_interval {
+        payload.insert("wakeup_interval".into(), wakeup_interval.into());
+    }
+    serde_json::Value::Object(payload)
+}
+
+/// Seconds since `ts` (a unix timestamp in seconds, or milliseconds as sent by e.g. NodeRED's
+/// `Date.now()`). Timestamps from the future count as brand new.
+fn command_age(ts: &serde_json::Value, now: u64) -> Result<u64, Box<dyn Error>> {
+    let ts = ts
+        .as_f64()
+        .ok_or_else(|| simple_error!("Bad command timestamp: {}", ts))?;
+    let seconds = if ts > 1e11 { ts / 1000.0 } else { ts };
+    Ok((now as f64 - seconds).max(0.0) as u64)
+}
+
 pub struct DeviceSyncer {
     config: Config,
     controller: Arc<dyn DeviceController>,
-    sender: Sender<Request>,
+    notes: Arc<NotesStore>,
+    registry: Arc<DeviceRegistry>,
+    pub stats: Arc<Stats>,
+    /// Replaced when the watchdog recreates the event loop.
+    sender: std::sync::RwLock<Sender<Request>>,
     repoll: Sender<DeviceId>,
     pub last_n_messages: Mutex<VecDeque<LoggedMessage>>,
+    retained_statuses: Mutex<Option<HashMap<DeviceId, serde_json::Value>>>,
+    reconciled: AtomicBool,
+    /// Set until the retained statuses have been compared, so no poll overwrites them first.
+    reconciling: AtomicBool,
+    connected_once: AtomicBool,
+    connection: Mutex<ConnectionStateMachine>,
+    exactly_once: Mutex<ExactlyOnceReceiver>,
+    subscription_stats: Mutex<HashMap<String, SubscriptionStats>>,
+    event_loop_details: Mutex<EventLoopDetails>,
+    publish_limiter: Option<Mutex<TokenBucket>>,
+    pending_sets: Mutex<HashMap<(DeviceId, AttributeId), u64>>,
+    hidden_devices: Mutex<HashSet<DeviceId>>,
+    hidden_devices_path: Option<PathBuf>,
+    shed_devices: Mutex<Vec<DeviceId>>,
+    poll_cycle: AtomicU32,
+    outbox: Mutex<HashMap<String, Publish>>,
+    known_devices: Mutex<Option<HashSet<DeviceId>>>,
+    discovery_topics: Mutex<HashMap<DeviceId, HashSet<String>>>,
+    undiscovered: Mutex<HashSet<DeviceId>>,
+    homie_nodes: Mutex<BTreeSet<DeviceId>>,
+    /// Last payload published to each homie topic; everything there is retained, so we only
+    /// need to publish changes.
+    homie_published: Mutex<HashMap<String, String>>,
+    /// The last status published for each device, to fall back on when a describe comes back
+    /// empty.
+    last_statuses: Mutex<HashMap<DeviceId, serde_json::Value>>,
+    /// The last payload published to each device's attributes topic. It's retained and rarely
+    /// changes, so it's only republished when it does.
+    last_attributes: Mutex<HashMap<DeviceId, serde_json::Value>>,
+    /// The last payload published to each group's status topic; also only republished on change.
+    last_group_statuses: Mutex<HashMap<GroupId, serde_json::Value>>,
+    attribute_changes: Mutex<ChangeTracker>,
+    /// Attribute schemas for the command path, refreshed by every poll.
+    schemas: Mutex<HashMap<DeviceId, LongDevice>>,
+    canary_config: Option<Config>,
 }
 
 impl<'a> DeviceSyncer {
-    pub fn new(config: &Config, controller: Arc<dyn DeviceController>) -> Arc<DeviceSyncer> {
-        let mut options = config.mqtt_options.as_ref().unwrap().clone();
-        info!(slog_scope::logger(), "opening_client"; "host" => options.broker_address().0, "port" => options.broker_address().1, "client_id" => &options.client_id());
-        options.set_clean_session(true);
-        let ev = EventLoop::new(options, 100);
-        let (repoll_sender, repoll_rx) = bounded(10);
-        let syncer = DeviceSyncer {
-            config: config.clone(),
-            controller,
-            sender: ev.handle(),
-            repoll: repoll_sender,
-            last_n_messages: Mutex::new(VecDeque::with_capacity(10)),
-        };
+    pub fn new(
+        config: &Config,
+        controller: Arc<dyn DeviceController>,
+        notes: Arc<NotesStore>,
+        registry: Arc<DeviceRegistry>,
+        stats: Arc<Stats>,
+    ) -> Arc<DeviceSyncer> {
+        let options = config.mqtt_options.as_ref().unwrap().clone();
+        info!(slog_scope::logger(), "opening_client"; "host" => options.broker_address().0, "port" => options.broker_address().1, "client_id" => &options.client_id(), "clean_session" => options.clean_session(), "queue_size" => config.mqtt_queue_size);
+        let ev = EventLoop::new(options, config.mqtt_queue_size);
+        let (syncer, repoll_rx) =
+            Self::with_sender(config, controller, notes, registry, stats, ev.handle());
         let this = Arc::new(syncer);
         trace!(slog_scope::logger(), "start_thread");
         tokio::task::spawn({
@@ -86,14 +283,77 @@ impl<'a> DeviceSyncer {
         tokio::task::spawn({
             let this = this.clone();
             async move {
+                this.restore_registered_devices().await;
                 this.clone()
                     .run_poller(this.clone().config.resync_interval, repoll_rx)
                     .await
             }
         });
+
+        if this.config.stats_interval > 0 && this.config.topic_prefix.is_some() {
+            tokio::task::spawn(this.clone().run_stats_publisher());
+        }
         this
     }
 
+    /// The syncer without any of its background tasks, sending its MQTT requests to `sender`.
+    /// Also returns the receiving end of its repoll requests.
+    fn with_sender(
+        config: &Config,
+        controller: Arc<dyn DeviceController>,
+        notes: Arc<NotesStore>,
+        registry: Arc<DeviceRegistry>,
+        stats: Arc<Stats>,
+        sender: Sender<Request>,
+    ) -> (DeviceSyncer, Receiver<DeviceId>) {
+        let (repoll_sender, repoll_rx) = bounded(10);
+        let hidden_devices_path = config
+            .data_dir
+            .as_ref()
+            .map(|dir| PathBuf::from(dir).join(HIDDEN_DEVICES_FILE));
+        let syncer = DeviceSyncer {
+            config: config.clone(),
+            controller,
+            notes,
+            registry,
+            stats,
+            sender: std::sync::RwLock::new(sender),
+            repoll: repoll_sender,
+            last_n_messages: Mutex::new(VecDeque::with_capacity(10)),
+            retained_statuses: Mutex::new(None),
+            reconciled: AtomicBool::new(false),
+            reconciling: AtomicBool::new(
+                config.reconcile_retained_state && config.status_subscribe_pattern().is_some(),
+            ),
+            connected_once: AtomicBool::new(false),
+            connection: Mutex::new(ConnectionStateMachine::new()),
+            exactly_once: Mutex::new(ExactlyOnceReceiver::new()),
+            subscription_stats: Mutex::new(HashMap::new()),
+            event_loop_details: Mutex::new(EventLoopDetails::default()),
+            publish_limiter: config
+                .publish_rate_limit
+                .map(|rate| Mutex::new(TokenBucket::new(rate, config.publish_burst as f64))),
+            pending_sets: Mutex::new(HashMap::new()),
+            hidden_devices: Mutex::new(load_hidden_devices(hidden_devices_path.as_ref())),
+            hidden_devices_path,
+            shed_devices: Mutex::new(Vec::new()),
+            poll_cycle: AtomicU32::new(0),
+            outbox: Mutex::new(HashMap::new()),
+            known_devices: Mutex::new(None),
+            discovery_topics: Mutex::new(HashMap::new()),
+            undiscovered: Mutex::new(HashSet::new()),
+            homie_nodes: Mutex::new(BTreeSet::new()),
+            homie_published: Mutex::new(HashMap::new()),
+            last_statuses: Mutex::new(HashMap::new()),
+            last_attributes: Mutex::new(HashMap::new()),
+            last_group_statuses: Mutex::new(HashMap::new()),
+            attribute_changes: Mutex::new(ChangeTracker::new()),
+            schemas: Mutex::new(HashMap::new()),
+            canary_config: config.canary_config(),
+        };
+        (syncer, repoll_rx)
+    }
+
     async fn start_broadcast_discovery_broadcast(self: Arc<Self>) {
         if self.config.discovery_topic_prefix.is_some() {
             tokio::task::spawn({
@@ -103,9 +363,106 @@ impl<'a> DeviceSyncer {
         }
     }
 
+    /// Waits until the publish rate limit (if any) allows another message to be sent. Every
+    /// publish goes through here, so this is also where they're counted.
+    async fn throttle_publish(&self) {
+        self.stats.record_publish();
+        let delay = match self.publish_limiter.as_ref() {
+            Some(limiter) => limiter.lock().await.reserve(),
+            None => return,
+        };
+        if delay > Duration::from_secs(0) {
+            trace!(slog_scope::logger(), "throttling_publish"; "delay_ms" => delay.as_millis() as u64);
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    /// Sends `publish` if we're connected, otherwise keeps the latest message per topic around
+    /// to send after the next ConnAck. Returns whether the message was sent right away.
+    async fn publish_or_queue(&self, publish: Publish) -> bool {
+        let connected = match self.connection.lock().await.state() {
+            ConnectionState::Subscribed | ConnectionState::Degraded => true,
+            ConnectionState::Connecting | ConnectionState::Backoff => false,
+        };
+        if connected {
+            self.throttle_publish().await;
+            match self.sender().try_send(Request::Publish(publish.clone())) {
+                Ok(_) => {
+                    // Anything queued for the topic earlier is out of date now.
+                    self.outbox.lock().await.remove(&publish.topic);
+                    return true;
+                }
+                Err(e) => {
+                    warn!(slog_scope::logger(), "send_failed_queueing"; "topic" => &publish.topic, "error" => ?e)
+                }
+            }
+        }
+
+        let mut outbox = self.outbox.lock().await;
+        if outbox.len() >= MAX_OUTBOX_SIZE && !outbox.contains_key(&publish.topic) {
+            warn!(slog_scope::logger(), "outbox_full_dropping"; "topic" => &publish.topic);
+            return false;
+        }
+        trace!(slog_scope::logger(), "queued_publish"; "topic" => &publish.topic);
+        outbox.insert(publish.topic.clone(), publish);
+        false
+    }
+
+    async fn flush_outbox(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        let queued = std::mem::take(&mut *self.outbox.lock().await);
+        if queued.is_empty() {
+            return Ok(());
+        }
+        info!(slog_scope::logger(), "flushing_outbox"; "count" => queued.len());
+        let mut queued = queued.into_iter();
+        while let Some((topic, publish)) = queued.next() {
+            let log_message = LoggedMessage::OutgoingMessage(
+                topic.clone(),
+                MaybeJsonString::new(&publish.payload.to_vec()),
+            );
+            self.throttle_publish().await;
+            if let Err(e) = self.sender().send(Request::Publish(publish.clone())).await {
+                // Keep the rest for the next ConnAck, unless newer messages were queued since.
+                let mut outbox = self.outbox.lock().await;
+                for (topic, publish) in std::iter::once((topic, publish)).chain(queued) {
+                    outbox.entry(topic).or_insert(publish);
+                }
+                return Err(e.into());
+            }
+            self.log_message(log_message).await;
+        }
+        Ok(())
+    }
+
+    async fn track_subscription(&self, pattern: &str) {
+        self.subscription_stats
+            .lock()
+            .await
+            .entry(pattern.to_string())
+            .or_default();
+    }
+
+    async fn record_incoming(&self, topic: &str) {
+        let now = unix_timestamp();
+        for (pattern, stats) in self.subscription_stats.lock().await.iter_mut() {
+            if topic_matches_pattern(pattern, topic) {
+                stats.messages += 1;
+                stats.last_received = Some(now);
+            }
+        }
+    }
+
+    pub async fn subscription_stats(&self) -> HashMap<String, SubscriptionStats> {
+        self.subscription_stats.lock().await.clone()
+    }
+
     async fn do_subscribe(&self) -> Result<(), Box<dyn Error>> {
+        for pattern in self.config.mqtt_topic_subscribe_patterns() {
+            self.track_subscription(&pattern).await;
+        }
+        let sender = self.sender();
         join_all(self.config.mqtt_topic_subscribe_patterns().map(|topic| {
-            self.sender.send(Request::Subscribe(Subscribe::new(
+            sender.send(Request::Subscribe(Subscribe::new(
                 topic,
                 rumqttc::QoS::AtLeastOnce,
             )))
@@ -114,8 +471,6 @@ impl<'a> DeviceSyncer {
         .into_iter()
         .collect::<Result<Vec<()>, rumqttc::SendError<rumqttc::Request>>>()?;
 
-        self.repoll.send(0).await?;
-
         Ok(())
     }
 
@@ -131,22 +486,164 @@ impl<'a> DeviceSyncer {
             {
                 return Ok(());
             }
+            if result.is_err() {
+                self.stats.record_failure("parse");
+            }
             result?
         };
 
+        if self.config.safe_mode && topic.is_command() {
+            warn!(slog_scope::logger(), "safe_mode_ignoring_command"; "topic" => &message.topic);
+            return Ok(());
+        }
+        if self.config.read_only && topic.is_command() {
+            info!(slog_scope::logger(), "read_only_ignoring_command"; "topic" => &message.topic);
+            let dead_letter = serde_json::json!({
+                "topic": &message.topic,
+                "payload": String::from_utf8_lossy(&message.payload),
+                "reason": READ_ONLY_ERROR,
+            });
+            self.publish_bridge_message("dead_letter", dead_letter.to_string(), false)
+                .await?;
+            return Ok(());
+        }
+        if message.retain && self.config.ignore_retained_commands && topic.is_command() {
+            info!(slog_scope::logger(), "ignoring_retained_command"; "topic" => &message.topic);
+            return Ok(());
+        }
+
         match topic {
             TopicType::SetJsonTopic(device_id) => {
+                self.stats.record_command();
                 self.set_device_attributes_json(device_id, &message.payload)
                     .await?;
             }
             TopicType::SetAttributeTopic(device_id, attribute_id) => {
-                self.set_device_attribute_by_id(device_id, attribute_id, &message.payload)
-                    .await?;
+                self.stats.record_command();
+                if self.debounce_set(device_id, attribute_id).await {
+                    self.set_device_attribute_by_id(device_id, attribute_id, &message.payload)
+                        .await?;
+                }
+            }
+            TopicType::HomieSetTopic(device_id, property) => {
+                self.stats.record_command();
+                let device = self.attribute_schema(device_id).await?;
+                let attribute_id = device
+                    .attributes
+                    .iter()
+                    .find(|a| homie::property_id(a) == property)
+                    .ok_or_else(|| {
+                        simple_error!("No property {} on device {}", property, device_id)
+                    })?
+                    .id;
+                if self.debounce_set(device_id, attribute_id).await {
+                    self.set_device_attribute_by_id(device_id, attribute_id, &message.payload)
+                        .await?;
+                }
             }
             TopicType::DiscoveryListenTopic() => {
-                self.broadcast_discovery().await;
+                // Home Assistant also publishes `offline` here when it shuts down.
+                if &message.payload[..] == HA_BIRTH_PAYLOAD {
+                    self.broadcast_discovery().await;
+                } else {
+                    debug!(slog_scope::logger(), "ignored_discovery_listen_message"; "payload" => ?message.payload);
+                }
             }
-            TopicType::StatusTopic(_) | TopicType::DiscoveryTopic(_, _) => {
+            TopicType::StatusTopic(device_id) if message.retain => {
+                self.record_retained_status(device_id, &message.payload)
+                    .await?;
+            }
+            TopicType::StatusTopic(_) if self.reconciling.load(Ordering::SeqCst) => {
+                // Our own statuses, published while we're subscribed to collect the retained ones.
+            }
+            TopicType::ExternalTopic(topic) => {
+                for subscription in self.config.extra_subscriptions_for(&topic) {
+                    let value = match subscription.map_payload(&message.payload) {
+                        Some(v) => v,
+                        None => {
+                            debug!(slog_scope::logger(), "ignored_external_message"; "topic" => &topic, "subscription" => &subscription.topic);
+                            continue;
+                        }
+                    };
+                    self.stats.record_command();
+                    info!(slog_scope::logger(), "external_command"; "topic" => &topic, "device_id" => subscription.device_id, "attribute_id" => subscription.attribute_id, "value" => &value);
+                    if self
+                        .debounce_set(subscription.device_id, subscription.attribute_id)
+                        .await
+                    {
+                        self.set_device_attribute_by_id(
+                            subscription.device_id,
+                            subscription.attribute_id,
+                            value.as_bytes(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            TopicType::BridgeTopic(name) if name == "devices/get" => {
+                let devices = self.controller.describe_all().await?;
+                self.publish_bridge_message(
+                    "devices",
+                    serde_json::json!({ "devices": devices }).to_string(),
+                    false,
+                )
+                .await?;
+            }
+            TopicType::BridgeTopic(name) if name == "undiscover" => {
+                let device_id = std::str::from_utf8(&message.payload)?
+                    .trim()
+                    .parse_numberish::<u64>()? as DeviceId;
+                self.undiscover_device(device_id).await;
+            }
+            TopicType::BridgeTopic(name) if name == "pair" => {
+                let radio = std::str::from_utf8(&message.payload)?.trim().to_string();
+                info!(slog_scope::logger(), "running_discovery"; "radio" => &radio);
+                let result = self
+                    .controller
+                    .start_pairing(&radio, DEFAULT_PAIRING_TIMEOUT)
+                    .await
+                    .map_err(|e| e.to_string());
+                self.publish_pairing_result("pair/result", result).await?;
+            }
+            TopicType::BridgeTopic(name) if name == "remove" => {
+                let device_id = std::str::from_utf8(&message.payload)?
+                    .trim()
+                    .parse_numberish::<u64>()? as DeviceId;
+                info!(slog_scope::logger(), "removing_device"; "device_id" => device_id);
+                let result = self
+                    .controller
+                    .remove_device(device_id)
+                    .await
+                    .map_err(|e| e.to_string());
+                self.publish_pairing_result("remove/result", result).await?;
+            }
+            TopicType::BridgeTopic(name) if name == "rename" => {
+                let request: serde_json::Value = serde_json::from_slice(&message.payload)?;
+                let device_id = request["id"]
+                    .as_u64()
+                    .ok_or_else(|| simple_error!("Rename needs a device id"))?
+                    as DeviceId;
+                let name = request["name"]
+                    .as_str()
+                    .ok_or_else(|| simple_error!("Rename needs a name"))?;
+                self.rename_device(device_id, name).await?;
+            }
+            TopicType::GroupSetTopic(group_id) => {
+                self.stats.record_command();
+                self.set_group_attributes_json(group_id, &message.payload)
+                    .await?;
+            }
+            TopicType::SceneSetTopic(scene_id) => {
+                self.stats.record_command();
+                self.activate_scene(scene_id).await?;
+            }
+            TopicType::StatusTopic(_)
+            | TopicType::DiscoveryTopic(_, _)
+            | TopicType::DiscoveryEntityTopic(_, _, _)
+            | TopicType::BridgeTopic(_)
+            | TopicType::ActionTopic(_)
+            | TopicType::AttributesTopic(_)
+            | TopicType::GroupStatusTopic(_) => {
                 // Don't need to do anything here; we really shouldn't get here though...
                 warn!(slog_scope::logger(), "unexpected_topic_seen"; "topic" => message.topic);
             }
@@ -155,71 +652,391 @@ impl<'a> DeviceSyncer {
         Ok(())
     }
 
+    async fn start_reconciliation(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        let topic = match self.config.status_subscribe_pattern() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        info!(slog_scope::logger(), "starting_reconciliation"; "topic" => &topic);
+        *self.retained_statuses.lock().await = Some(HashMap::new());
+        self.track_subscription(&topic).await;
+        self.sender()
+            .send(Request::Subscribe(Subscribe::new(
+                topic,
+                rumqttc::QoS::AtLeastOnce,
+            )))
+            .await?;
+
+        tokio::task::spawn({
+            let this = self.clone();
+            async move {
+                tokio::time::delay_for(RECONCILIATION_WINDOW).await;
+                this.clone()
+                    .reconcile_retained_state()
+                    .await
+                    .record_failing_result(&this.stats, "reconciliation");
+                // The polls held back until now republish every device, mismatched or not.
+                this.reconciling.store(false, Ordering::SeqCst);
+                let _ = this.repoll.send(0).await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn record_retained_status(
+        &self,
+        device_id: DeviceId,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut retained = self.retained_statuses.lock().await;
+        let retained = match retained.as_mut() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        if payload.is_empty() {
+            return Ok(());
+        }
+        let mut status: serde_json::Value = serde_json::from_slice(payload)?;
+        // Timestamps aren't device state; don't let them fail the comparison.
+        if let Object(map) = &mut status {
+            map.remove("last_changed");
+        }
+        retained.insert(device_id, status);
+        Ok(())
+    }
+
+    async fn reconcile_retained_state(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        let retained = self
+            .retained_statuses
+            .lock()
+            .await
+            .take()
+            .unwrap_or_default();
+        if let Some(topic) = self.config.status_subscribe_pattern() {
+            self.sender()
+                .send(Request::Unsubscribe(Unsubscribe::new(topic)))
+                .await?;
+        }
+
+        let devices = self.controller.list().await?;
+        let mut report = ReconciliationReport::default();
+        for device in devices.iter() {
+            let actual = match self
+                .controller
+                .describe(device.id)
+                .await
+                .record_failing_result(&self.stats, "reconciliation_describe")
+            {
+                Some(v) => device_status_payload(&self.config, &v),
+                None => continue,
+            };
+            match retained.get(&device.id) {
+                None => report.missing_retained.push(device.id),
+                Some(v) if *v == actual => report.matched.push(device.id),
+                Some(v) => {
+                    warn!(slog_scope::logger(), "stale_retained_status"; "device_id" => device.id, "retained" => %v, "actual" => %actual);
+                    report.mismatched.push(device.id);
+                }
+            }
+        }
+        report.unknown_devices = retained
+            .keys()
+            .filter(|id| !devices.iter().any(|d| d.id == **id))
+            .cloned()
+            .collect();
+        for device_id in report.unknown_devices.iter() {
+            self.clear_retained_topics(*device_id).await;
+        }
+
+        info!(slog_scope::logger(), "reconciliation_report"; "report" => ?report);
+        self.publish_bridge_message("reconciliation", serde_json::to_string(&report)?, false)
+            .await
+    }
+
+    /// Periodically publishes the bridge counters to `bridge/stats` for broker-side monitoring.
+    async fn run_stats_publisher(self: Arc<Self>) {
+        let mut timer = tokio::time::interval(Duration::from_secs(self.config.stats_interval));
+        loop {
+            timer.tick().await;
+            let connected = match self.connection.lock().await.state() {
+                ConnectionState::Subscribed | ConnectionState::Degraded => true,
+                ConnectionState::Connecting | ConnectionState::Backoff => false,
+            };
+            if !connected {
+                continue;
+            }
+            let report = StatsReport {
+                counters: self.stats.snapshot(),
+                controller: self.controller.stats(),
+            };
+            let payload = match serde_json::to_string(&report) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!(slog_scope::logger(), "serialize_stats_failed"; "error" => ?e);
+                    continue;
+                }
+            };
+            self.publish_bridge_message("stats", payload, false)
+                .await
+                .record_failing_result(&self.stats, "publish_stats");
+        }
+    }
+
+    async fn publish_bridge_message(
+        &self,
+        name: &str,
+        payload: String,
+        retain: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let topic = self
+            .config
+            .to_topic_string(&TopicType::BridgeTopic(name.to_string()))
+            .ok_or_else(|| simple_error!("No topic prefix defined"))?;
+        let log_message =
+            LoggedMessage::OutgoingMessage(topic.clone(), MaybeJsonString::new(&payload));
+        let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload);
+        publish.retain = retain;
+        self.throttle_publish().await;
+        self.sender().send(Request::Publish(publish)).await?;
+        self.log_message(log_message).await;
+        Ok(())
+    }
+
+    /// Publishes how pairing or removing a device went, failures included: whoever asked is
+    /// waiting on the result topic.
+    async fn publish_pairing_result(
+        &self,
+        name: &str,
+        result: Result<PairingResult, String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let payload = match &result {
+            Ok(result) => serde_json::to_value(result)?,
+            Err(e) => serde_json::json!({ "error": e }),
+        };
+        self.publish_bridge_message(name, payload.to_string(), false)
+            .await?;
+        result?;
+        Ok(())
+    }
+
+    /// Refuses to open garage doors unless --allow-garage-open was given. Refusals are audit
+    /// logged here; commands that go through are audit logged like any other.
+    fn check_garage_door_command(
+        &self,
+        device: &LongDevice,
+        attribute: &DeviceAttribute,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        match device.garage_door_attribute() {
+            Some(a) if a.id == attribute.id => {}
+            _ => return Ok(()),
+        };
+        if value.is_truthy() && !self.config.allow_garage_open {
+            audit::record(
+                &AuditEvent::new("mqtt", device.id, attribute, value)
+                    .denied("opening garage doors over mqtt requires --allow-garage-open"),
+            );
+            bail!(
+                "Refusing to open garage door {} without --allow-garage-open",
+                device.id
+            );
+        }
+        Ok(())
+    }
+
+    fn is_lock_attribute(device: &LongDevice, attribute: &DeviceAttribute) -> bool {
+        device.lock_attribute().map(|a| a.id) == Some(attribute.id)
+    }
+
+    /// Waits out the debounce window; returns false if a newer command for the same attribute
+    /// arrived in the meantime (in which case that one wins).
+    async fn debounce_set(&self, device_id: DeviceId, attribute_id: AttributeId) -> bool {
+        if self.config.set_debounce_ms == 0 {
+            return true;
+        }
+        let key = (device_id, attribute_id);
+        let generation = {
+            let mut pending = self.pending_sets.lock().await;
+            let generation = pending.entry(key).or_insert(0);
+            *generation += 1;
+            *generation
+        };
+
+        tokio::time::delay_for(Duration::from_millis(self.config.set_debounce_ms)).await;
+
+        let mut pending = self.pending_sets.lock().await;
+        if pending.get(&key) != Some(&generation) {
+            debug!(slog_scope::logger(), "coalesced_set"; "device_id" => device_id, "attribute_id" => attribute_id);
+            return false;
+        }
+        pending.remove(&key);
+        true
+    }
+
+    /// The device's attributes for validating a command; cached from the last poll, so commands
+    /// don't wait on a full describe.
+    async fn attribute_schema(&self, device_id: DeviceId) -> Result<LongDevice, Box<dyn Error>> {
+        let cached = self.schemas.lock().await.get(&device_id).cloned();
+        Ok(match cached {
+            Some(schema) => schema,
+            None => {
+                let schema = self.controller.attribute_schema(device_id).await?;
+                self.schemas.lock().await.insert(device_id, schema.clone());
+                schema
+            }
+        })
+    }
+
+    /// Re-reads a critical attribute right after setting it and publishes whether it took.
+    async fn verify_critical_set(
+        &self,
+        device_id: DeviceId,
+        attribute_id: AttributeId,
+        expected: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        let attribute = self.controller.get(device_id, attribute_id).await?;
+        let actual = attribute.setting_value.or(&attribute.current_value).clone();
+        let verified = actual == *expected;
+        if !verified {
+            warn!(slog_scope::logger(), "critical_set_not_verified"; "device_id" => device_id, "attribute_id" => attribute_id, "expected" => ?expected, "actual" => ?actual);
+        }
+        self.publish_bridge_message(
+            "command_result",
+            serde_json::json!({
+                "device_id": device_id,
+                "attribute_id": attribute_id,
+                "expected": expected,
+                "actual": actual,
+                "verified": verified,
+            })
+            .to_string(),
+            false,
+        )
+        .await
+    }
+
     async fn set_device_attribute_by_id(
         &self,
         device_id: DeviceId,
         attribute_id: AttributeId,
         payload: &[u8],
     ) -> Result<(), Box<dyn Error>> {
-        let (device_name, attribute) = {
-            let info = self.controller.describe(device_id).await?;
-            (
-                info.name,
-                info.attributes
-                    .into_iter()
-                    .find(|x| x.id == attribute_id)
-                    .ok_or_else(|| {
-                        simple_error!(
-                            "Couldn't find attribute with id {} on device {}",
-                            attribute_id,
-                            device_id
-                        )
-                    })?,
-            )
-        };
+        let info = self.attribute_schema(device_id).await?;
+        let device_name = &info.name;
+        let attribute = info
+            .attributes
+            .iter()
+            .find(|x| x.id == attribute_id)
+            .ok_or_else(|| {
+                simple_error!(
+                    "Couldn't find attribute with id {} on device {}",
+                    attribute_id,
+                    device_id
+                )
+            })?;
         if !attribute.supports_write {
             bail!("Attribute {} does not support write", attribute.description);
         };
 
-        let payload_str = std::str::from_utf8(payload)?;
+        let mut payload_str = std::str::from_utf8(payload)?;
+        if Self::is_lock_attribute(&info, attribute) {
+            payload_str = lock_command_payload(&attribute.attribute_type, payload_str);
+        }
         let value = attribute.attribute_type.parse(payload_str)?;
+        self.check_garage_door_command(&info, attribute, &value)?;
 
         self.controller.set(device_id, attribute_id, &value).await?;
-        info!(slog_scope::logger(), "set"; "device_id" => device_id, "device" => &device_name, "attribute" => &attribute.description, "value" => ?value);
+        audit::record(&AuditEvent::new("mqtt", device_id, attribute, &value));
+        info!(slog_scope::logger(), "set"; "device_id" => device_id, "device" => device_name, "attribute" => &attribute.description, "value" => ?value);
 
+        if self.config.is_critical(device_id, Some(attribute_id)) {
+            self.verify_critical_set(device_id, attribute_id, &value)
+                .await?;
+        }
         self.repoll.try_send(device_id)?;
 
         Ok(())
     }
 
-    async fn set_device_attributes_json(
+    fn parse_json_payload(
         &self,
         device_id: DeviceId,
         payload: &[u8],
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn Error>> {
         let input = std::str::from_utf8(&payload)?;
         debug!(slog_scope::logger(), "json_message"; "device_id" => device_id, "payload" => &input);
 
-        let value = match serde_json::from_str(input)? {
-            Object(map) => map,
+        if let Some(command) = self
+            .config
+            .device_override(device_id)
+            .and_then(|o| o.plain_payload)
+            .and_then(|format| format.to_json_command(input))
+        {
+            return Ok(command);
+        }
+
+        match serde_json::from_str(input)? {
+            Object(map) => Ok(map),
             _ => bail!("Input to set not a map: {}", input),
+        }
+    }
+
+    /// Publishes a command that was dropped, and why, to `bridge/dead_letter`.
+    async fn publish_dead_letter(&self, device_id: DeviceId, payload: &[u8], reason: &str) {
+        let message = serde_json::json!({
+            "device_id": device_id,
+            "payload": String::from_utf8_lossy(payload),
+            "reason": reason,
+        });
+        self.publish_bridge_message("dead_letter", message.to_string(), false)
+            .await
+            .record_failing_result(&self.stats, "publish_dead_letter");
+    }
+
+    async fn set_device_attributes_json(
+        &self,
+        device_id: DeviceId,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut value = match self.parse_json_payload(device_id, payload) {
+            Ok(value) => value,
+            Err(e) => {
+                self.stats.record_failure("parse");
+                return Err(e);
+            }
         };
+        if let Some(ts) = value.remove("ts") {
+            let age = command_age(&ts, unix_timestamp())?;
+            if self.config.max_command_age > 0 && age > self.config.max_command_age {
+                warn!(slog_scope::logger(), "expired_command"; "device_id" => device_id, "age" => age);
+                self.stats.record_failure("expired_command");
+                self.publish_dead_letter(
+                    device_id,
+                    payload,
+                    &format!("expired: sent {}s ago", age),
+                )
+                .await;
+                return Ok(());
+            }
+        }
 
         let controller = &self.controller;
 
-        let (device_name, attribute_names) = {
-            let info = controller.describe(device_id).await?;
-            (
-                info.name,
-                info.attributes
-                    .into_iter()
-                    .map(|item| (item.description.to_string(), item))
-                    .collect::<HashMap<_, _>>(),
-            )
-        };
+        let info = self.attribute_schema(device_id).await?;
+        let value = json_light_command(&info, value, &self.config.device_options(device_id))?;
+        let device_name = &info.name;
+        let attribute_names = info
+            .attributes
+            .iter()
+            .map(|item| (item.canonical_name(), item))
+            .collect::<HashMap<_, _>>();
 
-        for (k, v) in value.iter() {
-            let attribute = match attribute_names.get(k) {
+        // Set the transition time first so it applies to the rest of the command.
+        let mut items = value.iter().collect::<Vec<_>>();
+        items.sort_by_key(|(k, _)| self.config.canonical_attribute_name(k) != "WK_TransitionTime");
+        let mut sets = Vec::with_capacity(items.len());
+        for (k, v) in items.into_iter() {
+            let attribute = match attribute_names.get(self.config.canonical_attribute_name(k)) {
                 Some(v) => {
                     if !v.supports_write {
                         error!(
@@ -236,16 +1053,43 @@ impl<'a> DeviceSyncer {
                 }
             };
 
-            let value = match attribute.attribute_type.parse_json(v) {
+            let parsed = match v {
+                serde_json::Value::String(s) if Self::is_lock_attribute(&info, attribute) => {
+                    let payload = lock_command_payload(&attribute.attribute_type, s);
+                    attribute.attribute_type.parse(payload)
+                }
+                _ => attribute.attribute_type.parse_json(v),
+            }
+            .map_err(|e| e.to_string());
+            let value = match parsed {
                 Ok(v) => v,
                 Err(e) => {
-                    error!(slog_scope::logger(), "bad_setting_for_attribute"; "attribute" => &attribute.description, "value" => %v, "error" => ?e);
+                    error!(slog_scope::logger(), "bad_setting_for_attribute"; "attribute" => &attribute.description, "value" => %v, "error" => %e);
                     continue;
                 }
             };
 
-            info!(slog_scope::logger(), "set"; "device_id" => device_id, "device" => &device_name, "attribute" => k, "value" => ?value);
-            controller.set(device_id, attribute.id, &value).await?
+            if let Err(e) = self.check_garage_door_command(&info, attribute, &value) {
+                error!(slog_scope::logger(), "command_refused"; "attribute" => &attribute.description, "error" => ?e);
+                continue;
+            }
+
+            info!(slog_scope::logger(), "set"; "device_id" => device_id, "device" => device_name, "attribute" => k, "value" => ?value);
+            sets.push((attribute, value));
+        }
+
+        // One aprontest run for the whole command, rather than one per attribute.
+        let values = sets
+            .iter()
+            .map(|(attribute, value)| (attribute.id, value.clone()))
+            .collect::<Vec<_>>();
+        controller.set_many(device_id, &values).await?;
+        for (attribute, value) in sets.iter() {
+            audit::record(&AuditEvent::new("mqtt", device_id, attribute, value));
+            if self.config.is_critical(device_id, Some(attribute.id)) {
+                self.verify_critical_set(device_id, attribute.id, value)
+                    .await?;
+            }
         }
 
         self.repoll.try_send(device_id)?;
@@ -253,31 +1097,168 @@ impl<'a> DeviceSyncer {
         Ok(())
     }
 
-    async fn log_message(self: Arc<Self>, message: LoggedMessage) {
-        let mut msgs = self.last_n_messages.lock().await;
-        if msgs.len() == 10 {
+    /// Group commands go straight to the hub: groups have no attribute schema to check them
+    /// against, so attributes are given by id.
+    async fn set_group_attributes_json(
+        &self,
+        group_id: GroupId,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let value: serde_json::Map<String, serde_json::Value> =
+            match serde_json::from_slice(payload) {
+                Ok(value) => value,
+                Err(e) => {
+                    self.stats.record_failure("parse");
+                    return Err(e.into());
+                }
+            };
+        for (k, v) in value.iter() {
+            let attribute_id: AttributeId = k
+                .parse()
+                .map_err(|_| simple_error!("Group attributes are set by id, not {}", k))?;
+            let value = AttributeValue::from_untyped_json(v)?;
+            self.check_group_command(group_id, attribute_id, &value)
+                .await?;
+            info!(slog_scope::logger(), "group_set"; "group_id" => group_id, "attribute_id" => attribute_id, "value" => ?value);
+            self.controller
+                .set_group(group_id, attribute_id, &value)
+                .await?;
+            audit::record(&AuditEvent::group("mqtt", group_id, attribute_id, &value));
+        }
+
+        // Any device might be in the group.
+        self.repoll.try_send(0)?;
+
+        Ok(())
+    }
+
+    /// The hub doesn't say which devices are in a group, so any of them might be. Refuses group
+    /// commands to attributes that are guarded on any device: garage doors (unless opening them
+    /// is allowed), locks and critical attributes are only set one device at a time.
+    async fn check_group_command(
+        &self,
+        group_id: GroupId,
+        attribute_id: AttributeId,
+        value: &AttributeValue,
+    ) -> Result<(), Box<dyn Error>> {
+        let devices = self.controller.list().await?;
+        for device in devices.iter() {
+            let schema = self.attribute_schema(device.id).await?;
+            let reason = if schema.garage_door_attribute().map(|a| a.id) == Some(attribute_id)
+                && value.is_truthy()
+                && !self.config.allow_garage_open
+            {
+                "opening garage doors over mqtt requires --allow-garage-open"
+            } else if schema.lock_attribute().map(|a| a.id) == Some(attribute_id) {
+                "locks can't be set through groups"
+            } else if schema.attributes.iter().any(|a| a.id == attribute_id)
+                && self.config.is_critical(device.id, Some(attribute_id))
+            {
+                "critical attributes can't be set through groups"
+            } else {
+                continue;
+            };
+            audit::record(&AuditEvent::group("mqtt", group_id, attribute_id, value).denied(reason));
+            bail!(
+                "Refusing to set attribute {} of group {}: device {} might be in it, and {}",
+                attribute_id,
+                group_id,
+                device.id,
+                reason
+            );
+        }
+        Ok(())
+    }
+
+    async fn activate_scene(&self, scene_id: SceneId) -> Result<(), Box<dyn Error>> {
+        let scene = self
+            .config
+            .scenes
+            .iter()
+            .find(|s| s.id == scene_id)
+            .ok_or_else(|| simple_error!("Scene id {} not found", scene_id))?;
+        info!(slog_scope::logger(), "activating_scene"; "scene_id" => scene_id, "name" => &scene.name);
+        let result = self.controller.activate_scene(scene).await;
+        // Poll even if an action failed: the ones before it went through.
+        self.repoll.try_send(0)?;
+        result
+    }
+
+    async fn log_message(&self, message: LoggedMessage) {
+        let mut msgs = self.last_n_messages.lock().await;
+        if msgs.len() == 10 {
             msgs.pop_front();
         };
         msgs.push_back(message)
     }
 
-    async fn loop_once(self: Arc<Self>, ev: &mut EventLoop) -> Result<(), Box<dyn Error>> {
-        let message = match ev.poll().await? {
+    async fn handle_event(
+        self: Arc<Self>,
+        event: Event,
+    ) -> Result<Option<ConnectionEvent>, Box<dyn Error>> {
+        let message = match event {
             Event::Incoming(i) => i,
-            Event::Outgoing(_) => return Ok(()),
+            Event::Outgoing(_) => return Ok(None),
         };
 
         trace!(slog_scope::logger(), "mqtt_message"; "message" => ?message);
 
         return match message {
-            Incoming::Connect(_) => Ok(()),
-            Incoming::ConnAck(_) => {
+            Incoming::ConnAck(ack) => {
                 self.clone().log_message(LoggedMessage::Connected).await;
-                self.clone().do_subscribe().await?;
-                self.start_broadcast_discovery_broadcast().await;
-                Ok(())
+                self.event_loop_details.lock().await.last_connack = Some(unix_timestamp());
+                if self.connected_once.swap(true, Ordering::SeqCst) {
+                    self.stats.record_reconnect();
+                }
+                if ack.session_present {
+                    // The broker kept our subscriptions (and queued anything we missed).
+                    debug!(slog_scope::logger(), "session_resumed");
+                } else {
+                    self.exactly_once.lock().await.reset();
+                    self.clone().do_subscribe().await?;
+                }
+                if self.config.reconcile_retained_state
+                    && !self.reconciled.swap(true, Ordering::SeqCst)
+                {
+                    self.clone().start_reconciliation().await?;
+                }
+                tokio::task::spawn({
+                    let this = self.clone();
+                    async move {
+                        this.clone()
+                            .flush_outbox()
+                            .await
+                            .record_failing_result(&this.stats, "flush_outbox");
+                    }
+                });
+                // The last will marked us lost; republish everything on the next poll.
+                self.homie_nodes.lock().await.clear();
+                self.homie_published.lock().await.clear();
+                self.repoll.send(0).await?;
+                self.clone().start_broadcast_discovery_broadcast().await;
+                if self.config.safe_mode {
+                    self.publish_bridge_message(
+                        "warnings",
+                        serde_json::json!({
+                            "warning": "safe_mode",
+                            "message": "The bridge kept crashing, so it started in safe mode: commands and discovery are disabled. Fix the cause and restart it.",
+                        })
+                        .to_string(),
+                        false,
+                    )
+                    .await
+                    .record_failing_result(&self.stats, "publish_warning");
+                }
+                Ok(Some(ConnectionEvent::Subscribed))
             }
             Incoming::Publish(message) => {
+                self.record_incoming(&message.topic).await;
+                if message.qos == rumqttc::QoS::ExactlyOnce
+                    && !self.exactly_once.lock().await.receive(message.pkid)
+                {
+                    debug!(slog_scope::logger(), "duplicate_exactly_once_publish"; "topic" => &message.topic, "pkid" => message.pkid);
+                    return Ok(Some(ConnectionEvent::Traffic));
+                }
                 self.clone()
                     .log_message(LoggedMessage::IncomingMessage(
                         message.topic.clone(),
@@ -286,105 +1267,753 @@ impl<'a> DeviceSyncer {
                     .await;
                 let this = self.clone();
                 tokio::task::spawn(async move {
-                    this.process_one(message)
+                    this.clone()
+                        .process_one(message)
                         .await
-                        .log_failing_result("process_message_failed");
+                        .record_failing_result(&this.stats, "process_message");
                 });
-                Ok(())
-            }
-            Incoming::PubAck(_) => Ok(()),
-            Incoming::PubRec(_) => {
-                bail!("Unexpected pubrec");
-            }
-            Incoming::PubRel(_) => {
-                bail!("Unexpected pubrel");
-            }
-            Incoming::PubComp(_) => bail!("Unexpected pubcomp"),
-            Incoming::Subscribe(_) => bail!("Unexpected subscribe"),
-            Incoming::SubAck(_) => Ok(()),
-            Incoming::Unsubscribe(_) => bail!("Unexpected unsubscribe!"),
-            Incoming::UnsubAck(_) => bail!("Unexpected unsuback!"),
-            Incoming::PingReq => Ok(()),
-            Incoming::PingResp => Ok(()),
+                Ok(Some(ConnectionEvent::Traffic))
+            }
+            Incoming::PubRel(rel) => {
+                self.exactly_once.lock().await.release(rel.pkid);
+                Ok(Some(ConnectionEvent::Traffic))
+            }
+            // The event loop answers these itself (PUBREL/PUBCOMP) to complete the handshake.
+            Incoming::PubRec(_)
+            | Incoming::PubComp(_)
+            | Incoming::PubAck(_)
+            | Incoming::SubAck(_)
+            | Incoming::UnsubAck(_)
+            | Incoming::PingReq
+            | Incoming::PingResp => Ok(Some(ConnectionEvent::Traffic)),
             Incoming::Disconnect => {
                 self.clone().log_message(LoggedMessage::Disconnected).await;
-                Ok(())
+                Ok(Some(ConnectionEvent::Disconnected))
+            }
+            Incoming::Connect(_) | Incoming::Subscribe(_) | Incoming::Unsubscribe(_) => {
+                warn!(slog_scope::logger(), "unexpected_packet"; "packet" => ?message);
+                Ok(Some(ConnectionEvent::UnexpectedPacket))
             }
         };
     }
 
+    async fn transition(self: Arc<Self>, event: ConnectionEvent) -> ConnectionState {
+        let new_state = self.connection.lock().await.handle(event);
+        let status = self.status().await;
+        let current_state = status.connection.state;
+        if let Some(state) = new_state {
+            info!(slog_scope::logger(), "connection_state_changed"; "state" => ?state, "event" => ?event);
+            if state == ConnectionState::Subscribed || state == ConnectionState::Degraded {
+                let this = self.clone();
+                tokio::task::spawn(async move {
+                    let payload = serde_json::to_string(&status).unwrap();
+                    this.publish_bridge_message("state", payload, true)
+                        .await
+                        .record_failing_result(&this.stats, "publish_state");
+                });
+            }
+        }
+        current_state
+    }
+
+    pub async fn status(&self) -> SyncerStatus {
+        SyncerStatus {
+            connection: self.connection.lock().await.status(),
+            details: self.event_loop_details.lock().await.clone(),
+            safe_mode: self.config.safe_mode,
+        }
+    }
+
+    async fn record_event_loop_error(&self, error: String) {
+        let mut details = self.event_loop_details.lock().await;
+        details.last_error = Some(error);
+        details.last_error_at = Some(unix_timestamp());
+    }
+
+    fn sender(&self) -> Sender<Request> {
+        self.sender
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replaces a stuck event loop with a fresh one (and a fresh connection).
+    fn recreate_event_loop(&self) -> EventLoop {
+        let options = self.config.mqtt_options.as_ref().unwrap().clone();
+        let ev = EventLoop::new(options, self.config.mqtt_queue_size);
+        *self.sender.write().unwrap_or_else(|e| e.into_inner()) = ev.handle();
+        ev
+    }
+
     async fn run_mqtt(self: Arc<Self>, mut ev: EventLoop) -> () {
+        let watchdog_timeout =
+            self.config.mqtt_options.as_ref().unwrap().keep_alive() * WATCHDOG_KEEP_ALIVES;
         loop {
-            let should_delay = {
-                let result = self.clone().loop_once(&mut ev).await;
-                match result {
-                    Ok(_) => false,
+            // Only the poll is timed: handling a ConnAck (resubscribing, republishing) can take
+            // a while on a big hub without the connection being stuck.
+            let polled = tokio::time::timeout(watchdog_timeout, ev.poll()).await;
+            self.event_loop_details.lock().await.inflight = ev.state.inflight();
+            let event = match polled {
+                Ok(Ok(event)) => match self
+                    .clone()
+                    .handle_event(event)
+                    .await
+                    .map_err(|e| e.to_string())
+                {
+                    Ok(Some(event)) => event,
+                    Ok(None) => continue,
                     Err(e) => {
-                        warn!(slog_scope::logger(), "loop_encountered_error"; "err" => ?e);
-                        true
+                        warn!(slog_scope::logger(), "loop_encountered_error"; "err" => %e);
+                        self.record_event_loop_error(e).await;
+                        ConnectionEvent::ConnectionError
                     }
+                },
+                Ok(Err(e)) => {
+                    warn!(slog_scope::logger(), "loop_encountered_error"; "err" => ?e);
+                    self.record_event_loop_error(e.to_string()).await;
+                    ConnectionEvent::ConnectionError
+                }
+                Err(_) => {
+                    let state = self.connection.lock().await.state();
+                    error!(slog_scope::logger(), "mqtt_watchdog_fired"; "timeout_secs" => watchdog_timeout.as_secs(), "state" => ?state);
+                    self.stats.record_failure("mqtt_watchdog");
+                    self.record_event_loop_error(format!(
+                        "No progress for {}s; recreated the event loop",
+                        watchdog_timeout.as_secs()
+                    ))
+                    .await;
+                    ev = self.recreate_event_loop();
+                    ConnectionEvent::ConnectionError
                 }
             };
-            if should_delay {
-                tokio::time::delay_for(Duration::from_millis(200)).await
-            };
+            if self.clone().transition(event).await == ConnectionState::Backoff {
+                let delay = self.connection.lock().await.backoff_delay();
+                tokio::time::delay_for(delay).await;
+                self.clone()
+                    .transition(ConnectionEvent::BackoffElapsed)
+                    .await;
+            }
         }
     }
 
     async fn poll_device_(self: Arc<Self>, device_id: DeviceId) -> Result<(), Box<dyn Error>> {
         let device_info = { self.controller.describe(device_id).await? };
-        let attributes = device_info
-            .attributes
-            .into_iter()
-            .map(|x| {
-                (
-                    x.description,
-                    x.setting_value.or(&x.current_value).to_json(),
-                )
+        if !device_info.attributes.is_empty() {
+            self.registry.record(&device_info).await;
+        }
+        if device_info.attributes.is_empty() && !self.config.publish_empty_status {
+            // Sleepy devices sometimes report nothing; don't wipe their state over it.
+            self.mark_status_stale(device_id).await;
+            bail!("Device {} reported no attributes", device_id);
+        }
+        self.config
+            .remember_device_name(device_id, &device_info.name);
+        self.schemas
+            .lock()
+            .await
+            .insert(device_id, device_info.clone());
+        let now = unix_timestamp();
+        let changes = self
+            .attribute_changes
+            .lock()
+            .await
+            .update(&device_info, now);
+        for (attribute, value) in changes.into_iter() {
+            debug!(slog_scope::logger(), "attribute_changed"; "device_id" => device_id, "attribute" => &attribute, "value" => %value);
+            if let Some(action) = button_action(&device_info, &attribute, &value) {
+                self.publish_action(device_id, action).await;
+            }
+            self.log_message(LoggedMessage::AttributeChanged {
+                device_id,
+                attribute,
+                value,
+                at: now,
             })
-            .collect::<serde_json::Map<_, _>>();
+            .await;
+        }
+        let mut payload = device_status_payload(&self.config, &device_info);
+        if self.config.status_last_changed {
+            let last_changed = self.last_changed(device_id).await;
+            if let Object(map) = &mut payload {
+                map.insert("last_changed".into(), serde_json::to_value(last_changed)?);
+            }
+        }
+        if self.config.expose_notes {
+            let notes = self.notes.get(device_id).await;
+            if let Object(map) = &mut payload {
+                map.insert("notes".into(), notes.notes.into());
+                map.insert("labels".into(), notes.labels.into());
+            }
+        }
+        self.last_statuses
+            .lock()
+            .await
+            .insert(device_id, payload.clone());
+        self.publish_status(device_id, payload.to_string()).await;
+        self.publish_attributes(device_id, extended_attributes(&device_info))
+            .await;
+        if let Some(prefix) = self.config.homie_topic_prefix.as_ref() {
+            self.update_homie_node(prefix, &device_info).await;
+        }
+        Ok(())
+    }
 
-        let payload = serde_json::Value::Object(attributes).to_string();
-        trace!(slog_scope::logger(), "poll_device_status"; "device_id" => device_id, "payload" => &payload);
+    /// Publishes a momentary event (e.g. a button press) for Home Assistant device triggers.
+    async fn publish_action(&self, device_id: DeviceId, action: String) {
+        let topic = match self
+            .config
+            .to_topic_string(&TopicType::ActionTopic(device_id))
+        {
+            Some(topic) => topic,
+            None => return,
+        };
+        info!(slog_scope::logger(), "device_action"; "device_id" => device_id, "action" => &action);
+        let logged_message =
+            LoggedMessage::OutgoingMessage(topic.clone(), MaybeJsonString::new(&action));
+        let publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, action);
+        if self.publish_or_queue(publish).await {
+            self.log_message(logged_message).await;
+        }
+    }
+
+    async fn publish_attributes(&self, device_id: DeviceId, payload: serde_json::Value) {
+        let topic = match self
+            .config
+            .to_topic_string(&TopicType::AttributesTopic(device_id))
+        {
+            Some(topic) => topic,
+            None => return,
+        };
+        if self.last_attributes.lock().await.get(&device_id) == Some(&payload) {
+            return;
+        }
+        let payload_string = payload.to_string();
+        let logged_message =
+            LoggedMessage::OutgoingMessage(topic.clone(), MaybeJsonString::new(&payload_string));
+        let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload_string);
+        publish.retain = true;
+        if self.publish_or_queue(publish).await {
+            self.log_message(logged_message).await;
+            self.last_attributes.lock().await.insert(device_id, payload);
+        }
+    }
+
+    /// The hub doesn't report group state, so a group's status is what's known about it.
+    async fn publish_group_statuses(&self) -> Result<(), Box<dyn Error>> {
+        if self.config.topic_prefix.is_none() {
+            return Ok(());
+        }
+        let groups = self.controller.list_groups().await?;
+        for group in groups {
+            let payload = serde_json::to_value(&group)?;
+            if self.last_group_statuses.lock().await.get(&group.id) == Some(&payload) {
+                continue;
+            }
+            let topic = self
+                .config
+                .to_topic_string(&TopicType::GroupStatusTopic(group.id))
+                .unwrap();
+            let payload_string = payload.to_string();
+            let logged_message = LoggedMessage::OutgoingMessage(
+                topic.clone(),
+                MaybeJsonString::new(&payload_string),
+            );
+            let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload_string);
+            publish.retain = true;
+            if self.publish_or_queue(publish).await {
+                self.log_message(logged_message).await;
+                self.last_group_statuses
+                    .lock()
+                    .await
+                    .insert(group.id, payload);
+            }
+        }
+        Ok(())
+    }
 
+    async fn publish_status(&self, device_id: DeviceId, payload: String) {
+        trace!(slog_scope::logger(), "poll_device_status"; "device_id" => device_id, "payload" => &payload);
         let topic = self
             .config
             .to_topic_string(&TopicType::StatusTopic(device_id))
             .unwrap();
         let logged_message =
             LoggedMessage::OutgoingMessage(topic.clone(), MaybeJsonString::new(&payload));
-        let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload);
+        let mut publish = Publish::new(topic, self.config.status_qos_for(device_id), payload);
         publish.retain = true;
-        match self.sender.try_send(Request::Publish(publish)) {
-            Ok(_) => {
-                self.log_message(logged_message).await;
-                Ok(())
+        if self.publish_or_queue(publish).await {
+            self.log_message(logged_message).await;
+        }
+    }
+
+    /// Republishes the last known status with `"stale": true`. Only done once per stale
+    /// stretch; the next good poll publishes a fresh status without the flag.
+    async fn mark_status_stale(&self, device_id: DeviceId) {
+        let mut status = match self.last_statuses.lock().await.remove(&device_id) {
+            Some(status) => status,
+            None => return,
+        };
+        warn!(slog_scope::logger(), "marking_status_stale"; "device_id" => device_id);
+        if let Object(map) = &mut status {
+            map.insert("stale".into(), true.into());
+        }
+        self.publish_status(device_id, status.to_string()).await;
+    }
+
+    async fn unpublished_homie_messages(
+        &self,
+        messages: Vec<(String, String)>,
+    ) -> Vec<(String, String)> {
+        let published = self.homie_published.lock().await;
+        messages
+            .into_iter()
+            .filter(|(topic, payload)| published.get(topic) != Some(payload))
+            .collect()
+    }
+
+    async fn publish_homie_messages(&self, messages: Vec<(String, String)>) {
+        for (topic, payload) in messages.into_iter() {
+            self.homie_published
+                .lock()
+                .await
+                .insert(topic.clone(), payload.clone());
+            let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload);
+            publish.retain = true;
+            self.publish_or_queue(publish).await;
+        }
+    }
+
+    async fn publish_homie_topology(&self, prefix: &str, node_messages: Vec<(String, String)>) {
+        let state = format!("{}$state", prefix);
+        // Once ready, stay ready: new nodes are just added, without flapping through init.
+        let not_ready = !self
+            .unpublished_homie_messages(vec![(state.clone(), "ready".to_string())])
+            .await
+            .is_empty();
+        if not_ready {
+            self.publish_homie_messages(vec![(state.clone(), "init".to_string())])
+                .await;
+        }
+        self.publish_homie_messages(node_messages).await;
+        let nodes = self
+            .homie_nodes
+            .lock()
+            .await
+            .iter()
+            .map(|id| homie::node_id(*id))
+            .collect::<Vec<_>>();
+        let device_messages = homie::device_messages(prefix, HOMIE_DEVICE_NAME, &nodes);
+        let device_messages = self.unpublished_homie_messages(device_messages).await;
+        self.publish_homie_messages(device_messages).await;
+        let ready = self
+            .unpublished_homie_messages(vec![(state, "ready".to_string())])
+            .await;
+        self.publish_homie_messages(ready).await;
+    }
+
+    /// Publishes the homie node for the device if it's new or its attributes changed, and
+    /// any property values that changed.
+    async fn update_homie_node(&self, prefix: &str, device: &LongDevice) {
+        let is_new = self.homie_nodes.lock().await.insert(device.id);
+        let topology = self
+            .unpublished_homie_messages(homie::node_messages(prefix, device))
+            .await;
+        if is_new || !topology.is_empty() {
+            debug!(slog_scope::logger(), "homie_topology_changed"; "device_id" => device.id);
+            self.publish_homie_topology(prefix, topology).await;
+        }
+        let values = self
+            .unpublished_homie_messages(homie::property_value_messages(prefix, device))
+            .await;
+        self.publish_homie_messages(values).await;
+    }
+
+    async fn remove_homie_node(&self, device_id: DeviceId) {
+        let prefix = match self.config.homie_topic_prefix.as_ref() {
+            Some(v) => v,
+            None => return,
+        };
+        if self.homie_nodes.lock().await.remove(&device_id) {
+            self.publish_homie_topology(prefix, Vec::new()).await;
+        }
+    }
+
+    async fn poll_device(self: Arc<Self>, device_id: DeviceId) -> () {
+        self.clone()
+            .poll_device_(device_id)
+            .await
+            .record_failing_result(&self.stats, "poll_device");
+    }
+
+    /// Lists devices, minus any the user hid.
+    async fn visible_devices(&self) -> Result<Vec<ShortDevice>, Box<dyn Error>> {
+        let devices = self.controller.list().await?;
+        Ok(self.filter_hidden(devices).await)
+    }
+
+    async fn filter_hidden(&self, devices: Vec<ShortDevice>) -> Vec<ShortDevice> {
+        let hidden = self.hidden_devices.lock().await;
+        devices
+            .into_iter()
+            .filter(|d| !hidden.contains(&d.id))
+            .collect()
+    }
+
+    /// Publishes empty retained messages to every topic we published retained state to for the
+    /// device, so brokers (and Home Assistant) forget about it.
+    async fn clear_retained_topics(&self, device_id: DeviceId) {
+        let mut topics = self
+            .discovery_topics
+            .lock()
+            .await
+            .remove(&device_id)
+            .unwrap_or_default();
+        // After a restart only the registry remembers what the device was discovered as.
+        if let Some(device) = self.registry.get(device_id).await {
+            for message in device_to_discovery_payload(&self.config, &device) {
+                if let Some(topic) = self.config.to_topic_string(&message.topic(device_id)) {
+                    topics.insert(topic);
+                }
             }
-            Err(e) => {
-                crit!(slog_scope::logger(), "sending_failed_crashing_to_maybe_reconnect"; "error" => ?e);
-                panic!("{:?}", e)
+        }
+        for topic_type in [
+            TopicType::StatusTopic(device_id),
+            TopicType::AttributesTopic(device_id),
+        ]
+        .iter()
+        {
+            if let Some(topic) = self.config.to_topic_string(topic_type) {
+                topics.insert(topic);
             }
         }
+        for topic in topics.into_iter() {
+            info!(slog_scope::logger(), "clearing_retained_topic"; "device_id" => device_id, "topic" => &topic);
+            let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, Vec::<u8>::new());
+            publish.retain = true;
+            self.publish_or_queue(publish).await;
+        }
     }
 
-    async fn poll_device(self: Arc<Self>, device_id: DeviceId) -> () {
-        self.poll_device_(device_id)
+    /// Publishes aprontest output to `bridge/debug/aprontest` as it comes in.
+    pub async fn mirror_command_output(self: Arc<Self>, receiver: Receiver<CommandOutput>) {
+        while let Ok(output) = receiver.recv().await {
+            let payload = match serde_json::to_string(&output) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(slog_scope::logger(), "command_output_serialization_failed"; "error" => ?e);
+                    continue;
+                }
+            };
+            self.publish_bridge_message("debug/aprontest", payload, false)
+                .await
+                .record_failing_result(&self.stats, "publish_command_output");
+        }
+    }
+
+    pub async fn mirror_audit_events(self: Arc<Self>, receiver: Receiver<AuditEvent>) {
+        while let Ok(event) = receiver.recv().await {
+            let payload = match serde_json::to_string(&event) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(slog_scope::logger(), "audit_event_serialization_failed"; "error" => ?e);
+                    continue;
+                }
+            };
+            self.publish_bridge_message("audit", payload, false)
+                .await
+                .record_failing_result(&self.stats, "publish_audit_event");
+        }
+    }
+
+    /// Repolls devices as the log watcher sees them report in.
+    pub async fn repoll_watched_devices(self: Arc<Self>, receiver: Receiver<DeviceId>) {
+        while let Ok(device_id) = receiver.recv().await {
+            // The log line is newer than anything the controller cached.
+            self.controller.forget_cached(device_id).await;
+            self.repoll_device(device_id)
+                .await
+                .record_failing_result(&self.stats, "repoll_watched_device");
+        }
+    }
+
+    /// Publishes every unknown attribute type seen so far to `bridge/unknown_formats` (retained)
+    /// whenever a new one shows up.
+    pub async fn mirror_unknown_formats(self: Arc<Self>, receiver: Receiver<Vec<UnknownFormat>>) {
+        while let Ok(formats) = receiver.recv().await {
+            let payload = match serde_json::to_string(&formats) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(slog_scope::logger(), "unknown_formats_serialization_failed"; "error" => ?e);
+                    continue;
+                }
+            };
+            self.publish_bridge_message("unknown_formats", payload, true)
+                .await
+                .record_failing_result(&self.stats, "publish_unknown_formats");
+        }
+    }
+
+    /// Unix timestamps of the last value change of each of the device's attributes.
+    pub async fn last_changed(&self, device_id: DeviceId) -> BTreeMap<String, u64> {
+        self.attribute_changes.lock().await.last_changed(device_id)
+    }
+
+    /// Republishes discovery configs and statuses for every device, e.g. after the overrides
+    /// changed.
+    pub async fn rebroadcast_discovery(self: Arc<Self>) {
+        // The cached schemas may have attributes named per the old overrides.
+        self.schemas.lock().await.clear();
+        self.clone().start_broadcast_discovery_broadcast().await;
+        let _ = self.repoll.send(0).await;
+    }
+
+    /// Renames the device on the hub and republishes its discovery configs with the new name.
+    pub async fn rename_device(
+        self: Arc<Self>,
+        device_id: DeviceId,
+        name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        info!(slog_scope::logger(), "renaming_device"; "device_id" => device_id, "name" => name);
+        self.controller.rename(device_id, name).await?;
+        if self.config.safe_mode {
+            return Ok(());
+        }
+        self.clone().broadcast_device_discovery(device_id).await?;
+        // Topic templates may use the name; get the state onto the new topics too.
+        self.repoll_device(device_id).await
+    }
+
+    /// Republishes the device status, e.g. after its notes changed.
+    pub async fn repoll_device(&self, device_id: DeviceId) -> Result<(), Box<dyn Error>> {
+        self.repoll.send(device_id).await?;
+        Ok(())
+    }
+
+    /// Removes a device from Home Assistant by publishing empty discovery configs, and stops
+    /// rediscovering it until restart. Returns the topics that were cleared.
+    pub async fn undiscover_device(&self, device_id: DeviceId) -> Vec<String> {
+        self.undiscovered.lock().await.insert(device_id);
+
+        let mut topics = self
+            .discovery_topics
+            .lock()
+            .await
+            .remove(&device_id)
+            .unwrap_or_default();
+        // The device may be gone already; that's fine, we still know the default topics.
+        if let Ok(device) = self.controller.describe(device_id).await {
+            for message in device_to_discovery_payload(&self.config, &device) {
+                if let Some(topic) = self.config.to_topic_string(&message.topic(device_id)) {
+                    topics.insert(topic);
+                }
+            }
+        }
+        for component in DISCOVERY_COMPONENTS.iter() {
+            if let Some(topic) = self
+                .config
+                .to_topic_string(&TopicType::DiscoveryTopic(component.to_string(), device_id))
+            {
+                topics.insert(topic);
+            }
+        }
+
+        let mut topics = topics.into_iter().collect::<Vec<_>>();
+        topics.sort();
+        info!(slog_scope::logger(), "undiscover_device"; "device_id" => device_id, "topics" => ?topics);
+        for topic in topics.iter() {
+            let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, Vec::<u8>::new());
+            publish.retain = true;
+            self.publish_or_queue(publish).await;
+        }
+        topics
+    }
+
+    /// Compares the device list with the previous one and cleans up after removed devices.
+    /// Returns the newly added devices.
+    async fn handle_device_list_changes(&self, devices: &[ShortDevice]) -> HashSet<DeviceId> {
+        let current = devices.iter().map(|d| d.id).collect::<HashSet<_>>();
+        let (added, removed) = {
+            let mut known = self.known_devices.lock().await;
+            let (added, removed): (_, Vec<_>) = match known.as_ref() {
+                Some(previous) => (
+                    current.difference(previous).cloned().collect(),
+                    previous.difference(&current).cloned().collect(),
+                ),
+                // Everything gets discovered on connect anyway, but devices removed while we were
+                // down are only known from the registry.
+                None => {
+                    let registered = self
+                        .registry
+                        .devices()
+                        .await
+                        .into_iter()
+                        .map(|d| d.id)
+                        .collect::<HashSet<_>>();
+                    (
+                        HashSet::new(),
+                        registered.difference(&current).cloned().collect(),
+                    )
+                }
+            };
+            *known = Some(current.clone());
+            (added, removed)
+        };
+        for device_id in removed.into_iter() {
+            info!(slog_scope::logger(), "device_removed"; "device_id" => device_id);
+            self.clear_retained_topics(device_id).await;
+            self.remove_homie_node(device_id).await;
+            self.last_statuses.lock().await.remove(&device_id);
+            self.last_attributes.lock().await.remove(&device_id);
+            self.attribute_changes.lock().await.remove(device_id);
+            self.schemas.lock().await.remove(&device_id);
+        }
+        self.registry.retain(&current).await;
+        added
+    }
+
+    /// Splits devices into the ones within --max-devices and the excess (highest ids - most
+    /// likely ghosts from a runaway pairing session).
+    fn split_excess_devices(
+        &self,
+        mut devices: Vec<ShortDevice>,
+    ) -> (Vec<ShortDevice>, Vec<ShortDevice>) {
+        devices.sort_by_key(|d| d.id);
+        let excess = match self.config.max_devices {
+            Some(max) if devices.len() > max => devices.split_off(max),
+            _ => Vec::new(),
+        };
+        (devices, excess)
+    }
+
+    async fn update_shed_devices(&self, total: usize, excess: &[ShortDevice]) {
+        let excess_ids = excess.iter().map(|d| d.id).collect::<Vec<_>>();
+        {
+            let mut shed = self.shed_devices.lock().await;
+            if *shed == excess_ids {
+                return;
+            }
+            *shed = excess_ids.clone();
+        }
+        if excess_ids.is_empty() {
+            info!(slog_scope::logger(), "device_count_back_under_limit"; "device_count" => total);
+            return;
+        }
+        warn!(slog_scope::logger(), "too_many_devices"; "device_count" => total, "max_devices" => ?self.config.max_devices, "shed_devices" => ?excess_ids);
+        self.publish_bridge_message(
+            "warnings",
+            serde_json::json!({
+                "warning": "too_many_devices",
+                "device_count": total,
+                "max_devices": self.config.max_devices,
+                "shed_devices": excess_ids,
+            })
+            .to_string(),
+            false,
+        )
+        .await
+        .record_failing_result(&self.stats, "publish_warning");
+    }
+
+    /// Hides every device above --max-devices; returns the newly hidden ids.
+    pub async fn hide_excess_devices(&self) -> Result<Vec<DeviceId>, Box<dyn Error>> {
+        let (_, excess) = self.split_excess_devices(self.visible_devices().await?);
+        let ids = excess.iter().map(|d| d.id).collect::<Vec<_>>();
+        let mut hidden = self.hidden_devices.lock().await;
+        hidden.extend(ids.iter());
+        save_hidden_devices(self.hidden_devices_path.as_ref(), &hidden)?;
+        info!(slog_scope::logger(), "hid_excess_devices"; "device_ids" => ?ids);
+        Ok(ids)
+    }
+
+    pub async fn unhide_devices(&self) -> Result<(), Box<dyn Error>> {
+        let mut hidden = self.hidden_devices.lock().await;
+        hidden.clear();
+        save_hidden_devices(self.hidden_devices_path.as_ref(), &hidden)
+    }
+
+    pub async fn hidden_devices(&self) -> Vec<DeviceId> {
+        let mut hidden = self
+            .hidden_devices
+            .lock()
             .await
-            .log_failing_result("poll_device_failed");
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        hidden.sort();
+        hidden
     }
 
     async fn poll_all_(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
-        let all_devices = self.clone().controller.list().await?;
-        let all_tasks = all_devices
+        let listed = self.controller.list().await?;
+        let added = self.handle_device_list_changes(&listed).await;
+        let all_devices = self.filter_hidden(listed).await;
+        if !added.is_empty()
+            && self.config.discovery_topic_prefix.is_some()
+            && !self.config.safe_mode
+        {
+            // Newly paired devices show up in Home Assistant without waiting for a restart.
+            let discoveries = all_devices
+                .iter()
+                .filter(|d| added.contains(&d.id))
+                .map(|d| {
+                    info!(slog_scope::logger(), "device_added"; "device_id" => d.id, "name" => &d.name);
+                    self.clone().broadcast_device_discovery_quiet(d.id)
+                })
+                .collect::<Vec<_>>();
+            join_all(discoveries).await;
+        }
+        let all_devices = all_devices
             .into_iter()
-            .map(|x| self.clone().poll_device(x.id))
+            .filter(|d| self.config.polls_radio(d.interconnect.as_deref()))
             .collect::<Vec<_>>();
-        join_all(all_tasks).await;
+        let total = all_devices.len();
+        let (mut devices, excess) = self.split_excess_devices(all_devices);
+        self.update_shed_devices(total, &excess).await;
+        let cycle = self.poll_cycle.fetch_add(1, Ordering::SeqCst);
+        if cycle % SHED_DEVICE_POLL_EVERY == 0 {
+            devices.extend(excess);
+        }
+        if cycle % GROUP_POLL_EVERY == 0 {
+            self.publish_group_statuses()
+                .await
+                .record_failing_result(&self.stats, "publish_group_statuses");
+        }
+
+        stream::iter(devices.into_iter().map(|x| self.clone().poll_device(x.id)))
+            .buffer_unordered(self.config.poll_concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        self.registry
+            .save_if_due()
+            .await
+            .record_failing_result(&self.stats, "save_device_registry");
         Ok(())
     }
 
+    /// Queues the statuses saved by the last run, marked stale, so they go out as soon as we
+    /// connect instead of after the first full poll (which replaces any it gets to first).
+    async fn restore_registered_devices(&self) {
+        let devices = self.registry.devices().await;
+        if devices.is_empty() {
+            return;
+        }
+        info!(slog_scope::logger(), "restoring_registered_devices"; "count" => devices.len());
+        for device in devices.into_iter() {
+            self.config.remember_device_name(device.id, &device.name);
+            self.schemas.lock().await.insert(device.id, device.clone());
+            let mut payload = device_status_payload(&self.config, &device);
+            if let Object(map) = &mut payload {
+                map.insert("stale".into(), true.into());
+            }
+            self.publish_status(device.id, payload.to_string()).await;
+        }
+    }
+
     async fn poll_all(self: Arc<Self>) -> () {
-        self.poll_all_().await.log_failing_result("poll_all_failed");
+        self.clone()
+            .poll_all_()
+            .await
+            .record_failing_result(&self.stats, "poll_all");
     }
 
     async fn run_poller(self: Arc<Self>, resync_interval: u64, rx: Receiver<DeviceId>) -> () {
@@ -402,6 +2031,10 @@ impl<'a> DeviceSyncer {
         loop {
             let device_id = rx.recv().await.unwrap();
             trace!(slog_scope::logger(), "requested_repoll"; "device_id" => device_id);
+            if self.reconciling.load(Ordering::SeqCst) {
+                debug!(slog_scope::logger(), "repoll_held_for_reconciliation"; "device_id" => device_id);
+                continue;
+            }
             if device_id == 0 {
                 self.clone().poll_all().await;
             } else {
@@ -415,45 +2048,114 @@ impl<'a> DeviceSyncer {
         id: DeviceId,
     ) -> Result<(), Box<dyn Error>> {
         debug!(slog_scope::logger(), "broadcast_discovery"; "id" => id);
-
         let device = self.clone().controller.describe(id).await?;
+        self.publish_device_discovery(&device).await
+    }
 
-        match device_to_discovery_payload(&self.config, &device) {
-            Some(v) => {
-                let topic = self
-                    .config
-                    .to_topic_string(&TopicType::DiscoveryTopic(v.component.into(), device.id))
-                    .ok_or_else(|| simple_error!("No discovery topic for device {}", device.id))?;
-                let config = v.discovery_info.to_string();
-                info!(slog_scope::logger(), "discovered_device"; "id" => id, "name" => &device.name);
-                debug!(slog_scope::logger(), "broadcast_discovery_result"; "id" => id, "topic" => &topic, "config" => &config);
-                let log_message =
-                    LoggedMessage::OutgoingMessage(topic.clone(), MaybeJsonString::new(&config));
-                self.sender
-                    .send(Request::Publish(Publish::new(
-                        topic,
-                        rumqttc::QoS::AtLeastOnce,
-                        config,
-                    )))
-                    .await?;
-                self.log_message(log_message).await;
-                Ok(())
-            }
-            None => {
-                warn!(slog_scope::logger(), "unknown_device"; "device_id" => id, "device_info" => ?device);
-                Ok(())
-            }
+    async fn publish_device_discovery(&self, device: &LongDevice) -> Result<(), Box<dyn Error>> {
+        let id = device.id;
+        if self.undiscovered.lock().await.contains(&id) {
+            debug!(slog_scope::logger(), "skipping_undiscovered_device"; "id" => id);
+            return Ok(());
+        }
+        self.config.remember_device_name(id, &device.name);
+
+        let mut discovered = self.publish_discovery(&self.config, device).await?;
+        if let Some(canary) = self.canary_config.as_ref() {
+            discovered |= self.publish_discovery(canary, device).await?;
+        }
+        if !discovered {
+            warn!(slog_scope::logger(), "unknown_device"; "device_id" => id, "device_info" => ?device);
+            return Ok(());
+        }
+        info!(slog_scope::logger(), "discovered_device"; "id" => id, "name" => &device.name);
+        Ok(())
+    }
+
+    /// Publishes the discovery configs `config` yields for the device. Returns whether there
+    /// were any.
+    async fn publish_discovery(
+        &self,
+        config: &Config,
+        device: &LongDevice,
+    ) -> Result<bool, Box<dyn Error>> {
+        let id = device.id;
+        let messages = device_to_discovery_payload(config, device);
+        if messages.is_empty() {
+            return Ok(false);
+        }
+
+        for v in messages.into_iter() {
+            let topic = config
+                .to_topic_string(&v.topic(device.id))
+                .ok_or_else(|| simple_error!("No discovery topic for device {}", device.id))?;
+            let payload = v.discovery_info.to_string();
+            debug!(slog_scope::logger(), "broadcast_discovery_result"; "id" => id, "topic" => &topic, "config" => &payload);
+            let log_message =
+                LoggedMessage::OutgoingMessage(topic.clone(), MaybeJsonString::new(&payload));
+            self.discovery_topics
+                .lock()
+                .await
+                .entry(device.id)
+                .or_default()
+                .insert(topic.clone());
+            let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload);
+            publish.retain = config.retain_discovery;
+            self.throttle_publish().await;
+            self.sender().send(Request::Publish(publish)).await?;
+            self.log_message(log_message).await;
+        }
+        Ok(true)
+    }
+
+    /// Publishes discovery for the hub device that every device is linked to, along with
+    /// the scenes that belong to it.
+    async fn publish_hub_discovery(&self) -> Result<(), Box<dyn Error>> {
+        let payloads = hub_discovery_payload(&self.config).into_iter().chain(
+            self.config
+                .scenes
+                .iter()
+                .filter_map(|scene| scene_discovery_payload(&self.config, scene)),
+        );
+        for (topic, info) in payloads {
+            self.publish_hub_discovery_message(topic, info).await?;
         }
+        Ok(())
+    }
+
+    async fn publish_hub_discovery_message(
+        &self,
+        topic: String,
+        info: serde_json::Value,
+    ) -> Result<(), Box<dyn Error>> {
+        let payload = info.to_string();
+        debug!(slog_scope::logger(), "broadcast_hub_discovery"; "topic" => &topic, "config" => &payload);
+        let log_message =
+            LoggedMessage::OutgoingMessage(topic.clone(), MaybeJsonString::new(&payload));
+        let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload);
+        publish.retain = self.config.retain_discovery;
+        self.throttle_publish().await;
+        self.sender().send(Request::Publish(publish)).await?;
+        self.log_message(log_message).await;
+        Ok(())
     }
 
     async fn broadcast_device_discovery_quiet(self: Arc<Self>, id: DeviceId) {
-        self.broadcast_device_discovery(id)
+        self.clone()
+            .broadcast_device_discovery(id)
             .await
-            .log_failing_result("broadcast_device_discovery_failed");
+            .record_failing_result(&self.stats, "broadcast_device_discovery");
     }
 
     async fn broadcast_discovery(self: Arc<Self>) -> () {
-        let devices = match self.controller.list().await {
+        if self.config.safe_mode {
+            info!(slog_scope::logger(), "safe_mode_skipping_discovery");
+            return ();
+        }
+        self.publish_hub_discovery()
+            .await
+            .record_failing_result(&self.stats, "publish_hub_discovery");
+        let devices = match self.visible_devices().await {
             Ok(v) => v,
             Err(e) => {
                 error!(slog_scope::logger(), "failed_to_list_devices"; "error" => ?e);
@@ -463,8 +2165,268 @@ impl<'a> DeviceSyncer {
 
         let futures = devices
             .into_iter()
-            .map(|d| self.clone().broadcast_device_discovery_quiet(d.id))
+            .map(|d| {
+                let this = self.clone();
+                async move {
+                    // Devices seen by an earlier poll (or run) don't need another describe.
+                    match this.registry.get(d.id).await {
+                        Some(device) => this.publish_device_discovery(&device).await,
+                        None => this.clone().broadcast_device_discovery(d.id).await,
+                    }
+                    .record_failing_result(&this.stats, "broadcast_device_discovery");
+                }
+            })
             .collect::<Vec<_>>();
         join_all(futures).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribute_overrides::AttributeOverridesController;
+    use crate::controller::{AttributeType, FakeController};
+    use crate::overrides::DeviceOverride;
+    use crate::test_utils;
+    use serde_json::json;
+
+    /// A syncer without background tasks that thinks it's connected, the requests it sends and
+    /// its repoll requests.
+    async fn test_syncer(
+        config: &Config,
+        controller: Arc<dyn DeviceController>,
+        registry: Arc<DeviceRegistry>,
+    ) -> (Arc<DeviceSyncer>, Receiver<Request>, Receiver<DeviceId>) {
+        let (sender, requests) = bounded(100);
+        let (syncer, repolls) = DeviceSyncer::with_sender(
+            config,
+            controller,
+            Arc::new(NotesStore::load(None).unwrap()),
+            registry,
+            Arc::new(Stats::new()),
+            sender,
+        );
+        syncer
+            .connection
+            .lock()
+            .await
+            .handle(ConnectionEvent::Subscribed);
+        (Arc::new(syncer), requests, repolls)
+    }
+
+    /// The topics of the empty retained messages sent since the last call.
+    fn cleared_topics(requests: &Receiver<Request>) -> Vec<String> {
+        let mut topics = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            if let Request::Publish(publish) = request {
+                if publish.retain && publish.payload.is_empty() {
+                    topics.push(publish.topic);
+                }
+            }
+        }
+        topics
+    }
+
+    fn short_device(id: DeviceId) -> ShortDevice {
+        ShortDevice {
+            id,
+            name: format!("Device {}", id),
+            interconnect: None,
+        }
+    }
+
+    #[test]
+    fn persists_hidden_devices() {
+        let dir = std::env::temp_dir().join(format!("wink-hidden-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(HIDDEN_DEVICES_FILE);
+
+        assert!(load_hidden_devices(Some(&path)).is_empty());
+        let hidden = vec![7, 3].into_iter().collect::<HashSet<DeviceId>>();
+        save_hidden_devices(Some(&path), &hidden).unwrap();
+        assert_eq!(hidden, load_hidden_devices(Some(&path)));
+
+        fs::write(&path, "garbage").unwrap();
+        assert!(load_hidden_devices(Some(&path)).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn light_state_only_for_lights() {
+        let config = test_utils::config();
+        let mut dimmer = test_utils::device(vec![DeviceAttribute {
+            current_value: AttributeValue::UInt8(40),
+            ..test_utils::attribute(2, "Level", AttributeType::UInt8)
+        }]);
+        let payload = device_status_payload(&config, &dimmer);
+        assert_eq!(json!("ON"), payload["state"]);
+        assert_eq!(json!(40), payload["brightness"]);
+
+        // A shade's Level is its position, not a brightness.
+        dimmer
+            .attributes
+            .push(test_utils::attribute(3, "Up_Down", AttributeType::String));
+        let payload = device_status_payload(&config, &dimmer);
+        assert_eq!(None, payload.get("state"));
+        assert_eq!(None, payload.get("brightness"));
+        assert_eq!(json!(40), payload["Level"]);
+    }
+
+    #[tokio::test]
+    async fn clears_removed_devices() {
+        let config = test_utils::config();
+        let registry = Arc::new(DeviceRegistry::load(None));
+        registry.record(&test_utils::device(vec![])).await;
+        let (syncer, requests, _repolls) =
+            test_syncer(&config, Arc::new(FakeController::new()), registry).await;
+
+        // Device 5 was removed while the bridge was down; only the registry remembers it.
+        syncer
+            .handle_device_list_changes(&[short_device(1), short_device(2)])
+            .await;
+        let cleared = cleared_topics(&requests);
+        assert!(cleared.contains(&"home/wink/5/status".to_string()));
+        assert!(!cleared.contains(&"home/wink/1/status".to_string()));
+
+        syncer.handle_device_list_changes(&[short_device(1)]).await;
+        let cleared = cleared_topics(&requests);
+        assert!(cleared.contains(&"home/wink/2/status".to_string()));
+        assert!(!cleared.contains(&"home/wink/1/status".to_string()));
+    }
+
+    #[tokio::test]
+    async fn outbox_keeps_only_unsent_messages() {
+        let config = test_utils::config();
+        let (syncer, requests, _repolls) = test_syncer(
+            &config,
+            Arc::new(FakeController::new()),
+            Arc::new(DeviceRegistry::load(None)),
+        )
+        .await;
+        let publish = |topic: &str, payload: &str| {
+            let mut publish = Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload);
+            publish.retain = true;
+            publish
+        };
+
+        // Queued while the event loop was full, then sent for real: don't resend the old one.
+        syncer.outbox.lock().await.insert(
+            "home/wink/1/status".into(),
+            publish("home/wink/1/status", "old"),
+        );
+        assert!(
+            syncer
+                .publish_or_queue(publish("home/wink/1/status", "new"))
+                .await
+        );
+        assert!(syncer.outbox.lock().await.is_empty());
+
+        // A failed flush keeps everything it didn't get to.
+        {
+            let mut outbox = syncer.outbox.lock().await;
+            outbox.insert(
+                "home/wink/1/status".into(),
+                publish("home/wink/1/status", "a"),
+            );
+            outbox.insert(
+                "home/wink/2/status".into(),
+                publish("home/wink/2/status", "b"),
+            );
+        }
+        drop(requests);
+        assert!(syncer.clone().flush_outbox().await.is_err());
+        assert_eq!(2, syncer.outbox.lock().await.len());
+    }
+
+    #[tokio::test]
+    async fn guarded_group_commands() {
+        let mut config = test_utils::config();
+        let controller = Arc::new(FakeController::new());
+        let (syncer, _requests, _repolls) = test_syncer(
+            &config,
+            controller.clone(),
+            Arc::new(DeviceRegistry::load(None)),
+        )
+        .await;
+        syncer
+            .set_group_attributes_json(1, br#"{"1": true}"#)
+            .await
+            .unwrap();
+        assert_eq!(
+            AttributeValue::Bool(true),
+            controller.describe(4).await.unwrap().attributes[0].current_value
+        );
+
+        // Device 4's On_Off is critical; the group might contain it.
+        config.critical = vec![(4, Some(1))];
+        let (syncer, _requests, _repolls) = test_syncer(
+            &config,
+            controller.clone(),
+            Arc::new(DeviceRegistry::load(None)),
+        )
+        .await;
+        assert!(syncer
+            .set_group_attributes_json(1, br#"{"1": false}"#)
+            .await
+            .is_err());
+        assert_eq!(
+            AttributeValue::Bool(true),
+            controller.describe(4).await.unwrap().attributes[0].current_value
+        );
+    }
+
+    #[tokio::test]
+    async fn reconciles_aliased_statuses() {
+        let config = test_utils::config();
+        config.set_overrides(
+            vec![(
+                4,
+                DeviceOverride {
+                    attribute_aliases: Some(
+                        vec![("On_Off".to_string(), "Power".to_string())]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    ..DeviceOverride::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let controller = Arc::new(AttributeOverridesController::new(
+            Arc::new(FakeController::new()),
+            &config,
+        ));
+        let (syncer, requests, _repolls) =
+            test_syncer(&config, controller, Arc::new(DeviceRegistry::load(None))).await;
+        *syncer.retained_statuses.lock().await = Some(HashMap::new());
+
+        // What the last run published is what the broker hands back.
+        syncer.clone().poll_device_(4).await.unwrap();
+        while let Ok(request) = requests.try_recv() {
+            if let Request::Publish(publish) = request {
+                if publish.topic == "home/wink/4/status" {
+                    syncer
+                        .record_retained_status(4, &publish.payload)
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+
+        syncer.clone().reconcile_retained_state().await.unwrap();
+        let mut report = None;
+        while let Ok(request) = requests.try_recv() {
+            if let Request::Publish(publish) = request {
+                if publish.topic == "home/wink/bridge/reconciliation" {
+                    report = Some(
+                        serde_json::from_slice::<serde_json::Value>(&publish.payload).unwrap(),
+                    );
+                }
+            }
+        }
+        let report = report.unwrap();
+        assert_eq!(json!([4]), report["matched"]);
+        assert_eq!(json!([]), report["mismatched"]);
+    }
+}
@@ -0,0 +1,54 @@
+//! Fixtures shared by the unit tests.
+use crate::config::Config;
+use crate::controller::{AttributeId, AttributeType, AttributeValue, DeviceAttribute, LongDevice};
+
+/// A readable and writable attribute without a value.
+pub fn attribute(
+    id: AttributeId,
+    description: &str,
+    attribute_type: AttributeType,
+) -> DeviceAttribute {
+    DeviceAttribute {
+        id,
+        description: description.to_string(),
+        attribute_type,
+        supports_write: true,
+        supports_read: true,
+        current_value: AttributeValue::NoValue,
+        setting_value: AttributeValue::NoValue,
+    }
+}
+
+/// Device 5, named "Strip", with nothing known about it beyond `attributes`.
+pub fn device(attributes: Vec<DeviceAttribute>) -> LongDevice {
+    LongDevice {
+        gang_id: None,
+        generic_device_type: None,
+        specific_device_type: None,
+        manufacturer_id: None,
+        product_type: None,
+        product_number: None,
+        interconnect: None,
+        battery_level: None,
+        wakeup_interval: None,
+        failed_tx: None,
+        seconds_since_rx: None,
+        polling_period: None,
+        id: 5,
+        status: "".to_string(),
+        name: "Strip".to_string(),
+        attributes,
+    }
+}
+
+/// Statuses under `home/wink/`, discovery under `homeassistant/`.
+pub fn config() -> Config {
+    Config::new(
+        None,
+        Some("home/wink/"),
+        Some("homeassistant/"),
+        None,
+        10,
+        None,
+    )
+}
@@ -0,0 +1,115 @@
+//! Attribute types the aprontest parser doesn't understand (e.g. new ones from newer
+//! firmware). Those attributes are skipped, but every type is recorded with a few sample lines so
+//! users can report them.
+use crate::controller::DeviceId;
+use crate::utils::unix_timestamp;
+use async_channel::Sender;
+use serde::Serialize;
+use slog::debug;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+const MAX_SAMPLES: usize = 3;
+
+lazy_static! {
+    pub static ref UNKNOWN_FORMATS: UnknownFormats = UnknownFormats::new();
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct UnknownFormat {
+    pub attribute_type: String,
+    pub count: u64,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub device_ids: BTreeSet<DeviceId>,
+    /// The first few aprontest lines with this type.
+    pub samples: Vec<String>,
+}
+
+pub struct UnknownFormats {
+    formats: Mutex<BTreeMap<String, UnknownFormat>>,
+    /// Notified with every format the first time it's seen (and when it gets new samples).
+    sink: Mutex<Option<Sender<Vec<UnknownFormat>>>>,
+}
+
+impl UnknownFormats {
+    pub fn new() -> UnknownFormats {
+        UnknownFormats {
+            formats: Mutex::new(BTreeMap::new()),
+            sink: Mutex::new(None),
+        }
+    }
+
+    pub fn set_sink(&self, sender: Sender<Vec<UnknownFormat>>) {
+        *self.sink.lock().unwrap_or_else(|e| e.into_inner()) = Some(sender);
+    }
+
+    pub fn record(&self, attribute_type: &str, device_id: DeviceId, line: &str) {
+        let now = unix_timestamp();
+        let snapshot = {
+            let mut formats = self.formats.lock().unwrap_or_else(|e| e.into_inner());
+            let format = formats
+                .entry(attribute_type.to_string())
+                .or_insert_with(|| UnknownFormat {
+                    attribute_type: attribute_type.to_string(),
+                    first_seen: now,
+                    ..UnknownFormat::default()
+                });
+            format.count += 1;
+            format.last_seen = now;
+            let new_device = format.device_ids.insert(device_id);
+            let new_sample =
+                format.samples.len() < MAX_SAMPLES && !format.samples.iter().any(|s| s == line);
+            if new_sample {
+                format.samples.push(line.to_string());
+            }
+            if !new_device && !new_sample {
+                return;
+            }
+            formats.values().cloned().collect::<Vec<_>>()
+        };
+        if let Some(sink) = self.sink.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            if let Err(e) = sink.try_send(snapshot) {
+                debug!(slog_scope::logger(), "dropped_unknown_formats"; "error" => ?e);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<UnknownFormat> {
+        self.formats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_samples() {
+        let formats = UnknownFormats::new();
+        let (sender, receiver) = async_channel::bounded(10);
+        formats.set_sink(sender);
+
+        formats.record("FLOAT", 3, "5 | Temperature | FLOAT | R | 21.5 |");
+        formats.record("FLOAT", 3, "5 | Temperature | FLOAT | R | 21.5 |");
+        formats.record("INT16", 4, "2 | Offset | INT16 | R/W | -3 | -3");
+
+        let snapshot = formats.snapshot();
+        assert_eq!(2, snapshot.len());
+        assert_eq!("FLOAT", snapshot[0].attribute_type);
+        assert_eq!(2, snapshot[0].count);
+        assert_eq!(1, snapshot[0].samples.len());
+        assert_eq!(
+            vec![4],
+            snapshot[1].device_ids.iter().cloned().collect::<Vec<_>>()
+        );
+
+        // Repeats of the same line don't notify.
+        assert_eq!(2, receiver.len());
+    }
+}
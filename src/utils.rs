@@ -1,8 +1,12 @@
+use crate::stats::Stats;
 use slog::{crit, debug, error, info, trace, warn, Level};
 use slog_scope;
 use std::convert::TryFrom;
+use std::fs;
 use std::num::ParseIntError;
+use std::path::Path;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub(crate) trait ResultExtensions<T, E> {
     fn log_failing_result_at(self, level: Level, message: &str) -> Option<T>
@@ -15,9 +19,20 @@ pub(crate) trait ResultExtensions<T, E> {
     {
         self.log_failing_result_at(Level::Warning, message)
     }
+    /// Logs a failure as `<operation>_failed`, and counts it under `operation` in `stats`.
+    fn record_failing_result(self, stats: &Stats, operation: &str) -> Option<T>
+    where
+        E: std::fmt::Debug;
 }
 
 impl<T, E: std::fmt::Debug> ResultExtensions<T, E> for Result<T, E> {
+    fn record_failing_result(self, stats: &Stats, operation: &str) -> Option<T> {
+        if self.is_err() {
+            stats.record_failure(operation);
+        }
+        self.log_failing_result(&format!("{}_failed", operation))
+    }
+
     fn log_failing_result_at(self, level: Level, message: &str) -> Option<T> {
         match self {
             Ok(v) => Some(v),
@@ -66,3 +81,53 @@ impl Numberish for str {
         }
     }
 }
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Writes to the side and renames, so a power cut never leaves a half written file behind.
+pub fn write_file_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// A token bucket that lets callers go into debt: `reserve` always takes a token and returns how
+/// long the caller should wait before acting on it.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - 1.0;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Event-driven updates: tails the hub's logs (aprond and friends log whenever a device reports
+//! in) and asks for a repoll of that device, so a switch flipped by hand shows up right away
+//! instead of on the next full poll.
+use crate::controller::DeviceId;
+use async_channel::Sender;
+use regex::Regex;
+use simple_error::bail;
+use slog::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Matches lines like `... masterId 12 ...` or `... device 12 ...`.
+pub const DEFAULT_WATCH_PATTERN: &str =
+    r"(?i)(?:master ?id|device(?: ?id)?|node(?: ?id)?)[ :=#]*(?P<id>\d+)";
+
+// The same device logs several lines per report; one repoll covers them all.
+const REPOLL_DEBOUNCE: Duration = Duration::from_secs(1);
+
+// How long to wait before restarting the command when it exits, e.g. on log rotation.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+pub struct Watcher {
+    command: String,
+    pattern: Regex,
+    last_repoll: HashMap<DeviceId, Instant>,
+}
+
+impl Watcher {
+    /// Runs `command` with `sh -c`; the pattern needs an `id` group for the device id.
+    pub fn new(command: &str, pattern: &str) -> Result<Watcher, Box<dyn Error>> {
+        let pattern = Regex::new(pattern)?;
+        if !pattern.capture_names().any(|name| name == Some("id")) {
+            bail!("The watch pattern needs an `id` group, e.g. (?P<id>\\d+)")
+        }
+        Ok(Watcher {
+            command: command.to_string(),
+            pattern,
+            last_repoll: HashMap::new(),
+        })
+    }
+
+    /// Follows new lines of a log file.
+    pub fn for_file(path: &str, pattern: &str) -> Result<Watcher, Box<dyn Error>> {
+        if path.contains('\'') {
+            bail!("Bad watch file {}", path)
+        }
+        Self::new(&format!("tail -n 0 -F '{}'", path), pattern)
+    }
+
+    fn device_id(&self, line: &str) -> Option<DeviceId> {
+        self.pattern
+            .captures(line)
+            .and_then(|m| m.name("id").unwrap().as_str().parse().ok())
+            // 0 would repoll every device.
+            .filter(|device_id| *device_id != 0)
+    }
+
+    /// The device to repoll for the line, unless it was just repolled.
+    fn repoll_for(&mut self, line: &str, now: Instant) -> Option<DeviceId> {
+        let device_id = self.device_id(line)?;
+        match self.last_repoll.get(&device_id) {
+            Some(last) if now.duration_since(*last) < REPOLL_DEBOUNCE => None,
+            _ => {
+                self.last_repoll.insert(device_id, now);
+                Some(device_id)
+            }
+        }
+    }
+
+    fn run_once(&mut self, sink: &Sender<DeviceId>) -> Result<(), Box<dyn Error>> {
+        let mut child = Command::new("sh")
+            .args(&["-c", &self.command])
+            .stdout(Stdio::piped())
+            .stdin(Stdio::null())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            if let Some(device_id) = self.repoll_for(&line, Instant::now()) {
+                debug!(slog_scope::logger(), "watched_device_reported"; "device_id" => device_id);
+                if sink.try_send(device_id).is_err() {
+                    debug!(slog_scope::logger(), "dropped_watched_repoll"; "device_id" => device_id);
+                }
+            }
+        }
+        let status = child.wait()?;
+        bail!("Watch command exited: {}", status)
+    }
+
+    /// Tails the logs for good on a blocking thread, restarting the command if it exits.
+    pub fn spawn(mut self, sink: Sender<DeviceId>) {
+        info!(slog_scope::logger(), "starting_watcher"; "command" => &self.command);
+        tokio::task::spawn_blocking(move || loop {
+            if let Err(e) = self.run_once(&sink) {
+                warn!(slog_scope::logger(), "watcher_stopped"; "error" => ?e);
+            }
+            if sink.is_closed() {
+                error!(slog_scope::logger(), "watcher_sink_closed");
+                return;
+            }
+            std::thread::sleep(RESTART_DELAY);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_ids() {
+        let mut watcher = Watcher::new("true", DEFAULT_WATCH_PATTERN).unwrap();
+        let now = Instant::now();
+        assert_eq!(
+            Some(12),
+            watcher.repoll_for("aprond[123]: ZWave report from masterId 12", now)
+        );
+        assert_eq!(
+            None,
+            watcher.repoll_for("aprond[123]: masterId: 12 attribute 1 changed", now)
+        );
+        assert_eq!(
+            Some(12),
+            watcher.repoll_for("masterId 12", now + Duration::from_secs(2))
+        );
+        assert_eq!(Some(4), watcher.repoll_for("Device 4 is ONLINE", now));
+        assert_eq!(None, watcher.repoll_for("zigbee: joined network", now));
+
+        assert!(Watcher::new("true", r"\d+").is_err());
+        assert!(Watcher::for_file("/var/log/messages", DEFAULT_WATCH_PATTERN).is_ok());
+    }
+}
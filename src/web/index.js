@@ -172,7 +172,25 @@ const AttributeControl = ({attribute, ...props}) => {
   }
 }
 
-const DeviceDetails = ({device, changeName, setAttribute}) => {
+const DeviceNotes = ({notes, changeNotes}) => {
+  return <div>
+    <div className="d-flex align-items-center">
+      <h2>Notes</h2>
+      <a className="mx-3" href="#" onClick={(e) => {
+        e.preventDefault();
+        const text = prompt('Notes', notes.notes || '');
+        if (text === null) { return; }
+        const labels = prompt('Labels (comma separated)', (notes.labels || []).join(', '));
+        if (labels === null) { return; }
+        changeNotes({notes: text, labels: labels.split(',').map(l => l.trim()).filter(l => l)});
+      }}><PencilIcon /></a>
+    </div>
+    {(notes.labels || []).map((l) => <span key={l} className="badge bg-info me-1">{l}</span>)}
+    <p>{notes.notes}</p>
+  </div>;
+}
+
+const DeviceDetails = ({device, notes, changeName, changeNotes, setAttribute}) => {
   const [editNameModal, setEditNameModal] = React.useState(false);
 
   const interestingAttr = findInterestingAttr(device);
@@ -192,6 +210,8 @@ const DeviceDetails = ({device, changeName, setAttribute}) => {
     :
     <h3>Unknown device type</h3>}
   <div className="p-3" />
+  <DeviceNotes notes={notes || {}} changeNotes={changeNotes} />
+  <div className="p-3" />
   <h2>All Attributes</h2>
   <table className="table">
     <thead>
@@ -216,6 +236,7 @@ const DeviceDetails = ({device, changeName, setAttribute}) => {
   <div className="p-3" />
   <h2>Full JSON Data</h2>
   <reactJsonView.default name="device" sortKeys={true} src={device} />
+  <a href={'/api/devices/' + device.id + '/raw'} target="_blank">Raw aprontest output</a>
   </div>
 }
 
@@ -236,9 +257,10 @@ const MqttLog = () => {
 const HomePage = ({device, setDevice}) => {
   const [deviceRefresh, setDeviceRefresh] = React.useState(0);
   const [devicesList, setDevicesList] = React.useState(null);
+  const [notes, setNotes] = React.useState({});
 
   React.useEffect(() => {
-    api('/api/devices').then(l => setDevicesList(l.devices));
+    api('/api/devices').then(l => { setDevicesList(l.devices); setNotes(l.notes || {}); });
   }, [deviceRefresh]);
 
   if (!devicesList) {
@@ -248,7 +270,12 @@ const HomePage = ({device, setDevice}) => {
   const foundDevice = device && devicesList.filter(e => e.id == device)[0]
   if (foundDevice) {
     return <DeviceDetails device={foundDevice}
+                          notes={notes[foundDevice.id]}
                           changeName={(newName) => alert('Not implemented')}
+                          changeNotes={(newNotes) => {
+                            api({url: '/api/devices/' + device + '/meta', method: 'PATCH', data: newNotes})
+                                .then((_) => {setDeviceRefresh(v => v + 1)} )
+                          }}
                           setAttribute={(attribute, value) => {
                             api({url: '/api/devices/' + device + '/' + attribute.id, data: {value_text: value}})
                                 .then((_) => {setDeviceRefresh(v => v + 1)} )
@@ -275,11 +302,13 @@ const AddDevice = () => {
                                 api({url: '/api/devices/discovery', data: data})
                                   .then(v => {
                                     setDiscoveryOutput(
-                                      '' + (v.status ? 'OK' : 'ERROR') + '\n\n' +
-                                      'Stdout:\n' + v.stdout + '\n\n' +
-                                      'Stderr:\n' + v.stderr
+                                      'Added: ' + (v.added.length ? v.added.map(d => d.name + ' (' + d.id + ')').join(', ') : 'nothing') + '\n\n' +
+                                      'Output:\n' + v.output
                                     );
                                   })
+                                  .catch(e => {
+                                    setDiscoveryOutput('ERROR\n\n' + e);
+                                  })
                                   .finally(v => {
                                     setDiscoveryPending(false);
                                   })